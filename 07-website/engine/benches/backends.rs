@@ -0,0 +1,68 @@
+//! Compares the stack and register backends on a few authorization-style
+//! policies. Run with `cargo bench -p cel-engine`.
+//!
+//! Both backends compile each policy once and then only run it, which is how
+//! an authorization server would use them. The stack machine binds its
+//! activation at compile time and has to be reset between runs; the register
+//! machine takes the activation on every run.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use cel_engine::{parse, register, stack, Activation, Value};
+
+const REQUEST: &str = r#"{
+    "request": {
+        "method": "GET",
+        "path": "/api/v2/users/alice/posts",
+        "auth": {"uid": "svc-frontend", "roles": ["reader", "writer"]},
+        "params": {"limit": 20, "offset": 40}
+    },
+    "x": 17,
+    "y": 5
+}"#;
+
+const POLICIES: &[(&str, &str)] = &[
+    (
+        "equality",
+        r#"request.auth.uid == "svc-frontend" && request.method == "GET""#,
+    ),
+    ("arithmetic", "(x * 3 + y) % 7 < 5 ? x - y : y * x - 2"),
+    (
+        "collections",
+        r#"size(request.auth.roles) >= 2 && has(request.params.limit) && request.params.limit <= 100"#,
+    ),
+    (
+        "strings",
+        r#"request.path.matches("^/api/v[0-9]+/") && request.auth.uid.startsWith("svc-")"#,
+    ),
+];
+
+fn activation() -> Activation {
+    match serde_json::from_str(REQUEST).unwrap() {
+        Value::Map(kvs) => kvs,
+        other => panic!("not a map: {:?}", other),
+    }
+}
+
+fn backends(c: &mut Criterion) {
+    let activation = activation();
+    for &(name, source) in POLICIES {
+        let expr = parse(source).unwrap();
+        let mut group = c.benchmark_group(name);
+        let mut program = stack::compile_with(expr.clone(), activation.clone()).unwrap();
+        group.bench_function("stack", |b| {
+            b.iter(|| {
+                program.reset();
+                black_box(program.run().clone())
+            })
+        });
+        let mut program = register::compile(expr.clone()).unwrap();
+        group.bench_function("register", |b| {
+            b.iter(|| black_box(program.run(black_box(&activation))))
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, backends);
+criterion_main!(benches);
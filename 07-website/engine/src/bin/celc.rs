@@ -0,0 +1,28 @@
+use std::{env, fs, process};
+
+use cel_engine::{stack, wasm};
+
+/// Compiles an expression to a standalone wasm module (see `cel_engine::wasm`
+/// for its ABI) and prints the variables its input records are for, in order.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <expression> <output.wasm>", args[0]);
+        process::exit(2);
+    }
+    let module = cel_engine::parse(&args[1])
+        .map_err(|e| e.to_string())
+        .and_then(|expr| stack::compile(expr).map_err(|e| e.to_string()))
+        .and_then(|program| wasm::compile(&program).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+    if let Err(e) = fs::write(&args[2], &module.bytes) {
+        eprintln!("{}: {}", args[2], e);
+        process::exit(1);
+    }
+    for (i, id) in module.variables.iter().enumerate() {
+        println!("{}\t{}", i, id.0);
+    }
+}
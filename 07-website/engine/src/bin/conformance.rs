@@ -0,0 +1,26 @@
+use std::path::PathBuf;
+use std::{env, process};
+
+use cel_engine::conformance;
+
+/// Runs the conformance tests in the given directory (by default the ones
+/// vendored under `engine/conformance`) and prints a summary per section.
+/// Exits with 1 if anything failed that isn't a known gap.
+fn main() {
+    let dir = env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("conformance"));
+    let reports = conformance::run_dir(&dir).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let mut unexpected = false;
+    for report in &reports {
+        print!("{}", report);
+        unexpected |= report.is_unexpected();
+    }
+    if unexpected {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,51 @@
+use std::path::Path;
+use std::{env, fs, process};
+
+use prost_reflect::{DescriptorPool, DynamicMessage};
+
+const MESSAGE: &str = "cel.expr.conformance.test.SimpleTestFile";
+
+/// Converts cel-spec's simple tests from text format to the proto3 JSON that
+/// `src/conformance.rs` reads, whole files at a time:
+///
+///     conformance_data DESCRIPTORS TESTDATA OUT NAME...
+///
+/// reads `TESTDATA/NAME.textproto` and writes `OUT/NAME.json` for each name.
+/// `DESCRIPTORS` is a descriptor set that includes `SimpleTestFile`; the
+/// `conformance-data` recipe in the justfile makes one.
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 4 {
+        eprintln!("usage: conformance_data DESCRIPTORS TESTDATA OUT NAME...");
+        process::exit(2);
+    }
+    if let Err(e) = convert(
+        &args[0],
+        Path::new(&args[1]),
+        Path::new(&args[2]),
+        &args[3..],
+    ) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn convert(descriptors: &str, testdata: &Path, out: &Path, names: &[String]) -> Result<(), String> {
+    let bytes = fs::read(descriptors).map_err(|e| format!("{}: {}", descriptors, e))?;
+    let pool =
+        DescriptorPool::decode(bytes.as_slice()).map_err(|e| format!("{}: {}", descriptors, e))?;
+    let message = pool
+        .get_message_by_name(MESSAGE)
+        .ok_or_else(|| format!("{}: no {}", descriptors, MESSAGE))?;
+    for name in names {
+        let input = testdata.join(format!("{}.textproto", name));
+        let text = fs::read_to_string(&input).map_err(|e| format!("{}: {}", input.display(), e))?;
+        let file = DynamicMessage::parse_text_format(message.clone(), &text)
+            .map_err(|e| format!("{}: {}", input.display(), e))?;
+        let json = serde_json::to_string_pretty(&file)
+            .map_err(|e| format!("{}: {}", input.display(), e))?;
+        let output = out.join(format!("{}.json", name));
+        fs::write(&output, json + "\n").map_err(|e| format!("{}: {}", output.display(), e))?;
+    }
+    Ok(())
+}
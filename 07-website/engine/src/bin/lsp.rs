@@ -0,0 +1,11 @@
+use std::io;
+use std::process;
+
+use cel_engine::lsp;
+
+fn main() -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let code = lsp::run(&mut stdin.lock(), &mut stdout.lock())?;
+    process::exit(code)
+}
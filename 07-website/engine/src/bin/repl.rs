@@ -0,0 +1,18 @@
+use std::io::{self, BufRead, Write};
+
+use cel_engine::repl::Repl;
+
+fn main() -> io::Result<()> {
+    let mut repl = Repl::new();
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let mut line = String::new();
+    loop {
+        write!(stdout, "cel> ")?;
+        stdout.flush()?;
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 || !repl.handle(&line, &mut stdout)? {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,390 @@
+//! Runs the simple tests from the CEL spec (`tests/simple/testdata` in
+//! github.com/google/cel-spec) through the parser and the stack machine,
+//! driven by `src/bin/conformance.rs`.
+//!
+//! Test files are read in the proto3 JSON form of
+//! `cel.expr.conformance.test.SimpleTestFile`, one per `.json` file in a
+//! directory. Failures listed in that directory's `known_gaps.txt` (one
+//! `file/section/test` per line) are reported as known gaps rather than
+//! failures, and a listed test that passes is reported as fixed, so that the
+//! list only ever shrinks. The suite in `conformance/` is a subset of the
+//! spec's, see its README for where it came from and under what license.
+//!
+//! Expected errors are only compared by their presence: the spec's error
+//! messages are those of the Go and C++ implementations. Tests expecting
+//! anything other than a value or an error (types, unknowns) are skipped.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json as json;
+
+use crate::model::{Activation, Kind, Value};
+use crate::parser;
+use crate::stack;
+
+#[derive(Debug, Deserialize)]
+pub struct SimpleTestFile {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub section: Vec<SimpleTestSection>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimpleTestSection {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub test: Vec<SimpleTest>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimpleTest {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub expr: String,
+    /// `cel.expr.ExprValue`s by variable name.
+    #[serde(default)]
+    pub bindings: HashMap<String, json::Value>,
+    /// A `cel.expr.Value`.
+    pub value: Option<json::Value>,
+    /// A `cel.expr.ErrorSet`.
+    pub eval_error: Option<json::Value>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    /// A failure listed in the allowlist.
+    KnownGap(String),
+    /// A pass listed in the allowlist, which should be taken off it.
+    Fixed,
+    Skip(String),
+}
+
+impl Outcome {
+    /// Whether this outcome should fail a conformance run.
+    pub fn is_unexpected(&self) -> bool {
+        matches!(self, Outcome::Fail(_) | Outcome::Fixed)
+    }
+}
+
+/// The tests that are known to fail, as `file/section/test`.
+#[derive(Debug, Default)]
+pub struct Allowlist(HashSet<String>);
+
+impl Allowlist {
+    /// Reads one entry per line, ignoring blank lines and `#` comments.
+    pub fn parse(text: &str) -> Allowlist {
+        let entries = text
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .map(str::to_owned);
+        Allowlist(entries.collect())
+    }
+
+    pub fn contains(&self, file: &str, section: &str, test: &str) -> bool {
+        self.0.contains(&format!("{}/{}/{}", file, section, test))
+    }
+}
+
+#[derive(Debug)]
+pub struct FileReport {
+    pub name: String,
+    pub sections: Vec<SectionReport>,
+}
+
+#[derive(Debug)]
+pub struct SectionReport {
+    pub name: String,
+    pub outcomes: Vec<(String, Outcome)>,
+}
+
+impl FileReport {
+    pub fn is_unexpected(&self) -> bool {
+        self.sections
+            .iter()
+            .flat_map(|s| &s.outcomes)
+            .any(|(_, outcome)| outcome.is_unexpected())
+    }
+}
+
+impl fmt::Display for FileReport {
+    /// One line of counts per section, followed by anything unexpected.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.name)?;
+        for section in &self.sections {
+            let mut counts = [0; 4];
+            for (_, outcome) in &section.outcomes {
+                counts[match outcome {
+                    Outcome::Pass => 0,
+                    Outcome::Fail(_) | Outcome::Fixed => 1,
+                    Outcome::KnownGap(_) => 2,
+                    Outcome::Skip(_) => 3,
+                }] += 1;
+            }
+            writeln!(
+                f,
+                "  {}: {} passed, {} failed, {} known gaps, {} skipped",
+                section.name, counts[0], counts[1], counts[2], counts[3]
+            )?;
+            for (test, outcome) in &section.outcomes {
+                match outcome {
+                    Outcome::Fail(reason) => writeln!(f, "    FAIL {}: {}", test, reason)?,
+                    Outcome::Fixed => {
+                        writeln!(f, "    FIXED {}: remove it from the allowlist", test)?
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs every `.json` file in `dir`, in name order, against the allowlist in
+/// `dir/known_gaps.txt` if there is one.
+pub fn run_dir(dir: &Path) -> Result<Vec<FileReport>, String> {
+    let read =
+        |path: &Path| fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e));
+    let allowlist = match dir.join("known_gaps.txt") {
+        path if path.exists() => Allowlist::parse(&read(&path)?),
+        _ => Allowlist::default(),
+    };
+    let mut paths = fs::read_dir(dir)
+        .map_err(|e| format!("{}: {}", dir.display(), e))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", dir.display(), e))?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "json"));
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| {
+            let file =
+                json::from_str(&read(path)?).map_err(|e| format!("{}: {}", path.display(), e))?;
+            Ok(run(&file, &allowlist))
+        })
+        .collect()
+}
+
+pub fn run(file: &SimpleTestFile, allowlist: &Allowlist) -> FileReport {
+    let sections = file
+        .section
+        .iter()
+        .map(|section| SectionReport {
+            name: section.name.clone(),
+            outcomes: section
+                .test
+                .iter()
+                .map(|test| {
+                    let allowed = allowlist.contains(&file.name, &section.name, &test.name);
+                    let outcome = match check(test) {
+                        Outcome::Pass if allowed => Outcome::Fixed,
+                        Outcome::Fail(reason) if allowed => Outcome::KnownGap(reason),
+                        outcome => outcome,
+                    };
+                    (test.name.clone(), outcome)
+                })
+                .collect(),
+        })
+        .collect();
+    FileReport {
+        name: file.name.clone(),
+        sections,
+    }
+}
+
+/// Runs a single test, ignoring the allowlist.
+pub fn check(test: &SimpleTest) -> Outcome {
+    let expected = match (&test.value, &test.eval_error) {
+        (Some(value), _) => match from_json(value) {
+            Ok(value) => Some(value),
+            Err(e) => return Outcome::Skip(e),
+        },
+        (None, Some(_)) => None,
+        (None, None) => return Outcome::Skip("expects neither a value nor an error".to_owned()),
+    };
+    let mut activation = Activation::new();
+    for (name, binding) in &test.bindings {
+        match binding.get("value").map(from_json) {
+            Some(Ok(value)) => activation.insert(name.clone(), value),
+            Some(Err(e)) => return Outcome::Skip(e),
+            None => return Outcome::Skip(format!("{} is not bound to a value", name)),
+        };
+    }
+
+    let expr = match parser::parse(&test.expr) {
+        Ok(expr) => expr,
+        Err(e) => return Outcome::Fail(format!("parse error: {}", e)),
+    };
+    // Compile errors (e.g. a constant invalid regex) are evaluation errors as
+    // far as the spec is concerned.
+    let actual = stack::compile_with(expr, activation)
+        .map_err(|e| e.to_string())
+        .and_then(|mut program| program.run().clone().map_err(|e| format!("{:?}", e)));
+    match (expected, actual) {
+        (Some(want), Ok(got)) if want == got => Outcome::Pass,
+        (Some(want), Ok(got)) => Outcome::Fail(format!("expected {}, got {}", want, got)),
+        (Some(want), Err(e)) => Outcome::Fail(format!("expected {}, got {}", want, e)),
+        (None, Err(_)) => Outcome::Pass,
+        (None, Ok(got)) => Outcome::Fail(format!("expected an error, got {}", got)),
+    }
+}
+
+/// Converts the JSON form of a `cel.expr.Value`.
+fn from_json(value: &json::Value) -> Result<Value, String> {
+    let (kind, v) = match value.as_object() {
+        Some(fields) if fields.len() == 1 => fields.iter().next().unwrap(),
+        _ => return Err(format!("malformed value {}", value)),
+    };
+    let malformed = || format!("malformed {} {}", kind, v);
+    // 64-bit integers are strings in proto3 JSON, but numbers are accepted too.
+    let number = || match v {
+        json::Value::String(s) => Some(s.as_str()),
+        _ => None,
+    };
+    Ok(match kind.as_str() {
+        "nullValue" => Value::Null,
+        "boolValue" => Value::Bool(v.as_bool().ok_or_else(malformed)?),
+        "int64Value" => Value::I64(
+            v.as_i64()
+                .or_else(|| number()?.parse().ok())
+                .ok_or_else(malformed)?,
+        ),
+        "uint64Value" => Value::U64(
+            v.as_u64()
+                .or_else(|| number()?.parse().ok())
+                .ok_or_else(malformed)?,
+        ),
+        "doubleValue" => Value::F64(match number() {
+            Some("NaN") => f64::NAN,
+            Some("Infinity") => f64::INFINITY,
+            Some("-Infinity") => f64::NEG_INFINITY,
+            _ => v.as_f64().ok_or_else(malformed)?,
+        }),
+        "stringValue" => Value::String(v.as_str().ok_or_else(malformed)?.to_owned()),
+        "bytesValue" => Value::Bytes(v.as_str().and_then(decode_base64).ok_or_else(malformed)?),
+        "typeValue" => Value::Type(
+            v.as_str()
+                .and_then(Kind::from_type_name)
+                .ok_or_else(|| format!("unsupported type {}", v))?,
+        ),
+        "listValue" => Value::List(match v.get("values") {
+            Some(json::Value::Array(vs)) => vs.iter().map(from_json).collect(),
+            None => Ok(Vec::new()),
+            Some(_) => Err(malformed()),
+        }?),
+        "mapValue" => {
+            let entries = match v.get("entries") {
+                Some(json::Value::Array(entries)) => entries.as_slice(),
+                None => &[],
+                Some(_) => return Err(malformed()),
+            };
+            let mut kvs = HashMap::new();
+            for entry in entries {
+                let key = match entry.get("key").map(from_json) {
+                    Some(Ok(Value::String(key))) => key,
+                    Some(Ok(key)) => return Err(format!("unsupported {:?} map key", key.kind())),
+                    Some(Err(e)) => return Err(e),
+                    None => return Err(malformed()),
+                };
+                kvs.insert(key, from_json(entry.get("value").ok_or_else(malformed)?)?);
+            }
+            Value::Map(kvs)
+        }
+        _ => return Err(format!("unsupported {}", kind)),
+    })
+}
+
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        acc = acc << 6 | u32::from(sextet);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vendored_suite() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("conformance");
+        let reports = run_dir(&dir).unwrap();
+        assert!(!reports.is_empty());
+        for report in reports {
+            assert!(!report.is_unexpected(), "{}", report);
+        }
+    }
+
+    #[test]
+    fn allowlist() {
+        let file: SimpleTestFile = json::from_str(
+            r#"{"name": "f", "section": [{"name": "s", "test": [
+                {"name": "ok", "expr": "x + 1", "bindings": {"x": {"value": {"int64Value": "1"}}},
+                 "value": {"int64Value": "2"}},
+                {"name": "gap", "expr": "1 / 0", "value": {"int64Value": "0"}},
+                {"name": "fixed", "expr": "1 / 0", "evalError": {}},
+                {"name": "fail", "expr": "[b'\\xff']", "value": {"listValue": {}}},
+                {"name": "skip", "expr": "1", "value": {"objectValue": {}}}
+            ]}]}"#,
+        )
+        .unwrap();
+        let allowlist = Allowlist::parse("# comment\nf/s/gap\n\nf/s/fixed  # fixed since\n");
+        let outcomes = &run(&file, &allowlist).sections[0].outcomes;
+        assert_eq!(outcomes[0].1, Outcome::Pass);
+        assert!(matches!(outcomes[1].1, Outcome::KnownGap(_)));
+        assert_eq!(outcomes[2].1, Outcome::Fixed);
+        assert!(matches!(outcomes[3].1, Outcome::Fail(_)));
+        assert!(matches!(outcomes[4].1, Outcome::Skip(_)));
+    }
+
+    #[test]
+    fn values() {
+        let value = json::json!({"mapValue": {"entries": [
+            {"key": {"stringValue": "k"}, "value": {"listValue": {"values": [
+                {"uint64Value": "7"}, {"bytesValue": "AP8="}, {"doubleValue": "-Infinity"}
+            ]}}}
+        ]}});
+        let mut expected = HashMap::new();
+        expected.insert(
+            "k".to_owned(),
+            Value::List(vec![
+                Value::U64(7),
+                Value::Bytes(vec![0, 255]),
+                Value::F64(f64::NEG_INFINITY),
+            ]),
+        );
+        assert_eq!(from_json(&value), Ok(Value::Map(expected)));
+        assert!(from_json(&json::json!({"mapValue": {"entries": [
+            {"key": {"int64Value": "1"}, "value": {"nullValue": null}}
+        ]}}))
+        .is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! A CEL engine with no browser dependencies: it builds natively as well as for
+//! wasm32, and is what the playground in the parent crate runs on.
+//!
+//! The functions here cover the usual pipeline from source text to a value.
+//! For anything finer-grained (spans, stepping through a program, message
+//! types) use the modules directly.
+
+use std::fmt;
+
+pub mod conformance;
+pub mod lsp;
+pub mod model;
+pub mod parser;
+pub mod partial;
+pub mod policy;
+pub mod reference;
+pub mod register;
+pub mod repl;
+pub mod stack;
+pub mod wasm;
+
+pub use model::{Activation, Expression, Kind, Value};
+pub use parser::{parse, ParseError};
+pub use stack::{CompileError, Program};
+
+/// Anything that can go wrong between source text and a value.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Parse(ParseError),
+    Compile(CompileError),
+    Eval(model::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(e) => write!(f, "{}", e),
+            Error::Compile(e) => write!(f, "{}", e),
+            Error::Eval(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<CompileError> for Error {
+    fn from(e: CompileError) -> Self {
+        Error::Compile(e)
+    }
+}
+
+impl From<model::Error> for Error {
+    fn from(e: model::Error) -> Self {
+        Error::Eval(e)
+    }
+}
+
+/// Reports whether `source` would compile, without evaluating it.
+pub fn check(source: &str) -> Result<(), Error> {
+    stack::walker::linearize(parse(source)?)?;
+    Ok(())
+}
+
+/// Compiles `source` into a program whose free identifiers are looked up in `activation`.
+pub fn compile(source: &str, activation: Activation) -> Result<Program, Error> {
+    Ok(stack::compile_with(parse(source)?, activation)?)
+}
+
+/// The machines a compiled expression can run on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// `stack::Program`, which can be stepped through and back, as the
+    /// playground does.
+    Stack,
+    /// `register::Program`, which only runs straight to the result, but faster.
+    Register,
+}
+
+/// A compiled expression, along with the activation it runs against.
+#[derive(Debug)]
+pub enum Executable {
+    Stack(Program),
+    Register(register::Program, Activation),
+}
+
+impl Executable {
+    pub fn run(&mut self) -> Result<Value, Error> {
+        let result = match self {
+            Executable::Stack(program) => program.run().clone(),
+            Executable::Register(program, activation) => program.run(activation),
+        };
+        Ok(result?)
+    }
+}
+
+/// Like `compile`, for the given backend.
+pub fn compile_for(
+    source: &str,
+    activation: Activation,
+    backend: Backend,
+) -> Result<Executable, Error> {
+    let expr = parse(source)?;
+    Ok(match backend {
+        Backend::Stack => Executable::Stack(stack::compile_with(expr, activation)?),
+        Backend::Register => Executable::Register(register::compile(expr)?, activation),
+    })
+}
+
+/// Compiles and runs `source` against `activation`.
+pub fn evaluate(source: &str, activation: Activation) -> Result<Value, Error> {
+    let mut program = compile(source, activation)?;
+    Ok(program.run().clone()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pipeline() {
+        let mut activation = Activation::new();
+        activation.insert("x".to_owned(), Value::I64(40));
+        assert_eq!(evaluate("x + 2", activation), Ok(Value::I64(42)));
+        assert_eq!(check(r#" "a".matches("b") "#), Ok(()));
+    }
+
+    #[test]
+    fn backends() {
+        let mut activation = Activation::new();
+        activation.insert("x".to_owned(), Value::I64(40));
+        for &backend in &[Backend::Stack, Backend::Register] {
+            let mut executable = compile_for("x + 2", activation.clone(), backend).unwrap();
+            assert_eq!(executable.run(), Ok(Value::I64(42)));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(check("1 +"), Err(Error::Parse(_))));
+        assert!(matches!(
+            check(r#" "a".matches("(") "#),
+            Err(Error::Compile(CompileError::InvalidRegex(_, _)))
+        ));
+        assert_eq!(
+            evaluate("x", Activation::new()),
+            Err(Error::Eval(model::Error::NoSuchBinding(
+                model::Identifier::new("x")
+            )))
+        );
+        assert_eq!(
+            evaluate("x", Activation::new()).unwrap_err().to_string(),
+            "no such variable x"
+        );
+    }
+}
@@ -0,0 +1,762 @@
+//! A language server for CEL expressions, driven over stdio by
+//! `src/bin/lsp.rs`.
+//!
+//! Every open document is one expression, optionally preceded by `let`
+//! bindings. The server keeps the text of each open document and reparses it
+//! whenever it needs to: to publish diagnostics after every change, and to
+//! answer hover, completion and formatting requests. Documents are synced in
+//! full, and positions count UTF-16 code units, as the protocol does by
+//! default.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value as Json};
+
+use crate::model::{Expression, Identifier, Kind, Literal};
+use crate::parser::tokens::{tokens, Token, TokenKind};
+use crate::parser::{self, Span};
+use crate::stack::{strings, walker};
+
+/// The functions the engine implements, and the `has` macro.
+const FUNCTIONS: &[&str] = &[
+    "bool", "bytes", "double", "dyn", "has", "int", "matches", "size", "string", "type", "uint",
+];
+/// Methods on anything but strings; see `strings::METHODS` for the rest.
+const METHODS: &[&str] = &["get", "hasValue", "join", "matches", "orValue", "value"];
+/// Type names that aren't also conversion functions.
+const TYPES: &[&str] = &["list", "map", "null_type", "optional_type"];
+const KEYWORDS: &[&str] = &["false", "let", "null", "true"];
+
+// `CompletionItemKind`s.
+const COMPLETE_METHOD: u8 = 2;
+const COMPLETE_FUNCTION: u8 = 3;
+const COMPLETE_VARIABLE: u8 = 6;
+const COMPLETE_TYPE: u8 = 7;
+const COMPLETE_KEYWORD: u8 = 14;
+
+// JSON-RPC error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_NOT_INITIALIZED: i64 = -32002;
+
+/// The largest message body `read_message` accepts, so that a bad
+/// `Content-Length` can't make it allocate arbitrarily much.
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+type RequestResult = Result<Json, (i64, String)>;
+
+/// The let bindings in scope, innermost last, with the kinds of their values.
+type Scope<'a> = Vec<(&'a Identifier, Option<Kind>)>;
+
+#[derive(Debug, Default)]
+pub struct Server {
+    documents: HashMap<String, String>,
+    initialized: bool,
+    shutdown: bool,
+    exit_code: Option<i32>,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// Set once the client has sent `exit`: 0 if it asked the server to shut
+    /// down first, 1 otherwise.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Handles one message from the client, returning the messages to send
+    /// back: the response if it was a request, and any notifications.
+    pub fn handle(&mut self, message: Json) -> Vec<Json> {
+        let method = match message["method"].as_str() {
+            Some(method) => method,
+            // A response to a request of ours, and we don't make any.
+            None => return vec![],
+        };
+        let params = &message["params"];
+        match message.get("id") {
+            None => self.notify(method, params),
+            Some(id) => vec![match self.request(method, params) {
+                Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                Err((code, msg)) => error_response(id.clone(), code, &msg),
+            }],
+        }
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> RequestResult {
+        if method == "initialize" {
+            self.initialized = true;
+            return Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "completionProvider": {"triggerCharacters": ["."]},
+                    "documentFormattingProvider": true,
+                },
+                "serverInfo": {"name": "cel-lsp", "version": env!("CARGO_PKG_VERSION")},
+            }));
+        }
+        if !self.initialized {
+            return Err((SERVER_NOT_INITIALIZED, "not initialized yet".to_owned()));
+        }
+        if self.shutdown {
+            return Err((INVALID_REQUEST, "shutting down".to_owned()));
+        }
+        match method {
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            }
+            "textDocument/hover" => {
+                let (text, offset) = self.position(params)?;
+                Ok(hover(text, offset))
+            }
+            "textDocument/completion" => {
+                let (text, offset) = self.position(params)?;
+                Ok(completion(text, offset))
+            }
+            "textDocument/formatting" => Ok(formatting(self.document(params)?)),
+            _ => Err((METHOD_NOT_FOUND, format!("no method {}", method))),
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        if method == "exit" {
+            self.exit_code = Some(if self.shutdown { 0 } else { 1 });
+            return vec![];
+        }
+        if !self.initialized {
+            return vec![];
+        }
+        let uri = match params["textDocument"]["uri"].as_str() {
+            Some(uri) => uri.to_owned(),
+            None => return vec![],
+        };
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+            "textDocument/didChange" => params["contentChanges"]
+                .as_array()
+                .and_then(|changes| changes.last())
+                .and_then(|change| change["text"].as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec![publish_diagnostics(&uri, vec![])];
+            }
+            _ => return vec![],
+        };
+        match text {
+            Some(text) => {
+                let diagnostics = diagnostics(text);
+                self.documents.insert(uri.clone(), text.to_owned());
+                vec![publish_diagnostics(&uri, diagnostics)]
+            }
+            None => vec![],
+        }
+    }
+
+    fn document(&self, params: &Json) -> Result<&str, (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        self.documents
+            .get(uri)
+            .map(String::as_str)
+            .ok_or_else(|| (INVALID_PARAMS, format!("{} isn't open", uri)))
+    }
+
+    /// The text of the document in `params`, and the byte offset of the
+    /// position in it.
+    fn position(&self, params: &Json) -> Result<(&str, usize), (i64, String)> {
+        let text = self.document(params)?;
+        let line = params["position"]["line"].as_u64();
+        let character = params["position"]["character"].as_u64();
+        match (line, character) {
+            (Some(line), Some(character)) => {
+                Ok((text, offset(text, line as usize, character as usize)))
+            }
+            _ => Err((INVALID_PARAMS, "missing position".to_owned())),
+        }
+    }
+}
+
+/// Serves the client on the other end of `input` and `output` until it sends
+/// `exit`, returning the code the process should exit with.
+pub fn run(input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<i32> {
+    let mut server = Server::new();
+    while let Some(message) = read_message(input)? {
+        let replies = match message {
+            Ok(message) => server.handle(message),
+            Err(e) => vec![error_response(Json::Null, PARSE_ERROR, &e)],
+        };
+        for reply in &replies {
+            write_message(output, reply)?;
+        }
+        if let Some(code) = server.exit_code() {
+            return Ok(code);
+        }
+    }
+    // The client went away without asking us to exit.
+    Ok(1)
+}
+
+/// Reads one `Content-Length`-framed message, or `None` at the end of the
+/// input. A body that isn't JSON is returned as an error message, so that it
+/// can be answered; broken framing fails the read.
+pub fn read_message(input: &mut dyn BufRead) -> io::Result<Option<Result<Json, String>>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+    let mut length = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                let n = value.trim().parse::<usize>();
+                length = Some(n.map_err(|_| invalid("invalid Content-Length"))?);
+            }
+        }
+    }
+    let length = length.ok_or_else(|| invalid("missing Content-Length"))?;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(invalid("Content-Length too large"));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(
+        serde_json::from_slice(&body).map_err(|e| e.to_string()),
+    ))
+}
+
+pub fn write_message(output: &mut dyn Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+fn error_response(id: Json, code: i64, message: &str) -> Json {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Json>) -> Json {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": {"uri": uri, "diagnostics": diagnostics},
+    })
+}
+
+/// What is wrong with `text`: the parse error, or else the compile error, if any.
+fn diagnostics(text: &str) -> Vec<Json> {
+    let (message, span) = match parser::parse_with_spans(text) {
+        Err(e) => (e.to_string(), e.span(text)),
+        Ok((expr, spans)) => match walker::linearize(expr) {
+            Ok(_) => return vec![],
+            Err(e) => (e.to_string(), spans.get(e.node()).copied()),
+        },
+    };
+    // Errors about the input as a whole, like exceeding a limit, go at the start.
+    let span = span.unwrap_or(Span { start: 0, end: 0 });
+    vec![json!({
+        "range": range(text, span),
+        "severity": 1,
+        "source": "cel",
+        "message": message,
+    })]
+}
+
+/// The kind of the innermost expression at `offset`, or `null` if there isn't
+/// one, or the document doesn't parse.
+fn hover(text: &str, offset: usize) -> Json {
+    let (expr, spans) = match parser::parse_with_spans(text) {
+        Ok(parsed) => parsed,
+        Err(_) => return Json::Null,
+    };
+    let mut scope = Vec::new();
+    match node_at(&expr, &spans, offset, &mut scope) {
+        Some((e, span)) => {
+            let kind = infer(e, &mut scope).map_or("dyn", |kind| kind.type_name());
+            json!({
+                "contents": {"kind": "markdown", "value": format!("`{}`", kind)},
+                "range": range(text, span),
+            })
+        }
+        None => Json::Null,
+    }
+}
+
+/// The innermost node of `e` whose span contains `offset`, with its span,
+/// leaving the let bindings in scope there in `scope`. `spans` are those of
+/// `e` and its descendants, in pre-order. Over the name of a let binding,
+/// that's the value it is bound to.
+fn node_at<'a>(
+    e: &'a Expression,
+    spans: &[Span],
+    offset: usize,
+    scope: &mut Scope<'a>,
+) -> Option<(&'a Expression, Span)> {
+    if offset < spans[0].start || offset > spans[0].end {
+        return None;
+    }
+    if let Expression::LetBinding { id, value, body } = e {
+        let n = value.node_count();
+        if offset < spans[1].start {
+            return Some((value, spans[1]));
+        }
+        if let Some(found) = node_at(value, &spans[1..=n], offset, scope) {
+            return Some(found);
+        }
+        let kind = infer(value, scope);
+        scope.push((id, kind));
+        if let Some(found) = node_at(body, &spans[1 + n..], offset, scope) {
+            return Some(found);
+        }
+        scope.pop();
+        return Some((e, spans[0]));
+    }
+    let mut lo = 1;
+    for child in e.children() {
+        let n = child.node_count();
+        if let Some(found) = node_at(child, &spans[lo..lo + n], offset, scope) {
+            return Some(found);
+        }
+        lo += n;
+    }
+    Some((e, spans[0]))
+}
+
+/// The kind of value `e` evaluates to when it doesn't fail, if that doesn't
+/// depend on the activation.
+fn infer<'a>(e: &'a Expression, scope: &mut Scope<'a>) -> Option<Kind> {
+    match e {
+        Expression::LetBinding { id, value, body } => {
+            let kind = infer(value, scope);
+            scope.push((id, kind));
+            let kind = infer(body, scope);
+            scope.pop();
+            kind
+        }
+        Expression::Ternary {
+            true_branch,
+            false_branch,
+            ..
+        } => match (infer(true_branch, scope), infer(false_branch, scope)) {
+            (Some(a), Some(b)) if a == b => Some(a),
+            _ => None,
+        },
+        Expression::Or(_)
+        | Expression::And(_)
+        | Expression::Eq(_, _)
+        | Expression::Neq(_, _)
+        | Expression::Lt(_, _)
+        | Expression::Lte(_, _)
+        | Expression::Gte(_, _)
+        | Expression::Gt(_, _)
+        | Expression::Not(_)
+        | Expression::Has(_, _) => Some(Kind::Bool),
+        // Arithmetic never mixes kinds, so one known operand is enough.
+        Expression::Add(a, b)
+        | Expression::Sub(a, b)
+        | Expression::Mul(a, b)
+        | Expression::Div(a, b)
+        | Expression::Mod(a, b) => match (infer(a, scope), infer(b, scope)) {
+            (Some(a), Some(b)) if a != b => None,
+            (a, b) => a.or(b),
+        },
+        Expression::Neg(a) => infer(a, scope),
+        Expression::Member(_, _) => None,
+        Expression::OptionalMember(_, _) | Expression::OptionalIndex(_, _) => Some(Kind::Optional),
+        Expression::Method(_, id, _) => match id.0.as_str() {
+            "size" | "indexOf" => Some(Kind::I64),
+            "contains" | "startsWith" | "endsWith" | "matches" | "hasValue" => Some(Kind::Bool),
+            "lowerAscii" | "upperAscii" | "trim" | "replace" | "substring" | "charAt" | "join" => {
+                Some(Kind::String)
+            }
+            "split" => Some(Kind::List),
+            _ => None,
+        },
+        Expression::FunctionCall(id, _) => match id.0.as_str() {
+            "size" => Some(Kind::I64),
+            "matches" => Some(Kind::Bool),
+            "dyn" => None,
+            name => Kind::from_type_name(name),
+        },
+        Expression::Lit(lit) => Some(match lit {
+            Literal::I64(_) => Kind::I64,
+            Literal::F64(_) => Kind::F64,
+            Literal::Bool(_) => Kind::Bool,
+            Literal::String(_) => Kind::String,
+            Literal::Bytes(_) => Kind::Bytes,
+            Literal::List(_) => Kind::List,
+            Literal::Map(_) => Kind::Map,
+            Literal::Message(_, _) => Kind::Message,
+            Literal::Null => Kind::Null,
+        }),
+        Expression::Binding(id) => match scope.iter().rev().find(|(name, _)| *name == id) {
+            Some(&(_, kind)) => kind,
+            None => Kind::from_type_name(&id.0).map(|_| Kind::Type),
+        },
+    }
+}
+
+/// Completions for the identifier that ends at `offset`: methods after a
+/// `.`, and otherwise functions, type names, keywords and the identifiers
+/// used elsewhere in the document.
+fn completion(text: &str, offset: usize) -> Json {
+    let before = &text[..offset];
+    let start = before
+        .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .map_or(0, |i| i + 1);
+    let prefix = &before[start..];
+    let mut items = Vec::new();
+    let mut add = |names: &mut dyn Iterator<Item = &str>, kind: u8| {
+        for name in names.filter(|name| name.starts_with(prefix)) {
+            items.push(json!({"label": name, "kind": kind}));
+        }
+    };
+    if before[..start].trim_end().ends_with('.') {
+        let methods: BTreeSet<&str> = strings::METHODS.iter().chain(METHODS).copied().collect();
+        add(&mut methods.into_iter(), COMPLETE_METHOD);
+    } else {
+        let variables = identifiers(text, start);
+        add(&mut FUNCTIONS.iter().copied(), COMPLETE_FUNCTION);
+        add(&mut TYPES.iter().copied(), COMPLETE_TYPE);
+        add(&mut KEYWORDS.iter().copied(), COMPLETE_KEYWORD);
+        add(&mut variables.into_iter(), COMPLETE_VARIABLE);
+    }
+    json!(items)
+}
+
+/// The variables that `text` refers to or binds, leaving out the identifier
+/// at `skip`: identifier tokens that aren't functions or type names, and don't
+/// follow a `.` or come before a `(`.
+fn identifiers(text: &str, skip: usize) -> BTreeSet<&str> {
+    let tokens: Vec<Token> = tokens(text).collect();
+    let lexeme = |i: usize| {
+        tokens
+            .get(i)
+            .map(|t: &Token| &text[t.span.start..t.span.end])
+    };
+    let mut names = BTreeSet::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let word = &text[token.span.start..token.span.end];
+        let is_name = token.kind == TokenKind::Identifier
+            && token.span.start != skip
+            && (i == 0 || lexeme(i - 1) != Some("."))
+            && lexeme(i + 1) != Some("(")
+            && ![FUNCTIONS, TYPES].iter().any(|ws| ws.contains(&word));
+        if is_name {
+            names.insert(word);
+        }
+    }
+    names
+}
+
+/// An edit that replaces the whole document with its canonical form, none if
+/// it's already in that form, or `null` if it doesn't parse.
+fn formatting(text: &str) -> Json {
+    let formatted = match parser::parse(text) {
+        Ok(expr) => format!("{}\n", expr),
+        Err(_) => return Json::Null,
+    };
+    if formatted == text {
+        return json!([]);
+    }
+    let whole = Span {
+        start: 0,
+        end: text.len(),
+    };
+    json!([{"range": range(text, whole), "newText": formatted}])
+}
+
+fn range(text: &str, span: Span) -> Json {
+    json!({"start": position(text, span.start), "end": position(text, span.end)})
+}
+
+/// The line and UTF-16 column of byte `offset` in `text`.
+fn position(text: &str, offset: usize) -> Json {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let character: usize = before[line_start..].chars().map(char::len_utf16).sum();
+    json!({"line": before.matches('\n').count(), "character": character})
+}
+
+/// The byte offset of a line and UTF-16 column in `text`, clamped to the end
+/// of the line.
+fn offset(text: &str, line: usize, character: usize) -> usize {
+    let mut start = 0;
+    for _ in 0..line {
+        match text[start..].find('\n') {
+            Some(i) => start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let end = text[start..].find('\n').map_or(text.len(), |i| start + i);
+    start + parser::byte_offset(&text[start..end], character)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const URI: &str = "file:///policy.cel";
+
+    /// Runs a scripted session against a fresh server, returning what it
+    /// sent back and its exit code.
+    fn session(messages: &[Json]) -> (Vec<Json>, i32) {
+        let mut input = Vec::new();
+        for message in messages {
+            write_message(&mut input, message).unwrap();
+        }
+        let mut output = Vec::new();
+        let code = run(&mut &input[..], &mut output).unwrap();
+        let mut replies = Vec::new();
+        let mut reader = &output[..];
+        while let Some(reply) = read_message(&mut reader).unwrap() {
+            replies.push(reply.unwrap());
+        }
+        (replies, code)
+    }
+
+    fn request(id: u64, method: &str, params: Json) -> Json {
+        json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params})
+    }
+
+    fn notification(method: &str, params: Json) -> Json {
+        json!({"jsonrpc": "2.0", "method": method, "params": params})
+    }
+
+    fn initialize() -> Json {
+        request(0, "initialize", json!({"capabilities": {}}))
+    }
+
+    fn open(text: &str) -> Json {
+        notification(
+            "textDocument/didOpen",
+            json!({"textDocument": {"uri": URI, "languageId": "cel", "version": 1, "text": text}}),
+        )
+    }
+
+    fn at(id: u64, method: &str, line: u64, character: u64) -> Json {
+        request(
+            id,
+            method,
+            json!({"textDocument": {"uri": URI}, "position": {"line": line, "character": character}}),
+        )
+    }
+
+    /// The results of the requests in a session that opens `text`.
+    fn results(text: &str, requests: Vec<Json>) -> Vec<Json> {
+        let mut messages = vec![initialize(), open(text)];
+        messages.extend(requests);
+        let (replies, _) = session(&messages);
+        replies
+            .into_iter()
+            .filter(|reply| reply["id"].as_u64().is_some_and(|id| id > 0))
+            .map(|reply| reply["result"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn lifecycle() {
+        let (replies, code) = session(&[
+            request(1, "textDocument/hover", json!({})),
+            initialize(),
+            request(2, "bogus", json!({})),
+            request(3, "shutdown", Json::Null),
+            request(4, "textDocument/hover", json!({})),
+            notification("exit", Json::Null),
+            request(5, "shutdown", Json::Null),
+        ]);
+        assert_eq!(code, 0);
+        assert_eq!(replies.len(), 5);
+        assert_eq!(replies[0]["error"]["code"], SERVER_NOT_INITIALIZED);
+        assert_eq!(replies[1]["result"]["capabilities"]["hoverProvider"], true);
+        assert_eq!(replies[2]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(
+            replies[3],
+            json!({"jsonrpc": "2.0", "id": 3, "result": null})
+        );
+        assert_eq!(replies[4]["error"]["code"], INVALID_REQUEST);
+
+        let (_, code) = session(&[initialize(), notification("exit", Json::Null)]);
+        assert_eq!(code, 1);
+        let (replies, code) = session(&[]);
+        assert_eq!((replies.len(), code), (0, 1));
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let mut input = b"Content-Length: 3\r\n\r\n{]}".to_vec();
+        write_message(&mut input, &notification("exit", Json::Null)).unwrap();
+        let mut output = Vec::new();
+        assert_eq!(run(&mut &input[..], &mut output).unwrap(), 1);
+        let reply = read_message(&mut &output[..]).unwrap().unwrap().unwrap();
+        assert_eq!(reply["error"]["code"], PARSE_ERROR);
+
+        let input = b"Content-Type: json\r\n\r\n{}";
+        assert!(run(&mut &input[..], &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn oversized_messages() {
+        let input = format!("Content-Length: {}\r\n\r\n{{}}", usize::MAX);
+        let error = read_message(&mut input.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Content-Length too large");
+        assert!(run(&mut input.as_bytes(), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn diagnostics() {
+        let change = |text: &str| {
+            notification(
+                "textDocument/didChange",
+                json!({"textDocument": {"uri": URI, "version": 2}, "contentChanges": [{"text": text}]}),
+            )
+        };
+        let (replies, _) = session(&[
+            initialize(),
+            open("1 +\n  (2 +)"),
+            change("x.matches('(') || true"),
+            change("x + 1"),
+            notification(
+                "textDocument/didClose",
+                json!({"textDocument": {"uri": URI}}),
+            ),
+        ]);
+        let published: Vec<&Json> = replies[1..]
+            .iter()
+            .map(|reply| {
+                assert_eq!(reply["method"], "textDocument/publishDiagnostics");
+                assert_eq!(reply["params"]["uri"], URI);
+                &reply["params"]["diagnostics"]
+            })
+            .collect();
+        assert_eq!(
+            published[0],
+            &json!([{
+                "range": {"start": {"line": 1, "character": 6}, "end": {"line": 1, "character": 7}},
+                "severity": 1,
+                "source": "cel",
+                "message": "parse error @ L2:7",
+            }])
+        );
+        assert_eq!(
+            published[1][0]["range"],
+            json!({"start": {"line": 0, "character": 10}, "end": {"line": 0, "character": 13}})
+        );
+        assert!(published[1][0]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid regex"));
+        assert_eq!(published[2], &json!([]));
+        assert_eq!(published[3], &json!([]));
+    }
+
+    #[test]
+    fn hover() {
+        let text = "let xs = [1, 2];\nxs.size() + -d ? 'a' : xs[0]";
+        let hovers = results(
+            text,
+            vec![
+                at(1, "textDocument/hover", 0, 5),
+                at(2, "textDocument/hover", 1, 1),
+                at(3, "textDocument/hover", 1, 5),
+                at(4, "textDocument/hover", 1, 10),
+                at(5, "textDocument/hover", 1, 15),
+                at(6, "textDocument/hover", 1, 28),
+            ],
+        );
+        let kinds: Vec<&str> = hovers
+            .iter()
+            .map(|hover| hover["contents"]["value"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec!["`list`", "`list`", "`int`", "`int`", "`dyn`", "`dyn`"]
+        );
+        assert_eq!(
+            hovers[2]["range"],
+            json!({"start": {"line": 1, "character": 0}, "end": {"line": 1, "character": 9}})
+        );
+        assert_eq!(
+            results("1 +", vec![at(1, "textDocument/hover", 0, 0)]),
+            vec![Json::Null]
+        );
+    }
+
+    #[test]
+    fn completion() {
+        let labels = |text: &str, line: u64, character: u64| -> Vec<String> {
+            let items = results(
+                text,
+                vec![at(1, "textDocument/completion", line, character)],
+            );
+            items[0]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["label"].as_str().unwrap().to_owned())
+                .collect()
+        };
+        assert_eq!(labels("s.st", 0, 4), vec!["startsWith"]);
+        assert_eq!(labels("s. ", 0, 3).len(), strings::METHODS.len() + 6);
+        assert_eq!(labels("s", 0, 1), vec!["size", "string"]);
+        assert_eq!(
+            labels("let owner = user.id;\nor(\"o\") || o", 1, 13),
+            vec!["optional_type", "owner"]
+        );
+        assert_eq!(labels("n", 0, 1), vec!["null_type", "null"]);
+    }
+
+    #[test]
+    fn formatting() {
+        let edits = results(
+            "let x=1;x+2*  3",
+            vec![request(
+                1,
+                "textDocument/formatting",
+                json!({"textDocument": {"uri": URI}, "options": {"tabSize": 4, "insertSpaces": true}}),
+            )],
+        );
+        assert_eq!(
+            edits[0],
+            json!([{
+                "range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 15}},
+                "newText": "let x = 1;\nx + 2 * 3\n",
+            }])
+        );
+        let format = |text: &str| {
+            results(
+                text,
+                vec![request(
+                    1,
+                    "textDocument/formatting",
+                    json!({"textDocument": {"uri": URI}}),
+                )],
+            )
+            .remove(0)
+        };
+        assert_eq!(format("x + 1\n"), json!([]));
+        assert_eq!(format("x +"), Json::Null);
+    }
+
+    #[test]
+    fn positions() {
+        let text = "'😀' +\n  x";
+        assert_eq!(offset(text, 0, 3), 5);
+        assert_eq!(position(text, 5), json!({"line": 0, "character": 3}));
+        assert_eq!(offset(text, 1, 2), 11);
+        assert_eq!(offset(text, 0, 99), 8);
+        assert_eq!(offset(text, 5, 0), text.len());
+    }
+}
@@ -0,0 +1,653 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use prost::Message;
+use prost_reflect::DynamicMessage;
+
+pub mod de;
+mod print;
+pub mod proto;
+pub mod ser;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expression {
+    LetBinding {
+        id: Identifier,
+        value: Box<Expression>,
+        body: Box<Expression>,
+    },
+    Ternary {
+        condition: Box<Expression>,
+        true_branch: Box<Expression>,
+        false_branch: Box<Expression>,
+    },
+    Or(Vec<Expression>),
+    And(Vec<Expression>),
+    Eq(Box<Expression>, Box<Expression>),
+    Neq(Box<Expression>, Box<Expression>),
+    Lt(Box<Expression>, Box<Expression>),
+    Lte(Box<Expression>, Box<Expression>),
+    Gte(Box<Expression>, Box<Expression>),
+    Gt(Box<Expression>, Box<Expression>),
+    Add(Box<Expression>, Box<Expression>),
+    Sub(Box<Expression>, Box<Expression>),
+    Mul(Box<Expression>, Box<Expression>),
+    Div(Box<Expression>, Box<Expression>),
+    Mod(Box<Expression>, Box<Expression>),
+    Neg(Box<Expression>),
+    Not(Box<Expression>),
+    Member(Box<Expression>, Identifier),
+    OptionalMember(Box<Expression>, Identifier),
+    OptionalIndex(Box<Expression>, Box<Expression>),
+    Has(Box<Expression>, Identifier),
+    Method(Box<Expression>, Identifier, Vec<Expression>),
+    FunctionCall(Identifier, Vec<Expression>),
+    Lit(Literal),
+    Binding(Identifier),
+}
+
+impl Expression {
+    pub fn op(&self) -> Op {
+        match self {
+            Expression::Ternary { .. } => Op::Ternary,
+            Expression::LetBinding { .. } => Op::LetBinding,
+            Expression::Or(_) => Op::Or,
+            Expression::And(_) => Op::And,
+            Expression::Eq(_, _) => Op::Eq,
+            Expression::Neq(_, _) => Op::Neq,
+            Expression::Lt(_, _) => Op::Lt,
+            Expression::Lte(_, _) => Op::Lte,
+            Expression::Gte(_, _) => Op::Gte,
+            Expression::Gt(_, _) => Op::Gt,
+            Expression::Add(_, _) => Op::Plus,
+            Expression::Sub(_, _) => Op::Minus,
+            Expression::Mul(_, _) => Op::Times,
+            Expression::Div(_, _) => Op::Div,
+            Expression::Mod(_, _) => Op::Mod,
+            Expression::Neg(_) => Op::Neg,
+            Expression::Not(_) => Op::Not,
+            Expression::Member(_, _) => Op::Member,
+            Expression::OptionalMember(_, _) => Op::OptionalMember,
+            Expression::OptionalIndex(_, _) => Op::OptionalIndex,
+            Expression::Has(_, _) => Op::Has,
+            Expression::Method(_, id, _) => Op::Method(id.clone()),
+            Expression::FunctionCall(id, _) => Op::FunctionCall(id.clone()),
+            Expression::Lit(_) => Op::Lit,
+            Expression::Binding(_) => Op::Lookup,
+        }
+    }
+
+    /// The direct subexpressions, in the order the compiler visits them.
+    pub fn children(&self) -> Vec<&Expression> {
+        match self {
+            Expression::LetBinding { value, body, .. } => vec![value, body],
+            Expression::Ternary {
+                condition,
+                true_branch,
+                false_branch,
+            } => vec![condition, true_branch, false_branch],
+            Expression::Or(es) | Expression::And(es) => es.iter().collect(),
+            Expression::Eq(a, b)
+            | Expression::Neq(a, b)
+            | Expression::Lt(a, b)
+            | Expression::Lte(a, b)
+            | Expression::Gte(a, b)
+            | Expression::Gt(a, b)
+            | Expression::Add(a, b)
+            | Expression::Sub(a, b)
+            | Expression::Mul(a, b)
+            | Expression::Div(a, b)
+            | Expression::Mod(a, b)
+            | Expression::OptionalIndex(a, b) => vec![a, b],
+            Expression::Neg(a)
+            | Expression::Not(a)
+            | Expression::Member(a, _)
+            | Expression::OptionalMember(a, _)
+            | Expression::Has(a, _) => vec![a],
+            Expression::Method(operand, _, args) => {
+                std::iter::once(&**operand).chain(args.iter()).collect()
+            }
+            Expression::FunctionCall(_, args) => args.iter().collect(),
+            Expression::Lit(Literal::List(es)) => es.iter().collect(),
+            Expression::Lit(Literal::Map(kvs)) => {
+                kvs.iter().flat_map(|(k, v)| vec![k, v]).collect()
+            }
+            Expression::Lit(Literal::Message(_, fields)) => fields.iter().map(|(_, v)| v).collect(),
+            Expression::Lit(_) | Expression::Binding(_) => Vec::new(),
+        }
+    }
+
+    /// The number of nodes in this expression's tree, including itself.
+    pub fn node_count(&self) -> usize {
+        1 + self
+            .children()
+            .into_iter()
+            .map(Expression::node_count)
+            .sum::<usize>()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Kind {
+    I64,
+    U64,
+    F64,
+    Bool,
+    String,
+    Bytes,
+    List,
+    Map,
+    Null,
+    Type,
+    Optional,
+    Message,
+}
+
+impl Kind {
+    pub fn from_type_name(name: &str) -> Option<Kind> {
+        match name {
+            "int" => Some(Kind::I64),
+            "uint" => Some(Kind::U64),
+            "double" => Some(Kind::F64),
+            "bool" => Some(Kind::Bool),
+            "string" => Some(Kind::String),
+            "bytes" => Some(Kind::Bytes),
+            "list" => Some(Kind::List),
+            "map" => Some(Kind::Map),
+            "null_type" => Some(Kind::Null),
+            "type" => Some(Kind::Type),
+            "optional_type" => Some(Kind::Optional),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Kind::I64 => "int",
+            Kind::U64 => "uint",
+            Kind::F64 => "double",
+            Kind::Bool => "bool",
+            Kind::String => "string",
+            Kind::Bytes => "bytes",
+            Kind::List => "list",
+            Kind::Map => "map",
+            Kind::Null => "null_type",
+            Kind::Type => "type",
+            Kind::Optional => "optional_type",
+            // Every message type has its own name, see `proto::type_name`.
+            Kind::Message => "message",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Op {
+    Not,
+    Neg,
+    Plus,
+    Minus,
+    Times,
+    Div,
+    Mod,
+    Or,
+    And,
+    Eq,
+    Neq,
+    Lte,
+    Lt,
+    Gt,
+    Gte,
+    Lit,
+    Lookup,
+    Member,
+    OptionalMember,
+    OptionalIndex,
+    Has,
+    Method(Identifier),
+    FunctionCall(Identifier),
+    LetBinding,
+    Ternary,
+    Jump,
+}
+
+impl Value {
+    pub fn kind(&self) -> Kind {
+        match *self {
+            Value::I64(_) => Kind::I64,
+            Value::U64(_) => Kind::U64,
+            Value::F64(_) => Kind::F64,
+            Value::Bool(_) => Kind::Bool,
+            Value::String(_) => Kind::String,
+            Value::Bytes(_) => Kind::Bytes,
+            Value::List(_) => Kind::List,
+            Value::Map(_) => Kind::Map,
+            Value::Null => Kind::Null,
+            Value::Type(_) => Kind::Type,
+            Value::Optional(_) => Kind::Optional,
+            Value::Message(_) => Kind::Message,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        let transitive = match self {
+            Value::I64(_) => 0,
+            Value::U64(_) => 0,
+            Value::F64(_) => 0,
+            Value::Bool(_) => 0,
+            Value::String(s) => s.len(),
+            Value::Bytes(b) => b.len(),
+            Value::Null => 0,
+            Value::Type(_) => 0,
+            Value::Optional(v) => v.as_ref().map_or(0, |v| v.size()),
+            Value::Message(msg) => msg.encoded_len(),
+            Value::List(children) => children.iter().map(|v| v.size()).sum(),
+            Value::Map(children) => children.iter().map(|(k, v)| k.len() + v.size()).sum(),
+        };
+        std::mem::size_of_val(self) + transitive
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Literal {
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Bytes(Vec<u8>),
+    List(Vec<Expression>),
+    Map(Vec<(Expression, Expression)>),
+    /// `pkg.Type{field: value, ...}`, naming a message type from the program's descriptors.
+    Message(String, Vec<(Identifier, Expression)>),
+    Null,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Map(HashMap<String, Value>),
+    Null,
+    Type(Kind),
+    /// The result of optional navigation (`a.?b`, `m[?k]`): `None` when the
+    /// field or key was absent.
+    Optional(Option<Box<Value>>),
+    /// A protobuf message, see `proto` for how its fields map to values.
+    Message(DynamicMessage),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::I64(n) => write!(f, "{}", n),
+            Value::U64(n) => write!(f, "{}u", n),
+            Value::F64(x) => write!(f, "{:7.3}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::String(s) => write!(f, "\"{}\"", s),
+            Value::Bytes(bs) => {
+                write!(f, "b\"")?;
+                for b in bs {
+                    write!(f, "{:x}", b)?;
+                }
+                write!(f, "\"")
+            }
+            Value::List(vs) => {
+                write!(f, "[")?;
+                let mut first = true;
+                for v in vs {
+                    if !first {
+                        write!(f, ", ")?;
+                    }
+                    first = false;
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(kvs) => {
+                write!(f, "{{")?;
+                let mut first = true;
+                for (k, v) in kvs {
+                    if !first {
+                        write!(f, ", ")?;
+                    }
+                    first = false;
+                    write!(f, "\"{}\":{}", k, v)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Type(kind) => write!(f, "{}", kind.type_name()),
+            Value::Optional(None) => write!(f, "optional.none()"),
+            Value::Optional(Some(v)) => write!(f, "optional.of({})", v),
+            Value::Message(msg) => {
+                write!(f, "{}{{", proto::type_name(msg))?;
+                let mut first = true;
+                for (field, v) in msg.fields() {
+                    if !first {
+                        write!(f, ", ")?;
+                    }
+                    first = false;
+                    write!(f, "{}: {}", field.name(), proto::from_proto(v.clone()))?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Error {
+    NoMethod(Identifier),
+    NoMethodOnType(Kind, Identifier),
+    NoMethodWithSignature(Kind, Identifier, Vec<Kind>),
+    NoFunction(Identifier),
+    InvalidFunctionArity(Identifier, usize),
+    InvalidFunctionSignature(Identifier, Vec<Kind>),
+    FunctionExecutionError(Identifier),
+    InvalidTypeForOperator(Kind, Op),
+    InvalidTypesForOperator(Kind, Kind, Op),
+    DivisionByZero,
+    IndexOutOfRange(i64),
+    IntegerOverflow(Op),
+    ConversionFailed(Kind, String),
+    ConversionOutOfRange(Kind, Kind),
+    InvalidRegex(String),
+    NoSuchBinding(Identifier),
+    NoSuchMember(Identifier),
+    NoSuchType(String),
+    InvalidMessageField(Identifier, Kind),
+    EmptyOptional,
+    InvalidMapKey(Kind),
+    InvalidMapValue(Kind),
+    DuplicateMapKey(String),
+    /// A field given twice when constructing a message of the named type.
+    DuplicateMessageField(String, Identifier),
+    EvaluationTooLarge,
+    Aborted,
+    /// The result depends on these attributes, which were marked unknown; see
+    /// `partial`.
+    Unknown(Vec<String>),
+}
+
+impl Error {
+    /// The error `||` and `&&` report when both of their operands failed:
+    /// unknowns take precedence over other errors, and are merged.
+    pub fn absorb(self, other: Error) -> Error {
+        match (self, other) {
+            (Error::Unknown(mut a), Error::Unknown(b)) => {
+                a.extend(b);
+                a.sort();
+                a.dedup();
+                Error::Unknown(a)
+            }
+            (Error::Unknown(a), _) | (_, Error::Unknown(a)) => Error::Unknown(a),
+            (e, _) => e,
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Op::Not => "!",
+            Op::Neg | Op::Minus => "-",
+            Op::Plus => "+",
+            Op::Times => "*",
+            Op::Div => "/",
+            Op::Mod => "%",
+            Op::Or => "||",
+            Op::And => "&&",
+            Op::Eq => "==",
+            Op::Neq => "!=",
+            Op::Lte => "<=",
+            Op::Lt => "<",
+            Op::Gt => ">",
+            Op::Gte => ">=",
+            Op::Lit => "literal",
+            Op::Lookup => "lookup",
+            Op::Member => ".",
+            Op::OptionalMember => ".?",
+            Op::OptionalIndex => "[?]",
+            Op::Has => "has()",
+            Op::Method(id) => return write!(f, ".{}()", id.0),
+            Op::FunctionCall(id) => return write!(f, "{}()", id.0),
+            Op::LetBinding => "let",
+            Op::Ternary | Op::Jump => "?:",
+        };
+        f.write_str(symbol)
+    }
+}
+
+fn kind_names(kinds: &[Kind]) -> String {
+    let names: Vec<&str> = kinds.iter().map(Kind::type_name).collect();
+    names.join(", ")
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoMethod(id) => write!(f, "no method {}", id.0),
+            Error::NoMethodOnType(kind, id) => {
+                write!(f, "{} has no method {}", kind.type_name(), id.0)
+            }
+            Error::NoMethodWithSignature(kind, id, args) => write!(
+                f,
+                "no method {}.{}({})",
+                kind.type_name(),
+                id.0,
+                kind_names(args)
+            ),
+            Error::NoFunction(id) => write!(f, "no function {}", id.0),
+            Error::InvalidFunctionArity(id, n) => {
+                write!(f, "{} doesn't take {} arguments", id.0, n)
+            }
+            Error::InvalidFunctionSignature(id, args) => {
+                write!(f, "no function {}({})", id.0, kind_names(args))
+            }
+            Error::FunctionExecutionError(id) => write!(f, "{} failed", id.0),
+            Error::InvalidTypeForOperator(kind, op) => {
+                write!(f, "no operator {} for {}", op, kind.type_name())
+            }
+            Error::InvalidTypesForOperator(a, b, op) => write!(
+                f,
+                "no operator {} for {} and {}",
+                op,
+                a.type_name(),
+                b.type_name()
+            ),
+            Error::DivisionByZero => write!(f, "division by zero"),
+            Error::IndexOutOfRange(i) => write!(f, "index {} out of range", i),
+            Error::IntegerOverflow(op) => write!(f, "integer overflow in {}", op),
+            Error::ConversionFailed(kind, input) => {
+                write!(f, "can't convert {} to {}", input, kind.type_name())
+            }
+            Error::ConversionOutOfRange(from, to) => write!(
+                f,
+                "{} out of range for {}",
+                from.type_name(),
+                to.type_name()
+            ),
+            Error::InvalidRegex(msg) => write!(f, "invalid regex: {}", msg),
+            Error::NoSuchBinding(id) => write!(f, "no such variable {}", id.0),
+            Error::NoSuchMember(id) => write!(f, "no such member {}", id.0),
+            Error::NoSuchType(name) => write!(f, "no such type {}", name),
+            Error::InvalidMessageField(id, kind) => {
+                write!(f, "field {} can't hold a {}", id.0, kind.type_name())
+            }
+            Error::EmptyOptional => write!(f, "optional.none() has no value"),
+            Error::InvalidMapKey(kind) => write!(f, "{} can't be a map key", kind.type_name()),
+            Error::InvalidMapValue(kind) => {
+                write!(f, "{} can't be a map value", kind.type_name())
+            }
+            Error::DuplicateMapKey(key) => write!(f, "duplicate map key {}", key),
+            Error::DuplicateMessageField(name, id) => {
+                write!(f, "field {} of {} is set more than once", id.0, name)
+            }
+            Error::EvaluationTooLarge => write!(f, "evaluation too large"),
+            Error::Aborted => write!(f, "aborted"),
+            Error::Unknown(attributes) => {
+                write!(f, "depends on unknown {}", attributes.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A named expression in a policy file: `rule name = value;` for one that can
+/// be evaluated on its own, `let name = value;` for one that is only shared.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Declaration {
+    pub name: Identifier,
+    pub value: Expression,
+    pub rule: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Identifier(pub String);
+impl Identifier {
+    pub fn new(name: &str) -> Identifier {
+        Identifier(name.to_owned())
+    }
+}
+
+impl FromStr for Identifier {
+    type Err = ();
+    fn from_str(input: &str) -> Result<Identifier, ()> {
+        Ok(Identifier::new(input))
+    }
+}
+
+pub type EvalResult = Result<Value, Error>;
+
+/// Values for the free identifiers of an expression.
+///
+/// Type names such as `int` and `string` aren't free identifiers: CEL reserves
+/// them, so they always denote the type and a binding for one is never seen.
+/// The compilers resolve them before any activation is around, which is what
+/// lets the register machine and compiled modules take theirs at run time.
+/// Let bindings are in scope at compile time and do shadow them.
+pub type Activation = HashMap<String, Value>;
+
+/// Failure converting between `Value` and Rust data, see `ser::to_value` and `de::from_value`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SerdeError(pub String);
+
+impl fmt::Display for SerdeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SerdeError {}
+
+/// Failure turning protobuf data into a `Value`, see `proto::decode`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProtoError(pub String);
+
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ProtoError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const VALUE_SIZE: usize = std::mem::size_of::<Value>();
+
+    #[test]
+    fn sizeof_primitive() {
+        assert_eq!(Value::Null.size(), VALUE_SIZE);
+        assert_eq!(Value::Bool(true).size(), VALUE_SIZE);
+        assert_eq!(Value::I64(42).size(), VALUE_SIZE);
+        assert_eq!(Value::U64(42).size(), VALUE_SIZE);
+        assert_eq!(Value::F64(2.78).size(), VALUE_SIZE);
+        assert_eq!(Value::Type(Kind::Map).size(), VALUE_SIZE);
+    }
+
+    #[test]
+    fn sizeof_string() {
+        let v = Value::String("asdf".to_owned());
+        assert_eq!(v.size(), VALUE_SIZE + 4);
+    }
+
+    #[test]
+    fn sizeof_bytes() {
+        let v = Value::Bytes("asdf".as_bytes().to_owned());
+        assert_eq!(v.size(), VALUE_SIZE + 4);
+    }
+
+    #[test]
+    fn sizeof_list() {
+        let v = Value::List(vec![
+            Value::String("asdf".to_owned()),
+            Value::Null,
+            Value::List(vec![]),
+        ]);
+        assert_eq!(v.size(), 4 * VALUE_SIZE + 4);
+    }
+
+    #[test]
+    fn sizeof_map() {
+        let v = Value::Map(vec![("a".to_owned(), Value::Null)].into_iter().collect());
+        assert_eq!(v.size(), 2 * VALUE_SIZE + 1);
+    }
+
+    #[test]
+    fn error_messages() {
+        for (error, message) in [
+            (
+                Error::InvalidTypesForOperator(Kind::I64, Kind::String, Op::Plus),
+                "no operator + for int and string",
+            ),
+            (
+                Error::NoMethodWithSignature(
+                    Kind::String,
+                    Identifier::new("startsWith"),
+                    vec![Kind::I64, Kind::Bool],
+                ),
+                "no method string.startsWith(int, bool)",
+            ),
+            (Error::IntegerOverflow(Op::Neg), "integer overflow in -"),
+            (
+                Error::NoSuchBinding(Identifier::new("x")),
+                "no such variable x",
+            ),
+            (
+                Error::Unknown(vec!["a.b".to_owned(), "c".to_owned()]),
+                "depends on unknown a.b, c",
+            ),
+        ] {
+            assert_eq!(error.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn type_names_round_trip() {
+        for kind in &[
+            Kind::I64,
+            Kind::U64,
+            Kind::F64,
+            Kind::Bool,
+            Kind::String,
+            Kind::Bytes,
+            Kind::List,
+            Kind::Map,
+            Kind::Null,
+            Kind::Type,
+            Kind::Optional,
+        ] {
+            assert_eq!(Kind::from_type_name(kind.type_name()), Some(*kind));
+        }
+    }
+}
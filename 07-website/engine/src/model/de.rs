@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use serde::de::{self, Deserialize, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+
+use crate::model::{proto, SerdeError, Value};
+
+impl de::Error for SerdeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SerdeError(msg.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+/// Builds a `Value` from any self-describing format. Integers follow the same
+/// rules as `to_value`. Both `null` and a missing optional become `null`.
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a value representable in CEL")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::I64(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+        Ok(i64::try_from(v).map_or(Value::U64(v), Value::I64))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Value, E> {
+        Ok(Value::F64(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::String(v.to_owned()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Value, E> {
+        Ok(Value::String(v))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Value, E> {
+        Ok(Value::Bytes(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Value, E> {
+        Ok(Value::Bytes(v))
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Deserialize::deserialize(deserializer)
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut vs = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(v) = seq.next_element()? {
+            vs.push(v);
+        }
+        Ok(Value::List(vs))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut kvs = HashMap::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some(k) = map.next_key::<String>()? {
+            if kvs.contains_key(&k) {
+                return Err(de::Error::custom(format!("duplicate map key {:?}", k)));
+            }
+            let v = map.next_value()?;
+            kvs.insert(k, v);
+        }
+        Ok(Value::Map(kvs))
+    }
+}
+
+/// Converts a `Value` into any deserializable Rust type, e.g. the result of
+/// `Program::run` into a `#[derive(Deserialize)]` struct.
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, SerdeError> {
+    T::deserialize(value)
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self {
+            Value::I64(n) => visitor.visit_i64(n),
+            Value::U64(n) => visitor.visit_u64(n),
+            Value::F64(x) => visitor.visit_f64(x),
+            Value::Bool(b) => visitor.visit_bool(b),
+            Value::String(s) => visitor.visit_string(s),
+            Value::Bytes(bs) => visitor.visit_byte_buf(bs),
+            Value::List(vs) => {
+                let mut seq = de::value::SeqDeserializer::new(vs.into_iter());
+                let result = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(result)
+            }
+            Value::Map(kvs) => {
+                let mut map = de::value::MapDeserializer::new(kvs.into_iter());
+                let result = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(result)
+            }
+            Value::Null => visitor.visit_unit(),
+            Value::Type(kind) => visitor.visit_str(kind.type_name()),
+            Value::Optional(None) => visitor.visit_none(),
+            Value::Optional(Some(v)) => visitor.visit_some(*v),
+            Value::Message(msg) => {
+                let fields = msg
+                    .fields()
+                    .map(|(field, v)| (field.name().to_owned(), proto::from_proto(v.clone())));
+                let mut map = de::value::MapDeserializer::new(fields);
+                let result = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(result)
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SerdeError> {
+        match self {
+            Value::Null | Value::Optional(None) => visitor.visit_none(),
+            Value::Optional(Some(v)) => visitor.visit_some(*v),
+            other => visitor.visit_some(other),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        match self {
+            Value::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+            Value::Map(kvs) if kvs.len() == 1 => {
+                let (variant, content) = kvs.into_iter().next().unwrap();
+                visitor.visit_enum(EnumDeserializer(variant, content))
+            }
+            other => Err(de::Error::invalid_type(
+                de::Unexpected::Other(other.kind().type_name()),
+                &"a string or a single-entry map",
+            )),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, SerdeError> for Value {
+    type Deserializer = Value;
+
+    fn into_deserializer(self) -> Value {
+        self
+    }
+}
+
+/// An enum variant with content, encoded as `{variant: content}`.
+struct EnumDeserializer(String, Value);
+
+impl<'de> de::EnumAccess<'de> for EnumDeserializer {
+    type Error = SerdeError;
+    type Variant = Value;
+
+    fn variant_seed<S: de::DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<(S::Value, Value), SerdeError> {
+        let variant: de::value::StringDeserializer<SerdeError> = self.0.into_deserializer();
+        Ok((seed.deserialize(variant)?, self.1))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Value {
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<(), SerdeError> {
+        Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<S: de::DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<S::Value, SerdeError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SerdeError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::Activation;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Decision {
+        allow: bool,
+        reason: Option<String>,
+        score: f64,
+        tags: Vec<String>,
+        effect: Effect,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum Effect {
+        Deny,
+        Redirect(String),
+    }
+
+    fn map(kvs: Vec<(&str, Value)>) -> Value {
+        Value::Map(kvs.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+    }
+
+    #[test]
+    fn json_numbers_and_nulls() {
+        let v: Value = serde_json::from_str(r#"[1, -1, 1.0, 18446744073709551615, null]"#).unwrap();
+        assert_eq!(
+            v,
+            Value::List(vec![
+                Value::I64(1),
+                Value::I64(-1),
+                Value::F64(1.0),
+                Value::U64(u64::MAX),
+                Value::Null,
+            ])
+        );
+    }
+
+    #[test]
+    fn json_activation() {
+        let activation: Activation =
+            serde_json::from_str(r#"{"user": {"name": "alice", "admin": true}}"#).unwrap();
+        assert_eq!(
+            activation["user"],
+            map(vec![
+                ("name", Value::String("alice".to_owned())),
+                ("admin", Value::Bool(true)),
+            ])
+        );
+    }
+
+    #[test]
+    fn duplicate_json_keys_are_rejected() {
+        assert!(serde_json::from_str::<Value>(r#"{"a": 1, "a": 2}"#).is_err());
+    }
+
+    #[test]
+    fn value_to_struct() {
+        let v = map(vec![
+            ("allow", Value::Bool(false)),
+            ("reason", Value::Null),
+            ("score", Value::F64(0.25)),
+            ("tags", Value::List(vec![Value::String("x".to_owned())])),
+            (
+                "effect",
+                map(vec![("Redirect", Value::String("/login".to_owned()))]),
+            ),
+        ]);
+        assert_eq!(
+            from_value::<Decision>(v),
+            Ok(Decision {
+                allow: false,
+                reason: None,
+                score: 0.25,
+                tags: vec!["x".to_owned()],
+                effect: Effect::Redirect("/login".to_owned()),
+            })
+        );
+    }
+
+    #[test]
+    fn unit_variant_from_string() {
+        assert_eq!(
+            from_value::<Effect>(Value::String("Deny".to_owned())),
+            Ok(Effect::Deny)
+        );
+    }
+
+    #[test]
+    fn optional_values() {
+        assert_eq!(from_value::<Option<i64>>(Value::Optional(None)), Ok(None));
+        assert_eq!(
+            from_value::<Option<i64>>(Value::Optional(Some(Box::new(Value::I64(3))))),
+            Ok(Some(3))
+        );
+        assert_eq!(from_value::<Option<i64>>(Value::I64(3)), Ok(Some(3)));
+    }
+
+    #[test]
+    fn bytes() {
+        let v = Value::Bytes(vec![1, 2, 3]);
+        assert_eq!(
+            from_value::<serde_bytes::ByteBuf>(v).map(|b| b.into_vec()),
+            Ok(vec![1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn type_mismatch() {
+        assert!(from_value::<i64>(Value::String("1".to_owned())).is_err());
+    }
+}
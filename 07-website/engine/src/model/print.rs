@@ -0,0 +1,305 @@
+//! Prints an `Expression` back out as source text that parses to the same
+//! expression, with canonical spacing and only the parentheses it needs.
+
+use std::fmt::{self, Write};
+
+use crate::model::{Expression, Literal};
+
+impl fmt::Display for Expression {
+    // Anything more than a call goes in a function of its own, since deep
+    // trees recurse through here once per level.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expression::LetBinding { id, value, body } => let_binding(f, &id.0, value, body),
+            Expression::Ternary {
+                condition,
+                true_branch,
+                false_branch,
+            } => ternary(f, condition, true_branch, false_branch),
+            Expression::Or(es) => list(f, es, " || ", 2),
+            Expression::And(es) => list(f, es, " && ", 3),
+            Expression::Eq(a, b) => binary(f, a, "==", b, 4, 4),
+            Expression::Neq(a, b) => binary(f, a, "!=", b, 4, 4),
+            Expression::Lt(a, b) => binary(f, a, "<", b, 4, 4),
+            Expression::Lte(a, b) => binary(f, a, "<=", b, 4, 4),
+            Expression::Gte(a, b) => binary(f, a, ">=", b, 4, 4),
+            Expression::Gt(a, b) => binary(f, a, ">", b, 4, 4),
+            Expression::Add(a, b) => binary(f, a, "+", b, 4, 5),
+            Expression::Sub(a, b) => binary(f, a, "-", b, 4, 5),
+            Expression::Mul(a, b) => binary(f, a, "*", b, 5, 6),
+            Expression::Div(a, b) => binary(f, a, "/", b, 5, 6),
+            Expression::Mod(a, b) => binary(f, a, "%", b, 5, 6),
+            Expression::Neg(a) => prefix(f, "-", a),
+            Expression::Not(a) => prefix(f, "!", a),
+            Expression::Member(a, id) => member(f, a, ".", &id.0),
+            Expression::OptionalMember(a, id) => member(f, a, ".?", &id.0),
+            Expression::OptionalIndex(a, key) => index(f, a, "[?", key),
+            Expression::Has(a, id) => {
+                f.write_str("has(")?;
+                member(f, a, ".", &id.0)?;
+                f.write_char(')')
+            }
+            // Indexing parses to a call of `get`.
+            Expression::Method(a, id, args) if id.0 == "get" && args.len() == 1 => {
+                index(f, a, "[", &args[0])
+            }
+            Expression::Method(a, id, args) => {
+                member(f, a, ".", &id.0)?;
+                call(f, args)
+            }
+            Expression::FunctionCall(id, args) => {
+                f.write_str(&id.0)?;
+                call(f, args)
+            }
+            Expression::Lit(lit) => literal(f, lit),
+            Expression::Binding(id) => f.write_str(&id.0),
+        }
+    }
+}
+
+fn let_binding(
+    f: &mut fmt::Formatter<'_>,
+    id: &str,
+    value: &Expression,
+    body: &Expression,
+) -> fmt::Result {
+    write!(f, "let {} = {};\n{}", id, value, body)
+}
+
+fn ternary(
+    f: &mut fmt::Formatter<'_>,
+    condition: &Expression,
+    true_branch: &Expression,
+    false_branch: &Expression,
+) -> fmt::Result {
+    operand(f, condition, 1)?;
+    write!(f, " ? {} : {}", true_branch, false_branch)
+}
+
+fn prefix(f: &mut fmt::Formatter<'_>, op: &str, a: &Expression) -> fmt::Result {
+    f.write_str(op)?;
+    operand(f, a, 6)
+}
+
+fn member(f: &mut fmt::Formatter<'_>, a: &Expression, op: &str, id: &str) -> fmt::Result {
+    operand(f, a, 7)?;
+    f.write_str(op)?;
+    f.write_str(id)
+}
+
+fn index(f: &mut fmt::Formatter<'_>, a: &Expression, open: &str, key: &Expression) -> fmt::Result {
+    operand(f, a, 7)?;
+    f.write_str(open)?;
+    fmt::Display::fmt(key, f)?;
+    f.write_char(']')
+}
+
+/// The parenthesized arguments of a function or method call.
+fn call(f: &mut fmt::Formatter<'_>, args: &[Expression]) -> fmt::Result {
+    f.write_char('(')?;
+    list(f, args, ", ", 0)?;
+    f.write_char(')')
+}
+
+/// How tightly `e` binds: an operand that binds less tightly than its
+/// operator requires needs parentheses.
+fn precedence(e: &Expression) -> u8 {
+    match e {
+        Expression::LetBinding { .. } | Expression::Ternary { .. } => 0,
+        Expression::Or(_) => 1,
+        Expression::And(_) => 2,
+        Expression::Eq(_, _)
+        | Expression::Neq(_, _)
+        | Expression::Lt(_, _)
+        | Expression::Lte(_, _)
+        | Expression::Gte(_, _)
+        | Expression::Gt(_, _) => 3,
+        Expression::Add(_, _) | Expression::Sub(_, _) => 4,
+        Expression::Mul(_, _) | Expression::Div(_, _) | Expression::Mod(_, _) => 5,
+        Expression::Neg(_) | Expression::Not(_) => 6,
+        // A negative number is printed with a leading `-`.
+        Expression::Lit(Literal::I64(n)) if *n < 0 => 6,
+        Expression::Lit(Literal::F64(x)) if x.is_sign_negative() && x.is_finite() => 6,
+        _ => 7,
+    }
+}
+
+fn operand(f: &mut fmt::Formatter<'_>, e: &Expression, min: u8) -> fmt::Result {
+    // Formatting `e` directly rather than through `write!` keeps the frames
+    // of deeply nested expressions small.
+    if precedence(e) < min {
+        f.write_char('(')?;
+        fmt::Display::fmt(e, f)?;
+        f.write_char(')')
+    } else {
+        fmt::Display::fmt(e, f)
+    }
+}
+
+fn binary(
+    f: &mut fmt::Formatter<'_>,
+    a: &Expression,
+    op: &str,
+    b: &Expression,
+    left: u8,
+    right: u8,
+) -> fmt::Result {
+    operand(f, a, left)?;
+    f.write_char(' ')?;
+    f.write_str(op)?;
+    f.write_char(' ')?;
+    operand(f, b, right)
+}
+
+fn list(f: &mut fmt::Formatter<'_>, es: &[Expression], sep: &str, min: u8) -> fmt::Result {
+    for (i, e) in es.iter().enumerate() {
+        if i > 0 {
+            f.write_str(sep)?;
+        }
+        operand(f, e, min)?;
+    }
+    Ok(())
+}
+
+fn literal(f: &mut fmt::Formatter<'_>, lit: &Literal) -> fmt::Result {
+    match lit {
+        Literal::I64(n) => write!(f, "{}", n),
+        // There are no literals for these, but `double` parses "inf", "-inf"
+        // and "NaN", which is how Rust prints them.
+        Literal::F64(x) if !x.is_finite() => write!(f, "double(\"{}\")", x),
+        Literal::F64(x) => {
+            // The grammar wants digits on both sides of the point.
+            let s = x.to_string();
+            if s.contains('.') {
+                f.write_str(&s)
+            } else {
+                write!(f, "{}.0", s)
+            }
+        }
+        Literal::Bool(b) => write!(f, "{}", b),
+        Literal::Null => f.write_str("null"),
+        Literal::String(s) => {
+            f.write_char('"')?;
+            for c in s.chars() {
+                match c {
+                    '"' => f.write_str("\\\"")?,
+                    '\\' => f.write_str("\\\\")?,
+                    '\n' => f.write_str("\\n")?,
+                    '\t' => f.write_str("\\t")?,
+                    c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+                    c => f.write_char(c)?,
+                }
+            }
+            f.write_char('"')
+        }
+        Literal::Bytes(bs) => {
+            f.write_str("b\"")?;
+            for &b in bs {
+                match b {
+                    b'"' => f.write_str("\\\"")?,
+                    b'\\' => f.write_str("\\\\")?,
+                    b' '..=b'~' => f.write_char(b as char)?,
+                    _ => write!(f, "\\x{:02x}", b)?,
+                }
+            }
+            f.write_char('"')
+        }
+        Literal::List(es) => {
+            f.write_char('[')?;
+            list(f, es, ", ", 0)?;
+            f.write_char(']')
+        }
+        Literal::Map(kvs) => {
+            f.write_char('{')?;
+            for (i, (k, v)) in kvs.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{}: {}", k, v)?;
+            }
+            f.write_char('}')
+        }
+        Literal::Message(name, fields) => {
+            write!(f, "{}{{", name)?;
+            for (i, (id, v)) in fields.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{}: {}", id.0, v)?;
+            }
+            f.write_char('}')
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::model::{Expression, Literal, Value};
+    use crate::parser::parse;
+    use crate::stack;
+
+    fn print(source: &str) -> String {
+        parse(source).unwrap().to_string()
+    }
+
+    #[test]
+    fn canonical() {
+        assert_eq!(print("1+2 *3"), "1 + 2 * 3");
+        assert_eq!(print("(1 + 2) * 3"), "(1 + 2) * 3");
+        assert_eq!(print("1 - (2 - 3)"), "1 - (2 - 3)");
+        assert_eq!(
+            print("(a || b) || c && (d ? e : f)"),
+            "(a || b) || c && (d ? e : f)"
+        );
+        assert_eq!(print("-(a.b)[0] + -(1)"), "-a.b[0] + -1");
+        assert_eq!(print("(-a).b"), "(-a).b");
+        assert_eq!(
+            print("has( m.a ) ? m .?b : m[?'c']"),
+            "has(m.a) ? m.?b : m[?\"c\"]"
+        );
+        assert_eq!(
+            print("let x = [1,2.50,];\nlet y={'a':x};  y"),
+            "let x = [1, 2.5];\nlet y = {\"a\": x};\ny"
+        );
+        assert_eq!(
+            print(r#" "say \"hi\"" . size() "#),
+            r#""say \"hi\"".size()"#
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        for source in &[
+            r#"a ? b ? 1 : 2 : c ? 3 : 4"#,
+            r#"!(a == b) && -(-x) < 1.0 || s.matches("\\d+\n")"#,
+            r#"int(u) + size(l) % 3 != (1 - 2) * (3 / 4)"#,
+            r#"b"\x00\xff\"" + b'\\' == bytes("\t\u0001")"#,
+            r#"pkg.Msg{a: 1, b: [{}]}.a.?b[?0].orValue(null)"#,
+        ] {
+            let expr = parse(source).unwrap();
+            assert_eq!(parse(&expr.to_string()), Ok(expr), "{}", source);
+        }
+    }
+
+    #[test]
+    fn non_finite_doubles() {
+        for &x in &[f64::INFINITY, f64::NEG_INFINITY, f64::NAN] {
+            let expr = Expression::Neg(Box::new(Expression::Lit(Literal::F64(x))));
+            let source = expr.to_string();
+            let mut program = stack::compile(parse(&source).unwrap()).unwrap();
+            match program.run() {
+                Ok(Value::F64(y)) => assert!(
+                    y.to_bits() == (-x).to_bits() || (x.is_nan() && y.is_nan()),
+                    "{} gave {}",
+                    source,
+                    y
+                ),
+                other => panic!("{} gave {:?}", source, other),
+            }
+        }
+        assert_eq!(
+            Expression::Lit(Literal::F64(f64::NEG_INFINITY)).to_string(),
+            r#"double("-inf")"#
+        );
+    }
+}
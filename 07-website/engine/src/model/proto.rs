@@ -0,0 +1,242 @@
+//! Protobuf messages as values. A message keeps its prost-reflect descriptor, so
+//! its fields can be selected by name; each field value is converted on the way
+//! out with `from_proto` and checked against the field type on the way in with
+//! `to_proto`.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use prost::bytes::Bytes;
+use prost::Message;
+use prost_reflect::{
+    DescriptorPool, DynamicMessage, FieldDescriptor, Kind as ProtoKind, MapKey, MessageDescriptor,
+    ReflectMessage, Value as ProtoValue,
+};
+
+use crate::model::{Error, Identifier, ProtoError, Value};
+
+/// The fully qualified name of the message's type, e.g. `rpb.example.MyInput`.
+pub fn type_name(msg: &DynamicMessage) -> String {
+    msg.descriptor().full_name().to_owned()
+}
+
+/// Decodes the wire-format bytes of the message type `name` from `pool`.
+pub fn decode(pool: &DescriptorPool, name: &str, bytes: &[u8]) -> Result<Value, ProtoError> {
+    let desc = pool
+        .get_message_by_name(name)
+        .ok_or_else(|| ProtoError(format!("no message type named {}", name)))?;
+    DynamicMessage::decode(desc, bytes)
+        .map(Value::Message)
+        .map_err(|e| ProtoError(e.to_string()))
+}
+
+/// Converts a prost-generated message into a `Value`, given the descriptor of its type.
+pub fn from_message<M: Message>(desc: MessageDescriptor, msg: &M) -> Result<Value, ProtoError> {
+    DynamicMessage::decode(desc, msg.encode_to_vec().as_slice())
+        .map(Value::Message)
+        .map_err(|e| ProtoError(e.to_string()))
+}
+
+/// Narrower numeric types widen to `int`, `uint` and `double`, and enums are
+/// plain `int`s. Map keys are always strings here, so integer and bool keys
+/// are spelled out in decimal or as `true`/`false`.
+pub fn from_proto(v: ProtoValue) -> Value {
+    match v {
+        ProtoValue::Bool(b) => Value::Bool(b),
+        ProtoValue::I32(n) => Value::I64(i64::from(n)),
+        ProtoValue::I64(n) => Value::I64(n),
+        ProtoValue::U32(n) => Value::U64(u64::from(n)),
+        ProtoValue::U64(n) => Value::U64(n),
+        ProtoValue::F32(x) => Value::F64(f64::from(x)),
+        ProtoValue::F64(x) => Value::F64(x),
+        ProtoValue::String(s) => Value::String(s),
+        ProtoValue::Bytes(bs) => Value::Bytes(bs.to_vec()),
+        ProtoValue::EnumNumber(n) => Value::I64(i64::from(n)),
+        ProtoValue::Message(msg) => Value::Message(msg),
+        ProtoValue::List(vs) => Value::List(vs.into_iter().map(from_proto).collect()),
+        ProtoValue::Map(kvs) => Value::Map(
+            kvs.into_iter()
+                .map(|(k, v)| (map_key_string(k), from_proto(v)))
+                .collect(),
+        ),
+    }
+}
+
+fn map_key_string(k: MapKey) -> String {
+    match k {
+        MapKey::Bool(b) => b.to_string(),
+        MapKey::I32(n) => n.to_string(),
+        MapKey::I64(n) => n.to_string(),
+        MapKey::U32(n) => n.to_string(),
+        MapKey::U64(n) => n.to_string(),
+        MapKey::String(s) => s,
+    }
+}
+
+/// The inverse of `from_proto` for a value assigned to `field`. Fails if the
+/// value has the wrong type or doesn't fit in a 32-bit field.
+pub fn to_proto(field: &FieldDescriptor, v: Value) -> Result<ProtoValue, Error> {
+    let invalid = |kind| Error::InvalidMessageField(Identifier::new(field.name()), kind);
+    let kind = v.kind();
+    if field.is_map() {
+        let entry = match field.kind() {
+            ProtoKind::Message(entry) => entry,
+            _ => unreachable!("map fields are always backed by an entry message"),
+        };
+        let key_kind = entry.map_entry_key_field().kind();
+        let value_kind = entry.map_entry_value_field().kind();
+        let kvs = match v {
+            Value::Map(kvs) => kvs,
+            other => return Err(invalid(other.kind())),
+        };
+        let mut entries = HashMap::with_capacity(kvs.len());
+        for (k, v) in kvs {
+            let k = to_map_key(&key_kind, k).ok_or_else(|| invalid(kind))?;
+            let v_kind = v.kind();
+            let v = to_singular(&value_kind, v).ok_or_else(|| invalid(v_kind))?;
+            entries.insert(k, v);
+        }
+        Ok(ProtoValue::Map(entries))
+    } else if field.is_list() {
+        let vs = match v {
+            Value::List(vs) => vs,
+            other => return Err(invalid(other.kind())),
+        };
+        let mut elems = Vec::with_capacity(vs.len());
+        for v in vs {
+            let v_kind = v.kind();
+            elems.push(to_singular(&field.kind(), v).ok_or_else(|| invalid(v_kind))?);
+        }
+        Ok(ProtoValue::List(elems))
+    } else {
+        to_singular(&field.kind(), v).ok_or_else(|| invalid(kind))
+    }
+}
+
+fn to_singular(kind: &ProtoKind, v: Value) -> Option<ProtoValue> {
+    match (kind, v) {
+        (ProtoKind::Double, Value::F64(x)) => Some(ProtoValue::F64(x)),
+        (ProtoKind::Float, Value::F64(x)) => Some(ProtoValue::F32(x as f32)),
+        (ProtoKind::Int32, Value::I64(n))
+        | (ProtoKind::Sint32, Value::I64(n))
+        | (ProtoKind::Sfixed32, Value::I64(n)) => i32::try_from(n).ok().map(ProtoValue::I32),
+        (ProtoKind::Int64, Value::I64(n))
+        | (ProtoKind::Sint64, Value::I64(n))
+        | (ProtoKind::Sfixed64, Value::I64(n)) => Some(ProtoValue::I64(n)),
+        (ProtoKind::Uint32, Value::U64(n)) | (ProtoKind::Fixed32, Value::U64(n)) => {
+            u32::try_from(n).ok().map(ProtoValue::U32)
+        }
+        (ProtoKind::Uint64, Value::U64(n)) | (ProtoKind::Fixed64, Value::U64(n)) => {
+            Some(ProtoValue::U64(n))
+        }
+        (ProtoKind::Bool, Value::Bool(b)) => Some(ProtoValue::Bool(b)),
+        (ProtoKind::String, Value::String(s)) => Some(ProtoValue::String(s)),
+        (ProtoKind::Bytes, Value::Bytes(bs)) => Some(ProtoValue::Bytes(Bytes::from(bs))),
+        (ProtoKind::Enum(_), Value::I64(n)) => i32::try_from(n).ok().map(ProtoValue::EnumNumber),
+        (ProtoKind::Message(desc), Value::Message(msg)) if msg.descriptor() == *desc => {
+            Some(ProtoValue::Message(msg))
+        }
+        _ => None,
+    }
+}
+
+fn to_map_key(kind: &ProtoKind, k: String) -> Option<MapKey> {
+    match kind {
+        ProtoKind::String => Some(MapKey::String(k)),
+        ProtoKind::Bool => k.parse().ok().map(MapKey::Bool),
+        ProtoKind::Int32 | ProtoKind::Sint32 | ProtoKind::Sfixed32 => {
+            k.parse().ok().map(MapKey::I32)
+        }
+        ProtoKind::Int64 | ProtoKind::Sint64 | ProtoKind::Sfixed64 => {
+            k.parse().ok().map(MapKey::I64)
+        }
+        ProtoKind::Uint32 | ProtoKind::Fixed32 => k.parse().ok().map(MapKey::U32),
+        ProtoKind::Uint64 | ProtoKind::Fixed64 => k.parse().ok().map(MapKey::U64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::model::Kind;
+
+    /// `13-protos/api.proto`, regenerated with `just descriptors`.
+    pub(crate) fn api() -> DescriptorPool {
+        DescriptorPool::decode(&include_bytes!("../../testdata/api.desc")[..]).unwrap()
+    }
+
+    // A hand-written prost message with the same layout as `rpb.example.MyInput.Record`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Record {
+        #[prost(string, tag = "1")]
+        id: String,
+        #[prost(map = "string, string", tag = "2")]
+        params: HashMap<String, String>,
+    }
+
+    #[test]
+    fn decode_unknown_type() {
+        assert_eq!(
+            decode(&api(), "rpb.example.Nope", &[]),
+            Err(ProtoError(
+                "no message type named rpb.example.Nope".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn from_prost_message() {
+        let desc = api()
+            .get_message_by_name("rpb.example.MyInput.Record")
+            .unwrap();
+        let record = Record {
+            id: "r1".to_owned(),
+            params: vec![("k".to_owned(), "v".to_owned())].into_iter().collect(),
+        };
+        let msg = match from_message(desc.clone(), &record).unwrap() {
+            Value::Message(msg) => msg,
+            other => panic!("expected a message, got {:?}", other),
+        };
+        assert_eq!(type_name(&msg), "rpb.example.MyInput.Record");
+        let params = desc.get_field_by_name("params").unwrap();
+        assert_eq!(
+            from_proto(msg.get_field(&params).into_owned()),
+            Value::Map(
+                vec![("k".to_owned(), Value::String("v".to_owned()))]
+                    .into_iter()
+                    .collect()
+            )
+        );
+    }
+
+    #[test]
+    fn narrow_fields_are_range_checked() {
+        let desc = api().get_message_by_name("rpb.example.MyInput").unwrap();
+        let my_i32 = desc.get_field_by_name("my_i32").unwrap();
+        assert_eq!(to_proto(&my_i32, Value::I64(7)), Ok(ProtoValue::I32(7)));
+        assert_eq!(
+            to_proto(&my_i32, Value::I64(1 << 40)),
+            Err(Error::InvalidMessageField(
+                Identifier::new("my_i32"),
+                Kind::I64
+            ))
+        );
+        assert_eq!(
+            to_proto(&my_i32, Value::U64(7)),
+            Err(Error::InvalidMessageField(
+                Identifier::new("my_i32"),
+                Kind::U64
+            ))
+        );
+    }
+
+    #[test]
+    fn float_fields_widen() {
+        let desc = api().get_message_by_name("rpb.example.MyInput").unwrap();
+        let my_f32 = desc.get_field_by_name("my_f32").unwrap();
+        let v = to_proto(&my_f32, Value::F64(0.5)).unwrap();
+        assert_eq!(v, ProtoValue::F32(0.5));
+        assert_eq!(from_proto(v), Value::F64(0.5));
+    }
+}
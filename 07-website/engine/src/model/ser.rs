@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use serde::ser::{self, Serialize};
+
+use crate::model::{proto, SerdeError, Value};
+
+impl ser::Error for SerdeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        SerdeError(msg.to_string())
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use ser::{SerializeMap, SerializeSeq};
+        match self {
+            Value::I64(n) => serializer.serialize_i64(*n),
+            Value::U64(n) => serializer.serialize_u64(*n),
+            Value::F64(x) => serializer.serialize_f64(*x),
+            Value::Bool(b) => serializer.serialize_bool(*b),
+            Value::String(s) => serializer.serialize_str(s),
+            Value::Bytes(bs) => serializer.serialize_bytes(bs),
+            Value::List(vs) => {
+                let mut seq = serializer.serialize_seq(Some(vs.len()))?;
+                for v in vs {
+                    seq.serialize_element(v)?;
+                }
+                seq.end()
+            }
+            Value::Map(kvs) => {
+                let mut map = serializer.serialize_map(Some(kvs.len()))?;
+                for (k, v) in kvs {
+                    map.serialize_entry(k, v)?;
+                }
+                map.end()
+            }
+            Value::Null => serializer.serialize_unit(),
+            Value::Type(kind) => serializer.serialize_str(kind.type_name()),
+            Value::Optional(None) => serializer.serialize_none(),
+            Value::Optional(Some(v)) => serializer.serialize_some(v),
+            Value::Message(msg) => {
+                let mut map = serializer.serialize_map(None)?;
+                for (field, v) in msg.fields() {
+                    map.serialize_entry(field.name(), &proto::from_proto(v.clone()))?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+/// Converts any serializable Rust value into a `Value`.
+///
+/// Signed integers become `int`, unsigned integers become `int` when they
+/// fit and `uint` otherwise, and floats become `double`. `None` and `()`
+/// become `null`. `Vec<u8>` is a list of ints, as it is for every serde
+/// format. Only types that serialize as bytes, such as `serde_bytes::ByteBuf`,
+/// become `bytes`. Map keys must be strings. Enum variants with content
+/// become single-entry maps keyed by the variant name.
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, SerdeError> {
+    value.serialize(ValueSerializer)
+}
+
+struct ValueSerializer;
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeVariant<SerializeList>;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeVariant<SerializeMap>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, SerdeError> {
+        Ok(Value::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, SerdeError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, SerdeError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, SerdeError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, SerdeError> {
+        Ok(Value::I64(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, SerdeError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, SerdeError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, SerdeError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, SerdeError> {
+        Ok(i64::try_from(v).map_or(Value::U64(v), Value::I64))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, SerdeError> {
+        self.serialize_f64(f64::from(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, SerdeError> {
+        Ok(Value::F64(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, SerdeError> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, SerdeError> {
+        Ok(Value::String(v.to_owned()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, SerdeError> {
+        Ok(Value::Bytes(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Value, SerdeError> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, SerdeError> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, SerdeError> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value, SerdeError> {
+        Ok(Value::String(variant.to_owned()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, SerdeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, SerdeError> {
+        Ok(variant_map(variant, to_value(value)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeList, SerdeError> {
+        Ok(SerializeList(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeList, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, SerdeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeList>, SerdeError> {
+        Ok(SerializeVariant(variant, self.serialize_seq(Some(len))?))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeMap, SerdeError> {
+        Ok(SerializeMap(HashMap::new(), None))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeMap, SerdeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeMap>, SerdeError> {
+        Ok(SerializeVariant(variant, self.serialize_map(Some(len))?))
+    }
+}
+
+fn variant_map(variant: &str, value: Value) -> Value {
+    Value::Map(vec![(variant.to_owned(), value)].into_iter().collect())
+}
+
+struct SerializeList(Vec<Value>);
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        self.0.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        Ok(Value::List(self.0))
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+/// Accumulates map entries; holds on to a key until its value arrives.
+struct SerializeMap(HashMap<String, Value>, Option<String>);
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerdeError> {
+        match to_value(key)? {
+            Value::String(k) => {
+                self.1 = Some(k);
+                Ok(())
+            }
+            other => Err(SerdeError(format!(
+                "map keys must be strings, found {}",
+                other.kind().type_name()
+            ))),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        let key = self
+            .1
+            .take()
+            .expect("serialize_value called before serialize_key");
+        self.0.insert(key, to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        Ok(Value::Map(self.0))
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        self.0.insert(key.to_owned(), to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        Ok(Value::Map(self.0))
+    }
+}
+
+/// A tuple or struct variant: the content is wrapped in a map keyed by the variant name.
+struct SerializeVariant<T>(&'static str, T);
+
+impl ser::SerializeTupleVariant for SerializeVariant<SerializeList> {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerdeError> {
+        ser::SerializeSeq::serialize_element(&mut self.1, value)
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        Ok(variant_map(self.0, ser::SerializeSeq::end(self.1)?))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeVariant<SerializeMap> {
+    type Ok = Value;
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerdeError> {
+        ser::SerializeStruct::serialize_field(&mut self.1, key, value)
+    }
+
+    fn end(self) -> Result<Value, SerdeError> {
+        Ok(variant_map(self.0, ser::SerializeStruct::end(self.1)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct Request {
+        user: String,
+        roles: Vec<String>,
+        age: Option<u32>,
+        big: u64,
+        #[serde(with = "serde_bytes")]
+        token: Vec<u8>,
+        method: Method,
+    }
+
+    #[derive(Serialize)]
+    enum Method {
+        Get,
+        Post { body: String },
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_owned())
+    }
+
+    #[test]
+    fn struct_to_value() {
+        let request = Request {
+            user: "alice".to_owned(),
+            roles: vec!["admin".to_owned()],
+            age: None,
+            big: u64::MAX,
+            token: vec![1, 2],
+            method: Method::Get,
+        };
+        let expected = vec![
+            ("user", string("alice")),
+            ("roles", Value::List(vec![string("admin")])),
+            ("age", Value::Null),
+            ("big", Value::U64(u64::MAX)),
+            ("token", Value::Bytes(vec![1, 2])),
+            ("method", string("Get")),
+        ];
+        assert_eq!(
+            to_value(&request),
+            Ok(Value::Map(
+                expected
+                    .into_iter()
+                    .map(|(k, v)| (k.to_owned(), v))
+                    .collect()
+            ))
+        );
+    }
+
+    #[test]
+    fn unsigned_integers_prefer_int() {
+        assert_eq!(to_value(&7u64), Ok(Value::I64(7)));
+        assert_eq!(to_value(&7u8), Ok(Value::I64(7)));
+        assert_eq!(to_value(&-7i8), Ok(Value::I64(-7)));
+    }
+
+    #[test]
+    fn struct_variant_to_value() {
+        let method = Method::Post {
+            body: "hi".to_owned(),
+        };
+        let body = Value::Map(
+            vec![("body".to_owned(), string("hi"))]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(
+            to_value(&method),
+            Ok(Value::Map(
+                vec![("Post".to_owned(), body)].into_iter().collect()
+            ))
+        );
+    }
+
+    #[test]
+    fn non_string_keys_are_rejected() {
+        let map: HashMap<i32, i32> = vec![(1, 2)].into_iter().collect();
+        assert!(to_value(&map).is_err());
+    }
+
+    #[test]
+    fn value_to_json() {
+        let v = Value::List(vec![
+            Value::I64(-1),
+            Value::U64(2),
+            Value::F64(0.5),
+            Value::Null,
+            Value::Optional(None),
+            Value::Bytes(vec![1]),
+        ]);
+        assert_eq!(
+            serde_json::to_string(&v).unwrap(),
+            "[-1,2,0.5,null,null,[1]]"
+        );
+    }
+}
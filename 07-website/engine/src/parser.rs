@@ -0,0 +1,1251 @@
+use crate::model::{Declaration, Expression, Identifier, Literal};
+
+use pest::iterators::Pair;
+use pest::Parser;
+use pest_derive::Parser;
+
+use pest::error::LineColLocation;
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Parser)]
+#[grammar = "cel.pest"]
+struct CelParser;
+
+pub mod tokens;
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    Pest(LineColLocation),
+    IllegalInt(String),
+    IllegalFloat(String),
+    InvalidMacro(String),
+    /// The input exceeded one of the `Limits`, whose value is given.
+    LimitExceeded(Limit, usize),
+}
+
+impl<T: fmt::Debug> From<pest::error::Error<T>> for ParseError {
+    fn from(err: pest::error::Error<T>) -> Self {
+        ParseError::Pest(err.line_col)
+    }
+}
+impl From<std::num::ParseIntError> for ParseError {
+    fn from(err: std::num::ParseIntError) -> Self {
+        ParseError::IllegalInt(format!("{}", err))
+    }
+}
+impl From<std::num::ParseFloatError> for ParseError {
+    fn from(err: std::num::ParseFloatError) -> Self {
+        ParseError::IllegalFloat(format!("{}", err))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Pest(LineColLocation::Pos(pos)) => {
+                write!(f, "parse error @ L{}:{}", pos.0, pos.1)
+            }
+            ParseError::Pest(LineColLocation::Span(start, _end)) => {
+                write!(f, "parse error @ L{}:{}", start.0, start.1)
+            }
+            ParseError::IllegalInt(msg) => write!(f, "illegal integer: {}", msg),
+            ParseError::IllegalFloat(msg) => write!(f, "illegal integer: {}", msg),
+            ParseError::InvalidMacro(msg) => write!(f, "invalid macro: {}", msg),
+            ParseError::LimitExceeded(Limit::Length, max) => {
+                write!(f, "expression is longer than {} bytes", max)
+            }
+            ParseError::LimitExceeded(Limit::Depth, max) => {
+                write!(f, "expression nests deeper than {} levels", max)
+            }
+            ParseError::LimitExceeded(Limit::TreeDepth, max) => {
+                write!(f, "expression is more than {} operations deep", max)
+            }
+            ParseError::LimitExceeded(Limit::Nodes, max) => {
+                write!(f, "expression has more than {} nodes", max)
+            }
+        }
+    }
+}
+
+impl ParseError {
+    /// The byte range of `input` that the error points at, if it has one.
+    pub fn span(&self, input: &str) -> Option<Span> {
+        let offset = |(line, col): (usize, usize)| {
+            let start: usize = input.split('\n').take(line - 1).map(|l| l.len() + 1).sum();
+            let rest = input.get(start..).unwrap_or("");
+            start
+                + rest
+                    .chars()
+                    .take(col - 1)
+                    .map(char::len_utf8)
+                    .sum::<usize>()
+        };
+        match self {
+            ParseError::Pest(LineColLocation::Pos(pos)) => {
+                let start = offset(*pos);
+                let end = input[start..]
+                    .chars()
+                    .next()
+                    .map_or(start, |c| start + c.len_utf8());
+                Some(Span { start, end })
+            }
+            ParseError::Pest(LineColLocation::Span(start, end)) => Some(Span {
+                start: offset(*start),
+                end: offset(*end),
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub type ParseResult<T> = Result<T, ParseError>;
+
+/// A byte range of the source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    fn of(pair: &Pair<Rule>) -> Span {
+        let span = pair.as_span();
+        Span {
+            start: span.start(),
+            end: span.end(),
+        }
+    }
+}
+
+/// Converts a position in UTF-16 code units, as editors and the DOM count
+/// them, to a byte offset into `text`, clamped to its end.
+pub fn byte_offset(text: &str, utf16: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in text.char_indices() {
+        if units >= utf16 {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+/// Bounds on the input the parser accepts, so that a hostile or runaway
+/// expression is rejected up front rather than overflowing the stack of
+/// whatever recurses through it (the parser itself, `stack::walker`, the
+/// evaluators).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// In bytes.
+    pub max_length: usize,
+    /// How deeply brackets, ternaries and prefix operators may nest. Chains
+    /// of binary operators and member accesses don't count towards it.
+    pub max_depth: usize,
+    /// How deep the expression's tree may get, counting every operator and
+    /// member access, e.g. each `+` of `1 + 2 + 3`.
+    pub max_tree_depth: usize,
+    /// The number of nodes in the expression's tree.
+    pub max_nodes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_length: 100_000,
+            // Within what a thread with a 2MB stack (the default for tests)
+            // can take in a debug build: pest recurses through a dozen rules
+            // for every level of nesting, and whatever walks the tree through
+            // a few functions for every level of it.
+            max_depth: 50,
+            max_tree_depth: 1_000,
+            max_nodes: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Length,
+    Depth,
+    TreeDepth,
+    Nodes,
+}
+
+/// Parses `input` within the default `Limits`.
+pub fn parse(input: &str) -> ParseResult<Expression> {
+    parse_with_limits(input, &Limits::default())
+}
+
+pub fn parse_with_limits(input: &str, limits: &Limits) -> ParseResult<Expression> {
+    let mut nodes = Nodes::new(*limits);
+    parse_into(input, &mut nodes)
+}
+
+/// Like `parse`, but also returns the source span of every node, indexed in
+/// the same pre-order as the origins recorded by `stack::compile`.
+pub fn parse_with_spans(input: &str) -> ParseResult<(Expression, Vec<Span>)> {
+    let mut nodes = Nodes::new(Limits::default());
+    let expr = parse_into(input, &mut nodes)?;
+    let mut spans = Vec::with_capacity(nodes.spans.len());
+    pre_order(&expr, &nodes.spans, &mut spans);
+    Ok((expr, spans))
+}
+
+/// Parses a policy file, a sequence of `let name = value;` and `rule name =
+/// value;` declarations, within the default `Limits`. They apply to the file
+/// as a whole.
+pub fn parse_policy(input: &str) -> ParseResult<Vec<Declaration>> {
+    let mut nodes = Nodes::new(Limits::default());
+    check_input(input, &nodes.limits)?;
+    let parsed = CelParser::parse(Rule::Policy, input)?.next().unwrap();
+    let mut declarations = Vec::new();
+    for pair in parsed.into_inner() {
+        let rule = match pair.as_rule() {
+            Rule::NamedRule => true,
+            Rule::LetBinding => false,
+            _ => continue,
+        };
+        let mut pairs = pair.into_inner();
+        let name = extract_identifier(pairs.next().unwrap());
+        let value = extract_expression(pairs.next().unwrap(), &mut nodes)?;
+        declarations.push(Declaration { name, value, rule });
+    }
+    Ok(declarations)
+}
+
+fn parse_into(input: &str, nodes: &mut Nodes) -> ParseResult<Expression> {
+    check_input(input, &nodes.limits)?;
+    let mut parsed = CelParser::parse(Rule::TopLevel, input)?;
+    extract_top_level(parsed.next().unwrap(), nodes)
+}
+
+/// The checks on `input` that come before pest gets to it.
+fn check_input(input: &str, limits: &Limits) -> ParseResult<()> {
+    if input.len() > limits.max_length {
+        return Err(ParseError::LimitExceeded(Limit::Length, limits.max_length));
+    }
+    check_nesting(input, limits.max_depth)
+}
+
+/// Rejects input that would make the parser recurse more than `max_depth`
+/// levels before pest gets to it, since pest recurses as well. Brackets open
+/// a level, and within them every ternary and every prefix operator nests
+/// what follows one level deeper, the latter up to the next binary operator.
+fn check_nesting(input: &str, max_depth: usize) -> ParseResult<()> {
+    // For each enclosing bracket, the depth at the bracket and the levels
+    // opened within it by ternaries and by prefix operators. The depth is
+    // always worked out from these rather than kept in step with them.
+    let mut frames = vec![(0, 0, 0)];
+    let mut prev = None;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        let nested = frames.len() > 1;
+        let &mut (outer, ref mut ternaries, ref mut prefixes) = frames.last_mut().unwrap();
+        match c {
+            '"' | '\'' => {
+                while let Some(d) = chars.next() {
+                    match d {
+                        '\\' => {
+                            chars.next();
+                        }
+                        _ if d == c => break,
+                        _ => {}
+                    }
+                }
+            }
+            '(' | '[' | '{' => {
+                let depth = outer + *ternaries + *prefixes;
+                frames.push((depth + 1, 0, 0));
+            }
+            ')' | ']' | '}' if nested => {
+                frames.pop();
+            }
+            '?' if prev != Some('.') && prev != Some('[') => {
+                *ternaries += 1;
+                *prefixes = 0;
+            }
+            ',' | ';' => {
+                *ternaries = 0;
+                *prefixes = 0;
+            }
+            '-' | '!'
+                if chars.peek() != Some(&'=')
+                    && prev.is_none_or(|p| "([{,;?:+-*/%<>=!&|".contains(p)) =>
+            {
+                *prefixes += 1;
+            }
+            '+' | '-' | '*' | '/' | '%' | '<' | '>' | '=' | '!' | '&' | '|' | ':' => {
+                *prefixes = 0;
+            }
+            _ => {}
+        }
+        let &(outer, ternaries, prefixes) = frames.last().unwrap();
+        if outer + ternaries + prefixes > max_depth {
+            return Err(ParseError::LimitExceeded(Limit::Depth, max_depth));
+        }
+        if !c.is_whitespace() {
+            prev = Some(c);
+        }
+    }
+    Ok(())
+}
+
+fn pre_order(e: &Expression, post_order: &[Span], out: &mut Vec<Span>) {
+    out.push(post_order[post_order.len() - 1]);
+    let mut lo = 0;
+    for child in e.children() {
+        let n = child.node_count();
+        pre_order(child, &post_order[lo..lo + n], out);
+        lo += n;
+    }
+}
+
+/// What the extractors record about each node as they build it, i.e. in
+/// post-order.
+struct Nodes {
+    spans: Vec<Span>,
+    /// The depth of each node whose parent hasn't been built yet.
+    depths: Vec<usize>,
+    limits: Limits,
+}
+
+impl Nodes {
+    fn new(limits: Limits) -> Nodes {
+        Nodes {
+            spans: Vec::new(),
+            depths: Vec::new(),
+            limits,
+        }
+    }
+
+    /// Forgets the most recently built node, which had a single child.
+    fn unwrap_last(&mut self) {
+        self.spans.pop();
+        let depth = self.depths.pop().unwrap();
+        self.depths.push(depth - 1);
+    }
+}
+
+fn node(nodes: &mut Nodes, span: Span, e: Expression) -> ParseResult<Expression> {
+    let children = e.children().len();
+    let depth = 1 + nodes
+        .depths
+        .drain(nodes.depths.len() - children..)
+        .max()
+        .unwrap_or(0);
+    if depth > nodes.limits.max_tree_depth {
+        return Err(ParseError::LimitExceeded(
+            Limit::TreeDepth,
+            nodes.limits.max_tree_depth,
+        ));
+    }
+    if nodes.spans.len() == nodes.limits.max_nodes {
+        return Err(ParseError::LimitExceeded(
+            Limit::Nodes,
+            nodes.limits.max_nodes,
+        ));
+    }
+    nodes.depths.push(depth);
+    nodes.spans.push(span);
+    Ok(e)
+}
+
+/// The span from the start of `pair` to the end of the most recently built node.
+fn extend(pair_start: usize, nodes: &Nodes) -> Span {
+    Span {
+        start: pair_start,
+        end: nodes.spans.last().map_or(pair_start, |s| s.end),
+    }
+}
+
+fn extract_top_level(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Expression> {
+    assert_eq!(pair.as_rule(), Rule::TopLevel);
+    let mut pairs = pair.into_inner();
+
+    let mut bindings = vec![];
+    let body = loop {
+        let p = pairs.next().unwrap();
+        match p.as_rule() {
+            Rule::LetBinding => {
+                let start = p.as_span().start();
+                bindings.push((start, extract_binding(p, nodes)?));
+            }
+            _ => break extract_expression(p, nodes)?,
+        }
+    };
+
+    let end = nodes.spans.last().map_or(0, |s| s.end);
+    let mut expr = body;
+    for (start, (id, value)) in bindings.into_iter().rev() {
+        let e = Expression::LetBinding {
+            id,
+            value: Box::new(value),
+            body: Box::new(expr),
+        };
+        expr = node(nodes, Span { start, end }, e)?;
+    }
+    Ok(expr)
+}
+
+fn extract_binding(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<(Identifier, Expression)> {
+    assert_eq!(pair.as_rule(), Rule::LetBinding);
+    let mut pairs = pair.into_inner();
+    let id = extract_identifier(pairs.next().unwrap());
+    let value = extract_expression(pairs.next().unwrap(), nodes)?;
+    Ok((id, value))
+}
+
+fn extract_expression(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Expression> {
+    assert_eq!(pair.as_rule(), Rule::Ternary);
+    let span = Span::of(&pair);
+    let mut pairs = pair.into_inner();
+    let condition = extract_disjunction(pairs.next().unwrap(), nodes)?;
+    let (true_branch, false_branch) = match (pairs.next(), pairs.next()) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Ok(condition),
+    };
+    let true_branch = extract_expression(true_branch, nodes)?;
+    let false_branch = extract_expression(false_branch, nodes)?;
+    let e = Expression::Ternary {
+        condition: Box::new(condition),
+        true_branch: Box::new(true_branch),
+        false_branch: Box::new(false_branch),
+    };
+    node(nodes, span, e)
+}
+
+fn extract_disjunction(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Expression> {
+    assert_eq!(pair.as_rule(), Rule::Disjunction);
+    let span = Span::of(&pair);
+    let mut exprs: Vec<Expression> = pair
+        .into_inner()
+        .map(|p| extract_conjunction(p, nodes))
+        .collect::<ParseResult<_>>()?;
+    if exprs.len() == 1 {
+        Ok(exprs.swap_remove(0))
+    } else {
+        node(nodes, span, Expression::Or(exprs))
+    }
+}
+
+fn extract_conjunction(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Expression> {
+    assert_eq!(pair.as_rule(), Rule::Conjunction);
+    let span = Span::of(&pair);
+    let mut exprs: Vec<Expression> = pair
+        .into_inner()
+        .map(|p| extract_relation(p, nodes))
+        .collect::<ParseResult<_>>()?;
+    if exprs.len() == 1 {
+        Ok(exprs.swap_remove(0))
+    } else {
+        node(nodes, span, Expression::And(exprs))
+    }
+}
+
+fn extract_relation(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Expression> {
+    assert_eq!(pair.as_rule(), Rule::Relation);
+    let span = Span::of(&pair);
+    let mut pairs = pair.into_inner();
+    let a = extract_addition(pairs.next().unwrap(), nodes)?;
+    let outer = match pairs.next() {
+        None => a,
+        Some(op) => {
+            assert_eq!(op.as_rule(), Rule::RelOp);
+            let b = extract_addition(pairs.next().unwrap(), nodes)?;
+            let e = match op.as_str() {
+                "==" => Expression::Eq(Box::new(a), Box::new(b)),
+                "!=" => Expression::Neq(Box::new(a), Box::new(b)),
+                "<" => Expression::Lt(Box::new(a), Box::new(b)),
+                "<=" => Expression::Lte(Box::new(a), Box::new(b)),
+                ">=" => Expression::Gte(Box::new(a), Box::new(b)),
+                ">" => Expression::Gt(Box::new(a), Box::new(b)),
+                _ => unreachable!(),
+            };
+            node(nodes, span, e)?
+        }
+    };
+    Ok(outer)
+}
+
+fn extract_addition(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Expression> {
+    assert_eq!(pair.as_rule(), Rule::Addition);
+    let start = pair.as_span().start();
+    let mut pairs = pair.into_inner();
+    let mut a = extract_multiplication(pairs.next().unwrap(), nodes)?;
+    while let Some(op) = pairs.next() {
+        assert_eq!(op.as_rule(), Rule::AddOp);
+        let b = extract_multiplication(pairs.next().unwrap(), nodes)?;
+        let e = match op.as_str() {
+            "+" => Expression::Add(Box::new(a), Box::new(b)),
+            "-" => Expression::Sub(Box::new(a), Box::new(b)),
+            _ => unreachable!(),
+        };
+        a = node(nodes, extend(start, nodes), e)?;
+    }
+    Ok(a)
+}
+
+fn extract_multiplication(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Expression> {
+    assert_eq!(pair.as_rule(), Rule::Multiplication);
+    let start = pair.as_span().start();
+    let mut pairs = pair.into_inner();
+    let mut a = extract_unary(pairs.next().unwrap(), nodes)?;
+    while let Some(op) = pairs.next() {
+        assert_eq!(op.as_rule(), Rule::MulOp);
+        let b = extract_unary(pairs.next().unwrap(), nodes)?;
+        let e = match op.as_str() {
+            "*" => Expression::Mul(Box::new(a), Box::new(b)),
+            "/" => Expression::Div(Box::new(a), Box::new(b)),
+            "%" => Expression::Mod(Box::new(a), Box::new(b)),
+            _ => unreachable!(),
+        };
+        a = node(nodes, extend(start, nodes), e)?;
+    }
+    Ok(a)
+}
+
+fn extract_unary(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Expression> {
+    assert_eq!(pair.as_rule(), Rule::Unary);
+    let span = Span::of(&pair);
+    let mut pairs = pair.into_inner();
+    let a = pairs.next().unwrap();
+    match a.as_rule() {
+        Rule::Member => extract_member(a, nodes),
+        Rule::FunctionCall => extract_function_call(a, nodes),
+        Rule::UnaryOp => {
+            assert_eq!(a.as_rule(), Rule::UnaryOp);
+            let operand = Box::new(extract_unary(pairs.next().unwrap(), nodes)?);
+            let e = match a.as_str() {
+                "-" => Expression::Neg(operand),
+                "!" => Expression::Not(operand),
+                _ => unreachable!(),
+            };
+            node(nodes, span, e)
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn extract_member(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Expression> {
+    assert_eq!(pair.as_rule(), Rule::Member);
+    let start = pair.as_span().start();
+    let mut pairs = pair.into_inner();
+    let mut a = extract_operand(pairs.next().unwrap(), nodes)?;
+
+    for pair in pairs {
+        let span = Span {
+            start,
+            end: pair.as_span().end(),
+        };
+        a = match pair.as_rule() {
+            Rule::MethodCall => {
+                let (id, args) = extract_method_call(pair, nodes)?;
+                Expression::Method(Box::new(a), id, args)
+            }
+            Rule::MemberRef => {
+                let id = extract_member_ref(pair);
+                Expression::Member(Box::new(a), id)
+            }
+            Rule::OptionalMemberRef => {
+                let id = extract_optional_member_ref(pair);
+                Expression::OptionalMember(Box::new(a), id)
+            }
+            Rule::OptionalIndex => {
+                let key = extract_optional_index(pair, nodes)?;
+                Expression::OptionalIndex(Box::new(a), Box::new(key))
+            }
+            Rule::Index => {
+                let (id, args) = extract_index(pair, nodes)?;
+                Expression::Method(Box::new(a), id, vec![args])
+            }
+            _ => unreachable!(),
+        };
+        a = node(nodes, span, a)?;
+    }
+
+    Ok(a)
+}
+
+fn extract_function_call(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Expression> {
+    assert_eq!(pair.as_rule(), Rule::FunctionCall);
+    let span = Span::of(&pair);
+    let mut pairs = pair.into_inner();
+    let id = extract_identifier(pairs.next().unwrap());
+    let args = extract_args(pairs.next().unwrap(), nodes)?;
+    if id.0 == "has" {
+        let e = extract_has(args)?;
+        // The field selection isn't a node of its own once it's part of `has()`.
+        nodes.unwrap_last();
+        return node(nodes, span, e);
+    }
+    node(nodes, span, Expression::FunctionCall(id, args))
+}
+
+/// `has(a.b)` is a macro: its argument must be a field selection, which is
+/// tested for presence rather than evaluated.
+fn extract_has(mut args: Vec<Expression>) -> ParseResult<Expression> {
+    match args.pop() {
+        Some(Expression::Member(operand, id)) if args.is_empty() => {
+            Ok(Expression::Has(operand, id))
+        }
+        _ => Err(ParseError::InvalidMacro(
+            "has() requires a single field selection argument".to_owned(),
+        )),
+    }
+}
+
+fn extract_operand(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Expression> {
+    assert_eq!(pair.as_rule(), Rule::Operand);
+    let a = pair.into_inner().next().unwrap();
+    let span = Span::of(&a);
+    match a.as_rule() {
+        Rule::Literal => {
+            let e = Expression::Lit(extract_literal(a, nodes)?);
+            node(nodes, span, e)
+        }
+        Rule::MessageLiteral => {
+            let e = Expression::Lit(extract_message(a, nodes)?);
+            node(nodes, span, e)
+        }
+        Rule::Identifier => node(nodes, span, Expression::Binding(extract_identifier(a))),
+        Rule::FunctionCall => extract_function_call(a, nodes),
+        _ => extract_expression(a, nodes),
+    }
+}
+
+fn extract_method_call(
+    pair: Pair<Rule>,
+    nodes: &mut Nodes,
+) -> ParseResult<(Identifier, Vec<Expression>)> {
+    assert_eq!(pair.as_rule(), Rule::MethodCall);
+    let mut pairs = pair.into_inner();
+    Ok((
+        extract_identifier(pairs.next().unwrap()),
+        extract_args(pairs.next().unwrap(), nodes)?,
+    ))
+}
+
+fn extract_member_ref(pair: Pair<Rule>) -> Identifier {
+    assert_eq!(pair.as_rule(), Rule::MemberRef);
+    extract_identifier(pair.into_inner().next().unwrap())
+}
+
+fn extract_optional_member_ref(pair: Pair<Rule>) -> Identifier {
+    assert_eq!(pair.as_rule(), Rule::OptionalMemberRef);
+    extract_identifier(pair.into_inner().next().unwrap())
+}
+
+fn extract_optional_index(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Expression> {
+    assert_eq!(pair.as_rule(), Rule::OptionalIndex);
+    extract_expression(pair.into_inner().next().unwrap(), nodes)
+}
+
+fn extract_index(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<(Identifier, Expression)> {
+    assert_eq!(pair.as_rule(), Rule::Index);
+    let mut pairs = pair.into_inner();
+    Ok((
+        Identifier("get".to_owned()),
+        extract_expression(pairs.next().unwrap(), nodes)?,
+    ))
+}
+
+fn extract_identifier(pair: Pair<Rule>) -> Identifier {
+    assert_eq!(pair.as_rule(), Rule::Identifier);
+    pair.as_str().parse().expect("parse identifier")
+}
+
+fn extract_args(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Vec<Expression>> {
+    assert_eq!(pair.as_rule(), Rule::Args);
+    pair.into_inner()
+        .map(|p| extract_expression(p, nodes))
+        .collect()
+}
+
+fn extract_literal(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Literal> {
+    assert_eq!(pair.as_rule(), Rule::Literal);
+    let pair = pair.into_inner().next().unwrap();
+    match pair.as_rule() {
+        Rule::StringLiteral => Ok(Literal::String(extract_string(pair))),
+        Rule::BytesLiteral => Ok(Literal::Bytes(extract_bytes(pair))),
+        Rule::FloatLiteral => Ok(Literal::F64(pair.as_str().replace("_", "").parse()?)),
+        Rule::IntLiteral => Ok(Literal::I64(pair.as_str().replace("_", "").parse()?)),
+        Rule::ListLiteral => extract_list(pair, nodes),
+        Rule::MapLiteral => extract_map(pair, nodes),
+        Rule::BoolLiteral => Ok(Literal::Bool(pair.as_str().parse().unwrap())),
+        Rule::NullLiteral => Ok(Literal::Null),
+        _ => unreachable!(),
+    }
+}
+
+fn extract_string(pair: Pair<Rule>) -> String {
+    assert_eq!(pair.as_rule(), Rule::StringLiteral);
+    let mut buf = String::new();
+    for p in pair.into_inner() {
+        match unescape_sequence(&p) {
+            Unescaped::Byte(b) => buf.push(b as char),
+            Unescaped::Unicode(ch) => buf.push(ch),
+        };
+    }
+    buf
+}
+
+fn extract_bytes(pair: Pair<Rule>) -> Vec<u8> {
+    assert_eq!(pair.as_rule(), Rule::BytesLiteral);
+    let mut buf = Vec::new();
+    for p in pair.into_inner() {
+        match unescape_sequence(&p) {
+            Unescaped::Byte(b) => buf.push(b),
+            Unescaped::Unicode(ch) => buf.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes()),
+        };
+    }
+    buf
+}
+
+enum Unescaped {
+    Byte(u8),
+    Unicode(char),
+}
+fn unescape_sequence(pair: &Pair<Rule>) -> Unescaped {
+    match pair.as_rule() {
+        Rule::CharLiteral => Unescaped::Unicode(pair.as_str().chars().next().unwrap()),
+        Rule::Escape => {
+            let s = &pair.as_str()[1..];
+            match &s[..1] {
+                "t" => Unescaped::Byte(b'\t'),
+                "n" => Unescaped::Byte(b'\n'),
+                "\\" => Unescaped::Byte(b'\\'),
+                "\"" => Unescaped::Byte(b'"'),
+                "x" => Unescaped::Byte(u8::from_str_radix(&s[1..], 16).unwrap()),
+                "u" => Unescaped::Unicode(
+                    char::try_from(u32::from_str_radix(&s[1..], 16).unwrap()).unwrap(),
+                ),
+                "0" | "1" | "2" | "3" => Unescaped::Byte(u8::from_str_radix(s, 8).unwrap()),
+                _ => unreachable!("unexpected string literal {}", s),
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn extract_list(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Literal> {
+    assert_eq!(pair.as_rule(), Rule::ListLiteral);
+    let mut vs = Vec::new();
+    for p in pair.into_inner() {
+        vs.push(extract_expression(p, nodes)?);
+    }
+    Ok(Literal::List(vs))
+}
+
+fn extract_map(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Literal> {
+    assert_eq!(pair.as_rule(), Rule::MapLiteral);
+    let mut fields = Vec::new();
+    for p in pair.into_inner() {
+        fields.push(extract_map_field(p, nodes)?);
+    }
+    Ok(Literal::Map(fields))
+}
+
+fn extract_map_field(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<(Expression, Expression)> {
+    assert_eq!(pair.as_rule(), Rule::MapField);
+    let mut pairs = pair.into_inner();
+    Ok((
+        extract_expression(pairs.next().unwrap(), nodes)?,
+        extract_expression(pairs.next().unwrap(), nodes)?,
+    ))
+}
+
+fn extract_message(pair: Pair<Rule>, nodes: &mut Nodes) -> ParseResult<Literal> {
+    assert_eq!(pair.as_rule(), Rule::MessageLiteral);
+    let mut pairs = pair.into_inner();
+    let name = pairs.next().unwrap();
+    assert_eq!(name.as_rule(), Rule::TypeName);
+    let mut fields = Vec::new();
+    for p in pairs {
+        fields.push(extract_message_field(p, nodes)?);
+    }
+    Ok(Literal::Message(name.as_str().to_owned(), fields))
+}
+
+fn extract_message_field(
+    pair: Pair<Rule>,
+    nodes: &mut Nodes,
+) -> ParseResult<(Identifier, Expression)> {
+    assert_eq!(pair.as_rule(), Rule::MessageField);
+    let mut pairs = pair.into_inner();
+    Ok((
+        extract_identifier(pairs.next().unwrap()),
+        extract_expression(pairs.next().unwrap(), nodes)?,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::Literal;
+    use std::any::Any;
+
+    fn assert_valid(input: &str) {
+        parse(input).expect("failed to parse");
+    }
+
+    fn assert_invalid(input: &str) {
+        let parsed = parse(input);
+        assert!(parsed.is_err(), "{} was accepted as {:?}", input, parsed);
+    }
+
+    fn literal(x: &dyn Any) -> Expression {
+        if let Some(&s) = x.downcast_ref::<&str>() {
+            return Expression::Lit(Literal::String(String::from(s)));
+        }
+        if let Some(&b) = x.downcast_ref::<&[u8]>() {
+            return Expression::Lit(Literal::Bytes(b.to_vec()));
+        }
+        unimplemented!("literal of type {:?}", x.type_id())
+    }
+
+    #[test]
+    fn cel_valid() {
+        assert_valid("22 * (4 + 15)");
+        assert_valid("22 * -4");
+        assert_valid("!false");
+    }
+
+    #[test]
+    fn float_literals() {
+        assert_valid("3.1415926");
+        assert_valid("1_024__.1_4_1_5_____");
+        assert_invalid(".1415926");
+        assert_invalid("3.");
+        assert_invalid("3._0");
+    }
+
+    #[test]
+    fn float_literal_overflow() {
+        assert_eq!(
+            parse("9999999999999999999999999.0"),
+            Ok(Expression::Lit(Literal::F64(1e25))),
+        );
+    }
+
+    #[test]
+    fn null_literal() {
+        assert_valid("null");
+    }
+
+    #[test]
+    fn int_literals() {
+        assert_valid("1");
+        assert_valid("31415");
+        assert_valid("1_000_000_000");
+    }
+
+    #[test]
+    fn int_literal_overflow() {
+        assert_eq!(
+            parse("9999999999999999999999999"),
+            Err(ParseError::IllegalInt(
+                "number too large to fit in target type".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn cel_smoke() {
+        let input = "22 * (4 + 15)";
+        assert_eq!(
+            parse(input),
+            Ok(Expression::Mul(
+                Box::new(Expression::Lit(Literal::I64(22))),
+                Box::new(Expression::Add(
+                    Box::new(Expression::Lit(Literal::I64(4))),
+                    Box::new(Expression::Lit(Literal::I64(15))),
+                ))
+            ))
+        );
+    }
+
+    #[test]
+    fn cel_list() {
+        let input = "[0, false || true]";
+        assert_eq!(
+            parse(input),
+            Ok(Expression::Lit(Literal::List(vec![
+                Expression::Lit(Literal::I64(0)),
+                Expression::Or(vec![
+                    Expression::Lit(Literal::Bool(false)),
+                    Expression::Lit(Literal::Bool(true)),
+                ])
+            ])))
+        );
+    }
+
+    #[test]
+    fn valid_list() {
+        assert_valid(r#" [] "#); // empty
+        assert_valid(r#" [x, y, z] "#); // bindings
+        assert_valid(r#" [ "a", "b", "c",  ] "#); // trailing comma
+        assert_valid(r#" [[[[[0]]]]] "#); // nested
+    }
+
+    #[test]
+    fn valid_maps() {
+        assert_valid(r#" { "foo": "bar" } "#); // string literals
+        assert_valid(r#" { foo: bar } "#); // bindings
+        assert_valid(r#" { a: b, c: d, e: f } "#); // multiple fields
+        assert_valid(r#" {} "#); // empty
+        assert_valid(
+            r#" {
+          "a": "b",
+          "p": "q"
+        } "#,
+        ); // multi-line
+        assert_valid(
+            r#" {
+          "a": "b",
+          "p": "q",
+        } "#,
+        ); // trailing comma
+    }
+
+    #[test]
+    fn cel_string() {
+        let input = r#""asdf""#;
+        assert_eq!(
+            parse(input),
+            Ok(Expression::Lit(Literal::String(String::from("asdf"))))
+        );
+    }
+
+    #[test]
+    fn valid_string_literals() {
+        assert_valid(r#" "asdf" "#);
+        assert_valid(r#" 'asdf' "#);
+        assert_valid(r#" '¢' "#);
+    }
+
+    #[test]
+    fn cel_escaped_quote_string() {
+        let input = r#""as\"df""#;
+        assert_eq!(
+            parse(input),
+            Ok(Expression::Lit(Literal::String(String::from("as\"df"))))
+        );
+    }
+
+    #[test]
+    fn cel_escaped_backslash_string() {
+        let input = r#""a\\.b""#;
+        assert_eq!(
+            parse(input),
+            Ok(Expression::Lit(Literal::String(String::from("a\\.b"))))
+        );
+    }
+
+    #[test]
+    fn invalid_octal_escapes() {
+        assert_invalid(r#" "\0" "#);
+        assert_invalid(r#" "\7" "#);
+        assert_invalid(r#" "\07" "#);
+        assert_invalid(r#" "\77" "#);
+        assert_invalid(r#" "\8" "#);
+        assert_invalid(r#" "\378" "#);
+        assert_invalid(r#" "\400" "#);
+    }
+
+    #[test]
+    fn valid_octal_escapes() {
+        assert_eq!(parse(r#" "\000" "#).unwrap(), literal(&"\u{0000}"));
+        assert_eq!(parse(r#" "\007" "#).unwrap(), literal(&"\u{0007}"));
+        assert_eq!(parse(r#" "\377" "#).unwrap(), literal(&"\u{00FF}"));
+    }
+
+    #[test]
+    fn valid_hex_escapes() {
+        assert_eq!(parse(r#" "\x00" "#).unwrap(), literal(&"\u{0000}"));
+        assert_eq!(parse(r#" "\xFF" "#).unwrap(), literal(&"\u{00FF}"));
+    }
+
+    #[test]
+    fn valid_unicode_escapes() {
+        assert_eq!(parse(r#" "\u0000" "#).unwrap(), literal(&"\u{0000}"));
+        assert_eq!(parse(r#" "\u00FF" "#).unwrap(), literal(&"\u{00FF}"));
+        assert_eq!(parse(r#" "\uFF00" "#).unwrap(), literal(&"\u{FF00}"));
+        assert_eq!(parse(r#" "\uFFFF" "#).unwrap(), literal(&"\u{FFFF}"));
+    }
+
+    #[test]
+    fn valid_bytes() {
+        assert_eq!(parse(r#" b"asdf" "#).unwrap(), literal(&"asdf".as_bytes()));
+    }
+
+    #[test]
+    fn method_call() {
+        assert_valid(r#" [1, 2, 3].len() "#);
+        assert_valid(r#" 42.pow(42) "#);
+        assert_valid(r#" ([1] + [2]).len() "#);
+        assert_valid(r#" ([1] + [2]).len().pow(2) "#);
+        assert_valid(r#" ([1] + [2]).foo.bar.baz(1,2,3).length.asdf("asdf") "#);
+    }
+
+    #[test]
+    fn member_access() {
+        assert_valid(r#" foo "#);
+        assert_valid(r#" foo.bar.baz "#);
+    }
+
+    #[test]
+    fn multi_conjunction() {
+        assert_valid(r#" true && true && true && true "#);
+    }
+
+    #[test]
+    fn multi_disjunction() {
+        assert_valid(r#" true || true || true || true "#);
+    }
+
+    #[test]
+    fn relations() {
+        assert_valid(r#" 0 < 1 && 1 <= 2 && 3 == 3 && 4 >= 3 && 4 > 3 && 0 != 0 "#);
+    }
+
+    #[test]
+    fn let_binding_smoke() {
+        assert_valid(r#" let x = 42; x "#);
+    }
+
+    #[test]
+    fn let_binding_no_expression() {
+        assert_invalid(r#" let x = 42; "#);
+    }
+
+    #[test]
+    fn let_rebinding() {
+        assert_valid(r#" let x = 42; let x = x*x; x "#);
+    }
+
+    #[test]
+    fn ternary_operator() {
+        assert_valid(r#" true ? 1 : 2 "#);
+        assert_valid(r#" foo == bar || baz ? 1 : 2 "#);
+        assert_valid(r#" a ? b : c ? d : e "#); // chianing is fine
+        assert_valid(r#" a?b:c "#); // whitespace is optional
+        assert_valid(r#" ( foo ? true : bar ? true : true) "#); // inside parentheses
+    }
+
+    #[test]
+    fn ternary_operator_inside_map_literal() {
+        assert_valid(r#" { true ? "a" : "b" : "foo"  } "#); //  evaluates to { "a": "foo" }
+        assert_valid(r#" { "a" : true ? "foo" : bar } "#); //  evaluates to { "a": "foo" }
+    }
+
+    #[test]
+    fn function_call() {
+        assert_valid(r#"evaluate("SQL", {})"#);
+    }
+
+    #[test]
+    fn function_call_then_method() {
+        assert_valid(r#"evaluate("SQL", {}).len()"#);
+    }
+
+    #[test]
+    fn map_get() {
+        assert_valid(r#"{'a': 'a'}['a']"#);
+    }
+
+    #[test]
+    fn has_macro() {
+        assert_eq!(
+            parse(r#" has(a.b) "#),
+            Ok(Expression::Has(
+                Box::new(Expression::Binding(Identifier::new("a"))),
+                Identifier::new("b")
+            ))
+        );
+        assert_invalid(r#" has(a) "#);
+        assert_invalid(r#" has(a.b, c.d) "#);
+        assert_invalid(r#" has(a.b()) "#);
+    }
+
+    #[test]
+    fn optional_navigation() {
+        assert_valid(r#" a.?b "#);
+        assert_valid(r#" a.?b.c.?d "#);
+        assert_valid(r#" m[?"k"] "#);
+        assert_valid(r#" a.?b[?0].orValue(1) "#);
+        assert_valid(r#" a.?b ? 1 : 2 "#);
+        assert_invalid(r#" a?.b "#);
+    }
+
+    #[test]
+    fn message_literal() {
+        assert_eq!(
+            parse(r#" pkg.Msg{a: 1} "#),
+            Ok(Expression::Lit(Literal::Message(
+                "pkg.Msg".to_owned(),
+                vec![(Identifier::new("a"), Expression::Lit(Literal::I64(1)))]
+            )))
+        );
+        assert_valid(r#" .pkg.Msg{} "#);
+        assert_valid(r#" Msg{a: [1], b: Other{},}.a "#);
+        assert_valid(r#" a.b ? Msg{} : {} "#);
+        assert_invalid(r#" Msg{"a": 1} "#);
+    }
+
+    fn spanned(input: &str) -> Vec<&str> {
+        let (_, spans) = parse_with_spans(input).unwrap();
+        spans.iter().map(|s| &input[s.start..s.end]).collect()
+    }
+
+    #[test]
+    fn spans_follow_node_order() {
+        assert_eq!(
+            spanned(" 1 + x.y * 2 "),
+            vec!["1 + x.y * 2", "1", "x.y * 2", "x.y", "x", "2"]
+        );
+        assert_eq!(
+            spanned("let a = [1]; has(a.b) || -a"),
+            vec![
+                "let a = [1]; has(a.b) || -a",
+                "[1]",
+                "1",
+                "has(a.b) || -a",
+                "has(a.b)",
+                "a",
+                "-a",
+                "a",
+            ]
+        );
+        assert_eq!(
+            spanned("m.f(1)[2] ? {\"k\": v} : M{f: 3}"),
+            vec![
+                "m.f(1)[2] ? {\"k\": v} : M{f: 3}",
+                "m.f(1)[2]",
+                "m.f(1)",
+                "m",
+                "1",
+                "2",
+                "{\"k\": v}",
+                "\"k\"",
+                "v",
+                "M{f: 3}",
+                "3",
+            ]
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let limits = Limits {
+            max_length: 8,
+            ..Limits::default()
+        };
+        assert_eq!(
+            parse_with_limits("1 + 2", &limits),
+            Ok(parse("1 + 2").unwrap())
+        );
+        assert_eq!(
+            parse_with_limits("1 + 2 + 3", &limits),
+            Err(ParseError::LimitExceeded(Limit::Length, 8))
+        );
+        for input in &[
+            format!("{}x{}", "(".repeat(40_000), ")".repeat(40_000)),
+            format!("{}x{}", "f([{".repeat(1_000), "}])".repeat(1_000)),
+            "[".repeat(100_000),
+            format!("{}x", "!-".repeat(1_000)),
+            format!("{}x", "a ? b : ".repeat(1_000)),
+        ] {
+            assert_eq!(
+                parse(input),
+                Err(ParseError::LimitExceeded(Limit::Depth, 50)),
+                "{}",
+                &input[..20]
+            );
+        }
+        for input in &[
+            format!("{}x", "x + ".repeat(1_000)),
+            format!("x{}", ".y".repeat(1_000)),
+            format!("-({}x)", "x * ".repeat(999)),
+        ] {
+            assert_eq!(
+                parse(input),
+                Err(ParseError::LimitExceeded(Limit::TreeDepth, 1_000)),
+                "{}",
+                &input[..20]
+            );
+        }
+        assert_eq!(
+            parse(&format!("[{}]", "1,".repeat(10_000))),
+            Err(ParseError::LimitExceeded(Limit::Nodes, 10_000))
+        );
+    }
+
+    #[test]
+    fn limits_count_nesting() {
+        // Neither brackets in strings nor operands that follow one another nest.
+        assert_valid(&format!("'{}' + \"{}\"", "(".repeat(100), "[".repeat(100)));
+        assert_valid(&format!("{}x", "-x + ".repeat(45)));
+        assert_valid(&format!("[{}]", "(1), ".repeat(1_000)));
+        assert_valid(&format!("1{}", " + 1".repeat(999)));
+        assert_valid(&format!("x{}", ".y".repeat(999)));
+        assert_valid(&format!("{}x", "a ? b : ".repeat(45)));
+        assert_valid("a.?b[?c] == x ? [y] : -(z)");
+    }
+
+    #[test]
+    fn prefixes_before_a_ternary() {
+        for input in [
+            "!!true ? 1 : 2",
+            "--1 ? 1 : 2",
+            "(!!-x ? 1 : 2)",
+            "f(!!a ? b : c, 1)",
+        ] {
+            assert_valid(input);
+        }
+        assert_eq!(parse("!!true ? 1 : 2"), parse("(!(!true)) ? 1 : 2"));
+        assert_eq!(parse("--1 ? 1 : 2"), parse("(-(-1)) ? 1 : 2"));
+    }
+
+    #[test]
+    fn policy() {
+        let declarations = parse_policy(
+            "
+            let owner = request.user == resource.owner;
+            rule is_admin = request.role == 'admin';
+            rule allow = is_admin || owner;
+            ",
+        )
+        .unwrap();
+        let names: Vec<(&str, bool)> = declarations
+            .iter()
+            .map(|d| (d.name.0.as_str(), d.rule))
+            .collect();
+        assert_eq!(
+            names,
+            vec![("owner", false), ("is_admin", true), ("allow", true)]
+        );
+        assert_eq!(declarations[2].value, parse("is_admin || owner").unwrap());
+        assert_eq!(parse_policy(" "), Ok(vec![]));
+        assert!(parse_policy("rule allow = true").is_err());
+        assert!(parse_policy("rule allow = true; allow").is_err());
+    }
+
+    #[test]
+    fn parse_error_span() {
+        let input = "1 +\n  (2 +)";
+        let err = parse(input).unwrap_err();
+        assert_eq!(err.span(input), Some(Span { start: 10, end: 11 }));
+        assert_eq!(ParseError::IllegalInt(String::new()).span(input), None);
+    }
+
+    #[test]
+    fn utf16_offsets() {
+        let text = "'é😀' + x";
+        assert_eq!(byte_offset(text, 0), 0);
+        assert_eq!(byte_offset(text, 2), 3);
+        assert_eq!(byte_offset(text, 4), 7);
+        assert_eq!(byte_offset(text, 5), 8);
+        assert_eq!(byte_offset(text, 100), text.len());
+    }
+}
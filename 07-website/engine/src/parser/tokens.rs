@@ -0,0 +1,199 @@
+//! A flat token stream for highlighting, from the grammar's `Tokens` rule.
+//! Unlike `parse`, it accepts any input, so that an unfinished expression
+//! still highlights: whatever isn't a token comes out as `TokenKind::Unknown`,
+//! one character at a time.
+
+use pest::Parser;
+
+use super::{CelParser, Rule, Span};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Int,
+    Float,
+    String,
+    Bytes,
+    Bool,
+    Null,
+    /// `let` and `rule`.
+    Keyword,
+    Identifier,
+    Operator,
+    Bracket,
+    /// `,`, `.` and `;`.
+    Punctuation,
+    Unknown,
+}
+
+impl TokenKind {
+    /// A lowercase name, e.g. for a CSS class.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TokenKind::Int => "int",
+            TokenKind::Float => "float",
+            TokenKind::String => "string",
+            TokenKind::Bytes => "bytes",
+            TokenKind::Bool => "bool",
+            TokenKind::Null => "null",
+            TokenKind::Keyword => "keyword",
+            TokenKind::Identifier => "identifier",
+            TokenKind::Operator => "operator",
+            TokenKind::Bracket => "bracket",
+            TokenKind::Punctuation => "punctuation",
+            TokenKind::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// The tokens of `input`, in order. The whitespace between them isn't included.
+pub fn tokens(input: &str) -> impl Iterator<Item = Token> + '_ {
+    let stream = CelParser::parse(Rule::Tokens, input)
+        .expect("every input is a token stream")
+        .next()
+        .unwrap();
+    stream.into_inner().filter_map(move |pair| {
+        let kind = match pair.as_rule() {
+            Rule::IntLiteral => TokenKind::Int,
+            Rule::FloatLiteral => TokenKind::Float,
+            Rule::StringLiteral => TokenKind::String,
+            Rule::BytesLiteral => TokenKind::Bytes,
+            Rule::Identifier => match pair.as_str() {
+                "true" | "false" => TokenKind::Bool,
+                "null" => TokenKind::Null,
+                "let" | "rule" => TokenKind::Keyword,
+                _ => TokenKind::Identifier,
+            },
+            Rule::Operator => TokenKind::Operator,
+            Rule::Bracket => TokenKind::Bracket,
+            Rule::Punctuation => TokenKind::Punctuation,
+            Rule::Unknown => TokenKind::Unknown,
+            _ => return None,
+        };
+        Some(Token {
+            kind,
+            span: Span::of(&pair),
+        })
+    })
+}
+
+/// For each of `tokens`, the index of the bracket it pairs with, if it's a
+/// bracket with a partner. A closing bracket pairs with the innermost
+/// unpaired opening one of the same shape, and the opening brackets inside
+/// that are still unpaired stay so.
+pub fn match_brackets(tokens: &[Token], input: &str) -> Vec<Option<usize>> {
+    let mut partners = vec![None; tokens.len()];
+    let mut open: Vec<(usize, u8)> = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if token.kind != TokenKind::Bracket {
+            continue;
+        }
+        let expected = match input.as_bytes()[token.span.start] {
+            b')' => b'(',
+            b']' => b'[',
+            b'}' => b'{',
+            c => {
+                open.push((i, c));
+                continue;
+            }
+        };
+        if let Some(k) = open.iter().rposition(|&(_, c)| c == expected) {
+            let j = open[k].0;
+            open.truncate(k);
+            partners[i] = Some(j);
+            partners[j] = Some(i);
+        }
+    }
+    partners
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kinds(input: &str) -> Vec<(TokenKind, &str)> {
+        tokens(input)
+            .map(|t| (t.kind, &input[t.span.start..t.span.end]))
+            .collect()
+    }
+
+    #[test]
+    fn lexemes() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("let x = b'\\x00' + 1.5;\n  x.size() >= 2 || null != \"a\\\"b\""),
+            vec![
+                (Keyword, "let"),
+                (Identifier, "x"),
+                (Operator, "="),
+                (Bytes, "b'\\x00'"),
+                (Operator, "+"),
+                (Float, "1.5"),
+                (Punctuation, ";"),
+                (Identifier, "x"),
+                (Punctuation, "."),
+                (Identifier, "size"),
+                (Bracket, "("),
+                (Bracket, ")"),
+                (Operator, ">="),
+                (Int, "2"),
+                (Operator, "||"),
+                (Null, "null"),
+                (Operator, "!="),
+                (String, "\"a\\\"b\""),
+            ]
+        );
+    }
+
+    #[test]
+    fn unfinished_input() {
+        use TokenKind::*;
+        assert_eq!(kinds(""), vec![]);
+        assert_eq!(
+            kinds("f(true, 'ab\t#"),
+            vec![
+                (Identifier, "f"),
+                (Bracket, "("),
+                (Bool, "true"),
+                (Punctuation, ","),
+                (Unknown, "'"),
+                (Identifier, "ab"),
+                (Unknown, "\t"),
+                (Unknown, "#"),
+            ]
+        );
+    }
+
+    #[test]
+    fn brackets() {
+        let input = "[a(b), {c: (]])";
+        let tokens: Vec<Token> = tokens(input).collect();
+        let pairs: Vec<(&str, Option<&str>)> = match_brackets(&tokens, input)
+            .into_iter()
+            .zip(&tokens)
+            .filter(|(_, t)| t.kind == TokenKind::Bracket)
+            .map(|(partner, t)| {
+                let text = |t: &Token| &input[t.span.start..t.span.end];
+                (text(t), partner.map(|j| text(&tokens[j])))
+            })
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("[", Some("]")),
+                ("(", Some(")")),
+                (")", Some("(")),
+                ("{", None),
+                ("(", None),
+                ("]", Some("[")),
+                ("]", None),
+                (")", None),
+            ]
+        );
+    }
+}
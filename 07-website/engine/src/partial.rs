@@ -0,0 +1,440 @@
+//! Partial evaluation, for when some of an expression's inputs aren't known
+//! yet: e.g. an authorization pre-check that knows the principal but not the
+//! resource.
+//!
+//! Attributes, an identifier optionally followed by fields (`resource` or
+//! `resource.owner`), are marked unknown in `Unknowns`. Reading one evaluates
+//! to `Error::Unknown`, which propagates through operations like any other
+//! error, except in `||` and `&&`: an operand that decides the result still
+//! does, and otherwise unknowns win over errors. When the result is unknown,
+//! `evaluate` also returns the residual expression, in which everything that
+//! didn't depend on an unknown has been evaluated and short-circuited away.
+//! Evaluating the residual once the unknowns are known gives the same result
+//! as evaluating the original expression would have.
+
+use prost_reflect::DescriptorPool;
+
+use crate::model::{Activation, Error, EvalResult, Expression, Identifier, Kind, Literal, Value};
+use crate::reference::{attribute_path, Evaluator};
+
+/// Attributes whose values aren't known yet.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Unknowns(Vec<Vec<String>>);
+
+impl Unknowns {
+    pub fn new() -> Unknowns {
+        Unknowns::default()
+    }
+
+    /// Marks `attribute`, e.g. `resource.owner`, as unknown. Anything read
+    /// through it (`resource.owner.name`) is unknown too, as is anything that
+    /// contains it (`resource`).
+    pub fn mark(&mut self, attribute: &str) {
+        self.0
+            .push(attribute.split('.').map(|s| s.trim().to_owned()).collect());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether reading `path` would read a marked attribute.
+    pub(crate) fn overlaps(&self, path: &[&Identifier]) -> bool {
+        self.0
+            .iter()
+            .any(|marked| marked.iter().zip(path).all(|(name, id)| *name == id.0))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Partial {
+    /// The result didn't depend on any unknown attribute.
+    Known(EvalResult),
+    /// The result depends on `attributes`; `residual` is what is left to
+    /// evaluate once they are known.
+    Unknown {
+        attributes: Vec<String>,
+        residual: Expression,
+    },
+}
+
+impl Partial {
+    /// The result, with an unknown one as `Error::Unknown`.
+    pub fn result(self) -> EvalResult {
+        match self {
+            Partial::Known(result) => result,
+            Partial::Unknown { attributes, .. } => Err(Error::Unknown(attributes)),
+        }
+    }
+}
+
+/// Evaluates `expr` with its free identifiers bound by `activation`, except
+/// for the attributes in `unknowns`.
+pub fn evaluate(expr: &Expression, activation: &Activation, unknowns: &Unknowns) -> Partial {
+    evaluate_with_types(expr, activation, unknowns, &DescriptorPool::new())
+}
+
+/// Like `evaluate`, additionally resolving message construction against the
+/// message types in `types`.
+pub fn evaluate_with_types(
+    expr: &Expression,
+    activation: &Activation,
+    unknowns: &Unknowns,
+    types: &DescriptorPool,
+) -> Partial {
+    let mut evaluator = Evaluator::new(activation, types, unknowns);
+    match evaluator.eval(expr) {
+        Err(Error::Unknown(attributes)) => Partial::Unknown {
+            attributes,
+            residual: residual(&mut evaluator, expr),
+        },
+        result => Partial::Known(result),
+    }
+}
+
+/// `e` with every subexpression whose value is known replaced by that value,
+/// where it can be written as a literal. The kinds of node that need more
+/// than `map_children` get functions of their own, which keeps this one's
+/// frame small.
+fn residual(evaluator: &mut Evaluator, e: &Expression) -> Expression {
+    match evaluator.eval(e) {
+        Err(Error::Unknown(_)) => {}
+        Ok(value) => return literal(&value).unwrap_or_else(|| e.clone()),
+        // Left as it is, to fail the same way when the residual is evaluated.
+        Err(_) => return e.clone(),
+    }
+    match e {
+        Expression::Binding(_) | Expression::Member(_, _) if attribute_path(e).is_some() => {
+            e.clone()
+        }
+        Expression::Has(a, _) if attribute_path(a).is_some() => e.clone(),
+        Expression::LetBinding { id, value, body } => residual_let(evaluator, id, value, body),
+        Expression::Or(es) | Expression::And(es) => residual_logical(evaluator, e, es),
+        Expression::Ternary {
+            condition,
+            true_branch,
+            false_branch,
+        } => residual_ternary(evaluator, condition, true_branch, false_branch),
+        _ => map_children(e, |child| residual(evaluator, child)),
+    }
+}
+
+/// The body is simplified with the binding in scope, as it's evaluated.
+fn residual_let(
+    evaluator: &mut Evaluator,
+    id: &Identifier,
+    value: &Expression,
+    body: &Expression,
+) -> Expression {
+    let bound = evaluator.eval(value);
+    let value = residual(evaluator, value);
+    evaluator.bind(id, bound);
+    let body = residual(evaluator, body);
+    evaluator.unbind();
+    Expression::LetBinding {
+        id: id.clone(),
+        value: Box::new(value),
+        body: Box::new(body),
+    }
+}
+
+/// `e` is `||` or `&&` over `es`. Neither has an operand that decides the
+/// result, or it would be known, so only the operands that are already
+/// `false` (for `||`) or `true` (for `&&`) can go. If that leaves one operand
+/// that could be something other than a bool, they all stay, so that it's
+/// still checked and fails the same way.
+fn residual_logical(evaluator: &mut Evaluator, e: &Expression, es: &[Expression]) -> Expression {
+    let operands: Vec<(Expression, bool)> = es
+        .iter()
+        .map(|e| {
+            let known = matches!(evaluator.eval(e), Ok(Value::Bool(_)));
+            (residual(evaluator, e), known)
+        })
+        .collect();
+    let mut unknown: Vec<Expression> = operands
+        .iter()
+        .filter(|(_, known)| !known)
+        .map(|(e, _)| e.clone())
+        .collect();
+    let operands = match unknown.len() {
+        1 if is_bool(&unknown[0]) => return unknown.pop().unwrap(),
+        1 => operands.into_iter().map(|(e, _)| e).collect(),
+        _ => unknown,
+    };
+    match e {
+        Expression::Or(_) => Expression::Or(operands),
+        _ => Expression::And(operands),
+    }
+}
+
+fn residual_ternary(
+    evaluator: &mut Evaluator,
+    condition: &Expression,
+    true_branch: &Expression,
+    false_branch: &Expression,
+) -> Expression {
+    match evaluator.eval(condition) {
+        Ok(Value::Bool(true)) => residual(evaluator, true_branch),
+        Ok(Value::Bool(false)) => residual(evaluator, false_branch),
+        _ => Expression::Ternary {
+            condition: Box::new(residual(evaluator, condition)),
+            true_branch: Box::new(residual(evaluator, true_branch)),
+            false_branch: Box::new(residual(evaluator, false_branch)),
+        },
+    }
+}
+
+/// Whether `e` can only evaluate to a bool, if it doesn't fail.
+fn is_bool(e: &Expression) -> bool {
+    matches!(
+        e,
+        Expression::Or(_)
+            | Expression::And(_)
+            | Expression::Eq(_, _)
+            | Expression::Neq(_, _)
+            | Expression::Lt(_, _)
+            | Expression::Lte(_, _)
+            | Expression::Gte(_, _)
+            | Expression::Gt(_, _)
+            | Expression::Not(_)
+            | Expression::Has(_, _)
+            | Expression::Lit(Literal::Bool(_))
+    )
+}
+
+/// An expression that evaluates to `value`, if there's one to write.
+fn literal(value: &Value) -> Option<Expression> {
+    let lit = match value {
+        Value::I64(n) => Literal::I64(*n),
+        // There are no uint literals.
+        Value::U64(n) if *n <= i64::MAX as u64 => {
+            return Some(Expression::FunctionCall(
+                Identifier::new("uint"),
+                vec![Expression::Lit(Literal::I64(*n as i64))],
+            ))
+        }
+        Value::F64(x) => Literal::F64(*x),
+        Value::Bool(b) => Literal::Bool(*b),
+        Value::String(s) => Literal::String(s.clone()),
+        Value::Bytes(bs) => Literal::Bytes(bs.clone()),
+        Value::Null => Literal::Null,
+        Value::List(vs) => Literal::List(vs.iter().map(literal).collect::<Option<_>>()?),
+        Value::Map(kvs) => {
+            let mut kvs: Vec<_> = kvs.iter().collect();
+            kvs.sort_by(|a, b| a.0.cmp(b.0));
+            Literal::Map(
+                kvs.into_iter()
+                    .map(|(k, v)| Some((Expression::Lit(Literal::String(k.clone())), literal(v)?)))
+                    .collect::<Option<_>>()?,
+            )
+        }
+        Value::Type(kind) if *kind != Kind::Message => {
+            return Some(Expression::Binding(Identifier::new(kind.type_name())))
+        }
+        Value::U64(_) | Value::Type(_) | Value::Optional(_) | Value::Message(_) => return None,
+    };
+    Some(Expression::Lit(lit))
+}
+
+/// `e` with each of its direct subexpressions replaced by `f` of it.
+fn map_children(e: &Expression, mut f: impl FnMut(&Expression) -> Expression) -> Expression {
+    let mut children = Vec::new();
+    for child in e.children() {
+        children.push(f(child));
+    }
+    with_children(e, children)
+}
+
+/// `e` with its direct subexpressions replaced by `children`, in the order of
+/// `Expression::children`.
+fn with_children(e: &Expression, children: Vec<Expression>) -> Expression {
+    let mut children = children.into_iter();
+    let mut g = || Box::new(children.next().unwrap());
+    match e {
+        Expression::LetBinding { id, .. } => Expression::LetBinding {
+            id: id.clone(),
+            value: g(),
+            body: g(),
+        },
+        Expression::Ternary { .. } => Expression::Ternary {
+            condition: g(),
+            true_branch: g(),
+            false_branch: g(),
+        },
+        Expression::Or(es) => Expression::Or(es.iter().map(|_| *g()).collect()),
+        Expression::And(es) => Expression::And(es.iter().map(|_| *g()).collect()),
+        Expression::Eq(_, _) => Expression::Eq(g(), g()),
+        Expression::Neq(_, _) => Expression::Neq(g(), g()),
+        Expression::Lt(_, _) => Expression::Lt(g(), g()),
+        Expression::Lte(_, _) => Expression::Lte(g(), g()),
+        Expression::Gte(_, _) => Expression::Gte(g(), g()),
+        Expression::Gt(_, _) => Expression::Gt(g(), g()),
+        Expression::Add(_, _) => Expression::Add(g(), g()),
+        Expression::Sub(_, _) => Expression::Sub(g(), g()),
+        Expression::Mul(_, _) => Expression::Mul(g(), g()),
+        Expression::Div(_, _) => Expression::Div(g(), g()),
+        Expression::Mod(_, _) => Expression::Mod(g(), g()),
+        Expression::OptionalIndex(_, _) => Expression::OptionalIndex(g(), g()),
+        Expression::Neg(_) => Expression::Neg(g()),
+        Expression::Not(_) => Expression::Not(g()),
+        Expression::Member(_, id) => Expression::Member(g(), id.clone()),
+        Expression::OptionalMember(_, id) => Expression::OptionalMember(g(), id.clone()),
+        Expression::Has(_, id) => Expression::Has(g(), id.clone()),
+        Expression::Method(_, id, args) => {
+            Expression::Method(g(), id.clone(), args.iter().map(|_| *g()).collect())
+        }
+        Expression::FunctionCall(id, args) => {
+            Expression::FunctionCall(id.clone(), args.iter().map(|_| *g()).collect())
+        }
+        Expression::Lit(Literal::List(es)) => {
+            Expression::Lit(Literal::List(es.iter().map(|_| *g()).collect()))
+        }
+        Expression::Lit(Literal::Map(kvs)) => {
+            Expression::Lit(Literal::Map(kvs.iter().map(|_| (*g(), *g())).collect()))
+        }
+        Expression::Lit(Literal::Message(name, fields)) => Expression::Lit(Literal::Message(
+            name.clone(),
+            fields.iter().map(|(id, _)| (id.clone(), *g())).collect(),
+        )),
+        Expression::Lit(_) | Expression::Binding(_) => e.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse;
+    use crate::reference;
+
+    fn activation() -> Activation {
+        let mut activation = Activation::new();
+        activation.insert("principal".to_owned(), Value::String("alice".to_owned()));
+        activation.insert("n".to_owned(), Value::U64(2));
+        activation.insert(
+            "resource".to_owned(),
+            Value::Map(
+                vec![
+                    ("id".to_owned(), Value::I64(7)),
+                    ("owner".to_owned(), Value::String("alice".to_owned())),
+                    ("public".to_owned(), Value::Bool(false)),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+        );
+        activation
+    }
+
+    /// Evaluates `source` with `unknown` marked, and checks that its residual
+    /// agrees with evaluating `source` when nothing is unknown.
+    fn partial(source: &str, unknown: &str) -> Partial {
+        let expr = parse(source).unwrap();
+        let mut unknowns = Unknowns::new();
+        unknowns.mark(unknown);
+        let partial = evaluate(&expr, &activation(), &unknowns);
+        if let Partial::Unknown { residual, .. } = &partial {
+            assert_eq!(
+                reference::evaluate(residual, &activation()),
+                reference::evaluate(&expr, &activation()),
+                "{}",
+                source
+            );
+        }
+        partial
+    }
+
+    fn unknown(attributes: &[&str], residual: &str) -> Partial {
+        Partial::Unknown {
+            attributes: attributes.iter().map(|&a| a.to_owned()).collect(),
+            residual: parse(residual).unwrap(),
+        }
+    }
+
+    #[test]
+    fn residuals() {
+        assert_eq!(
+            partial(
+                r#"principal == "alice" && resource.owner == principal"#,
+                "resource"
+            ),
+            unknown(&["resource.owner"], r#"resource.owner == "alice""#)
+        );
+        assert_eq!(
+            partial("principal.size() + resource.id * 2 > 10", "resource"),
+            unknown(&["resource.id"], "5 + resource.id * 2 > 10")
+        );
+        assert_eq!(
+            partial("[n, resource.id]", "resource"),
+            unknown(&["resource.id"], "[uint(2), resource.id]")
+        );
+        assert_eq!(
+            partial(r#"principal == "alice" ? resource.id : 0"#, "resource"),
+            unknown(&["resource.id"], "resource.id")
+        );
+        assert_eq!(
+            partial(r#"resource.public ? [principal] : []"#, "resource"),
+            unknown(&["resource.public"], r#"resource.public ? ["alice"] : []"#)
+        );
+    }
+
+    #[test]
+    fn absorption() {
+        assert_eq!(
+            partial(r#"principal == "bob" && resource.public"#, "resource"),
+            Partial::Known(Ok(Value::Bool(false)))
+        );
+        assert_eq!(
+            partial("resource.public || true", "resource"),
+            Partial::Known(Ok(Value::Bool(true)))
+        );
+        assert_eq!(
+            partial("resource.public || 1 / 0 == 1", "resource"),
+            unknown(&["resource.public"], "resource.public || 1 / 0 == 1")
+        );
+        assert_eq!(
+            partial("resource.public || resource.id == 1", "resource").result(),
+            Err(Error::Unknown(vec![
+                "resource.id".to_owned(),
+                "resource.public".to_owned()
+            ]))
+        );
+        // A lone operand that's left is still checked for being a bool.
+        assert_eq!(
+            partial(r#"principal == "alice" && resource.id"#, "resource"),
+            unknown(&["resource.id"], "true && resource.id")
+        );
+        assert_eq!(
+            partial(r#"principal == "bob" || resource.id"#, "resource"),
+            unknown(&["resource.id"], "false || resource.id")
+        );
+        assert_eq!(
+            partial(r#"principal == "bob" || !resource.public"#, "resource"),
+            unknown(&["resource.public"], "!resource.public")
+        );
+        // Strict operators report the first of their operands to fail.
+        assert_eq!(
+            partial("1 / 0 + resource.id", "resource"),
+            Partial::Known(Err(Error::DivisionByZero))
+        );
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(
+            partial("resource.id == 7 && has(resource.public)", "resource.owner"),
+            Partial::Known(Ok(Value::Bool(true)))
+        );
+        assert_eq!(
+            partial("has(resource.owner)", "resource.owner"),
+            unknown(&["resource.owner"], "has(resource.owner)")
+        );
+        assert_eq!(
+            partial("size(resource) > 0", "resource.owner"),
+            unknown(&["resource"], "size(resource) > 0")
+        );
+        assert_eq!(
+            partial("resource.owner.size()", "resource.owner.first"),
+            unknown(&["resource.owner"], "resource.owner.size()")
+        );
+    }
+}
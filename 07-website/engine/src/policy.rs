@@ -0,0 +1,415 @@
+//! Policy files: several named expressions that refer to each other by name.
+//!
+//! ```text
+//! let owner = request.user == resource.owner;
+//! rule is_admin = request.role == "admin";
+//! rule allow = is_admin || owner;
+//! ```
+//!
+//! Rules are the entry points; `let` declarations are only there to be
+//! shared. A name refers to the declaration wherever it appears, shadowing the
+//! activation, and declarations can come in any order as long as none of them
+//! depends on itself.
+//!
+//! The whole file compiles to one register program, with an entry point for
+//! each declaration that leaves the other declarations' values to globals.
+//! Evaluating a rule runs the declarations it depends on, directly or not,
+//! and then the rule itself. It never evaluates declarations it doesn't refer
+//! to, but it evaluates all the ones it does up front, even if the expression
+//! then short-circuits past them. Within one `Evaluation`, every declaration
+//! is evaluated at most once, however many rules share it.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use prost_reflect::DescriptorPool;
+
+use crate::model::{Activation, Declaration, Error, EvalResult, Expression, Identifier, Value};
+use crate::parser::{self, ParseError};
+use crate::register;
+use crate::stack::{walker, CompileError};
+
+#[derive(Debug, PartialEq)]
+pub enum PolicyError {
+    Parse(ParseError),
+    /// Two declarations with the same name.
+    Duplicate(Identifier),
+    /// Declarations that depend on each other, from the first back to itself.
+    Cycle(Vec<Identifier>),
+    /// A declaration that doesn't compile.
+    Compile(Identifier, CompileError),
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::Parse(e) => write!(f, "{}", e),
+            PolicyError::Duplicate(id) => write!(f, "{} is declared more than once", id.0),
+            PolicyError::Cycle(path) => {
+                let names: Vec<&str> = path.iter().map(|id| id.0.as_str()).collect();
+                write!(f, "{} depends on itself: {}", names[0], names.join(" -> "))
+            }
+            PolicyError::Compile(id, e) => write!(f, "{}: {}", id.0, e),
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+impl From<ParseError> for PolicyError {
+    fn from(e: ParseError) -> Self {
+        PolicyError::Parse(e)
+    }
+}
+
+pub type PolicyResult<T> = Result<T, PolicyError>;
+
+/// A compiled policy file, with its rules as entry points.
+#[derive(Debug)]
+pub struct Policy {
+    program: register::Program,
+    /// Every declaration, in the order they were declared. The value of
+    /// declaration `i` is global `i` of the program.
+    declarations: Vec<Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    name: Identifier,
+    expression: Expression,
+    rule: bool,
+    /// Where the declaration starts in the program.
+    entry: usize,
+    /// The declarations it depends on, directly or not, each after its own
+    /// dependencies.
+    dependencies: Vec<usize>,
+}
+
+pub fn compile(source: &str) -> PolicyResult<Policy> {
+    compile_with_types(source, DescriptorPool::new())
+}
+
+/// Like `compile`, additionally resolving message construction against the
+/// message types in `types`.
+pub fn compile_with_types(source: &str, types: DescriptorPool) -> PolicyResult<Policy> {
+    let declarations = parser::parse_policy(source)?;
+    let order = dependency_order(&declarations)?;
+    let names: Vec<Identifier> = declarations.iter().map(|d| d.name.clone()).collect();
+    let blocks = declarations
+        .iter()
+        .map(|declaration| {
+            walker::linearize_in_scope(&declaration.value, &names)
+                .map_err(|e| PolicyError::Compile(declaration.name.clone(), e))
+        })
+        .collect::<PolicyResult<Vec<_>>>()?;
+    let (program, entries) = register::Program::link(blocks, names.len(), types);
+    let declarations = declarations
+        .into_iter()
+        .zip(entries)
+        .zip(order)
+        .map(|((declaration, entry), dependencies)| Entry {
+            name: declaration.name,
+            expression: declaration.value,
+            rule: declaration.rule,
+            entry,
+            dependencies,
+        })
+        .collect();
+    Ok(Policy {
+        program,
+        declarations,
+    })
+}
+
+impl Policy {
+    /// The names of the rules, in the order they were declared.
+    pub fn rules(&self) -> impl Iterator<Item = &Identifier> {
+        self.declarations
+            .iter()
+            .filter(|entry| entry.rule)
+            .map(|entry| &entry.name)
+    }
+
+    /// The expression `rule` was declared with.
+    pub fn expression(&self, rule: &str) -> Option<&Expression> {
+        self.rule(rule).map(|i| &self.declarations[i].expression)
+    }
+
+    /// The declarations that evaluating `rule` evaluates first, in order.
+    pub fn dependencies(&self, rule: &str) -> Option<Vec<&Identifier>> {
+        self.rule(rule).map(|i| {
+            self.declarations[i]
+                .dependencies
+                .iter()
+                .map(|&j| &self.declarations[j].name)
+                .collect()
+        })
+    }
+
+    /// Evaluates `rule` with the free identifiers of the policy bound by
+    /// `activation`. To evaluate several rules, `bind` the activation once.
+    pub fn evaluate(&mut self, rule: &str, activation: &Activation) -> EvalResult {
+        self.bind(activation).evaluate(rule)
+    }
+
+    /// Starts evaluating rules against `activation`, sharing the values of
+    /// the declarations between them.
+    pub fn bind<'a>(&'a mut self, activation: &'a Activation) -> Evaluation<'a> {
+        let count = self.declarations.len();
+        Evaluation {
+            policy: self,
+            activation,
+            values: (0..count).map(|_| Ok(Value::Null)).collect(),
+            evaluated: vec![false; count],
+            runs: 0,
+        }
+    }
+
+    fn rule(&self, rule: &str) -> Option<usize> {
+        self.declarations
+            .iter()
+            .position(|entry| entry.rule && entry.name.0 == rule)
+    }
+}
+
+/// A policy with an activation bound, see `Policy::bind`.
+pub struct Evaluation<'a> {
+    policy: &'a mut Policy,
+    activation: &'a Activation,
+    /// The globals of the program, for the declarations evaluated so far.
+    values: Vec<EvalResult>,
+    evaluated: Vec<bool>,
+    /// How many declarations have been evaluated.
+    runs: usize,
+}
+
+impl Evaluation<'_> {
+    /// Evaluates `rule`, and the declarations it depends on that no rule
+    /// evaluated before it.
+    pub fn evaluate(&mut self, rule: &str) -> EvalResult {
+        let i = match self.policy.rule(rule) {
+            Some(i) => i,
+            None => return Err(Error::NoSuchBinding(Identifier::new(rule))),
+        };
+        let declarations = &self.policy.declarations;
+        for &j in declarations[i].dependencies.iter().chain(Some(&i)) {
+            if self.evaluated[j] {
+                continue;
+            }
+            self.values[j] =
+                self.policy
+                    .program
+                    .run_from(declarations[j].entry, self.activation, &self.values);
+            self.evaluated[j] = true;
+            self.runs += 1;
+        }
+        self.values[i].clone()
+    }
+}
+
+/// For each declaration, the indices of the declarations it depends on,
+/// directly or not, each after its own dependencies.
+fn dependency_order(declarations: &[Declaration]) -> PolicyResult<Vec<Vec<usize>>> {
+    let mut index = HashMap::new();
+    for (i, declaration) in declarations.iter().enumerate() {
+        if index.insert(&declaration.name, i).is_some() {
+            return Err(PolicyError::Duplicate(declaration.name.clone()));
+        }
+    }
+    let references: Vec<Vec<usize>> = declarations
+        .iter()
+        .map(|declaration| {
+            let mut names = Vec::new();
+            bindings(&declaration.value, &mut names);
+            names
+                .iter()
+                .filter_map(|id| index.get(id).copied())
+                .collect()
+        })
+        .collect();
+
+    let mut search = Search {
+        references: &references,
+        declarations,
+        finished: vec![None; declarations.len()],
+        path: Vec::new(),
+    };
+    for i in 0..declarations.len() {
+        search.visit(i)?;
+    }
+    Ok(search.finished.into_iter().map(Option::unwrap).collect())
+}
+
+/// A depth-first search over references, collecting each declaration's
+/// dependencies in post-order.
+struct Search<'a> {
+    references: &'a [Vec<usize>],
+    declarations: &'a [Declaration],
+    finished: Vec<Option<Vec<usize>>>,
+    /// The declarations being visited, outermost first.
+    path: Vec<usize>,
+}
+
+impl Search<'_> {
+    fn visit(&mut self, i: usize) -> PolicyResult<()> {
+        if self.finished[i].is_some() {
+            return Ok(());
+        }
+        if let Some(start) = self.path.iter().position(|&j| j == i) {
+            let mut cycle: Vec<Identifier> = self.path[start..]
+                .iter()
+                .map(|&j| self.declarations[j].name.clone())
+                .collect();
+            cycle.push(self.declarations[i].name.clone());
+            return Err(PolicyError::Cycle(cycle));
+        }
+        self.path.push(i);
+        let mut order = Vec::new();
+        for &j in &self.references[i] {
+            self.visit(j)?;
+            for &k in self.finished[j].as_ref().unwrap().iter().chain(Some(&j)) {
+                if !order.contains(&k) {
+                    order.push(k);
+                }
+            }
+        }
+        self.path.pop();
+        self.finished[i] = Some(order);
+        Ok(())
+    }
+}
+
+/// Every identifier that `e` looks up, in order.
+fn bindings<'a>(e: &'a Expression, out: &mut Vec<&'a Identifier>) {
+    match e {
+        Expression::Binding(id) => out.push(id),
+        _ => {
+            for child in e.children() {
+                bindings(child, out);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse;
+
+    const SOURCE: &str = r#"
+        let owner = request.user == resource.owner;
+        rule is_admin = request.role == "admin";
+        rule allow = is_admin || owner || public;
+        let public = resource.visibility == "public";
+        rule deny = !allow;
+    "#;
+
+    fn request(user: &str, role: &str, owner: &str, visibility: &str) -> Activation {
+        let map = |kvs: &[(&str, &str)]| {
+            Value::Map(
+                kvs.iter()
+                    .map(|(k, v)| (k.to_string(), Value::String(v.to_string())))
+                    .collect(),
+            )
+        };
+        let mut activation = Activation::new();
+        activation.insert("request".to_owned(), map(&[("user", user), ("role", role)]));
+        activation.insert(
+            "resource".to_owned(),
+            map(&[("owner", owner), ("visibility", visibility)]),
+        );
+        activation
+    }
+
+    #[test]
+    fn entry_points() {
+        let mut policy = compile(SOURCE).unwrap();
+        let rules: Vec<&str> = policy.rules().map(|id| id.0.as_str()).collect();
+        assert_eq!(rules, vec!["is_admin", "allow", "deny"]);
+        for (activation, allowed) in &[
+            (request("ann", "admin", "bob", "private"), true),
+            (request("bob", "user", "bob", "private"), true),
+            (request("cat", "user", "bob", "public"), true),
+            (request("cat", "user", "bob", "private"), false),
+        ] {
+            assert_eq!(
+                policy.evaluate("allow", activation),
+                Ok(Value::Bool(*allowed))
+            );
+            assert_eq!(
+                policy.evaluate("deny", activation),
+                Ok(Value::Bool(!allowed))
+            );
+        }
+        assert_eq!(
+            policy.evaluate("owner", &Activation::new()),
+            Err(Error::NoSuchBinding(Identifier::new("owner")))
+        );
+    }
+
+    #[test]
+    fn only_dependencies() {
+        let policy = compile(SOURCE).unwrap();
+        assert_eq!(
+            policy.expression("is_admin"),
+            Some(&parse(r#" request.role == "admin" "#).unwrap())
+        );
+        assert_eq!(policy.dependencies("is_admin"), Some(vec![]));
+        let dependencies = policy.dependencies("deny").unwrap();
+        let names: Vec<&str> = dependencies.iter().map(|id| id.0.as_str()).collect();
+        assert_eq!(names, vec!["is_admin", "owner", "public", "allow"]);
+        assert_eq!(policy.dependencies("owner"), None);
+    }
+
+    #[test]
+    fn shared_declarations_run_once() {
+        let mut policy = compile(SOURCE).unwrap();
+        let activation = request("cat", "user", "bob", "public");
+        let mut evaluation = policy.bind(&activation);
+        assert_eq!(evaluation.evaluate("allow"), Ok(Value::Bool(true)));
+        assert_eq!(evaluation.runs, 4);
+        assert_eq!(evaluation.evaluate("deny"), Ok(Value::Bool(false)));
+        assert_eq!(evaluation.runs, 5);
+        assert_eq!(evaluation.evaluate("is_admin"), Ok(Value::Bool(false)));
+        assert_eq!(evaluation.runs, 5);
+    }
+
+    #[test]
+    fn declarations_shadow_type_names() {
+        let mut policy =
+            compile("let int = 2; rule a = int * 3; rule b = type(1) == int;").unwrap();
+        assert_eq!(policy.evaluate("a", &Activation::new()), Ok(Value::I64(6)));
+        assert_eq!(
+            policy.evaluate("b", &Activation::new()),
+            Ok(Value::Bool(false))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(compile("rule a = ;"), Err(PolicyError::Parse(_))));
+        assert_eq!(
+            compile("rule a = 1; let a = 2;").unwrap_err(),
+            PolicyError::Duplicate(Identifier::new("a"))
+        );
+        let cycle = compile("rule a = b; let b = c + 1; let c = a.x; rule d = a;").unwrap_err();
+        assert_eq!(
+            cycle,
+            PolicyError::Cycle(vec![
+                Identifier::new("a"),
+                Identifier::new("b"),
+                Identifier::new("c"),
+                Identifier::new("a"),
+            ])
+        );
+        assert_eq!(cycle.to_string(), "a depends on itself: a -> b -> c -> a");
+        assert_eq!(
+            compile("rule a = a;").unwrap_err(),
+            PolicyError::Cycle(vec![Identifier::new("a"), Identifier::new("a")])
+        );
+        assert!(matches!(
+            compile(r#"rule a = "x".matches("(");"#),
+            Err(PolicyError::Compile(_, CompileError::InvalidRegex(_, _)))
+        ));
+    }
+}
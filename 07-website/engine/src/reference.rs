@@ -0,0 +1,719 @@
+//! A direct, recursive evaluator over `Expression`: the semantic reference
+//! that the compiler and the machines are checked against.
+//!
+//! Operators are implemented here from scratch rather than shared with
+//! `stack`, so that a mistake in either shows up as a disagreement instead of
+//! the same wrong answer twice. Member access, functions and methods do share
+//! the library code in `stack`, since they don't depend on how an expression
+//! was compiled.
+//!
+//! Where the machines' behaviour is observable, this spells it out: the
+//! condition of `?:` and every operand of `||` and `&&` but the last must be a
+//! bool, or evaluates to `InvalidTypeForOperator(_, Op::Jump)`, and the value
+//! of a let binding is evaluated up front, but only fails the body if the body
+//! uses it.
+//!
+//! It is also what `partial` evaluates with, so attributes that were marked
+//! unknown evaluate to `Error::Unknown` here.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use prost_reflect::DescriptorPool;
+
+use crate::model::{
+    Activation, Error, EvalResult, Expression, Identifier, Kind, Literal, Op, Value,
+};
+use crate::partial::Unknowns;
+use crate::stack::matching::PatternCache;
+use crate::stack::{self, functions, messages};
+
+/// Evaluates `expr` with its free identifiers bound by `activation`.
+pub fn evaluate(expr: &Expression, activation: &Activation) -> EvalResult {
+    evaluate_with_types(expr, activation, &DescriptorPool::new())
+}
+
+/// Like `evaluate`, additionally resolving message construction against the
+/// message types in `types`.
+pub fn evaluate_with_types(
+    expr: &Expression,
+    activation: &Activation,
+    types: &DescriptorPool,
+) -> EvalResult {
+    Evaluator::new(activation, types, &Unknowns::new()).eval(expr)
+}
+
+pub(crate) struct Evaluator<'a> {
+    activation: &'a Activation,
+    types: &'a DescriptorPool,
+    unknowns: &'a Unknowns,
+    patterns: PatternCache,
+    /// The let bindings in scope, innermost last.
+    scope: Vec<(Identifier, EvalResult)>,
+}
+
+impl<'a> Evaluator<'a> {
+    pub(crate) fn new(
+        activation: &'a Activation,
+        types: &'a DescriptorPool,
+        unknowns: &'a Unknowns,
+    ) -> Evaluator<'a> {
+        Evaluator {
+            activation,
+            types,
+            unknowns,
+            patterns: PatternCache::default(),
+            scope: Vec::new(),
+        }
+    }
+
+    /// Binds `id` to `value` for what is evaluated until the matching `unbind`.
+    pub(crate) fn bind(&mut self, id: &Identifier, value: EvalResult) {
+        self.scope.push((id.clone(), value));
+    }
+
+    pub(crate) fn unbind(&mut self) {
+        self.scope.pop();
+    }
+
+    fn local(&self, id: &Identifier) -> Option<&EvalResult> {
+        self.scope
+            .iter()
+            .rev()
+            .find(|(name, _)| name == id)
+            .map(|(_, value)| value)
+    }
+
+    /// Only dispatches on the kind of `e`, so that its frame, which every
+    /// level of a deeply nested expression puts on the stack, stays small.
+    pub(crate) fn eval(&mut self, e: &Expression) -> EvalResult {
+        match e {
+            Expression::LetBinding { id, value, body } => self.let_binding(id, value, body),
+            Expression::Ternary {
+                condition,
+                true_branch,
+                false_branch,
+            } => self.ternary(condition, true_branch, false_branch),
+            Expression::Or(es) => self.logical(es, Op::Or),
+            Expression::And(es) => self.logical(es, Op::And),
+            Expression::Eq(a, b)
+            | Expression::Neq(a, b)
+            | Expression::Lt(a, b)
+            | Expression::Lte(a, b)
+            | Expression::Gte(a, b)
+            | Expression::Gt(a, b)
+            | Expression::Add(a, b)
+            | Expression::Sub(a, b)
+            | Expression::Mul(a, b)
+            | Expression::Div(a, b)
+            | Expression::Mod(a, b)
+            | Expression::OptionalIndex(a, b) => self.binary(e, a, b),
+            Expression::Neg(a) | Expression::Not(a) | Expression::OptionalMember(a, _) => {
+                self.unary(e, a)
+            }
+            Expression::Member(a, id) => self.member(e, a, id),
+            Expression::Has(a, id) => self.has(a, id),
+            Expression::Method(operand, id, args) => self.method(operand, id, args),
+            Expression::FunctionCall(id, args) => self.call(id, args),
+            Expression::Lit(lit) => self.literal(lit),
+            Expression::Binding(id) => self.binding(id),
+        }
+    }
+
+    fn let_binding(
+        &mut self,
+        id: &Identifier,
+        value: &Expression,
+        body: &Expression,
+    ) -> EvalResult {
+        let value = self.eval(value);
+        self.bind(id, value);
+        let result = self.eval(body);
+        self.unbind();
+        result
+    }
+
+    fn ternary(
+        &mut self,
+        condition: &Expression,
+        true_branch: &Expression,
+        false_branch: &Expression,
+    ) -> EvalResult {
+        if self.condition(condition)? {
+            self.eval(true_branch)
+        } else {
+            self.eval(false_branch)
+        }
+    }
+
+    /// `e`, an operator with the operands `a` and `b`.
+    fn binary(&mut self, e: &Expression, a: &Expression, b: &Expression) -> EvalResult {
+        // Both are evaluated before either error is returned, like `operands`.
+        let a = self.eval(a);
+        let b = self.eval(b);
+        binary(e, a, b)
+    }
+
+    /// `e`, an operator with the operand `a`.
+    fn unary(&mut self, e: &Expression, a: &Expression) -> EvalResult {
+        let a = self.eval(a);
+        unary(e, a)
+    }
+
+    /// `e`, the member `id` of `a`.
+    fn member(&mut self, e: &Expression, a: &Expression, id: &Identifier) -> EvalResult {
+        match attribute_path(e) {
+            Some(path) => self.attribute(&path),
+            None => stack::eval_member(self.eval(a)?, id),
+        }
+    }
+
+    fn has(&mut self, a: &Expression, id: &Identifier) -> EvalResult {
+        let operand = match attribute_path(a) {
+            Some(mut path) => {
+                path.push(id);
+                self.known(&path)?;
+                path.pop();
+                self.lookup(&path)
+            }
+            None => self.eval(a),
+        };
+        stack::eval_has(operand?, id)
+    }
+
+    fn method(&mut self, operand: &Expression, id: &Identifier, args: &[Expression]) -> EvalResult {
+        let operand = self.eval(operand)?;
+        let mut args = self.all(args)?;
+        if id.0 == "matches" {
+            args.insert(0, operand);
+            self.patterns.call(id, args)
+        } else {
+            stack::eval_method(operand, id, args)
+        }
+    }
+
+    fn binding(&mut self, id: &Identifier) -> EvalResult {
+        match Kind::from_type_name(&id.0) {
+            Some(kind) if self.local(id).is_none() => Ok(Value::Type(kind)),
+            _ => self.attribute(&[id]),
+        }
+    }
+
+    fn call(&mut self, id: &Identifier, args: &[Expression]) -> EvalResult {
+        let args = self.all(args)?;
+        if id.0 == "matches" {
+            self.patterns.call(id, args)
+        } else {
+            functions::call(id, args)
+        }
+    }
+
+    /// The value of `path`, an identifier followed by fields.
+    fn attribute(&mut self, path: &[&Identifier]) -> EvalResult {
+        self.known(path)?;
+        self.lookup(path)
+    }
+
+    /// Fails with `Unknown` if any part of `path` was marked unknown. Let
+    /// bindings shadow the activation, and so its unknowns.
+    fn known(&self, path: &[&Identifier]) -> Result<(), Error> {
+        if self.local(path[0]).is_none() && self.unknowns.overlaps(path) {
+            let names: Vec<&str> = path.iter().map(|id| id.0.as_str()).collect();
+            return Err(Error::Unknown(vec![names.join(".")]));
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, path: &[&Identifier]) -> EvalResult {
+        let mut value = match self.local(path[0]) {
+            Some(value) => value.clone()?,
+            None => self
+                .activation
+                .get(&path[0].0)
+                .cloned()
+                .ok_or_else(|| Error::NoSuchBinding(path[0].clone()))?,
+        };
+        for id in &path[1..] {
+            value = stack::eval_member(value, id)?;
+        }
+        Ok(value)
+    }
+
+    fn condition(&mut self, e: &Expression) -> Result<bool, Error> {
+        match self.eval(e)? {
+            Value::Bool(b) => Ok(b),
+            other => Err(Error::InvalidTypeForOperator(other.kind(), Op::Jump)),
+        }
+    }
+
+    /// Both operands of a binary operator, or the first error among them.
+    fn operands(&mut self, a: &Expression, b: &Expression) -> Result<(Value, Value), Error> {
+        let a = self.eval(a);
+        let b = self.eval(b);
+        Ok((a?, b?))
+    }
+
+    fn all(&mut self, es: &[Expression]) -> Result<Vec<Value>, Error> {
+        es.iter().map(|e| self.eval(e)).collect()
+    }
+
+    /// `||` or `&&` over `es`, left to right. A bool that decides the result
+    /// (`true` and `false` respectively) wins over errors on either side of it,
+    /// and between two errors, unknowns win.
+    fn logical(&mut self, es: &[Expression], op: Op) -> EvalResult {
+        let decisive = op == Op::Or;
+        let mut acc = self.eval(&es[0]);
+        for e in &es[1..] {
+            let a = match acc {
+                Ok(Value::Bool(b)) if b == decisive => return Ok(Value::Bool(b)),
+                Ok(Value::Bool(b)) => Ok(b),
+                Ok(other) => Err(Error::InvalidTypeForOperator(other.kind(), Op::Jump)),
+                Err(e) => Err(e),
+            };
+            acc = match (a, self.eval(e)) {
+                (_, Ok(Value::Bool(b))) if b == decisive => Ok(Value::Bool(b)),
+                (Ok(_), Ok(Value::Bool(b))) => Ok(Value::Bool(b)),
+                (Ok(_), Ok(b)) => Err(Error::InvalidTypesForOperator(
+                    Kind::Bool,
+                    b.kind(),
+                    op.clone(),
+                )),
+                (Err(a), Err(b)) => Err(a.absorb(b)),
+                (Err(e), _) | (_, Err(e)) => Err(e),
+            };
+        }
+        acc
+    }
+
+    fn literal(&mut self, lit: &Literal) -> EvalResult {
+        match lit {
+            Literal::Null => Ok(Value::Null),
+            Literal::I64(n) => Ok(Value::I64(*n)),
+            Literal::F64(x) => Ok(Value::F64(*x)),
+            Literal::Bool(b) => Ok(Value::Bool(*b)),
+            Literal::String(s) => Ok(Value::String(s.clone())),
+            Literal::Bytes(bs) => Ok(Value::Bytes(bs.clone())),
+            Literal::List(es) => self.all(es).map(Value::List),
+            Literal::Map(kvs) => {
+                let mut map = HashMap::with_capacity(kvs.len());
+                for (k, v) in kvs {
+                    let (k, v) = self.operands(k, v)?;
+                    match k {
+                        Value::String(k) => {
+                            if map.insert(k.clone(), v).is_some() {
+                                return Err(Error::DuplicateMapKey(k));
+                            }
+                        }
+                        other => return Err(Error::InvalidMapKey(other.kind())),
+                    }
+                }
+                Ok(Value::Map(map))
+            }
+            Literal::Message(name, fields) => {
+                let mut ids = Vec::with_capacity(fields.len());
+                let mut values = Vec::with_capacity(fields.len());
+                for (id, e) in fields {
+                    ids.push(id.clone());
+                    values.push(self.eval(e));
+                }
+                let values = values.into_iter().collect::<Result<_, _>>()?;
+                messages::make(self.types, name, &ids, values)
+            }
+        }
+    }
+}
+
+/// The identifiers in `e` if it is a chain of member accesses on a free
+/// identifier, e.g. `[request, auth, uid]` for `request.auth.uid`.
+pub(crate) fn attribute_path(e: &Expression) -> Option<Vec<&Identifier>> {
+    match e {
+        Expression::Binding(id) if Kind::from_type_name(&id.0).is_none() => Some(vec![id]),
+        Expression::Member(a, id) => {
+            let mut path = attribute_path(a)?;
+            path.push(id);
+            Some(path)
+        }
+        _ => None,
+    }
+}
+
+/// Applies `e`, an operator with one operand, to its value, or fails with its
+/// error.
+fn unary(e: &Expression, a: EvalResult) -> EvalResult {
+    match (e, a?) {
+        (Expression::Neg(_), Value::I64(n)) => n
+            .checked_neg()
+            .map(Value::I64)
+            .ok_or(Error::IntegerOverflow(Op::Neg)),
+        (Expression::Neg(_), Value::F64(x)) => Ok(Value::F64(-x)),
+        (Expression::Neg(_), other) => Err(Error::InvalidTypeForOperator(other.kind(), Op::Neg)),
+        (Expression::Not(_), Value::Bool(b)) => Ok(Value::Bool(!b)),
+        (Expression::Not(_), other) => Err(Error::InvalidTypeForOperator(other.kind(), Op::Not)),
+        (Expression::OptionalMember(_, id), a) => stack::eval_optional_member(a, id),
+        _ => unreachable!(),
+    }
+}
+
+/// Applies `e`, an operator with two operands, to their values, or fails
+/// with the first error among them.
+fn binary(e: &Expression, a: EvalResult, b: EvalResult) -> EvalResult {
+    let (a, b) = (a?, b?);
+    match e {
+        Expression::Eq(_, _) => Ok(Value::Bool(a == b)),
+        Expression::Neq(_, _) => Ok(Value::Bool(a != b)),
+        Expression::Lt(_, _)
+        | Expression::Lte(_, _)
+        | Expression::Gte(_, _)
+        | Expression::Gt(_, _) => compare(e.op(), a, b),
+        Expression::OptionalIndex(_, _) => stack::eval_optional_index(a, b),
+        _ => arithmetic(e.op(), a, b),
+    }
+}
+
+fn compare(op: Op, a: Value, b: Value) -> EvalResult {
+    let ord = match (&a, &b) {
+        (Value::I64(a), Value::I64(b)) => a.cmp(b),
+        (Value::U64(a), Value::U64(b)) => a.cmp(b),
+        _ => return Err(Error::InvalidTypesForOperator(a.kind(), b.kind(), op)),
+    };
+    Ok(Value::Bool(match op {
+        Op::Lt => ord == Ordering::Less,
+        Op::Lte => ord != Ordering::Greater,
+        Op::Gte => ord != Ordering::Less,
+        _ => ord == Ordering::Greater,
+    }))
+}
+
+fn arithmetic(op: Op, a: Value, b: Value) -> EvalResult {
+    let overflow = || Error::IntegerOverflow(op.clone());
+    match (a, b) {
+        (Value::I64(a), Value::I64(b)) => {
+            let result = match op {
+                Op::Plus => a.checked_add(b),
+                Op::Minus => a.checked_sub(b),
+                Op::Times => a.checked_mul(b),
+                _ if b == 0 => return Err(Error::DivisionByZero),
+                Op::Div => a.checked_div(b),
+                _ => a.checked_rem(b),
+            };
+            result.map(Value::I64).ok_or_else(overflow)
+        }
+        (Value::U64(a), Value::U64(b)) => {
+            let result = match op {
+                Op::Plus => a.checked_add(b),
+                Op::Minus => a.checked_sub(b),
+                Op::Times => a.checked_mul(b),
+                _ if b == 0 => return Err(Error::DivisionByZero),
+                Op::Div => Some(a / b),
+                _ => Some(a % b),
+            };
+            result.map(Value::U64).ok_or_else(overflow)
+        }
+        (Value::F64(a), Value::F64(b)) => match op {
+            Op::Plus => Ok(Value::F64(a + b)),
+            Op::Minus => Ok(Value::F64(a - b)),
+            Op::Times => Ok(Value::F64(a * b)),
+            _ if b == 0.0 => Err(Error::DivisionByZero),
+            Op::Div => Ok(Value::F64(a / b)),
+            _ => Ok(Value::F64(a % b)),
+        },
+        (a, b) => Err(Error::InvalidTypesForOperator(a.kind(), b.kind(), op)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::Identifier;
+    use crate::parser::parse;
+    use crate::{partial, register};
+
+    fn eval(source: &str) -> EvalResult {
+        evaluate(&parse(source).unwrap(), &activation())
+    }
+
+    fn activation() -> Activation {
+        let mut activation = Activation::new();
+        activation.insert("i".to_owned(), Value::I64(3));
+        activation.insert("u".to_owned(), Value::U64(5));
+        activation.insert("d".to_owned(), Value::F64(0.5));
+        activation.insert("t".to_owned(), Value::Bool(true));
+        activation.insert("s".to_owned(), Value::String("ab".to_owned()));
+        activation.insert(
+            "l".to_owned(),
+            Value::List(vec![Value::I64(1), Value::String("x".to_owned())]),
+        );
+        activation.insert(
+            "m".to_owned(),
+            Value::Map(
+                vec![
+                    ("a".to_owned(), Value::I64(1)),
+                    ("b".to_owned(), Value::List(vec![])),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+        );
+        activation
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(eval("1 < 2"), Ok(Value::Bool(true)));
+        assert_eq!(eval("u >= u"), Ok(Value::Bool(true)));
+        assert_eq!(eval("(i + 1) * 2 % 5"), Ok(Value::I64(3)));
+        assert_eq!(
+            eval("-9223372036854775807 - 2"),
+            Err(Error::IntegerOverflow(Op::Minus))
+        );
+        assert_eq!(eval("d / 0.0"), Err(Error::DivisionByZero));
+        assert_eq!(
+            eval("1 < d"),
+            Err(Error::InvalidTypesForOperator(Kind::I64, Kind::F64, Op::Lt))
+        );
+    }
+
+    #[test]
+    fn logic() {
+        assert_eq!(eval("nope || t"), Ok(Value::Bool(true)));
+        assert_eq!(eval("false && nope"), Ok(Value::Bool(false)));
+        assert_eq!(
+            eval("1 || false"),
+            Err(Error::InvalidTypeForOperator(Kind::I64, Op::Jump))
+        );
+        assert_eq!(
+            eval("false || 1"),
+            Err(Error::InvalidTypesForOperator(
+                Kind::Bool,
+                Kind::I64,
+                Op::Or
+            ))
+        );
+        assert_eq!(eval("t ? 1 : nope"), Ok(Value::I64(1)));
+        assert_eq!(
+            eval("s ? 1 : 2"),
+            Err(Error::InvalidTypeForOperator(Kind::String, Op::Jump))
+        );
+    }
+
+    #[test]
+    fn first_error_wins() {
+        assert_eq!(
+            eval("[nope, 1 / 0]"),
+            Err(Error::NoSuchBinding(Identifier::new("nope")))
+        );
+        assert_eq!(eval(r#"{"a": 1 / 0, 1: 2}"#), Err(Error::DivisionByZero));
+        assert_eq!(
+            eval(r#"{"a": 1, "a": 2}"#),
+            Err(Error::DuplicateMapKey("a".to_owned()))
+        );
+    }
+
+    #[test]
+    fn lets() {
+        assert_eq!(eval("let x = i + 1; x * x"), Ok(Value::I64(16)));
+        assert_eq!(eval("let i = i * 2; let i = i + 1; i"), Ok(Value::I64(7)));
+        assert_eq!(eval("let int = m; int.a"), Ok(Value::I64(1)));
+        assert_eq!(eval("let x = 1 / 0; t || x"), Ok(Value::Bool(true)));
+        assert_eq!(eval("let x = 1 / 0; [x]"), Err(Error::DivisionByZero));
+    }
+
+    #[test]
+    fn lets_after_jumps() {
+        // Branches that join again must leave the later slots where they are.
+        let activation = activation();
+        for (source, expected) in &[
+            (
+                "let z = 0; let a = true ? 1 : 2; let b = 10; b",
+                Value::I64(10),
+            ),
+            (
+                "let z = 0; let a = true || false; let b = 10; b",
+                Value::I64(10),
+            ),
+            ("let z = 0; let a = t && z < 1; let b = [z, a]; b", {
+                Value::List(vec![Value::I64(0), Value::Bool(true)])
+            }),
+        ] {
+            let expr = parse(source).unwrap();
+            assert_eq!(
+                evaluate(&expr, &activation),
+                Ok(expected.clone()),
+                "{}",
+                source
+            );
+            let mut program = stack::compile_with(expr.clone(), activation.clone()).unwrap();
+            assert_eq!(program.run(), &Ok(expected.clone()), "{}", source);
+            let mut program = register::compile(expr).unwrap();
+            assert_eq!(program.run(&activation), Ok(expected.clone()), "{}", source);
+        }
+    }
+
+    #[test]
+    fn long_chains() {
+        // As long a chain as the default limits allow, which everything that
+        // walks the tree must get through on a test thread's stack.
+        let source = format!("1{}", " + 1".repeat(999));
+        let expr = parse(&source).unwrap();
+        assert_eq!(parse(&expr.to_string()), Ok(expr.clone()));
+        let activation = Activation::new();
+        assert_eq!(evaluate(&expr, &activation), Ok(Value::I64(1_000)));
+        let mut program = stack::compile(expr.clone()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::I64(1_000)));
+        let mut program = register::compile(expr).unwrap();
+        assert_eq!(program.run(&activation), Ok(Value::I64(1_000)));
+
+        let mut unknowns = partial::Unknowns::new();
+        unknowns.mark("x");
+        let expr = parse(&format!("x{}", " + 1".repeat(999))).unwrap();
+        match partial::evaluate(&expr, &activation, &unknowns) {
+            partial::Partial::Unknown { residual, .. } => assert_eq!(residual, expr),
+            known => panic!("{:?}", known),
+        }
+    }
+
+    /// A small xorshift generator, so that a failing case can be reproduced
+    /// from its seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn new(seed: u64) -> Rng {
+            Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn pick<'a, T>(&mut self, xs: &'a [T]) -> &'a T {
+            &xs[self.below(xs.len())]
+        }
+    }
+
+    fn leaf(rng: &mut Rng) -> Expression {
+        match rng.below(6) {
+            0 => Expression::Lit(Literal::I64(*rng.pick(&[
+                0,
+                1,
+                2,
+                -1,
+                7,
+                i64::MAX,
+                i64::MIN,
+            ]))),
+            1 => Expression::Lit(Literal::F64(*rng.pick(&[0.0, 1.5, -2.25]))),
+            2 => Expression::Lit(Literal::Bool(rng.below(2) == 0)),
+            3 => Expression::Lit(
+                rng.pick(&[
+                    Literal::Null,
+                    Literal::String(String::new()),
+                    Literal::String("a".to_owned()),
+                ])
+                .clone(),
+            ),
+            _ => Expression::Binding(Identifier::new(rng.pick::<&str>(&[
+                "i", "u", "d", "t", "s", "l", "m", "nope", "int", "x", "y", "z",
+            ]))),
+        }
+    }
+
+    /// A random expression at most `depth` operators deep. Many are ill-typed,
+    /// to exercise the error paths.
+    fn expression(rng: &mut Rng, depth: usize) -> Expression {
+        if depth == 0 || rng.below(5) == 0 {
+            return leaf(rng);
+        }
+        let sub = |rng: &mut Rng| Box::new(expression(rng, depth - 1));
+        match rng.below(24) {
+            0 => Expression::Ternary {
+                condition: sub(rng),
+                true_branch: sub(rng),
+                false_branch: sub(rng),
+            },
+            1 => Expression::Or((0..2 + rng.below(2)).map(|_| *sub(rng)).collect()),
+            2 => Expression::And((0..2 + rng.below(2)).map(|_| *sub(rng)).collect()),
+            3 => Expression::Eq(sub(rng), sub(rng)),
+            4 => Expression::Neq(sub(rng), sub(rng)),
+            5 => Expression::Lt(sub(rng), sub(rng)),
+            6 => Expression::Lte(sub(rng), sub(rng)),
+            7 => Expression::Gte(sub(rng), sub(rng)),
+            8 => Expression::Gt(sub(rng), sub(rng)),
+            9 => Expression::Add(sub(rng), sub(rng)),
+            10 => Expression::Sub(sub(rng), sub(rng)),
+            11 => Expression::Mul(sub(rng), sub(rng)),
+            12 => Expression::Div(sub(rng), sub(rng)),
+            13 => Expression::Mod(sub(rng), sub(rng)),
+            14 => Expression::Neg(sub(rng)),
+            15 => Expression::Not(sub(rng)),
+            16 => {
+                let id = Identifier::new(rng.pick::<&str>(&["a", "b", "nope"]));
+                match rng.below(3) {
+                    0 => Expression::Member(sub(rng), id),
+                    1 => Expression::OptionalMember(sub(rng), id),
+                    _ => Expression::Has(sub(rng), id),
+                }
+            }
+            17 => Expression::OptionalIndex(sub(rng), sub(rng)),
+            18 => {
+                let (id, arity) = *rng.pick(&[("size", 0), ("orValue", 1), ("get", 1)]);
+                let operand = sub(rng);
+                let args = (0..arity).map(|_| *sub(rng)).collect();
+                Expression::Method(operand, Identifier::new(id), args)
+            }
+            19 => Expression::FunctionCall(Identifier::new("size"), vec![*sub(rng)]),
+            20 => Expression::LetBinding {
+                id: Identifier::new(rng.pick::<&str>(&["x", "i", "int"])),
+                value: sub(rng),
+                body: sub(rng),
+            },
+            // A chain of lets, each of whose values may use the earlier ones.
+            21 => {
+                let ids = ["x", "y", "z"];
+                let count = 2 + rng.below(2);
+                let mut body = *sub(rng);
+                for id in ids[..count].iter().rev() {
+                    body = Expression::LetBinding {
+                        id: Identifier::new(id),
+                        value: sub(rng),
+                        body: Box::new(body),
+                    };
+                }
+                body
+            }
+            22 => Expression::Lit(Literal::List(
+                (0..rng.below(3)).map(|_| *sub(rng)).collect(),
+            )),
+            _ => Expression::Lit(Literal::Map(
+                (0..rng.below(3)).map(|_| (*sub(rng), *sub(rng))).collect(),
+            )),
+        }
+    }
+
+    #[test]
+    fn machines_agree_with_the_reference() {
+        let activation = activation();
+        for seed in 1..=3000 {
+            let expr = expression(&mut Rng::new(seed), 4);
+            let expected = evaluate(&expr, &activation);
+            let mut program = stack::compile_with(expr.clone(), activation.clone()).unwrap();
+            assert_eq!(
+                program.run(),
+                &expected,
+                "stack machine, seed {}: {:?}",
+                seed,
+                expr
+            );
+            let mut program = register::compile(expr.clone()).unwrap();
+            assert_eq!(
+                program.run(&activation),
+                expected,
+                "register machine, seed {}: {:?}",
+                seed,
+                expr
+            );
+        }
+    }
+}
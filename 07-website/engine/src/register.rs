@@ -0,0 +1,360 @@
+//! A register machine that runs the same operations as `stack::Program`,
+//! for when only the result matters.
+//!
+//! The stack machine pushes and pops every intermediate value and snapshots
+//! its whole stack after each step, so that the playground can step back and
+//! forth. Here, every stack slot becomes a register whose index is fixed at
+//! compile time: operands are moved out of their registers instead of popped,
+//! jumps have absolute targets, and nothing is recorded along the way. A
+//! compiled program can be run against any number of activations.
+//!
+//! Several expressions can also share one program, each with an entry point
+//! of its own, and refer to each other's values as globals: see
+//! `Program::link`. That's how `policy` compiles a policy file.
+
+use std::mem;
+
+use prost_reflect::DescriptorPool;
+
+use crate::model::{Activation, Error, EvalResult, Expression, Identifier, Op, Value};
+use crate::stack::matching::PatternCache;
+use crate::stack::{self, walker, CompileResult, Operation, VerifyError};
+
+pub type Register = usize;
+
+#[derive(Debug, PartialEq)]
+pub enum Instruction {
+    Load(Register, Value),
+    Lookup(Register, Identifier),
+    Copy {
+        from: Register,
+        to: Register,
+    },
+    /// Copies the value of a global, see `Program::run_from`.
+    Global(Register, usize),
+    Move {
+        from: Register,
+        to: Register,
+    },
+    /// An operation that takes one operand, replaced by the result.
+    Unary(Operation, Register),
+    /// An operation whose operands are in `r` and `r + 1`; the result goes in `r`.
+    Binary(Operation, Register),
+    /// An operation whose operands start at `r`; the result goes in `r`.
+    Nary(Operation, Register),
+    Jump(usize),
+    /// Jumps if the register holds `true`, turning anything but a bool into an error.
+    JumpIf(Register, usize),
+    /// Jumps if the register holds `false`, turning anything but a bool into an error.
+    JumpIfNot(Register, usize),
+    JumpError(Register, usize),
+    Abort,
+    /// Ends the run, as running off the end of the program does.
+    Return,
+}
+
+#[derive(Debug)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    registers: Vec<EvalResult>,
+    types: DescriptorPool,
+    patterns: PatternCache,
+}
+
+pub fn compile(expr: Expression) -> CompileResult<Program> {
+    compile_with_types(expr, DescriptorPool::new())
+}
+
+/// Like `compile`, additionally resolving message construction (`pkg.Type{...}`)
+/// against the message types in `types`.
+pub fn compile_with_types(expr: Expression, types: DescriptorPool) -> CompileResult<Program> {
+    let operations = walker::linearize(expr)?;
+    Ok(Program::from_operations(operations, types))
+}
+
+impl Program {
+    /// Allocates registers for a program that the walker produced.
+    pub fn from_operations(operations: Vec<Operation>, types: DescriptorPool) -> Program {
+        Program::load(operations, types).expect("the walker only produces well-formed programs")
+    }
+
+    /// Like `from_operations`, for operations that didn't come from the
+    /// walker: fails if `stack::stack_heights` rejects them.
+    pub fn load(operations: Vec<Operation>, types: DescriptorPool) -> Result<Program, VerifyError> {
+        let mut program = Program {
+            instructions: Vec::new(),
+            registers: Vec::new(),
+            types,
+            patterns: PatternCache::default(),
+        };
+        program.append(operations, 0)?;
+        Ok(program)
+    }
+
+    /// Links blocks that the walker produced with `linearize_in_scope`, each
+    /// in the scope of `globals` values, into one program. Returns it with
+    /// the entry point of each block, which ends with `Instruction::Return`.
+    pub fn link(
+        blocks: Vec<Vec<Operation>>,
+        globals: usize,
+        types: DescriptorPool,
+    ) -> (Program, Vec<usize>) {
+        let mut program = Program {
+            instructions: Vec::new(),
+            registers: Vec::new(),
+            types,
+            patterns: PatternCache::default(),
+        };
+        let mut entries = Vec::with_capacity(blocks.len());
+        for operations in blocks {
+            entries.push(program.instructions.len());
+            program
+                .append(operations, globals)
+                .expect("the walker only produces well-formed programs");
+            program.instructions.push(Instruction::Return);
+        }
+        (program, entries)
+    }
+
+    /// Translates `operations`, which start with `globals` values on the
+    /// stack, after the instructions so far, making sure there are enough
+    /// registers for them. The globals don't take registers.
+    fn append(&mut self, operations: Vec<Operation>, globals: usize) -> Result<(), VerifyError> {
+        let heights = stack::stack_heights_from(&operations, globals)?;
+        let start = self.instructions.len();
+        // Pops and unreachable operations aren't translated, so jump targets
+        // have to be renumbered.
+        let mut targets = Vec::with_capacity(operations.len() + 1);
+        let mut count = 0;
+        for (op, height) in operations.iter().zip(&heights) {
+            targets.push(start + count);
+            if height.is_some() && *op != Operation::Pop {
+                count += 1;
+            }
+        }
+        targets.push(start + count);
+
+        self.instructions.reserve(count);
+        for (i, op) in operations.into_iter().enumerate() {
+            if let Some(h) = heights[i] {
+                let target = op.jump_offset().map_or(0, |n| targets[i + 1 + n]);
+                self.instructions
+                    .extend(translate(op, h - globals, target, globals));
+            }
+        }
+
+        let registers = heights.iter().flatten().max().map_or(0, |h| h - globals);
+        while self.registers.len() < registers.max(1) {
+            self.registers.push(Ok(Value::Null));
+        }
+        Ok(())
+    }
+
+    /// Runs the program with its free identifiers bound by `activation`.
+    pub fn run(&mut self, activation: &Activation) -> EvalResult {
+        self.run_from(0, activation, &[])
+    }
+
+    /// Like `run`, starting at instruction `entry`, e.g. one that `link`
+    /// returned, with the values of the globals it refers to in `globals`.
+    pub fn run_from(
+        &mut self,
+        entry: usize,
+        activation: &Activation,
+        globals: &[EvalResult],
+    ) -> EvalResult {
+        let registers = &mut self.registers;
+        let mut pc = entry;
+        while let Some(instruction) = self.instructions.get(pc) {
+            pc += 1;
+            match *instruction {
+                Instruction::Load(r, ref v) => registers[r] = Ok(v.clone()),
+                Instruction::Lookup(r, ref id) => {
+                    registers[r] = match activation.get(&id.0) {
+                        Some(v) => Ok(v.clone()),
+                        None => Err(Error::NoSuchBinding(id.clone())),
+                    }
+                }
+                Instruction::Copy { from, to } => registers[to] = registers[from].clone(),
+                Instruction::Move { from, to } => registers[to] = take(&mut registers[from]),
+                Instruction::Global(r, global) => registers[r] = globals[global].clone(),
+                Instruction::Unary(ref op, r) => {
+                    registers[r] = stack::eval_unary(op, take(&mut registers[r]));
+                }
+                Instruction::Binary(ref op, r) => {
+                    let b = take(&mut registers[r + 1]);
+                    registers[r] = stack::eval_binary(op, take(&mut registers[r]), b);
+                }
+                Instruction::Nary(ref op, r) => {
+                    let (pops, _) = op.stack_effect();
+                    let args = registers[r..r + pops].iter_mut().map(take).collect();
+                    registers[r] = stack::eval_nary(op, args, &self.types, &mut self.patterns);
+                }
+                Instruction::Jump(target) => pc = target,
+                Instruction::JumpIf(r, target) | Instruction::JumpIfNot(r, target) => {
+                    let jump_on = matches!(instruction, Instruction::JumpIf(_, _));
+                    match registers[r] {
+                        Ok(Value::Bool(b)) if b == jump_on => pc = target,
+                        Ok(Value::Bool(_)) | Err(_) => {}
+                        Ok(ref other) => {
+                            registers[r] =
+                                Err(Error::InvalidTypeForOperator(other.kind(), Op::Jump))
+                        }
+                    }
+                }
+                Instruction::JumpError(r, target) => {
+                    if registers[r].is_err() {
+                        pc = target;
+                    }
+                }
+                Instruction::Abort => {
+                    registers[0] = Err(Error::Aborted);
+                    break;
+                }
+                Instruction::Return => break,
+            }
+        }
+        take(&mut registers[0])
+    }
+}
+
+fn take(r: &mut EvalResult) -> EvalResult {
+    mem::replace(r, Ok(Value::Null))
+}
+
+/// The instruction for `op` when the stack is `h` values high above the
+/// `globals`, if it needs one. `target` is where a jump goes.
+fn translate(op: Operation, h: usize, target: usize, globals: usize) -> Option<Instruction> {
+    let instruction = match op {
+        Operation::Lit(v) => Instruction::Load(h, v),
+        Operation::Lookup(id) => Instruction::Lookup(h, id),
+        Operation::Clone => Instruction::Copy { from: h - 1, to: h },
+        Operation::Pop => return None,
+        Operation::Local(slot) if slot < globals => Instruction::Global(h, slot),
+        Operation::Local(slot) => Instruction::Copy {
+            from: slot - globals,
+            to: h,
+        },
+        Operation::Ternary | Operation::EndLet => Instruction::Move {
+            from: h - 1,
+            to: h - 2,
+        },
+        Operation::Jump(_) => Instruction::Jump(target),
+        Operation::JumpIf(_) => Instruction::JumpIf(h - 1, target),
+        Operation::JumpIfNot(_) => Instruction::JumpIfNot(h - 1, target),
+        Operation::JumpError(_) => Instruction::JumpError(h - 1, target),
+        Operation::Abort => Instruction::Abort,
+        Operation::Add
+        | Operation::Sub
+        | Operation::Mul
+        | Operation::Div
+        | Operation::Mod
+        | Operation::Or
+        | Operation::And
+        | Operation::Eq
+        | Operation::Lt
+        | Operation::Lte
+        | Operation::Gte
+        | Operation::Gt
+        | Operation::OptionalIndex => Instruction::Binary(op, h - 2),
+        Operation::MakeList(_)
+        | Operation::MakeMap(_)
+        | Operation::MakeMessage(_, _)
+        | Operation::Call(_, _)
+        | Operation::Method(_, _) => {
+            let (pops, _) = op.stack_effect();
+            Instruction::Nary(op, h - pops)
+        }
+        Operation::TypeError(_)
+        | Operation::Neg
+        | Operation::Not
+        | Operation::Member(_)
+        | Operation::OptionalMember(_)
+        | Operation::Has(_)
+        | Operation::Match(_) => Instruction::Unary(op, h - 1),
+    };
+    Some(instruction)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse;
+
+    fn assert_agrees(source: &str, activation: &Activation) {
+        let expr = parse(source).unwrap();
+        let mut expected = stack::compile_with(expr.clone(), activation.clone()).unwrap();
+        let mut program = compile(expr).unwrap();
+        assert_eq!(&program.run(activation), expected.run(), "{}", source);
+    }
+
+    #[test]
+    fn same_results_as_the_stack_machine() {
+        let mut activation = Activation::new();
+        activation.insert("x".to_owned(), Value::I64(3));
+        activation.insert("s".to_owned(), Value::String("hello".to_owned()));
+        activation.insert(
+            "m".to_owned(),
+            Value::Map(vec![("a".to_owned(), Value::I64(1))].into_iter().collect()),
+        );
+        for source in &[
+            "1 + 2 * x",
+            "x > 2 ? s.size() : -1",
+            "x ? 1 : 2",
+            "nope || true",
+            "true && nope",
+            "[x, x * 2, s][2] + \"!\"",
+            "{\"k\": x}.k == x && has(m.a)",
+            "m.?b.orValue(0) + m.a",
+            "s.startsWith(\"he\") && s.matches(\"l+\")",
+            "matches(s, s + \"(\")",
+            "size([1, nope, 3])",
+            "let y = 1; y",
+            "let y = x * 2; let x = y + 1; [x, y, m.a]",
+            "let e = 1 / 0; x > 2 || e",
+        ] {
+            assert_agrees(source, &activation);
+        }
+    }
+
+    #[test]
+    fn reusable_across_activations() {
+        let mut program = compile(parse("x * 2").unwrap()).unwrap();
+        for n in 0..3 {
+            let mut activation = Activation::new();
+            activation.insert("x".to_owned(), Value::I64(n));
+            assert_eq!(program.run(&activation), Ok(Value::I64(n * 2)));
+        }
+        assert_eq!(
+            program.run(&Activation::new()),
+            Err(Error::NoSuchBinding(Identifier::new("x")))
+        );
+    }
+
+    #[test]
+    fn pops_need_no_instruction() {
+        let operations = vec![
+            Operation::Lit(Value::Bool(true)),
+            Operation::Clone,
+            Operation::Pop,
+            Operation::JumpIf(2),
+            Operation::Lit(Value::I64(1)),
+            Operation::Jump(1),
+            Operation::Lit(Value::I64(2)),
+            Operation::Ternary,
+        ];
+        let mut program = Program::from_operations(operations, DescriptorPool::new());
+        assert_eq!(program.instructions.len(), 7);
+        assert_eq!(program.instructions[2], Instruction::JumpIf(0, 5));
+        assert_eq!(program.run(&Activation::new()), Ok(Value::I64(2)));
+    }
+
+    #[test]
+    fn load_rejects_malformed_programs() {
+        let operations = vec![Operation::Lit(Value::I64(1)), Operation::Jump(2)];
+        assert_eq!(
+            Program::load(operations, DescriptorPool::new()).unwrap_err(),
+            VerifyError::JumpOutOfRange(1)
+        );
+    }
+}
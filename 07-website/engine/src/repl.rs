@@ -0,0 +1,217 @@
+//! A line-oriented front end for the stack machine, driven by `src/bin/repl.rs`.
+//!
+//! Each line is either an expression, which is evaluated and printed, or one of
+//! the commands listed in `HELP`.
+
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+
+use crate::model::{Activation, EvalResult, Value};
+use crate::parser;
+use crate::stack::{self, Program};
+
+const HELP: &str = "\
+<expr>                 evaluate an expression
+%let <name> = <expr>   evaluate an expression and bind the result to <name>
+%load <name> <path>    read a JSON file and bind its contents to <name>
+:vars                  list the current bindings
+:trace                 toggle printing every operation and the stack after it
+:help                  show this message
+:quit                  exit";
+
+#[derive(Debug, Default)]
+pub struct Repl {
+    activation: Activation,
+    trace: bool,
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl::default()
+    }
+
+    /// Handles one line of input, writing any output to `out`. Returns `false`
+    /// once the user asks to quit.
+    pub fn handle(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let line = line.trim();
+        let (command, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        match command {
+            "" => {}
+            ":quit" | ":q" => return Ok(false),
+            ":help" => writeln!(out, "{}", HELP)?,
+            ":trace" => {
+                self.trace = !self.trace;
+                writeln!(out, "trace {}", if self.trace { "on" } else { "off" })?;
+            }
+            ":vars" => {
+                let mut names: Vec<&String> = self.activation.keys().collect();
+                names.sort();
+                for name in names {
+                    writeln!(out, "{} = {}", name, self.activation[name])?;
+                }
+            }
+            "%let" => match rest.find('=') {
+                Some(i) if is_identifier(rest[..i].trim()) => {
+                    let name = rest[..i].trim().to_owned();
+                    if let Some(v) = self.eval(&rest[i + 1..], out)? {
+                        self.activation.insert(name, v);
+                    }
+                }
+                _ => writeln!(out, "usage: %let <name> = <expr>")?,
+            },
+            "%load" => match rest.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [name, path] if is_identifier(name) => match load_json(path) {
+                    Ok(v) => {
+                        self.activation.insert((*name).to_owned(), v);
+                    }
+                    Err(e) => writeln!(out, "error: {}: {}", path, e)?,
+                },
+                _ => writeln!(out, "usage: %load <name> <path>")?,
+            },
+            _ if command.starts_with(':') || command.starts_with('%') => {
+                writeln!(out, "unknown command {}, try :help", command)?
+            }
+            _ => {
+                if let Some(v) = self.eval(line, out)? {
+                    writeln!(out, "{}", v)?;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Evaluates `input` against the current bindings. Errors are reported to
+    /// `out` rather than returned.
+    fn eval(&self, input: &str, out: &mut dyn Write) -> io::Result<Option<Value>> {
+        let expr = match parser::parse(input) {
+            Ok(expr) => expr,
+            Err(e) => {
+                writeln!(out, "error: {}", e)?;
+                return Ok(None);
+            }
+        };
+        let mut program = match stack::compile_with(expr, self.activation.clone()) {
+            Ok(program) => program,
+            Err(e) => {
+                writeln!(out, "error: {}", e)?;
+                return Ok(None);
+            }
+        };
+        let result = if self.trace {
+            trace(&mut program, out)?
+        } else {
+            program.run().clone()
+        };
+        match result {
+            Ok(v) => Ok(Some(v)),
+            Err(e) => {
+                writeln!(out, "error: {}", e)?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Runs `program` one step at a time, printing each operation and the stack
+/// after it, bottom first.
+fn trace(program: &mut Program, out: &mut dyn Write) -> io::Result<EvalResult> {
+    loop {
+        let pointer = program.pointer;
+        if !program.step() {
+            break;
+        }
+        let stack: Vec<String> = program.stack.iter().map(show).collect();
+        writeln!(
+            out,
+            "{:>4} {:<7} [{}]",
+            pointer,
+            program.operations[pointer].short(),
+            stack.join(", ")
+        )?;
+    }
+    Ok(program.run().clone())
+}
+
+fn show(v: &EvalResult) -> String {
+    match v {
+        Ok(v) => format!("{}", v),
+        Err(e) => e.to_string(),
+    }
+}
+
+fn load_json(path: &str) -> Result<Value, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|e| e.to_string())
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn session(lines: &[&str]) -> String {
+        let mut repl = Repl::new();
+        let mut out = Vec::new();
+        for line in lines {
+            repl.handle(line, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn evaluate_expressions() {
+        assert_eq!(session(&["1 + 2", "", "true && false"]), "3\nfalse\n");
+    }
+
+    #[test]
+    fn let_bindings() {
+        assert_eq!(
+            session(&["%let x = 40", "%let y = x + 2", "y", ":vars"]),
+            "42\nx = 40\ny = 42\n"
+        );
+        assert_eq!(session(&["%let 1x = 2"]), "usage: %let <name> = <expr>\n");
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        assert_eq!(session(&["1 +"]), "error: parse error @ L1:4\n");
+        assert_eq!(session(&["nobody"]), "error: no such variable nobody\n");
+        assert_eq!(session(&[":bogus"]), "unknown command :bogus, try :help\n");
+    }
+
+    #[test]
+    fn load_json_files() {
+        let path = std::env::temp_dir().join("stack-machine-repl-test.json");
+        std::fs::write(&path, r#"{"user": {"name": "alice"}}"#).unwrap();
+        let load = format!("%load request {}", path.display());
+        assert_eq!(session(&[&load, "request.user.name"]), "\"alice\"\n");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(session(&["%load x /no/such/file.json"]).starts_with("error: /no/such/file.json"));
+    }
+
+    #[test]
+    fn trace_steps() {
+        assert_eq!(
+            session(&[":trace", "1 + 2"]),
+            "trace on\n   0 LIT     [1]\n   1 LIT     [1, 2]\n   2 ADD     [3]\n3\n"
+        );
+    }
+
+    #[test]
+    fn quit() {
+        let mut out = Vec::new();
+        assert!(!Repl::new().handle(":quit", &mut out).unwrap());
+        assert!(Repl::new().handle("1", &mut out).unwrap());
+    }
+}
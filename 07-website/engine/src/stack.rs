@@ -0,0 +1,1783 @@
+use std::collections::HashMap;
+
+use crate::model::{Activation, Error, EvalResult, Expression, Identifier, Kind, Op, Value};
+use matching::{Pattern, PatternCache};
+use prost_reflect::DescriptorPool;
+use std::cmp::Ordering;
+use std::fmt;
+
+pub mod functions;
+pub mod graph;
+pub mod matching;
+pub mod messages;
+pub mod profile;
+pub mod strings;
+pub mod walker;
+
+#[derive(Debug, PartialEq)]
+pub enum CompileError {
+    /// A constant pattern that doesn't compile, with the index of its node.
+    InvalidRegex(usize, String),
+}
+
+impl CompileError {
+    /// The pre-order index of the expression node the error is about.
+    pub fn node(&self) -> usize {
+        match self {
+            CompileError::InvalidRegex(node, _) => *node,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::InvalidRegex(_, msg) => write!(f, "invalid regex: {}", msg),
+        }
+    }
+}
+
+pub type CompileResult<T> = Result<T, CompileError>;
+
+/// Why a sequence of operations isn't a program that can run, each with the
+/// index of the offending operation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    /// The operation pops more values than the stack holds.
+    Underflow(usize),
+    /// A `Local` reads a slot at or above the top of the stack.
+    LocalOutOfRange(usize),
+    /// A jump lands past the end of the program.
+    JumpOutOfRange(usize),
+    /// The operation can be reached with two different stack heights.
+    InconsistentHeights(usize, usize, usize),
+    /// The program ends with this many values instead of one.
+    EndHeight(usize),
+    /// The operation takes more operands than a stack can hold.
+    OperandOverflow(usize),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Underflow(pc) => write!(f, "operation {} underflows the stack", pc),
+            VerifyError::LocalOutOfRange(pc) => {
+                write!(f, "operation {} reads past the top of the stack", pc)
+            }
+            VerifyError::JumpOutOfRange(pc) => write!(f, "operation {} jumps past the end", pc),
+            VerifyError::InconsistentHeights(pc, a, b) => write!(
+                f,
+                "operation {} is reached with stack heights {} and {}",
+                pc, a, b
+            ),
+            VerifyError::EndHeight(h) => write!(f, "the program ends with {} values", h),
+            VerifyError::OperandOverflow(pc) => {
+                write!(f, "operation {} takes more operands than can exist", pc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+#[derive(Debug, PartialEq)]
+pub enum Operation {
+    Lit(Value),
+    TypeError(Op),
+    MakeList(usize),
+    MakeMap(usize),
+    MakeMessage(String, Vec<Identifier>),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Neg,
+    Not,
+    Or,
+    And,
+    Eq,
+    Lt,
+    Lte,
+    Gte,
+    Gt,
+    Clone,
+    Pop,
+    JumpIf(usize),
+    JumpIfNot(usize),
+    JumpError(usize),
+    Jump(usize),
+    Abort,
+    Lookup(Identifier),
+    Member(Identifier),
+    OptionalMember(Identifier),
+    OptionalIndex,
+    Has(Identifier),
+    Call(Identifier, usize),
+    Method(Identifier, usize),
+    Match(Pattern),
+    Ternary,
+    /// Pushes a copy of the let-bound value in the given stack slot, counting
+    /// from the bottom.
+    Local(usize),
+    /// Drops the let-bound value below the result.
+    EndLet,
+}
+
+impl Operation {
+    #[allow(clippy::useless_format)]
+    pub fn short(&self) -> String {
+        match self {
+            Operation::Lit(_) => format!("LIT"),
+            Operation::MakeList(_) => format!("MKLIST"),
+            Operation::MakeMap(_) => format!("MKMAP"),
+            Operation::MakeMessage(_, _) => format!("MKMSG"),
+            Operation::Add => format!("ADD"),
+            Operation::Sub => format!("SUB"),
+            Operation::Mul => format!("MUL"),
+            Operation::Div => format!("DIV"),
+            Operation::Mod => format!("MOD"),
+            Operation::Neg => format!("NEG"),
+            Operation::Not => format!("NOT"),
+            Operation::Or => format!("OR"),
+            Operation::And => format!("AND"),
+            Operation::Eq => format!("EQ"),
+            Operation::Lt => format!("LT"),
+            Operation::Lte => format!("LTE"),
+            Operation::Gte => format!("GTE"),
+            Operation::Gt => format!("GT"),
+            Operation::Jump(_) => format!("JMP"),
+            Operation::JumpError(_) => format!("JMPERR"),
+            Operation::JumpIf(_) => format!("JMPIF"),
+            Operation::JumpIfNot(_) => format!("JMPIFN"),
+            Operation::Clone => format!("CLONE"),
+            Operation::Pop => format!("POP"),
+            Operation::TypeError(_) => format!("TYPERR"),
+            Operation::Abort => format!("ABRT"),
+            Operation::Lookup(_) => format!("LKUP"),
+            Operation::Member(_) => format!("MBR"),
+            Operation::OptionalMember(_) => format!("OMBR"),
+            Operation::OptionalIndex => format!("OIDX"),
+            Operation::Has(_) => format!("HAS"),
+            Operation::Call(_, _) => format!("CALL"),
+            Operation::Method(_, _) => format!("MTHD"),
+            Operation::Match(_) => format!("MATCH"),
+            Operation::Ternary => format!("TERNRY"),
+            Operation::Local(_) => format!("LOCAL"),
+            Operation::EndLet => format!("ENDLET"),
+        }
+    }
+
+    #[allow(clippy::useless_format)]
+    pub fn tooltip(&self) -> String {
+        match self {
+            Operation::Lit(v) => format!("pushes the value {} onto the stack", v),
+            Operation::MakeList(n) => format!("pop {} items and construct a list from them", n),
+            Operation::MakeMap(n) => format!(
+                "pop {} items and construct a map from the key-value pairs",
+                2 * n
+            ),
+            Operation::MakeMessage(name, fields) => format!(
+                "pop {} field values and construct a {} message from them",
+                fields.len(),
+                name
+            ),
+            Operation::Add => format!("pop 2 items and push their sum"),
+            Operation::Sub => format!("pop 2 items and push their difference"),
+            Operation::Mul => format!("pop 2 items and push their product"),
+            Operation::Div => format!("pop 2 items and divide them"),
+            Operation::Mod => format!("pop 2 items and take the modulus"),
+            Operation::Neg => format!("pop a number and negate it"),
+            Operation::Not => format!("pop a boolean and negate it"),
+            Operation::Or => format!("combine two booleans"),
+            Operation::And => format!("combine two booleans"),
+            Operation::Eq => format!("pop two items and check if they're equal"),
+            Operation::Jump(n) => format!("jump {} operations", n),
+            Operation::JumpError(n) => format!(
+                "peek at the top of the stack; jmp {} operations if it is an error",
+                n
+            ),
+            Operation::JumpIf(n) => format!(
+                "peek at the top of the stack; jump {} operations if it is true",
+                n
+            ),
+            Operation::JumpIfNot(n) => format!(
+                "peek at the top of the stack; jump {} operations if it is false",
+                n
+            ),
+            Operation::Clone => {
+                format!("pop an item and push two copies of it back onto the stack")
+            }
+            Operation::Pop => format!("pop an item and discard it"),
+            Operation::TypeError(op) => {
+                format!("pop an item and construct a type-error for {:?}", op)
+            }
+            Operation::Lt => format!("pop two items and check if the second is < than the first"),
+            Operation::Lte => {
+                format!("pop two items and check if the second is <= than the first")
+            }
+            Operation::Gte => {
+                format!("pop two items and check if the second is >= than the first")
+            }
+            Operation::Gt => format!("pop two items and check if the second is > than the first"),
+            Operation::Abort => {
+                format!("abort the program (usually because something isn't implemented)")
+            }
+            Operation::Lookup(id) => format!("push the value bound to {}", id.0),
+            Operation::Member(id) => format!("pop a map and push its member {}", id.0),
+            Operation::OptionalMember(id) => format!(
+                "pop a map and push its member {} as an optional value",
+                id.0
+            ),
+            Operation::OptionalIndex => {
+                format!("pop a key and a container, then push the element as an optional value")
+            }
+            Operation::Has(id) => format!("pop a map and check whether it has a member {}", id.0),
+            Operation::Call(id, n) => format!("pop {} arguments and call {}() with them", n, id.0),
+            Operation::Method(id, n) => format!(
+                "pop {} arguments and a receiver, then call .{}() on the receiver",
+                n, id.0
+            ),
+            Operation::Match(pattern) => format!(
+                "pop a string and check whether it matches /{}/",
+                pattern.as_str()
+            ),
+            Operation::Ternary => {
+                format!("pop two values off the stack, push the original head back on")
+            }
+            Operation::Local(slot) => {
+                format!("push a copy of the let-bound value in stack slot {}", slot)
+            }
+            Operation::EndLet => {
+                format!("pop the result and the let-bound value below it, push the result back on")
+            }
+        }
+    }
+
+    /// How many values the operation pops and pushes. `Abort` discards the
+    /// whole stack instead, and jumps only decide where execution continues.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        self.checked_stack_effect()
+            .expect("the operand count of a verified operation fits in a usize")
+    }
+
+    /// Like `stack_effect`, or `None` if the operand count overflows, which
+    /// only a malformed operation can ask for.
+    pub fn checked_stack_effect(&self) -> Option<(usize, usize)> {
+        let effect = match *self {
+            Operation::Lit(_) | Operation::Lookup(_) | Operation::Local(_) => (0, 1),
+            Operation::Clone => (1, 2),
+            Operation::Pop => (1, 0),
+            Operation::Jump(_) | Operation::Abort => (0, 0),
+            Operation::TypeError(_)
+            | Operation::Neg
+            | Operation::Not
+            | Operation::Member(_)
+            | Operation::OptionalMember(_)
+            | Operation::Has(_)
+            | Operation::Match(_)
+            | Operation::JumpIf(_)
+            | Operation::JumpIfNot(_)
+            | Operation::JumpError(_) => (1, 1),
+            Operation::Add
+            | Operation::Sub
+            | Operation::Mul
+            | Operation::Div
+            | Operation::Mod
+            | Operation::Or
+            | Operation::And
+            | Operation::Eq
+            | Operation::Lt
+            | Operation::Lte
+            | Operation::Gte
+            | Operation::Gt
+            | Operation::OptionalIndex
+            | Operation::Ternary
+            | Operation::EndLet => (2, 1),
+            Operation::MakeList(n) | Operation::Call(_, n) => (n, 1),
+            Operation::MakeMap(n) => (n.checked_mul(2)?, 1),
+            Operation::MakeMessage(_, ref fields) => (fields.len(), 1),
+            Operation::Method(_, n) => (n.checked_add(1)?, 1),
+        };
+        Some(effect)
+    }
+
+    /// How many operations a jump skips past the next one.
+    pub fn jump_offset(&self) -> Option<usize> {
+        match *self {
+            Operation::Jump(n)
+            | Operation::JumpIf(n)
+            | Operation::JumpIfNot(n)
+            | Operation::JumpError(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+/// The height of the stack before each operation, and after the last one, or
+/// `None` for operations that can't be reached. Fails if the stack could
+/// underflow, if a jump leaves the program, if an operation can be reached
+/// with different heights, or if the program doesn't end with one value: every
+/// program that passes runs to the end without panicking.
+pub fn stack_heights(operations: &[Operation]) -> Result<Vec<Option<usize>>, VerifyError> {
+    stack_heights_from(operations, 0)
+}
+
+/// Like `stack_heights`, for a program that starts with `base` values on the
+/// stack that it mustn't pop, e.g. from `walker::linearize_in_scope`, and
+/// ends with one more.
+pub fn stack_heights_from(
+    operations: &[Operation],
+    base: usize,
+) -> Result<Vec<Option<usize>>, VerifyError> {
+    let mut heights = vec![None; operations.len() + 1];
+    heights[0] = Some(base);
+    // Every jump is forwards, so one pass sees every way into an operation first.
+    for (pc, op) in operations.iter().enumerate() {
+        let h = match heights[pc] {
+            Some(h) => h,
+            None => continue,
+        };
+        let mut successors = Vec::with_capacity(2);
+        if *op == Operation::Abort {
+            successors.push((operations.len(), 1));
+        } else {
+            let (pops, pushes) = op
+                .checked_stack_effect()
+                .ok_or(VerifyError::OperandOverflow(pc))?;
+            if h < base + pops {
+                return Err(VerifyError::Underflow(pc));
+            }
+            if let Operation::Local(slot) = *op {
+                if slot >= h {
+                    return Err(VerifyError::LocalOutOfRange(pc));
+                }
+            }
+            let after = h - pops + pushes;
+            if !matches!(op, Operation::Jump(_)) {
+                successors.push((pc + 1, after));
+            }
+            if let Some(n) = op.jump_offset() {
+                successors.push(((pc + 1).saturating_add(n), after));
+            }
+        }
+        for (next, after) in successors {
+            match heights.get(next) {
+                None => return Err(VerifyError::JumpOutOfRange(pc)),
+                Some(Some(existing)) if *existing != after => {
+                    return Err(VerifyError::InconsistentHeights(next, *existing, after))
+                }
+                _ => heights[next] = Some(after),
+            }
+        }
+    }
+    match heights[operations.len()] {
+        Some(h) if h != base + 1 => Err(VerifyError::EndHeight(h - base)),
+        _ => Ok(heights),
+    }
+}
+
+/// The machine state between two operations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub pointer: usize,
+    pub stack: Vec<EvalResult>,
+    /// For each error on the stack, the operation that produced it.
+    pub blame: Vec<Option<usize>>,
+}
+
+#[derive(Debug)]
+pub struct Program {
+    pub operations: Vec<Operation>,
+    /// The AST node each operation was compiled from, see `walker::linearize_with_origins`.
+    pub origins: Vec<usize>,
+    pub pointer: usize,
+    pub stack: Vec<EvalResult>,
+    /// Alongside `stack`, the index of the operation that produced each error
+    /// on it, or `None` for values.
+    blame: Vec<Option<usize>>,
+    activation: Activation,
+    types: DescriptorPool,
+    patterns: PatternCache,
+    /// Every state the program has reached so far if it's `recording`, or
+    /// else just the initial one; `history[position]` is the current one.
+    /// Stepping back keeps the later states around so that stepping forward
+    /// again just replays them.
+    history: Vec<Snapshot>,
+    recording: bool,
+    /// How many steps the program has taken.
+    position: usize,
+}
+
+impl Program {
+    /// Loads operations that didn't come from the compiler, e.g. deserialized
+    /// ones, checking them with `stack_heights` first. Every operation is
+    /// attributed to node 0, since there's no expression to point into.
+    pub fn load(
+        operations: Vec<Operation>,
+        activation: Activation,
+        types: DescriptorPool,
+    ) -> Result<Program, VerifyError> {
+        stack_heights(&operations)?;
+        let origins = vec![0; operations.len()];
+        Ok(Program::new(operations, origins, activation, types))
+    }
+
+    fn new(
+        operations: Vec<Operation>,
+        origins: Vec<usize>,
+        activation: Activation,
+        types: DescriptorPool,
+    ) -> Program {
+        Program {
+            operations,
+            origins,
+            pointer: 0,
+            stack: Vec::new(),
+            blame: Vec::new(),
+            activation,
+            types,
+            patterns: PatternCache::default(),
+            history: vec![Snapshot {
+                pointer: 0,
+                stack: Vec::new(),
+                blame: Vec::new(),
+            }],
+            recording: false,
+            position: 0,
+        }
+    }
+
+    /// Makes the program keep every state it reaches in its `history`, for a
+    /// debugger to step back and forth through, and rewinds it to the start.
+    /// Otherwise stepping back has to run the program again from the start,
+    /// but stepping forward doesn't copy the stack every time.
+    pub fn recording(mut self) -> Program {
+        self.recording = true;
+        self.rewind();
+        self
+    }
+
+    /// Executes the next operation, returning `false` if the program has already finished.
+    pub fn step(&mut self) -> bool {
+        if self.position + 1 < self.history.len() {
+            self.position += 1;
+            self.restore();
+            return true;
+        }
+        if !self.execute() {
+            return false;
+        }
+        if self.recording {
+            self.history.push(Snapshot {
+                pointer: self.pointer,
+                stack: self.stack.clone(),
+                blame: self.blame.clone(),
+            });
+        }
+        self.position += 1;
+        true
+    }
+
+    /// Undoes the last step, returning `false` if the program is at its start.
+    pub fn step_back(&mut self) -> bool {
+        if self.position == 0 {
+            return false;
+        }
+        if self.recording {
+            self.position -= 1;
+            self.restore();
+        } else {
+            self.seek(self.position - 1);
+        }
+        true
+    }
+
+    pub fn run_to_end(&mut self) {
+        while self.step() {}
+    }
+
+    /// Rewinds to the initial state. The recorded history is kept.
+    pub fn reset(&mut self) {
+        if self.recording {
+            self.seek(0);
+        } else {
+            self.rewind();
+        }
+    }
+
+    /// Steps until the operation at `op_index` is the next to execute. Returns
+    /// `false` if the program finished without getting there.
+    pub fn run_to(&mut self, op_index: usize) -> bool {
+        if self.pointer > op_index {
+            self.reset();
+        }
+        while self.pointer != op_index {
+            if !self.step() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Moves to the state after `position` steps, as far as the program gets.
+    pub fn seek(&mut self, position: usize) {
+        if !self.recording && position < self.position {
+            self.rewind();
+        }
+        while self.position > position && self.step_back() {}
+        while self.position < position && self.step() {}
+    }
+
+    /// The states recorded so far, starting with the initial one, which is
+    /// the only one unless the program is `recording`.
+    pub fn history(&self) -> &[Snapshot] {
+        &self.history
+    }
+
+    /// How many steps the program has taken, and so into `history` it is if
+    /// it's `recording`.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Runs the program from the start to the end, like `run`, counting how
+    /// often each operation runs and how much data it moves.
+    pub fn run_profiled(&mut self) -> profile::Profile {
+        self.reset();
+        let mut counts = vec![profile::Counts::default(); self.operations.len()];
+        while self.pointer < self.operations.len() {
+            let pc = self.pointer;
+            let (pops, pushes) = self.operations[pc].stack_effect();
+            let popped = profile::traffic(&self.stack[self.stack.len().saturating_sub(pops)..]);
+            self.step();
+            let pushed = profile::traffic(&self.stack[self.stack.len().saturating_sub(pushes)..]);
+            counts[pc].executions += 1;
+            counts[pc].traffic += popped + pushed;
+        }
+        profile::Profile::new(&self.operations, &self.origins, counts)
+    }
+
+    /// The control-flow graph of the operations in Graphviz DOT, see `graph::to_dot`.
+    pub fn to_dot(&self) -> String {
+        graph::to_dot(&self.operations)
+    }
+
+    /// The index of the operation that produced the error on top of the
+    /// stack, if there is one, rather than any that just passed it on.
+    pub fn error_origin(&self) -> Option<usize> {
+        self.blame.last().copied().flatten()
+    }
+
+    fn restore(&mut self) {
+        let snapshot = &self.history[self.position];
+        self.pointer = snapshot.pointer;
+        self.stack = snapshot.stack.clone();
+        self.blame = snapshot.blame.clone();
+    }
+
+    /// Goes back to the initial state, forgetting any recorded history.
+    fn rewind(&mut self) {
+        self.history.truncate(1);
+        self.position = 0;
+        self.restore();
+    }
+
+    fn execute(&mut self) -> bool {
+        if self.pointer >= self.operations.len() {
+            return false;
+        }
+        let pc = self.pointer;
+        let op = &self.operations[pc];
+        // The errors among the operands, to tell one that's passed on from one
+        // that the operation produces.
+        let (pops, _) = op.stack_effect();
+        let start = self.stack.len().saturating_sub(pops);
+        let operand_errors: Vec<(Error, usize)> = match op {
+            Operation::Lit(_)
+            | Operation::Clone
+            | Operation::Pop
+            | Operation::Jump(_)
+            | Operation::JumpError(_)
+            | Operation::JumpIf(_)
+            | Operation::JumpIfNot(_)
+            | Operation::Abort
+            | Operation::Lookup(_)
+            | Operation::Ternary
+            | Operation::EndLet
+            | Operation::Local(_) => Vec::new(),
+            _ => self.stack[start..]
+                .iter()
+                .zip(&self.blame[start..])
+                .filter_map(|(v, &blame)| Some((v.as_ref().err()?.clone(), blame?)))
+                .collect(),
+        };
+        match *op {
+            Operation::Lit(ref v) => {
+                self.stack.push(Ok(v.clone()));
+                self.blame.push(None);
+            }
+            Operation::Clone => {
+                let a = self.stack.pop().unwrap();
+                self.stack.push(a.clone());
+                self.stack.push(a);
+                let blame = *self.blame.last().unwrap();
+                self.blame.push(blame);
+            }
+            Operation::Pop => {
+                self.stack.pop();
+                self.blame.pop();
+            }
+            Operation::TypeError(_)
+            | Operation::Neg
+            | Operation::Not
+            | Operation::Member(_)
+            | Operation::OptionalMember(_)
+            | Operation::Has(_)
+            | Operation::Match(_) => {
+                let a = self.stack.pop().unwrap();
+                let result = eval_unary(op, a);
+                self.blame.truncate(start);
+                self.blame.push(blame(&result, &operand_errors, pc));
+                self.stack.push(result);
+            }
+            Operation::Add
+            | Operation::Sub
+            | Operation::Mul
+            | Operation::Div
+            | Operation::Mod
+            | Operation::Or
+            | Operation::And
+            | Operation::Eq
+            | Operation::Lt
+            | Operation::Lte
+            | Operation::Gte
+            | Operation::Gt
+            | Operation::OptionalIndex => {
+                let b = self.stack.pop().unwrap();
+                let a = self.stack.pop().unwrap();
+                let result = eval_binary(op, a, b);
+                self.blame.truncate(start);
+                self.blame.push(blame(&result, &operand_errors, pc));
+                self.stack.push(result);
+            }
+            Operation::MakeList(_)
+            | Operation::MakeMap(_)
+            | Operation::MakeMessage(_, _)
+            | Operation::Call(_, _)
+            | Operation::Method(_, _) => {
+                let args = self.stack.split_off(start);
+                let result = eval_nary(op, args, &self.types, &mut self.patterns);
+                self.blame.truncate(start);
+                self.blame.push(blame(&result, &operand_errors, pc));
+                self.stack.push(result);
+            }
+            Operation::Jump(n) => {
+                self.pointer += n;
+            }
+            Operation::JumpError(n) => {
+                let condition = self.stack.last().unwrap();
+                if condition.is_err() {
+                    self.pointer += n;
+                }
+            }
+            Operation::JumpIf(n) | Operation::JumpIfNot(n) => {
+                let condition = self.stack.pop().unwrap();
+                let jump_on = matches!(op, Operation::JumpIf(_));
+                match condition {
+                    Ok(Value::Bool(a)) => {
+                        if a == jump_on {
+                            self.pointer += n;
+                        }
+                        self.stack.push(condition.clone());
+                    }
+                    Ok(other) => {
+                        self.stack
+                            .push(Err(Error::InvalidTypeForOperator(other.kind(), Op::Jump)));
+                        *self.blame.last_mut().unwrap() = Some(pc);
+                    }
+                    Err(_) => {
+                        self.stack.push(condition.clone());
+                    }
+                }
+            }
+            Operation::Abort => {
+                self.stack.clear();
+                self.stack.push(Err(Error::Aborted));
+                self.blame.clear();
+                self.blame.push(Some(pc));
+                self.pointer = self.operations.len() - 1;
+            }
+            Operation::Lookup(ref id) => {
+                let result = match self.activation.get(&id.0) {
+                    Some(v) => Ok(v.clone()),
+                    None => Err(Error::NoSuchBinding(id.clone())),
+                };
+                self.blame.push(result.as_ref().err().map(|_| pc));
+                self.stack.push(result);
+            }
+            Operation::Ternary | Operation::EndLet => {
+                let result = self.stack.pop().unwrap();
+                self.stack.pop();
+                self.stack.push(result);
+                let blame = self.blame.pop().unwrap();
+                *self.blame.last_mut().unwrap() = blame;
+            }
+            Operation::Local(slot) => {
+                let value = self.stack[slot].clone();
+                self.stack.push(value);
+                self.blame.push(self.blame[slot]);
+            }
+        }
+        self.pointer += 1;
+        true
+    }
+
+    pub fn run(&mut self) -> &EvalResult {
+        self.run_to_end();
+        assert_eq!(self.stack.len(), 1, "valid programs always terminate with exactly one value on the stack, this one has {:?}", self.stack);
+        self.stack.first().unwrap()
+    }
+}
+
+pub fn compile(expr: Expression) -> CompileResult<Program> {
+    compile_with(expr, Activation::new())
+}
+
+/// Compiles `expr` into a program whose free identifiers are looked up in `activation`.
+pub fn compile_with(expr: Expression, activation: Activation) -> CompileResult<Program> {
+    compile_with_types(expr, activation, DescriptorPool::new())
+}
+
+/// Like `compile_with`, additionally resolving message construction
+/// (`pkg.Type{...}`) against the message types in `types`.
+pub fn compile_with_types(
+    expr: Expression,
+    activation: Activation,
+    types: DescriptorPool,
+) -> CompileResult<Program> {
+    let (operations, origins) = walker::linearize_with_origins(expr)?;
+    Ok(Program::new(operations, origins, activation, types))
+}
+
+/// Which operation to blame if `result`, produced by the one at `pc`, is an
+/// error: the one that produced the same error among the operands, if any.
+fn blame(result: &EvalResult, operand_errors: &[(Error, usize)], pc: usize) -> Option<usize> {
+    let e = result.as_ref().err()?;
+    match operand_errors.iter().find(|(operand, _)| operand == e) {
+        Some(&(_, origin)) => Some(origin),
+        None => Some(pc),
+    }
+}
+
+/// The result of an operation that pops one value, given that value.
+pub(crate) fn eval_unary(op: &Operation, a: EvalResult) -> EvalResult {
+    match *op {
+        Operation::TypeError(ref op) => match a {
+            Ok(v) => Err(Error::InvalidTypeForOperator(v.kind(), op.clone())),
+            Err(e) => Err(e),
+        },
+        Operation::Neg => match a {
+            Ok(Value::I64(n)) => n
+                .checked_neg()
+                .map(Value::I64)
+                .ok_or(Error::IntegerOverflow(Op::Neg)),
+            Ok(Value::F64(x)) => Ok(Value::F64(-x)),
+            Ok(other) => Err(Error::InvalidTypeForOperator(other.kind(), Op::Neg)),
+            Err(e) => Err(e),
+        },
+        Operation::Not => match a {
+            Ok(Value::Bool(b)) => Ok(Value::Bool(!b)),
+            Ok(other) => Err(Error::InvalidTypeForOperator(other.kind(), Op::Not)),
+            Err(e) => Err(e),
+        },
+        Operation::Member(ref id) => a.and_then(|v| eval_member(v, id)),
+        Operation::OptionalMember(ref id) => a.and_then(|v| eval_optional_member(v, id)),
+        Operation::Has(ref id) => a.and_then(|v| eval_has(v, id)),
+        Operation::Match(ref pattern) => match a {
+            Ok(Value::String(s)) => Ok(Value::Bool(pattern.is_match(&s))),
+            Ok(other) => Err(Error::InvalidFunctionSignature(
+                Identifier::new("matches"),
+                vec![other.kind(), Kind::String],
+            )),
+            Err(e) => Err(e),
+        },
+        ref other => unreachable!("{:?} doesn't take one operand", other),
+    }
+}
+
+/// The result of an operation that pops two values, given the lower one `a`
+/// and the top one `b`.
+pub(crate) fn eval_binary(op: &Operation, a: EvalResult, b: EvalResult) -> EvalResult {
+    match *op {
+        Operation::Add | Operation::Sub | Operation::Mul | Operation::Div | Operation::Mod => {
+            let (a, b) = match (a, b) {
+                (Ok(a), Ok(b)) => (a, b),
+                (Err(e), _) | (_, Err(e)) => return Err(e),
+            };
+            match *op {
+                Operation::Add => eval_add(a, b),
+                Operation::Sub => eval_sub(a, b),
+                Operation::Mul => eval_mul(a, b),
+                Operation::Div => eval_div(a, b),
+                _ => eval_mod(a, b),
+            }
+        }
+        Operation::Or => match (a, b) {
+            (Ok(Value::Bool(true)), _) | (_, Ok(Value::Bool(true))) => Ok(Value::Bool(true)),
+            (Ok(Value::Bool(false)), Ok(Value::Bool(false))) => Ok(Value::Bool(false)),
+            (Ok(a), Ok(b)) => Err(Error::InvalidTypesForOperator(a.kind(), b.kind(), Op::Or)),
+            (Err(a), Err(b)) => Err(a.absorb(b)),
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        },
+        Operation::And => match (a, b) {
+            (Ok(Value::Bool(false)), _) | (_, Ok(Value::Bool(false))) => Ok(Value::Bool(false)),
+            (Ok(Value::Bool(true)), Ok(Value::Bool(true))) => Ok(Value::Bool(true)),
+            (Ok(a), Ok(b)) => Err(Error::InvalidTypesForOperator(a.kind(), b.kind(), Op::And)),
+            (Err(a), Err(b)) => Err(a.absorb(b)),
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        },
+        Operation::Eq => match (a, b) {
+            (Ok(a), Ok(b)) => Ok(Value::Bool(a == b)),
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        },
+        Operation::Lt => eval_cmp(a, b, Op::Lt),
+        Operation::Lte => eval_cmp(a, b, Op::Lte),
+        Operation::Gte => eval_cmp(a, b, Op::Gte),
+        Operation::Gt => eval_cmp(a, b, Op::Gt),
+        Operation::OptionalIndex => match (a, b) {
+            (Err(e), _) | (_, Err(e)) => Err(e),
+            (Ok(operand), Ok(key)) => eval_optional_index(operand, key),
+        },
+        ref other => unreachable!("{:?} doesn't take two operands", other),
+    }
+}
+
+/// The result of an operation that pops a variable number of values, given
+/// those values in the order they were pushed.
+pub(crate) fn eval_nary(
+    op: &Operation,
+    mut args: Vec<EvalResult>,
+    types: &DescriptorPool,
+    patterns: &mut PatternCache,
+) -> EvalResult {
+    match *op {
+        Operation::MakeList(_) => args.into_iter().collect::<Result<_, _>>().map(Value::List),
+        Operation::MakeMap(n) => {
+            let mut kvs = HashMap::with_capacity(n);
+            let mut args = args.into_iter();
+            while let (Some(k), Some(v)) = (args.next(), args.next()) {
+                match (k?, v?) {
+                    (Value::String(k), v) => {
+                        if kvs.insert(k.clone(), v).is_some() {
+                            return Err(Error::DuplicateMapKey(k));
+                        }
+                    }
+                    (other, _) => return Err(Error::InvalidMapKey(other.kind())),
+                }
+            }
+            Ok(Value::Map(kvs))
+        }
+        Operation::MakeMessage(ref name, ref fields) => {
+            let values = args.into_iter().collect::<Result<Vec<Value>, Error>>()?;
+            messages::make(types, name, fields, values)
+        }
+        Operation::Call(ref id, _) => {
+            let args = args.into_iter().collect::<Result<Vec<Value>, Error>>()?;
+            if id.0 == "matches" {
+                patterns.call(id, args)
+            } else {
+                functions::call(id, args)
+            }
+        }
+        Operation::Method(ref id, _) => {
+            let operand = args.remove(0);
+            let args = args.into_iter().collect::<Result<Vec<Value>, Error>>();
+            match (operand, args) {
+                (Err(e), _) | (_, Err(e)) => Err(e),
+                (Ok(operand), Ok(mut args)) if id.0 == "matches" => {
+                    args.insert(0, operand);
+                    patterns.call(id, args)
+                }
+                (Ok(operand), Ok(args)) => eval_method(operand, id, args),
+            }
+        }
+        ref other => unreachable!("{:?} doesn't take a variable number of operands", other),
+    }
+}
+
+pub(crate) fn eval_method(operand: Value, id: &Identifier, mut args: Vec<Value>) -> EvalResult {
+    match (operand, id.0.as_str()) {
+        (Value::String(s), _) => strings::call_method(s, id, args),
+        (Value::List(vs), "join") => strings::join(vs, id, args),
+        (operand, "get") if args.len() == 1 => eval_index(operand, args.pop().unwrap()),
+        (Value::Optional(v), _) => eval_optional_method(v, id, args),
+        (operand, "size") if args.is_empty() => functions::call(id, vec![operand]),
+        (operand, _) => Err(Error::NoMethodOnType(operand.kind(), id.clone())),
+    }
+}
+
+fn eval_optional_method(
+    v: Option<Box<Value>>,
+    id: &Identifier,
+    mut args: Vec<Value>,
+) -> EvalResult {
+    match (id.0.as_str(), args.len()) {
+        ("hasValue", 0) => Ok(Value::Bool(v.is_some())),
+        ("value", 0) => v.map(|v| *v).ok_or(Error::EmptyOptional),
+        ("orValue", 1) => Ok(v.map_or_else(|| args.pop().unwrap(), |v| *v)),
+        ("hasValue", _) | ("value", _) | ("orValue", _) => Err(Error::NoMethodWithSignature(
+            Kind::Optional,
+            id.clone(),
+            args.iter().map(Value::kind).collect(),
+        )),
+        _ => Err(Error::NoMethodOnType(Kind::Optional, id.clone())),
+    }
+}
+
+// Regular selection and indexing look through optional values, so that the
+// remainder of an `a.?b.c[0]` chain stays optional instead of failing.
+
+pub(crate) fn eval_member(operand: Value, id: &Identifier) -> EvalResult {
+    match operand {
+        Value::Map(mut kvs) => kvs
+            .remove(&id.0)
+            .ok_or_else(|| Error::NoSuchMember(id.clone())),
+        Value::Message(msg) => messages::member(&msg, id),
+        Value::Optional(None) => Ok(Value::Optional(None)),
+        Value::Optional(Some(v)) => eval_member(*v, id).map(|v| Value::Optional(Some(Box::new(v)))),
+        other => Err(Error::InvalidTypeForOperator(other.kind(), Op::Member)),
+    }
+}
+
+pub(crate) fn eval_optional_member(operand: Value, id: &Identifier) -> EvalResult {
+    match operand {
+        Value::Map(mut kvs) => Ok(Value::Optional(kvs.remove(&id.0).map(Box::new))),
+        Value::Message(msg) => messages::optional_member(&msg, id),
+        Value::Optional(None) => Ok(Value::Optional(None)),
+        Value::Optional(Some(v)) => eval_optional_member(*v, id),
+        other => Err(Error::InvalidTypeForOperator(
+            other.kind(),
+            Op::OptionalMember,
+        )),
+    }
+}
+
+fn eval_index(operand: Value, key: Value) -> EvalResult {
+    match (operand, key) {
+        (Value::Map(mut kvs), Value::String(k)) => match kvs.remove(&k) {
+            Some(v) => Ok(v),
+            None => Err(Error::NoSuchMember(Identifier(k))),
+        },
+        (Value::List(mut vs), Value::I64(i)) => {
+            if i >= 0 && (i as usize) < vs.len() {
+                Ok(vs.swap_remove(i as usize))
+            } else {
+                Err(Error::IndexOutOfRange(i))
+            }
+        }
+        (Value::Optional(None), _) => Ok(Value::Optional(None)),
+        (Value::Optional(Some(v)), key) => {
+            eval_index(*v, key).map(|v| Value::Optional(Some(Box::new(v))))
+        }
+        (operand, key) => Err(Error::InvalidTypesForOperator(
+            operand.kind(),
+            key.kind(),
+            Op::Method(Identifier::new("get")),
+        )),
+    }
+}
+
+pub(crate) fn eval_optional_index(operand: Value, key: Value) -> EvalResult {
+    match (operand, key) {
+        (Value::Map(mut kvs), Value::String(k)) => {
+            Ok(Value::Optional(kvs.remove(&k).map(Box::new)))
+        }
+        (Value::List(mut vs), Value::I64(i)) => {
+            if i >= 0 && (i as usize) < vs.len() {
+                Ok(Value::Optional(Some(Box::new(vs.swap_remove(i as usize)))))
+            } else {
+                Ok(Value::Optional(None))
+            }
+        }
+        (Value::Optional(None), _) => Ok(Value::Optional(None)),
+        (Value::Optional(Some(v)), key) => eval_optional_index(*v, key),
+        (operand, key) => Err(Error::InvalidTypesForOperator(
+            operand.kind(),
+            key.kind(),
+            Op::OptionalIndex,
+        )),
+    }
+}
+
+pub(crate) fn eval_has(operand: Value, id: &Identifier) -> EvalResult {
+    match operand {
+        Value::Map(kvs) => Ok(Value::Bool(kvs.contains_key(&id.0))),
+        Value::Message(msg) => messages::has(&msg, id),
+        Value::Optional(None) => Ok(Value::Bool(false)),
+        Value::Optional(Some(v)) => eval_has(*v, id),
+        other => Err(Error::InvalidTypeForOperator(other.kind(), Op::Has)),
+    }
+}
+
+fn eval_add(a: Value, b: Value) -> EvalResult {
+    match (a, b) {
+        (Value::I64(a), Value::I64(b)) => a
+            .checked_add(b)
+            .map(Value::I64)
+            .ok_or(Error::IntegerOverflow(Op::Plus)),
+        (Value::U64(a), Value::U64(b)) => a
+            .checked_add(b)
+            .map(Value::U64)
+            .ok_or(Error::IntegerOverflow(Op::Plus)),
+        (Value::F64(a), Value::F64(b)) => Ok(Value::F64(a + b)),
+        (a, b) => Err(Error::InvalidTypesForOperator(a.kind(), b.kind(), Op::Plus)),
+    }
+}
+
+fn eval_sub(a: Value, b: Value) -> EvalResult {
+    match (a, b) {
+        (Value::I64(a), Value::I64(b)) => a
+            .checked_sub(b)
+            .map(Value::I64)
+            .ok_or(Error::IntegerOverflow(Op::Minus)),
+        (Value::U64(a), Value::U64(b)) => a
+            .checked_sub(b)
+            .map(Value::U64)
+            .ok_or(Error::IntegerOverflow(Op::Minus)),
+        (Value::F64(a), Value::F64(b)) => Ok(Value::F64(a - b)),
+        (a, b) => Err(Error::InvalidTypesForOperator(
+            a.kind(),
+            b.kind(),
+            Op::Minus,
+        )),
+    }
+}
+
+fn eval_mul(a: Value, b: Value) -> EvalResult {
+    match (a, b) {
+        (Value::I64(a), Value::I64(b)) => a
+            .checked_mul(b)
+            .map(Value::I64)
+            .ok_or(Error::IntegerOverflow(Op::Times)),
+        (Value::U64(a), Value::U64(b)) => a
+            .checked_mul(b)
+            .map(Value::U64)
+            .ok_or(Error::IntegerOverflow(Op::Times)),
+        (Value::F64(a), Value::F64(b)) => Ok(Value::F64(a * b)),
+        (a, b) => Err(Error::InvalidTypesForOperator(
+            a.kind(),
+            b.kind(),
+            Op::Times,
+        )),
+    }
+}
+
+fn eval_div(a: Value, b: Value) -> EvalResult {
+    match (a, b) {
+        (Value::I64(a), Value::I64(b)) => {
+            if b != 0 {
+                a.checked_div(b)
+                    .map(Value::I64)
+                    .ok_or(Error::IntegerOverflow(Op::Div))
+            } else {
+                Err(Error::DivisionByZero)
+            }
+        }
+        (Value::U64(a), Value::U64(b)) => a
+            .checked_div(b)
+            .map(Value::U64)
+            .ok_or(Error::DivisionByZero),
+        (Value::F64(a), Value::F64(b)) => {
+            if b != 0.0 {
+                Ok(Value::F64(a / b))
+            } else {
+                Err(Error::DivisionByZero)
+            }
+        }
+        (a, b) => Err(Error::InvalidTypesForOperator(a.kind(), b.kind(), Op::Div)),
+    }
+}
+
+fn eval_mod(a: Value, b: Value) -> EvalResult {
+    match (a, b) {
+        (Value::I64(a), Value::I64(b)) => {
+            if b != 0 {
+                a.checked_rem(b)
+                    .map(Value::I64)
+                    .ok_or(Error::IntegerOverflow(Op::Mod))
+            } else {
+                Err(Error::DivisionByZero)
+            }
+        }
+        (Value::U64(a), Value::U64(b)) => a
+            .checked_rem(b)
+            .map(Value::U64)
+            .ok_or(Error::DivisionByZero),
+        (Value::F64(a), Value::F64(b)) => {
+            if b != 0.0 {
+                Ok(Value::F64(a % b))
+            } else {
+                Err(Error::DivisionByZero)
+            }
+        }
+        (a, b) => Err(Error::InvalidTypesForOperator(a.kind(), b.kind(), Op::Mod)),
+    }
+}
+
+fn eval_cmp(a: EvalResult, b: EvalResult, op: Op) -> EvalResult {
+    let ord = match (a?, b?) {
+        (Value::I64(a), Value::I64(b)) => Ok(a.cmp(&b)),
+        (Value::U64(a), Value::U64(b)) => Ok(a.cmp(&b)),
+        (a, b) => Err(Error::InvalidTypesForOperator(
+            a.kind(),
+            b.kind(),
+            op.clone(),
+        )),
+    };
+    let result = match ord? {
+        Ordering::Less => op == Op::Lt || op == Op::Lte,
+        Ordering::Equal => op == Op::Lte || op == Op::Gte,
+        Ordering::Greater => op == Op::Gte || op == Op::Gt,
+    };
+    Ok(Value::Bool(result))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parser::parse;
+
+    use super::*;
+
+    #[test]
+    fn simple_add() {
+        let mut program = compile(parse(r#" 1 + 2 "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::I64(1 + 2)));
+    }
+
+    #[test]
+    fn simple_sub() {
+        let mut program = compile(parse(r#" 3 - 2 "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::I64(3 - 2)));
+    }
+
+    #[test]
+    fn simple_mul() {
+        let mut program = compile(parse(r#" 2 * 3 "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::I64(2 * 3)));
+    }
+
+    #[test]
+    fn simple_div() {
+        let mut program = compile(parse(r#" 6 / 3 "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::I64(6 / 3)));
+    }
+
+    #[test]
+    fn simple_mod() {
+        let mut program = compile(parse(r#" 7 % 3 "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::I64(7 % 3)));
+    }
+
+    #[test]
+    fn float_mod() {
+        let mut program = compile(parse(r#" 7.0 % 3.4 "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::F64(7.0 % 3.4)));
+    }
+
+    #[test]
+    fn simple_lt() {
+        let mut program = compile(parse(r#" 1 < 2 "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::Bool(true)));
+        let mut program = compile(parse(r#" 3 >= 4 "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::Bool(false)));
+    }
+
+    #[test]
+    fn comparisons_keep_their_operands_in_order() {
+        for (source, expected) in [
+            ("1 < 2", true),
+            ("2 < 1", false),
+            ("1 <= 2", true),
+            ("2 <= 1", false),
+            ("1 >= 2", false),
+            ("2 >= 1", true),
+            ("1 > 2", false),
+            ("2 > 1", true),
+            ("1 <= 1 && 1 >= 1", true),
+            ("-3 < -2", true),
+            ("uint(3) > uint(2)", true),
+            ("uint(3) < uint(2)", false),
+        ] {
+            let mut program = compile(parse(source).unwrap()).unwrap();
+            assert_eq!(program.run(), &Ok(Value::Bool(expected)), "{}", source);
+        }
+    }
+
+    #[test]
+    fn int_overflow() {
+        let mut program = compile(parse(r#" 9223372036854775807 + 1 "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Err(Error::IntegerOverflow(Op::Plus)));
+        for (source, op) in [
+            (" -9223372036854775807 - 2 ", Op::Minus),
+            (" 4611686018427387904 * 2 ", Op::Times),
+            (" (-9223372036854775807 - 1) / -1 ", Op::Div),
+            (" (-9223372036854775807 - 1) % -1 ", Op::Mod),
+            (" -(-9223372036854775807 - 1) ", Op::Neg),
+        ] {
+            let mut program = compile(parse(source).unwrap()).unwrap();
+            assert_eq!(
+                program.run(),
+                &Err(Error::IntegerOverflow(op)),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn simple_or() {
+        let mut program = compile(parse(r#" true || false "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn or_err() {
+        let mut program = compile(parse(r#" false || "asdf" || false "#).unwrap()).unwrap();
+        assert_eq!(
+            program.run(),
+            &Err(Error::InvalidTypesForOperator(
+                Kind::Bool,
+                Kind::String,
+                Op::Or
+            )),
+        );
+    }
+
+    #[test]
+    fn or_err_recovery() {
+        let mut program =
+            compile(parse(r#" 0 || false || 2 || true || 4 || false || 6 || 7 "#).unwrap())
+                .unwrap();
+        assert_eq!(program.run(), &Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn simple_ternary() {
+        let mut program = compile(parse(r#" 1 + 1 == 2 ? "okay" : "nope" "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::String("okay".to_owned())),);
+    }
+
+    #[test]
+    fn ternary_bad_type() {
+        let mut program = compile(parse(r#" 1 ? 2 : 3 "#).unwrap()).unwrap();
+        assert_eq!(
+            program.run(),
+            &Err(Error::InvalidTypeForOperator(Kind::I64, Op::Jump))
+        );
+    }
+
+    #[test]
+    fn ternary_err() {
+        let mut program = compile(parse(r#" 1 / 0 ? 2 : 3 "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Err(Error::DivisionByZero));
+    }
+
+    #[test]
+    fn simple_list() {
+        let mut program = compile(parse(r#" [1, 2 + 3] "#).unwrap()).unwrap();
+        assert_eq!(
+            program.run(),
+            &Ok(Value::List(vec![Value::I64(1), Value::I64(2 + 3)]))
+        );
+    }
+
+    #[test]
+    fn type_conversions() {
+        let mut program = compile(parse(r#" int("12") + int(3.7) "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::I64(15)));
+    }
+
+    #[test]
+    fn type_conversion_error() {
+        let mut program = compile(parse(r#" int("abc") "#).unwrap()).unwrap();
+        assert_eq!(
+            program.run(),
+            &Err(Error::ConversionFailed(Kind::I64, "abc".to_owned()))
+        );
+    }
+
+    #[test]
+    fn type_values() {
+        let mut program =
+            compile(parse(r#" type("x") == string && type(type(1)) == type "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn type_names_are_not_variables() {
+        let mut activation = Activation::new();
+        activation.insert("string".to_owned(), Value::I64(1));
+        let mut program = compile_with(parse(r#" string "#).unwrap(), activation).unwrap();
+        assert_eq!(program.run(), &Ok(Value::Type(Kind::String)));
+        let mut program = compile(parse(r#" let string = 1; string + 1 "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::I64(2)));
+    }
+
+    #[test]
+    fn uint_arithmetic() {
+        let mut program = compile(parse(r#" uint(1) - uint(2) "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Err(Error::IntegerOverflow(Op::Minus)));
+    }
+
+    #[test]
+    fn unknown_function() {
+        let mut program = compile(parse(r#" frobnicate(1) "#).unwrap()).unwrap();
+        assert_eq!(
+            program.run(),
+            &Err(Error::NoFunction(Identifier::new("frobnicate")))
+        );
+    }
+
+    #[test]
+    fn string_methods() {
+        let mut program =
+            compile(parse(r#" "a,b".split(",").join("-").upperAscii() == "A-B" "#).unwrap())
+                .unwrap();
+        assert_eq!(program.run(), &Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn string_size() {
+        let mut program = compile(parse(r#" "héllo".size() + size("wörld") "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::I64(10)));
+    }
+
+    #[test]
+    fn method_bad_signature() {
+        let mut program = compile(parse(r#" "abc".startsWith(1) "#).unwrap()).unwrap();
+        assert_eq!(
+            program.run(),
+            &Err(Error::NoMethodWithSignature(
+                Kind::String,
+                Identifier::new("startsWith"),
+                vec![Kind::I64]
+            ))
+        );
+    }
+
+    #[test]
+    fn method_on_error() {
+        let mut program = compile(parse(r#" (1 / 0).size() "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Err(Error::DivisionByZero));
+    }
+
+    #[test]
+    fn regex_matches() {
+        let mut program = compile(
+            parse(r#" "alice@corp.com".matches("^[a-z]+@corp\\.com$") && !matches("bob@evil.com", "@corp") "#)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(program.run(), &Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn regex_constant_pattern_is_precompiled() {
+        let program = compile(parse(r#" "abc".matches("b") "#).unwrap()).unwrap();
+        assert_eq!(
+            program.operations,
+            vec![
+                Operation::Lit(Value::String("abc".to_owned())),
+                Operation::Match(Pattern::new("b").unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn regex_invalid_constant_pattern() {
+        match compile(parse(r#" "abc".matches("(") "#).unwrap()) {
+            Err(CompileError::InvalidRegex(2, _)) => {}
+            other => panic!("expected an invalid regex, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn regex_dynamic_pattern() {
+        let mut program = compile(parse(r#" "abc".matches("B".lowerAscii()) "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::Bool(true)));
+        let mut program = compile(parse(r#" "abc".matches("(".lowerAscii()) "#).unwrap()).unwrap();
+        match program.run() {
+            Err(Error::InvalidRegex(_)) => {}
+            other => panic!("expected an invalid regex, got {:?}", other),
+        }
+    }
+
+    fn request() -> Activation {
+        let claims = Value::Map(
+            vec![("sub".to_owned(), Value::String("alice".to_owned()))]
+                .into_iter()
+                .collect(),
+        );
+        let auth = Value::Map(vec![("claims".to_owned(), claims)].into_iter().collect());
+        let request = Value::Map(
+            vec![
+                ("auth".to_owned(), auth),
+                (
+                    "path".to_owned(),
+                    Value::List(vec![Value::String("users".to_owned())]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        vec![("request".to_owned(), request)].into_iter().collect()
+    }
+
+    fn eval(input: &str) -> EvalResult {
+        let mut program = compile_with(parse(input).unwrap(), request()).unwrap();
+        program.run().clone()
+    }
+
+    #[test]
+    fn member_access() {
+        assert_eq!(
+            eval(r#" request.auth.claims.sub "#),
+            Ok(Value::String("alice".to_owned()))
+        );
+        assert_eq!(
+            eval(r#" request.auth.claims.email "#),
+            Err(Error::NoSuchMember(Identifier::new("email")))
+        );
+        assert_eq!(
+            eval(r#" nobody.home "#),
+            Err(Error::NoSuchBinding(Identifier::new("nobody")))
+        );
+    }
+
+    #[test]
+    fn index_access() {
+        assert_eq!(
+            eval(r#" request["path"][0] "#),
+            Ok(Value::String("users".to_owned()))
+        );
+        assert_eq!(eval(r#" request.path[1] "#), Err(Error::IndexOutOfRange(1)));
+    }
+
+    #[test]
+    fn has_macro() {
+        assert_eq!(
+            eval(r#" has(request.auth.claims.sub) "#),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(
+            eval(r#" has(request.auth.claims.email) "#),
+            Ok(Value::Bool(false))
+        );
+        assert_eq!(
+            eval(r#" has(request.auth.claims.sub.length) "#),
+            Err(Error::InvalidTypeForOperator(Kind::String, Op::Has))
+        );
+    }
+
+    #[test]
+    fn optional_member() {
+        assert_eq!(
+            eval(r#" request.auth.claims.?email.orValue("nobody@example.com") "#),
+            Ok(Value::String("nobody@example.com".to_owned()))
+        );
+        assert_eq!(
+            eval(r#" request.auth.claims.?sub.orValue("nobody") "#),
+            Ok(Value::String("alice".to_owned()))
+        );
+        assert_eq!(
+            eval(r#" request.?session.id.hasValue() "#),
+            Ok(Value::Bool(false))
+        );
+        assert_eq!(
+            eval(r#" request.?auth.claims.sub "#),
+            Ok(Value::Optional(Some(Box::new(Value::String(
+                "alice".to_owned()
+            )))))
+        );
+    }
+
+    #[test]
+    fn optional_index() {
+        assert_eq!(
+            eval(r#" request.path[?3].orValue("none") "#),
+            Ok(Value::String("none".to_owned()))
+        );
+        assert_eq!(
+            eval(r#" request[?"auth"].claims[?"sub"].value() "#),
+            Ok(Value::String("alice".to_owned()))
+        );
+        assert_eq!(eval(r#" {}[?"x"].value() "#), Err(Error::EmptyOptional));
+    }
+
+    #[test]
+    fn simple_map() {
+        let mut program = compile(parse(r#" {"a": "b"} "#).unwrap()).unwrap();
+        assert_eq!(
+            program.run(),
+            &Ok(Value::Map(
+                vec![("a".to_owned(), Value::String("b".to_owned()))]
+                    .into_iter()
+                    .collect()
+            ))
+        );
+    }
+
+    #[test]
+    fn map_keys_come_before_values() {
+        let mut program = compile(parse(r#" {"a": "b", "c": 1} "#).unwrap()).unwrap();
+        assert_eq!(
+            program.run(),
+            &Ok(Value::Map(
+                vec![
+                    ("a".to_owned(), Value::String("b".to_owned())),
+                    ("c".to_owned(), Value::I64(1)),
+                ]
+                .into_iter()
+                .collect()
+            ))
+        );
+        let mut program = compile(parse(r#" {1: "a"} "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Err(Error::InvalidMapKey(Kind::I64)));
+    }
+
+    #[test]
+    fn serde_activation_and_result() {
+        let activation: Activation =
+            serde_json::from_str(r#"{"user": {"name": "alice", "roles": ["admin"]}}"#).unwrap();
+        let expr = parse(r#" {"allow": user.roles[0] == "admin", "who": user.name} "#).unwrap();
+        let mut program = compile_with(expr, activation).unwrap();
+        let result = program.run().clone().unwrap();
+        let decision: HashMap<String, serde_json::Value> =
+            crate::model::de::from_value(result).unwrap();
+        assert_eq!(decision["allow"], serde_json::json!(true));
+        assert_eq!(decision["who"], serde_json::json!("alice"));
+    }
+
+    fn eval_proto(input: &str) -> EvalResult {
+        let types = crate::model::proto::test::api();
+        let record = r#" rpb.example.MyInput.Record{id: "r1", params: {"k": "v"}} "#;
+        let mut program =
+            compile_with_types(parse(record).unwrap(), Activation::new(), types.clone()).unwrap();
+        let record = program.run().clone().unwrap();
+        let input = format!(
+            "rpb.example.MyInput{{my_i32: 45, my_string: \"hi\", records: [r]}}{}",
+            input
+        );
+        let activation = vec![("r".to_owned(), record)].into_iter().collect();
+        let mut program = compile_with_types(parse(&input).unwrap(), activation, types).unwrap();
+        program.run().clone()
+    }
+
+    #[test]
+    fn message_fields() {
+        assert_eq!(eval_proto(".my_i32"), Ok(Value::I64(45)));
+        assert_eq!(eval_proto(".my_bool"), Ok(Value::Bool(false)));
+        assert_eq!(
+            eval_proto(r#".records[0].params["k"]"#),
+            Ok(Value::String("v".to_owned()))
+        );
+        assert_eq!(
+            eval_proto(".records[0].nope"),
+            Err(Error::NoSuchMember(Identifier::new("nope")))
+        );
+    }
+
+    #[test]
+    fn message_presence() {
+        assert_eq!(eval_proto(".my_string.size()"), Ok(Value::I64(2)));
+        assert_eq!(eval_proto(".?my_bool.orValue(true)"), Ok(Value::Bool(true)));
+        let mut program = compile_with_types(
+            parse(r#" has(rpb.example.MyInput{my_string: ""}.my_string) "#).unwrap(),
+            Activation::new(),
+            crate::model::proto::test::api(),
+        )
+        .unwrap();
+        assert_eq!(program.run(), &Ok(Value::Bool(false)));
+    }
+
+    #[test]
+    fn message_construction_errors() {
+        let mut program = compile(parse(r#" rpb.example.MyInput{} "#).unwrap()).unwrap();
+        assert_eq!(
+            program.run(),
+            &Err(Error::NoSuchType("rpb.example.MyInput".to_owned()))
+        );
+        assert_eq!(
+            eval_proto(r#" == rpb.example.MyInput{my_i32: uint(1)} "#),
+            Err(Error::InvalidMessageField(
+                Identifier::new("my_i32"),
+                Kind::U64
+            ))
+        );
+    }
+
+    #[test]
+    fn message_display() {
+        let v = eval_proto("").unwrap();
+        assert_eq!(
+            v.to_string(),
+            r#"rpb.example.MyInput{my_i32: 45, my_string: "hi", records: [rpb.example.MyInput.Record{id: "r1", params: {"k":"v"}}]}"#
+        );
+    }
+
+    #[test]
+    fn step_back_restores_state() {
+        let mut program = compile(parse(r#" (1 + 2) * 3 "#).unwrap()).unwrap();
+        assert!(!program.step_back());
+        program.step();
+        program.step();
+        let before = (program.pointer, program.stack.clone());
+        program.step();
+        assert!(program.step_back());
+        assert_eq!((program.pointer, program.stack.clone()), before);
+        assert_eq!(program.position(), 2);
+    }
+
+    #[test]
+    fn only_recording_programs_keep_history() {
+        let mut program = compile(parse(r#" (1 + 2) * 3 "#).unwrap()).unwrap();
+        assert_eq!(program.run(), &Ok(Value::I64(9)));
+        assert_eq!(program.history().len(), 1);
+        assert_eq!(program.position(), 5);
+        assert!(program.step_back());
+        assert_eq!(program.stack, vec![Ok(Value::I64(3)), Ok(Value::I64(3))]);
+        program.reset();
+        assert_eq!((program.pointer, program.stack.len()), (0, 0));
+
+        let mut program = program.recording();
+        assert_eq!(program.run(), &Ok(Value::I64(9)));
+        assert_eq!(program.history().len(), 6);
+    }
+
+    #[test]
+    fn stepping_forward_replays_history() {
+        let mut program = compile(parse(r#" [1, 2].size() "#).unwrap())
+            .unwrap()
+            .recording();
+        program.run_to_end();
+        let steps = program.history().len();
+        program.reset();
+        assert_eq!((program.pointer, program.stack.len()), (0, 0));
+        assert_eq!(program.history().len(), steps);
+        assert_eq!(program.run(), &Ok(Value::I64(2)));
+        assert_eq!(program.history().len(), steps);
+    }
+
+    #[test]
+    fn run_to_operation() {
+        let mut program = compile(parse(r#" 1 + 2 - 3 "#).unwrap()).unwrap();
+        assert!(program.run_to(3));
+        assert_eq!(program.pointer, 3);
+        assert_eq!(program.stack, vec![Ok(Value::I64(3))]);
+        assert!(program.run_to(1));
+        assert_eq!(program.stack, vec![Ok(Value::I64(1))]);
+        assert!(!program.run_to(100));
+        assert_eq!(program.stack, vec![Ok(Value::I64(0))]);
+    }
+
+    #[test]
+    fn seek_is_clamped() {
+        let mut program = compile(parse(r#" 1 + 2 "#).unwrap()).unwrap().recording();
+        program.seek(100);
+        assert_eq!(program.position(), 3);
+        program.seek(1);
+        assert_eq!(
+            program.history()[program.position()],
+            Snapshot {
+                pointer: 1,
+                stack: vec![Ok(Value::I64(1))],
+                blame: vec![None],
+            }
+        );
+    }
+
+    #[test]
+    fn error_origin() {
+        let mut program = compile(parse(r#" 1 + x.y * 2 "#).unwrap())
+            .unwrap()
+            .recording();
+        assert_eq!(program.error_origin(), None);
+        program.run_to_end();
+        assert_eq!(program.error_origin(), Some(1));
+        assert_eq!(program.origins[1], 4);
+        program.seek(1);
+        assert_eq!(program.error_origin(), None);
+
+        // The first `missing` fails too, but `||` swallows that error.
+        let mut program = compile(parse(r#" (missing || true) && missing "#).unwrap()).unwrap();
+        assert_eq!(
+            program.run(),
+            &Err(Error::NoSuchBinding(Identifier::new("missing")))
+        );
+        let origin = program.error_origin().unwrap();
+        assert_eq!(
+            program.operations[origin],
+            Operation::Lookup(Identifier::new("missing"))
+        );
+        assert_ne!(origin, 0);
+    }
+
+    #[test]
+    fn verifier_rejects_malformed_programs() {
+        let one = || Operation::Lit(Value::I64(1));
+        let load = |operations| Program::load(operations, Activation::new(), DescriptorPool::new());
+        for (operations, error) in [
+            (vec![], VerifyError::EndHeight(0)),
+            (vec![one(), Operation::Add], VerifyError::Underflow(1)),
+            (
+                vec![one(), Operation::Local(1)],
+                VerifyError::LocalOutOfRange(1),
+            ),
+            (
+                vec![one(), Operation::JumpIf(1)],
+                VerifyError::JumpOutOfRange(1),
+            ),
+            (
+                vec![one(), Operation::Jump(usize::MAX)],
+                VerifyError::JumpOutOfRange(1),
+            ),
+            (
+                vec![one(), Operation::JumpIf(1), one(), Operation::Not],
+                VerifyError::InconsistentHeights(3, 1, 2),
+            ),
+            (vec![one(), one()], VerifyError::EndHeight(2)),
+            (
+                vec![one(), Operation::MakeMap(usize::MAX / 2 + 1)],
+                VerifyError::OperandOverflow(1),
+            ),
+            (
+                vec![
+                    one(),
+                    Operation::Method(Identifier::new("size"), usize::MAX),
+                ],
+                VerifyError::OperandOverflow(1),
+            ),
+        ] {
+            assert_eq!(load(operations).unwrap_err(), error);
+        }
+        assert_eq!(
+            VerifyError::InconsistentHeights(3, 1, 2).to_string(),
+            "operation 3 is reached with stack heights 1 and 2"
+        );
+
+        let mut program = load(vec![
+            Operation::Lookup(Identifier::new("x")),
+            Operation::Local(0),
+            Operation::EndLet,
+            // Unreachable, so its underflow doesn't matter.
+            Operation::Jump(1),
+            Operation::Add,
+        ])
+        .unwrap();
+        assert_eq!(
+            program.run(),
+            &Err(Error::NoSuchBinding(Identifier::new("x")))
+        );
+    }
+}
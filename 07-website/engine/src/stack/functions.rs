@@ -0,0 +1,289 @@
+use std::convert::TryFrom;
+
+use crate::model::{Error, EvalResult, Identifier, Kind, Value};
+
+// 2^63 and 2^64 are exactly representable as doubles, unlike i64::MAX and u64::MAX.
+const I64_UPPER_BOUND: f64 = 9_223_372_036_854_775_808.0;
+const U64_UPPER_BOUND: f64 = 18_446_744_073_709_551_616.0;
+
+pub fn call(id: &Identifier, args: Vec<Value>) -> EvalResult {
+    match id.0.as_str() {
+        "int" => unary(id, args, to_int),
+        "uint" => unary(id, args, to_uint),
+        "double" => unary(id, args, to_double),
+        "string" => unary(id, args, to_string),
+        "bytes" => unary(id, args, to_bytes),
+        "bool" => unary(id, args, to_bool),
+        "dyn" => unary(id, args, |_, v| Ok(v)),
+        "type" => unary(id, args, |_, v| Ok(Value::Type(v.kind()))),
+        "size" => unary(id, args, size),
+        _ => Err(Error::NoFunction(id.clone())),
+    }
+}
+
+fn unary(
+    id: &Identifier,
+    mut args: Vec<Value>,
+    f: fn(&Identifier, Value) -> EvalResult,
+) -> EvalResult {
+    if args.len() != 1 {
+        return Err(Error::InvalidFunctionArity(id.clone(), args.len()));
+    }
+    f(id, args.pop().unwrap())
+}
+
+fn to_int(id: &Identifier, v: Value) -> EvalResult {
+    match v {
+        Value::I64(n) => Ok(Value::I64(n)),
+        Value::U64(n) => i64::try_from(n)
+            .map(Value::I64)
+            .map_err(|_| Error::ConversionOutOfRange(Kind::U64, Kind::I64)),
+        Value::F64(x) => {
+            if (-I64_UPPER_BOUND..I64_UPPER_BOUND).contains(&x) {
+                Ok(Value::I64(x as i64))
+            } else {
+                Err(Error::ConversionOutOfRange(Kind::F64, Kind::I64))
+            }
+        }
+        Value::String(s) => match s.parse() {
+            Ok(n) => Ok(Value::I64(n)),
+            Err(_) => Err(Error::ConversionFailed(Kind::I64, s)),
+        },
+        other => Err(Error::InvalidFunctionSignature(
+            id.clone(),
+            vec![other.kind()],
+        )),
+    }
+}
+
+fn to_uint(id: &Identifier, v: Value) -> EvalResult {
+    match v {
+        Value::I64(n) => u64::try_from(n)
+            .map(Value::U64)
+            .map_err(|_| Error::ConversionOutOfRange(Kind::I64, Kind::U64)),
+        Value::U64(n) => Ok(Value::U64(n)),
+        Value::F64(x) => {
+            if (0.0..U64_UPPER_BOUND).contains(&x) {
+                Ok(Value::U64(x as u64))
+            } else {
+                Err(Error::ConversionOutOfRange(Kind::F64, Kind::U64))
+            }
+        }
+        Value::String(s) => match s.parse() {
+            Ok(n) => Ok(Value::U64(n)),
+            Err(_) => Err(Error::ConversionFailed(Kind::U64, s)),
+        },
+        other => Err(Error::InvalidFunctionSignature(
+            id.clone(),
+            vec![other.kind()],
+        )),
+    }
+}
+
+fn to_double(id: &Identifier, v: Value) -> EvalResult {
+    match v {
+        Value::I64(n) => Ok(Value::F64(n as f64)),
+        Value::U64(n) => Ok(Value::F64(n as f64)),
+        Value::F64(x) => Ok(Value::F64(x)),
+        Value::String(s) => match s.parse() {
+            Ok(x) => Ok(Value::F64(x)),
+            Err(_) => Err(Error::ConversionFailed(Kind::F64, s)),
+        },
+        other => Err(Error::InvalidFunctionSignature(
+            id.clone(),
+            vec![other.kind()],
+        )),
+    }
+}
+
+fn to_string(id: &Identifier, v: Value) -> EvalResult {
+    match v {
+        Value::I64(n) => Ok(Value::String(n.to_string())),
+        Value::U64(n) => Ok(Value::String(n.to_string())),
+        Value::F64(x) => Ok(Value::String(x.to_string())),
+        Value::Bool(b) => Ok(Value::String(b.to_string())),
+        Value::String(s) => Ok(Value::String(s)),
+        Value::Bytes(bs) => String::from_utf8(bs).map(Value::String).map_err(|e| {
+            Error::ConversionFailed(Kind::String, Value::Bytes(e.into_bytes()).to_string())
+        }),
+        other => Err(Error::InvalidFunctionSignature(
+            id.clone(),
+            vec![other.kind()],
+        )),
+    }
+}
+
+fn to_bytes(id: &Identifier, v: Value) -> EvalResult {
+    match v {
+        Value::Bytes(bs) => Ok(Value::Bytes(bs)),
+        Value::String(s) => Ok(Value::Bytes(s.into_bytes())),
+        other => Err(Error::InvalidFunctionSignature(
+            id.clone(),
+            vec![other.kind()],
+        )),
+    }
+}
+
+fn to_bool(id: &Identifier, v: Value) -> EvalResult {
+    match v {
+        Value::Bool(b) => Ok(Value::Bool(b)),
+        Value::String(s) => match s.as_str() {
+            "1" | "t" | "true" | "TRUE" | "True" => Ok(Value::Bool(true)),
+            "0" | "f" | "false" | "FALSE" | "False" => Ok(Value::Bool(false)),
+            _ => Err(Error::ConversionFailed(Kind::Bool, s)),
+        },
+        other => Err(Error::InvalidFunctionSignature(
+            id.clone(),
+            vec![other.kind()],
+        )),
+    }
+}
+
+fn size(id: &Identifier, v: Value) -> EvalResult {
+    let n = match v {
+        Value::String(s) => s.chars().count(),
+        Value::Bytes(bs) => bs.len(),
+        Value::List(vs) => vs.len(),
+        Value::Map(kvs) => kvs.len(),
+        other => {
+            return Err(Error::InvalidFunctionSignature(
+                id.clone(),
+                vec![other.kind()],
+            ))
+        }
+    };
+    Ok(Value::I64(n as i64))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn call1(name: &str, v: Value) -> EvalResult {
+        call(&Identifier::new(name), vec![v])
+    }
+
+    #[test]
+    fn int_conversions() {
+        assert_eq!(call1("int", Value::U64(42)), Ok(Value::I64(42)));
+        assert_eq!(call1("int", Value::F64(-2.9)), Ok(Value::I64(-2)));
+        assert_eq!(
+            call1("int", Value::String("-17".to_owned())),
+            Ok(Value::I64(-17))
+        );
+    }
+
+    #[test]
+    fn int_conversion_failures() {
+        assert_eq!(
+            call1("int", Value::String("abc".to_owned())),
+            Err(Error::ConversionFailed(Kind::I64, "abc".to_owned()))
+        );
+        assert_eq!(
+            call1("int", Value::F64(1e19)),
+            Err(Error::ConversionOutOfRange(Kind::F64, Kind::I64))
+        );
+        assert_eq!(
+            call1("int", Value::F64(f64::NAN)),
+            Err(Error::ConversionOutOfRange(Kind::F64, Kind::I64))
+        );
+        assert_eq!(
+            call1("int", Value::U64(u64::MAX)),
+            Err(Error::ConversionOutOfRange(Kind::U64, Kind::I64))
+        );
+    }
+
+    #[test]
+    fn uint_conversions() {
+        assert_eq!(call1("uint", Value::I64(42)), Ok(Value::U64(42)));
+        assert_eq!(
+            call1("uint", Value::I64(-1)),
+            Err(Error::ConversionOutOfRange(Kind::I64, Kind::U64))
+        );
+        assert_eq!(
+            call1("uint", Value::F64(-0.5)),
+            Err(Error::ConversionOutOfRange(Kind::F64, Kind::U64))
+        );
+    }
+
+    #[test]
+    fn double_conversions() {
+        assert_eq!(call1("double", Value::I64(3)), Ok(Value::F64(3.0)));
+        assert_eq!(
+            call1("double", Value::String("2.5".to_owned())),
+            Ok(Value::F64(2.5))
+        );
+        assert_eq!(
+            call1("double", Value::String("two".to_owned())),
+            Err(Error::ConversionFailed(Kind::F64, "two".to_owned()))
+        );
+    }
+
+    #[test]
+    fn string_conversions() {
+        assert_eq!(
+            call1("string", Value::U64(7)),
+            Ok(Value::String("7".to_owned()))
+        );
+        assert_eq!(
+            call1("string", Value::Bytes(vec![0xc2, 0xa2])),
+            Ok(Value::String("¢".to_owned()))
+        );
+        assert_eq!(
+            call1("string", Value::Bytes(vec![0xff])),
+            Err(Error::ConversionFailed(Kind::String, "b\"ff\"".to_owned()))
+        );
+    }
+
+    #[test]
+    fn bool_conversions() {
+        assert_eq!(
+            call1("bool", Value::String("true".to_owned())),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(
+            call1("bool", Value::String("yes".to_owned())),
+            Err(Error::ConversionFailed(Kind::Bool, "yes".to_owned()))
+        );
+    }
+
+    #[test]
+    fn unsupported_signature() {
+        assert_eq!(
+            call1("bytes", Value::I64(1)),
+            Err(Error::InvalidFunctionSignature(
+                Identifier::new("bytes"),
+                vec![Kind::I64]
+            ))
+        );
+        assert_eq!(
+            call(&Identifier::new("int"), vec![]),
+            Err(Error::InvalidFunctionArity(Identifier::new("int"), 0))
+        );
+    }
+
+    #[test]
+    fn size_of() {
+        assert_eq!(
+            call1("size", Value::String("¢¢".to_owned())),
+            Ok(Value::I64(2))
+        );
+        assert_eq!(
+            call1("size", Value::Bytes(vec![0xc2, 0xa2])),
+            Ok(Value::I64(2))
+        );
+        assert_eq!(
+            call1("size", Value::List(vec![Value::Null])),
+            Ok(Value::I64(1))
+        );
+    }
+
+    #[test]
+    fn type_of() {
+        assert_eq!(call1("type", Value::Null), Ok(Value::Type(Kind::Null)));
+        assert_eq!(
+            call1("type", Value::Type(Kind::I64)),
+            Ok(Value::Type(Kind::Type))
+        );
+    }
+}
@@ -0,0 +1,228 @@
+//! The control-flow graph of a stack program: its basic blocks, the jumps
+//! between them, and the stack height each block starts with. `to_dot`
+//! renders it for Graphviz, e.g. `dot -Tsvg`.
+
+use std::fmt::Write;
+
+use crate::stack::{stack_heights, Operation};
+
+/// A run of operations that is only entered at its first one and only left
+/// after its last one.
+#[derive(Debug, PartialEq)]
+pub struct Block {
+    /// The index of the first operation.
+    pub start: usize,
+    /// One past the index of the last operation.
+    pub end: usize,
+    /// The stack height on entry, or `None` if the block can't be reached
+    /// (or the program is malformed).
+    pub depth: Option<usize>,
+    pub edges: Vec<Edge>,
+}
+
+/// Where control can go after a block.
+#[derive(Debug, PartialEq)]
+pub struct Edge {
+    /// The operation execution continues at, which starts another block or,
+    /// if it's the length of the program, ends it.
+    pub target: usize,
+    /// What the edge is taken on, for conditional jumps and aborts.
+    pub label: Option<&'static str>,
+}
+
+/// Splits `operations` into basic blocks, in program order.
+pub fn blocks(operations: &[Operation]) -> Vec<Block> {
+    let heights = stack_heights(operations).ok();
+    let mut leaders = vec![false; operations.len() + 1];
+    leaders[0] = true;
+    for (pc, op) in operations.iter().enumerate() {
+        if let Some(n) = op.jump_offset() {
+            leaders[(pc + 1).saturating_add(n).min(operations.len())] = true;
+        }
+        if op.jump_offset().is_some() || *op == Operation::Abort {
+            leaders[pc + 1] = true;
+        }
+    }
+
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    for end in (1..=operations.len()).filter(|&end| leaders[end]) {
+        let edges = match operations[end - 1] {
+            Operation::Jump(n) => vec![edge(end.saturating_add(n), None)],
+            Operation::JumpIf(n) => vec![
+                edge(end.saturating_add(n), Some("true")),
+                edge(end, Some("otherwise")),
+            ],
+            Operation::JumpIfNot(n) => vec![
+                edge(end.saturating_add(n), Some("false")),
+                edge(end, Some("otherwise")),
+            ],
+            Operation::JumpError(n) => {
+                vec![
+                    edge(end.saturating_add(n), Some("error")),
+                    edge(end, Some("ok")),
+                ]
+            }
+            Operation::Abort => vec![edge(operations.len(), Some("abort"))],
+            _ => vec![edge(end, None)],
+        };
+        blocks.push(Block {
+            start,
+            end,
+            depth: heights.as_ref().and_then(|h| h[start]),
+            edges,
+        });
+        start = end;
+    }
+    blocks
+}
+
+fn edge(target: usize, label: Option<&'static str>) -> Edge {
+    Edge { target, label }
+}
+
+/// The control-flow graph of `operations` in Graphviz DOT. Each block lists
+/// its operations under the stack height it starts with; blocks that can't be
+/// reached are dashed.
+pub fn to_dot(operations: &[Operation]) -> String {
+    let blocks = blocks(operations);
+    let mut dot = String::new();
+    dot.push_str("digraph program {\n");
+    dot.push_str("    node [shape=box, fontname=monospace];\n");
+    for block in &blocks {
+        let mut label = match block.depth {
+            Some(depth) => format!("depth {}\\l", depth),
+            None => "unreachable\\l".to_owned(),
+        };
+        for (pc, op) in operations[block.start..block.end]
+            .iter()
+            .enumerate()
+            .map(|(i, op)| (block.start + i, op))
+        {
+            label.push_str(&escape(&match operand(op) {
+                Some(operand) => format!("{}: {} {}", pc, op.short(), operand),
+                None => format!("{}: {}", pc, op.short()),
+            }));
+            label.push_str("\\l");
+        }
+        let style = if block.depth.is_none() {
+            ", style=dashed"
+        } else {
+            ""
+        };
+        writeln!(dot, "    b{} [label=\"{}\"{}];", block.start, label, style).unwrap();
+    }
+    writeln!(
+        dot,
+        "    b{} [label=\"end\", shape=oval];",
+        operations.len()
+    )
+    .unwrap();
+    for block in &blocks {
+        for edge in &block.edges {
+            write!(dot, "    b{} -> b{}", block.start, edge.target).unwrap();
+            if let Some(label) = edge.label {
+                write!(dot, " [label=\"{}\"]", label).unwrap();
+            }
+            dot.push_str(";\n");
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// The argument worth showing next to an operation's name, if any. Jump
+/// offsets are left out, since the edges show where jumps go.
+fn operand(op: &Operation) -> Option<String> {
+    match op {
+        Operation::Lit(v) => Some(v.to_string()),
+        Operation::MakeList(n) | Operation::MakeMap(n) | Operation::Local(n) => Some(n.to_string()),
+        Operation::MakeMessage(name, _) => Some(name.clone()),
+        Operation::Lookup(id)
+        | Operation::Member(id)
+        | Operation::OptionalMember(id)
+        | Operation::Has(id) => Some(id.0.clone()),
+        Operation::Call(id, n) | Operation::Method(id, n) => Some(format!("{}/{}", id.0, n)),
+        _ => None,
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::parse;
+    use crate::stack::walker::linearize;
+
+    fn operations(source: &str) -> Vec<Operation> {
+        linearize(parse(source).unwrap()).unwrap()
+    }
+
+    fn block(
+        start: usize,
+        end: usize,
+        depth: usize,
+        edges: &[(usize, Option<&'static str>)],
+    ) -> Block {
+        Block {
+            start,
+            end,
+            depth: Some(depth),
+            edges: edges
+                .iter()
+                .map(|&(target, label)| edge(target, label))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn ternary() {
+        assert_eq!(
+            blocks(&operations("x ? 1 : 2")),
+            vec![
+                block(0, 2, 0, &[(5, Some("true")), (2, Some("otherwise"))]),
+                block(2, 3, 1, &[(7, Some("error")), (3, Some("ok"))]),
+                block(3, 5, 1, &[(6, None)]),
+                block(5, 6, 1, &[(6, None)]),
+                block(6, 7, 2, &[(7, None)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn dot() {
+        assert_eq!(
+            to_dot(&operations(r#"a || "b""#)),
+            r#"digraph program {
+    node [shape=box, fontname=monospace];
+    b0 [label="depth 0\l0: LKUP a\l1: JMPIF\l"];
+    b2 [label="depth 1\l2: LIT \"b\"\l3: OR\l"];
+    b4 [label="end", shape=oval];
+    b0 -> b4 [label="true"];
+    b0 -> b2 [label="otherwise"];
+    b2 -> b4;
+}
+"#
+        );
+    }
+
+    #[test]
+    fn unreachable_blocks() {
+        let operations = vec![
+            Operation::Lit(crate::model::Value::I64(1)),
+            Operation::Jump(1),
+            Operation::Pop,
+        ];
+        let blocks = blocks(&operations);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].depth, None);
+        assert!(
+            to_dot(&operations).contains(r#"b2 [label="unreachable\l2: POP\l", style=dashed];"#)
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use regex::{Regex, RegexBuilder};
+
+use crate::model::{Error, EvalResult, Identifier, Value};
+
+// The regex crate guarantees linear-time matching, so the only thing a hostile
+// pattern can blow up is the size of the compiled automaton.
+const SIZE_LIMIT: usize = 1 << 20;
+const CACHE_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Pattern, String> {
+        build(pattern).map(Pattern)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn is_match(&self, s: &str) -> bool {
+        self.0.is_match(s)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+fn build(pattern: &str) -> Result<Regex, String> {
+    RegexBuilder::new(pattern)
+        .size_limit(SIZE_LIMIT)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// Compiled patterns for `matches` calls whose pattern is only known at runtime.
+#[derive(Debug, Default)]
+pub struct PatternCache(HashMap<String, Pattern>);
+
+impl PatternCache {
+    /// Evaluates `matches(s, pattern)`; the method form passes its receiver as `s`.
+    pub fn call(&mut self, id: &Identifier, args: Vec<Value>) -> EvalResult {
+        match args.as_slice() {
+            [Value::String(s), Value::String(p)] => {
+                let pattern = self.get(p)?;
+                Ok(Value::Bool(pattern.is_match(s)))
+            }
+            _ => Err(Error::InvalidFunctionSignature(
+                id.clone(),
+                args.iter().map(Value::kind).collect(),
+            )),
+        }
+    }
+
+    fn get(&mut self, p: &str) -> Result<&Pattern, Error> {
+        if !self.0.contains_key(p) {
+            if self.0.len() >= CACHE_CAPACITY {
+                self.0.clear();
+            }
+            let regex = build(p).map_err(Error::InvalidRegex)?;
+            self.0.insert(p.to_owned(), Pattern(regex));
+        }
+        Ok(&self.0[p])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::Kind;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_owned())
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        assert!(Pattern::new("(unclosed").is_err());
+    }
+
+    #[test]
+    fn pattern_search_semantics() {
+        let pattern = Pattern::new("b+").unwrap();
+        assert!(pattern.is_match("abbc"));
+        assert!(!pattern.is_match("ac"));
+    }
+
+    #[test]
+    fn pathological_pattern_is_linear() {
+        let pattern = Pattern::new("(a+)+$").unwrap();
+        let input = format!("{}!", "a".repeat(10_000));
+        assert!(!pattern.is_match(&input));
+    }
+
+    #[test]
+    fn cache_reuses_patterns() {
+        let mut cache = PatternCache::default();
+        let id = Identifier::new("matches");
+        for _ in 0..3 {
+            assert_eq!(
+                cache.call(&id, vec![string("abc"), string("^a")]),
+                Ok(Value::Bool(true))
+            );
+        }
+        assert_eq!(cache.0.len(), 1);
+    }
+
+    #[test]
+    fn cache_reports_invalid_patterns() {
+        let mut cache = PatternCache::default();
+        let id = Identifier::new("matches");
+        match cache.call(&id, vec![string("abc"), string("[")]) {
+            Err(Error::InvalidRegex(_)) => {}
+            other => panic!("expected an invalid regex error, got {:?}", other),
+        }
+        assert_eq!(
+            cache.call(&id, vec![Value::I64(1), string("a")]),
+            Err(Error::InvalidFunctionSignature(
+                id,
+                vec![Kind::I64, Kind::String]
+            ))
+        );
+    }
+}
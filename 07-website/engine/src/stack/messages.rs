@@ -0,0 +1,158 @@
+//! Field selection and construction for `Value::Message`. Selecting an unset
+//! field yields its default, as in every protobuf runtime, while `has()` and
+//! `.?` follow proto3 presence: message fields and `optional` scalars are
+//! present once set, other scalars when they differ from their default, and
+//! repeated and map fields when they are non-empty.
+
+use prost_reflect::{
+    DescriptorPool, DynamicMessage, FieldDescriptor, Kind as ProtoKind, ReflectMessage,
+};
+
+use crate::model::proto::{from_proto, to_proto};
+use crate::model::{Error, EvalResult, Identifier, Value};
+
+fn field(msg: &DynamicMessage, id: &Identifier) -> Result<FieldDescriptor, Error> {
+    msg.descriptor()
+        .get_field_by_name(&id.0)
+        .ok_or_else(|| Error::NoSuchMember(id.clone()))
+}
+
+pub fn member(msg: &DynamicMessage, id: &Identifier) -> EvalResult {
+    let field = field(msg, id)?;
+    Ok(from_proto(msg.get_field(&field).into_owned()))
+}
+
+pub fn optional_member(msg: &DynamicMessage, id: &Identifier) -> EvalResult {
+    let field = field(msg, id)?;
+    if msg.has_field(&field) {
+        let v = from_proto(msg.get_field(&field).into_owned());
+        Ok(Value::Optional(Some(Box::new(v))))
+    } else {
+        Ok(Value::Optional(None))
+    }
+}
+
+pub fn has(msg: &DynamicMessage, id: &Identifier) -> EvalResult {
+    let field = field(msg, id)?;
+    Ok(Value::Bool(msg.has_field(&field)))
+}
+
+/// Builds a message of type `name`, assigning `values` to `fields` pairwise.
+/// Message-typed fields may be given `null`, which leaves them unset.
+pub fn make(
+    types: &DescriptorPool,
+    name: &str,
+    fields: &[Identifier],
+    values: Vec<Value>,
+) -> EvalResult {
+    let desc = types
+        .get_message_by_name(name.trim_start_matches('.'))
+        .ok_or_else(|| Error::NoSuchType(name.to_owned()))?;
+    let mut msg = DynamicMessage::new(desc);
+    for (i, (id, v)) in fields.iter().zip(values).enumerate() {
+        if fields[..i].contains(id) {
+            let name = msg.descriptor().full_name().to_owned();
+            return Err(Error::DuplicateMessageField(name, id.clone()));
+        }
+        let field = field(&msg, id)?;
+        let singular_message =
+            matches!(field.kind(), ProtoKind::Message(_)) && !field.is_list() && !field.is_map();
+        if singular_message && v == Value::Null {
+            continue;
+        }
+        let v = to_proto(&field, v)?;
+        msg.set_field(&field, v);
+    }
+    Ok(Value::Message(msg))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::proto::test::api;
+    use crate::model::Kind;
+
+    fn input(fields: &[(&str, Value)]) -> DynamicMessage {
+        let ids: Vec<Identifier> = fields.iter().map(|(k, _)| Identifier::new(k)).collect();
+        let values = fields.iter().map(|(_, v)| v.clone()).collect();
+        match make(&api(), "rpb.example.MyInput", &ids, values) {
+            Ok(Value::Message(msg)) => msg,
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unset_fields_have_defaults() {
+        let msg = input(&[]);
+        let id = Identifier::new("my_string");
+        assert_eq!(member(&msg, &id), Ok(Value::String(String::new())));
+        assert_eq!(has(&msg, &id), Ok(Value::Bool(false)));
+        assert_eq!(optional_member(&msg, &id), Ok(Value::Optional(None)));
+    }
+
+    #[test]
+    fn proto3_presence() {
+        let msg = input(&[
+            ("my_i32", Value::I64(0)),
+            ("my_bool", Value::Bool(true)),
+            ("records", Value::List(vec![])),
+        ]);
+        assert_eq!(
+            has(&msg, &Identifier::new("my_i32")),
+            Ok(Value::Bool(false))
+        );
+        assert_eq!(
+            has(&msg, &Identifier::new("my_bool")),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(
+            has(&msg, &Identifier::new("records")),
+            Ok(Value::Bool(false))
+        );
+    }
+
+    #[test]
+    fn unknown_fields_and_types() {
+        let msg = input(&[]);
+        assert_eq!(
+            member(&msg, &Identifier::new("nope")),
+            Err(Error::NoSuchMember(Identifier::new("nope")))
+        );
+        assert_eq!(
+            make(&api(), "rpb.example.Nope", &[], vec![]),
+            Err(Error::NoSuchType("rpb.example.Nope".to_owned()))
+        );
+    }
+
+    #[test]
+    fn field_types_are_checked() {
+        let ids = [Identifier::new("my_bool")];
+        assert_eq!(
+            make(&api(), "rpb.example.MyInput", &ids, vec![Value::I64(1)]),
+            Err(Error::InvalidMessageField(
+                Identifier::new("my_bool"),
+                Kind::I64
+            ))
+        );
+    }
+
+    #[test]
+    fn duplicate_fields() {
+        let id = Identifier::new("my_bool");
+        let error = make(
+            &api(),
+            "rpb.example.MyInput",
+            &[id.clone(), id.clone()],
+            vec![Value::Bool(true), Value::Bool(false)],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            Error::DuplicateMessageField("rpb.example.MyInput".to_owned(), id)
+        );
+        assert_eq!(
+            error.to_string(),
+            "field my_bool of rpb.example.MyInput is set more than once"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Execution profiles of stack programs, from `Program::run_profiled`: how
+//! often each operation ran and how much data it moved, by operation index,
+//! by kind of operation and by the expression node it was compiled from.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::model::EvalResult;
+use crate::stack::Operation;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Counts {
+    pub executions: u64,
+    /// The summed `Value::size` of everything popped off and pushed onto the
+    /// stack.
+    pub traffic: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: Counts) {
+        self.executions += other.executions;
+        self.traffic += other.traffic;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Profile {
+    /// Indexed like the program's operations.
+    pub operations: Vec<Counts>,
+    /// By `Operation::short` name.
+    pub kinds: BTreeMap<String, Counts>,
+    /// Indexed by the pre-order index of expression nodes, counting only the
+    /// operations compiled from the node itself and not its descendants.
+    pub nodes: Vec<Counts>,
+}
+
+impl Profile {
+    /// Totals up the counts of each operation, compiled from the node in
+    /// `origins`, by kind and by node.
+    pub(crate) fn new(operations: &[Operation], origins: &[usize], counts: Vec<Counts>) -> Profile {
+        let mut kinds = BTreeMap::new();
+        let mut nodes = vec![Counts::default(); origins.iter().max().map_or(0, |&n| n + 1)];
+        for ((op, &node), &c) in operations.iter().zip(origins).zip(&counts) {
+            kinds
+                .entry(op.short())
+                .or_insert_with(Counts::default)
+                .add(c);
+            nodes[node].add(c);
+        }
+        Profile {
+            operations: counts,
+            kinds,
+            nodes,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("a profile is plain data")
+    }
+}
+
+/// How much data `values` amount to. An error counts as its in-memory size.
+pub(crate) fn traffic(values: &[EvalResult]) -> u64 {
+    values
+        .iter()
+        .map(|v| match v {
+            Ok(v) => v.size(),
+            Err(e) => std::mem::size_of_val(e),
+        })
+        .sum::<usize>() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Activation, Value};
+    use crate::parser::parse;
+    use crate::stack::compile_with;
+
+    #[test]
+    fn counts() {
+        let mut activation = Activation::new();
+        activation.insert("s".to_owned(), Value::String("x".repeat(100)));
+        // Short-circuiting skips the second `size()`.
+        let expr = parse("s.size() > 1 || s.size() > 2").unwrap();
+        let mut program = compile_with(expr, activation).unwrap();
+        let profile = program.run_profiled();
+        assert_eq!(program.stack, vec![Ok(Value::Bool(true))]);
+
+        let executions: Vec<u64> = profile.operations.iter().map(|c| c.executions).collect();
+        assert_eq!(executions, vec![1, 1, 1, 1, 1, 0, 0, 0, 0, 0]);
+        assert_eq!(profile.kinds["MTHD"].executions, 1);
+        assert_eq!(profile.kinds["OR"], Counts::default());
+        // The lookup pushes the string and `size` pops it again.
+        let string = Value::String("x".repeat(100)).size() as u64;
+        assert_eq!(profile.operations[0].traffic, string);
+        assert!(profile.operations[1].traffic > string);
+
+        // `||`, `>`, `.size()`, `s`, `1`, then the right-hand side.
+        assert_eq!(profile.nodes.len(), 9);
+        assert_eq!(profile.nodes[3], profile.operations[0]);
+        assert_eq!(profile.nodes[0], profile.operations[4]);
+        assert_eq!(profile.nodes[5].executions, 0);
+
+        let json: serde_json::Value = serde_json::from_str(&profile.to_json()).unwrap();
+        assert_eq!(json["kinds"]["LKUP"]["executions"], 1);
+        assert_eq!(json["operations"].as_array().unwrap().len(), 10);
+    }
+
+    #[test]
+    fn reruns() {
+        let mut program = compile_with(parse("[1, 2].size()").unwrap(), Activation::new()).unwrap();
+        let first = program.run_profiled();
+        program.step_back();
+        assert_eq!(program.run_profiled(), first);
+        assert_eq!(first.kinds["LIT"].executions, 2);
+    }
+}
@@ -0,0 +1,306 @@
+//! Methods on `Value::String`. Sizes and indices count Unicode code points,
+//! never bytes, so `"¢".size() == 1`.
+
+use crate::model::{Error, EvalResult, Identifier, Kind, Value};
+
+pub(crate) const METHODS: &[&str] = &[
+    "size",
+    "contains",
+    "startsWith",
+    "endsWith",
+    "lowerAscii",
+    "upperAscii",
+    "trim",
+    "split",
+    "replace",
+    "substring",
+    "charAt",
+    "indexOf",
+];
+
+pub fn call_method(s: String, id: &Identifier, args: Vec<Value>) -> EvalResult {
+    match (id.0.as_str(), args.as_slice()) {
+        ("size", []) => Ok(Value::I64(s.chars().count() as i64)),
+        ("contains", [Value::String(t)]) => Ok(Value::Bool(s.contains(t.as_str()))),
+        ("startsWith", [Value::String(t)]) => Ok(Value::Bool(s.starts_with(t.as_str()))),
+        ("endsWith", [Value::String(t)]) => Ok(Value::Bool(s.ends_with(t.as_str()))),
+        ("lowerAscii", []) => Ok(Value::String(s.to_ascii_lowercase())),
+        ("upperAscii", []) => Ok(Value::String(s.to_ascii_uppercase())),
+        ("trim", []) => Ok(Value::String(s.trim().to_owned())),
+        ("split", [Value::String(sep)]) => Ok(split(&s, sep, -1)),
+        ("split", [Value::String(sep), Value::I64(n)]) => Ok(split(&s, sep, *n)),
+        ("replace", [Value::String(from), Value::String(to)]) => {
+            Ok(Value::String(s.replace(from.as_str(), to)))
+        }
+        ("replace", [Value::String(from), Value::String(to), Value::I64(n)]) => {
+            Ok(Value::String(replace(&s, from, to, *n)))
+        }
+        ("substring", [Value::I64(start)]) => {
+            let len = s.chars().count() as i64;
+            substring(&s, *start, len)
+        }
+        ("substring", [Value::I64(start), Value::I64(end)]) => substring(&s, *start, *end),
+        ("charAt", [Value::I64(i)]) => char_at(&s, *i),
+        ("indexOf", [Value::String(t)]) => index_of(&s, t, 0),
+        ("indexOf", [Value::String(t), Value::I64(start)]) => index_of(&s, t, *start),
+        (name, _) if METHODS.contains(&name) => Err(Error::NoMethodWithSignature(
+            Kind::String,
+            id.clone(),
+            args.iter().map(Value::kind).collect(),
+        )),
+        _ => Err(Error::NoMethodOnType(Kind::String, id.clone())),
+    }
+}
+
+/// `[...].join()` and `[...].join(sep)`; every element of the list must be a
+/// string, or there's no such method for it, as with a bad argument.
+pub fn join(vs: Vec<Value>, id: &Identifier, args: Vec<Value>) -> EvalResult {
+    let sep = match args.as_slice() {
+        [] => "",
+        [Value::String(sep)] => sep.as_str(),
+        _ => {
+            return Err(Error::NoMethodWithSignature(
+                Kind::List,
+                id.clone(),
+                args.iter().map(Value::kind).collect(),
+            ))
+        }
+    };
+    let mut parts = Vec::with_capacity(vs.len());
+    for v in vs {
+        match v {
+            Value::String(s) => parts.push(s),
+            _ => {
+                return Err(Error::NoMethodWithSignature(
+                    Kind::List,
+                    id.clone(),
+                    args.iter().map(Value::kind).collect(),
+                ))
+            }
+        }
+    }
+    Ok(Value::String(parts.join(sep)))
+}
+
+/// Splits into at most `n` pieces; a negative `n` means no limit.
+fn split(s: &str, sep: &str, n: i64) -> Value {
+    let parts: Vec<Value> = match n {
+        0 => Vec::new(),
+        n if n < 0 => s.split(sep).map(|p| Value::String(p.to_owned())).collect(),
+        n => s
+            .splitn(n as usize, sep)
+            .map(|p| Value::String(p.to_owned()))
+            .collect(),
+    };
+    Value::List(parts)
+}
+
+/// Replaces at most `n` occurrences; a negative `n` means no limit.
+fn replace(s: &str, from: &str, to: &str, n: i64) -> String {
+    if n < 0 {
+        s.replace(from, to)
+    } else {
+        s.replacen(from, to, n as usize)
+    }
+}
+
+/// Converts a code point index into a byte offset. The index one past the
+/// last code point is valid and maps to `s.len()`.
+fn byte_offset(s: &str, i: i64) -> Result<usize, Error> {
+    if i < 0 {
+        return Err(Error::IndexOutOfRange(i));
+    }
+    s.char_indices()
+        .map(|(offset, _)| offset)
+        .chain(std::iter::once(s.len()))
+        .nth(i as usize)
+        .ok_or(Error::IndexOutOfRange(i))
+}
+
+fn substring(s: &str, start: i64, end: i64) -> EvalResult {
+    if end < start {
+        return Err(Error::IndexOutOfRange(end));
+    }
+    let start = byte_offset(s, start)?;
+    let end = byte_offset(s, end)?;
+    Ok(Value::String(s[start..end].to_owned()))
+}
+
+fn char_at(s: &str, i: i64) -> EvalResult {
+    let offset = byte_offset(s, i)?;
+    let ch = s[offset..].chars().next().map(String::from);
+    Ok(Value::String(ch.unwrap_or_default()))
+}
+
+fn index_of(s: &str, t: &str, start: i64) -> EvalResult {
+    let offset = byte_offset(s, start)?;
+    let found = s[offset..]
+        .find(t)
+        .map(|i| s[..offset + i].chars().count() as i64);
+    Ok(Value::I64(found.unwrap_or(-1)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_owned())
+    }
+
+    fn call(s: &str, name: &str, args: Vec<Value>) -> EvalResult {
+        call_method(s.to_owned(), &Identifier::new(name), args)
+    }
+
+    #[test]
+    fn size_counts_code_points() {
+        assert_eq!(call("", "size", vec![]), Ok(Value::I64(0)));
+        assert_eq!(call("a¢€𐍈", "size", vec![]), Ok(Value::I64(4)));
+    }
+
+    #[test]
+    fn predicates() {
+        assert_eq!(
+            call("hello", "contains", vec![string("ell")]),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(
+            call("hello", "startsWith", vec![string("he")]),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(
+            call("hello", "endsWith", vec![string("he")]),
+            Ok(Value::Bool(false))
+        );
+    }
+
+    #[test]
+    fn case_conversion_is_ascii_only() {
+        assert_eq!(call("ÀbC", "lowerAscii", vec![]), Ok(string("Àbc")));
+        assert_eq!(call("àbC", "upperAscii", vec![]), Ok(string("àBC")));
+    }
+
+    #[test]
+    fn trim_whitespace() {
+        assert_eq!(call(" \t x y \n", "trim", vec![]), Ok(string("x y")));
+    }
+
+    #[test]
+    fn split_with_limit() {
+        assert_eq!(
+            call("a,b,c", "split", vec![string(",")]),
+            Ok(Value::List(vec![string("a"), string("b"), string("c")]))
+        );
+        assert_eq!(
+            call("a,b,c", "split", vec![string(","), Value::I64(2)]),
+            Ok(Value::List(vec![string("a"), string("b,c")]))
+        );
+        assert_eq!(
+            call("a,b,c", "split", vec![string(","), Value::I64(0)]),
+            Ok(Value::List(vec![]))
+        );
+    }
+
+    #[test]
+    fn join_strings() {
+        let id = Identifier::new("join");
+        let vs = vec![string("a"), string("b")];
+        assert_eq!(join(vs.clone(), &id, vec![]), Ok(string("ab")));
+        assert_eq!(join(vs, &id, vec![string("-")]), Ok(string("a-b")));
+        assert_eq!(
+            join(vec![string("a"), Value::I64(1)], &id, vec![]),
+            Err(Error::NoMethodWithSignature(Kind::List, id.clone(), vec![]))
+        );
+        assert_eq!(
+            join(vec![Value::Null], &id, vec![string("-")]),
+            Err(Error::NoMethodWithSignature(
+                Kind::List,
+                id,
+                vec![Kind::String]
+            ))
+        );
+    }
+
+    #[test]
+    fn replace_with_limit() {
+        assert_eq!(
+            call("aaa", "replace", vec![string("a"), string("b")]),
+            Ok(string("bbb"))
+        );
+        assert_eq!(
+            call(
+                "aaa",
+                "replace",
+                vec![string("a"), string("b"), Value::I64(2)]
+            ),
+            Ok(string("bba"))
+        );
+    }
+
+    #[test]
+    fn substring_by_code_points() {
+        assert_eq!(
+            call("€uro", "substring", vec![Value::I64(1)]),
+            Ok(string("uro"))
+        );
+        assert_eq!(
+            call("€uro", "substring", vec![Value::I64(0), Value::I64(2)]),
+            Ok(string("€u"))
+        );
+        assert_eq!(
+            call("€uro", "substring", vec![Value::I64(4), Value::I64(4)]),
+            Ok(string(""))
+        );
+        assert_eq!(
+            call("€uro", "substring", vec![Value::I64(2), Value::I64(5)]),
+            Err(Error::IndexOutOfRange(5))
+        );
+        assert_eq!(
+            call("€uro", "substring", vec![Value::I64(2), Value::I64(1)]),
+            Err(Error::IndexOutOfRange(1))
+        );
+    }
+
+    #[test]
+    fn char_at_by_code_points() {
+        assert_eq!(call("¢€", "charAt", vec![Value::I64(1)]), Ok(string("€")));
+        assert_eq!(call("¢€", "charAt", vec![Value::I64(2)]), Ok(string("")));
+        assert_eq!(
+            call("¢€", "charAt", vec![Value::I64(-1)]),
+            Err(Error::IndexOutOfRange(-1))
+        );
+    }
+
+    #[test]
+    fn index_of_by_code_points() {
+        assert_eq!(
+            call("¢€¢€", "indexOf", vec![string("€")]),
+            Ok(Value::I64(1))
+        );
+        assert_eq!(
+            call("¢€¢€", "indexOf", vec![string("€"), Value::I64(2)]),
+            Ok(Value::I64(3))
+        );
+        assert_eq!(call("¢€", "indexOf", vec![string("x")]), Ok(Value::I64(-1)));
+        assert_eq!(call("¢€", "indexOf", vec![string("")]), Ok(Value::I64(0)));
+    }
+
+    #[test]
+    fn bad_signatures() {
+        assert_eq!(
+            call("abc", "contains", vec![Value::I64(1)]),
+            Err(Error::NoMethodWithSignature(
+                Kind::String,
+                Identifier::new("contains"),
+                vec![Kind::I64]
+            ))
+        );
+        assert_eq!(
+            call("abc", "frobnicate", vec![]),
+            Err(Error::NoMethodOnType(
+                Kind::String,
+                Identifier::new("frobnicate")
+            ))
+        );
+    }
+}
@@ -0,0 +1,479 @@
+use crate::model::{Expression, Identifier, Kind, Literal, Value};
+use crate::stack::matching::Pattern;
+use crate::stack::{CompileError, CompileResult, Operation};
+
+pub fn linearize(e: Expression) -> CompileResult<Vec<Operation>> {
+    linearize_with_origins(e).map(|(operations, _)| operations)
+}
+
+/// Like `linearize`, also returning the origin of every operation: the index of
+/// the AST node it was compiled from, numbering nodes in pre-order over
+/// `Expression::children`. A node and its descendants occupy the indices
+/// `i..i + node_count()`.
+pub fn linearize_with_origins(e: Expression) -> CompileResult<(Vec<Operation>, Vec<usize>)> {
+    let mut walker = Walker::new(0, 0, Vec::new());
+    walker.walk(&e)?;
+    Ok((walker.operations, walker.origins))
+}
+
+/// Like `linearize`, for an expression that sits inside let bindings for
+/// `scope`: their values are in the bottom slots of the stack, in order, and
+/// the program leaves its result on top of them. See `stack_heights_from`.
+pub fn linearize_in_scope(e: &Expression, scope: &[Identifier]) -> CompileResult<Vec<Operation>> {
+    let lets = scope.iter().cloned().zip(0..).collect();
+    let mut walker = Walker::new(0, scope.len(), lets);
+    walker.walk(e)?;
+    Ok(walker.operations)
+}
+
+struct Walker {
+    operations: Vec<Operation>,
+    origins: Vec<usize>,
+    next_node: usize,
+    current_node: usize,
+    /// The height of the stack after the operations so far.
+    height: usize,
+    /// The let bindings in scope, innermost last, with the stack slots holding their values.
+    lets: Vec<(Identifier, usize)>,
+}
+
+impl Walker {
+    fn new(next_node: usize, height: usize, lets: Vec<(Identifier, usize)>) -> Walker {
+        Walker {
+            operations: Vec::new(),
+            origins: Vec::new(),
+            next_node,
+            current_node: next_node,
+            height,
+            lets,
+        }
+    }
+
+    fn emit(&mut self, op: Operation) {
+        let (pops, pushes) = op.stack_effect();
+        self.height = self.height - pops + pushes;
+        self.operations.push(op);
+        self.origins.push(self.current_node);
+    }
+
+    /// Compiles `e` on its own, continuing the node numbering, so that it can be
+    /// spliced in after a jump. It starts from the current stack height.
+    fn subprogram(&mut self, e: &Expression) -> CompileResult<Walker> {
+        let mut sub = Walker::new(self.next_node, self.height, self.lets.clone());
+        sub.walk(e)?;
+        self.next_node = sub.next_node;
+        Ok(sub)
+    }
+
+    /// Splices in a subprogram that started at the current stack height,
+    /// leaving the height where the subprogram left it.
+    fn append(&mut self, mut sub: Walker) {
+        self.operations.append(&mut sub.operations);
+        self.origins.append(&mut sub.origins);
+        self.height = sub.height;
+    }
+
+    /// Accounts for a subexpression that produces no operations of its own.
+    fn skip(&mut self, e: &Expression) {
+        self.next_node += e.node_count();
+    }
+
+    /// Numbers `e` and hands it to the method for its kind. A tree as deep as
+    /// the parser allows puts this on the stack once per level, so the work
+    /// happens elsewhere.
+    fn walk(&mut self, e: &Expression) -> CompileResult<()> {
+        let parent = self.current_node;
+        self.current_node = self.next_node;
+        self.next_node += 1;
+        match e {
+            Expression::LetBinding { id, value, body } => self.walk_let(id, value, body),
+            Expression::Ternary {
+                condition,
+                true_branch,
+                false_branch,
+            } => self.walk_ternary(condition, true_branch, false_branch),
+            Expression::Or(exprs) => self.walk_logical(exprs, true),
+            Expression::And(exprs) => self.walk_logical(exprs, false),
+            Expression::Method(operand, name, args) => self.walk_method(operand, name, args),
+            Expression::Lit(lit) => self.walk_literal(lit),
+            Expression::Binding(id) => {
+                self.walk_binding(id);
+                Ok(())
+            }
+            Expression::FunctionCall(id, args) => self.walk_call(id, args),
+            _ => self.walk_operator(e),
+        }?;
+        self.current_node = parent;
+        Ok(())
+    }
+
+    fn walk_let(
+        &mut self,
+        id: &Identifier,
+        value: &Expression,
+        body: &Expression,
+    ) -> CompileResult<()> {
+        // The value stays on the stack below the body, which copies it out of
+        // its slot wherever it refers to `id`.
+        let slot = self.height;
+        self.walk(value)?;
+        self.lets.push((id.clone(), slot));
+        self.walk(body)?;
+        self.lets.pop();
+        self.emit(Operation::EndLet);
+        Ok(())
+    }
+
+    fn walk_ternary(
+        &mut self,
+        condition: &Expression,
+        true_branch: &Expression,
+        false_branch: &Expression,
+    ) -> CompileResult<()> {
+        self.walk(condition)?;
+        let true_subprogram = self.subprogram(true_branch)?;
+        let false_subprogram = self.subprogram(false_branch)?;
+
+        let true_len = true_subprogram.operations.len();
+        let false_len = false_subprogram.operations.len();
+
+        self.emit(Operation::JumpIf(false_len + 2));
+        self.emit(Operation::JumpError(false_len + true_len + 2));
+        self.append(false_subprogram);
+        self.emit(Operation::Jump(true_len));
+        self.append(true_subprogram);
+        self.emit(Operation::Ternary);
+        Ok(())
+    }
+
+    /// `||` if `or`, else `&&`.
+    fn walk_logical(&mut self, exprs: &[Expression], or: bool) -> CompileResult<()> {
+        self.walk(&exprs[0])?;
+        for expr in &exprs[1..] {
+            let subprogram = self.subprogram(expr)?;
+            let skip = subprogram.operations.len() + 1;
+            if or {
+                self.emit(Operation::JumpIf(skip));
+                self.append(subprogram);
+                self.emit(Operation::Or);
+            } else {
+                self.emit(Operation::JumpIfNot(skip));
+                self.append(subprogram);
+                self.emit(Operation::And);
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles the operands of an operator, then the operator itself.
+    fn walk_operator(&mut self, e: &Expression) -> CompileResult<()> {
+        let (a, b) = operands(e);
+        self.walk(a)?;
+        if let Some(b) = b {
+            self.walk(b)?;
+        }
+        self.emit_operator(e);
+        Ok(())
+    }
+
+    /// The operations that apply `e`, an operator, to its operands.
+    fn emit_operator(&mut self, e: &Expression) {
+        let op = match e {
+            Expression::Eq(_, _) | Expression::Neq(_, _) => Operation::Eq,
+            Expression::Lt(_, _) => Operation::Lt,
+            Expression::Lte(_, _) => Operation::Lte,
+            Expression::Gte(_, _) => Operation::Gte,
+            Expression::Gt(_, _) => Operation::Gt,
+            Expression::Add(_, _) => Operation::Add,
+            Expression::Sub(_, _) => Operation::Sub,
+            Expression::Mul(_, _) => Operation::Mul,
+            Expression::Div(_, _) => Operation::Div,
+            Expression::Mod(_, _) => Operation::Mod,
+            Expression::OptionalIndex(_, _) => Operation::OptionalIndex,
+            Expression::Neg(_) => Operation::Neg,
+            Expression::Not(_) => Operation::Not,
+            Expression::Member(_, id) => Operation::Member(id.clone()),
+            Expression::OptionalMember(_, id) => Operation::OptionalMember(id.clone()),
+            Expression::Has(_, id) => Operation::Has(id.clone()),
+            _ => unreachable!("not an operator"),
+        };
+        self.emit(op);
+        if let Expression::Neq(_, _) = e {
+            self.emit(Operation::Not);
+        }
+    }
+
+    fn walk_method(
+        &mut self,
+        operand: &Expression,
+        name: &Identifier,
+        args: &[Expression],
+    ) -> CompileResult<()> {
+        let pattern = constant_pattern(name, args, 1).map_err(|e| {
+            CompileError::InvalidRegex(self.current_node + 1 + operand.node_count(), e)
+        })?;
+        if let Some(pattern) = pattern {
+            self.walk(operand)?;
+            self.skip(&args[0]);
+            self.emit(Operation::Match(pattern));
+            return Ok(());
+        }
+        self.walk(operand)?;
+        for arg in args {
+            self.walk(arg)?;
+        }
+        self.emit(Operation::Method(name.clone(), args.len()));
+        Ok(())
+    }
+
+    /// Let bindings shadow type names, which shadow the activation; see
+    /// `Activation`.
+    fn walk_binding(&mut self, id: &Identifier) {
+        let local = self.lets.iter().rev().find(|(name, _)| name == id);
+        match (local, Kind::from_type_name(&id.0)) {
+            (Some(&(_, slot)), _) => self.emit(Operation::Local(slot)),
+            (None, Some(kind)) => self.emit(Operation::Lit(Value::Type(kind))),
+            (None, None) => self.emit(Operation::Lookup(id.clone())),
+        }
+    }
+
+    fn walk_call(&mut self, id: &Identifier, args: &[Expression]) -> CompileResult<()> {
+        let pattern = constant_pattern(id, args, 2).map_err(|e| {
+            CompileError::InvalidRegex(self.current_node + 1 + args[0].node_count(), e)
+        })?;
+        if let Some(pattern) = pattern {
+            self.walk(&args[0])?;
+            self.skip(&args[1]);
+            self.emit(Operation::Match(pattern));
+            return Ok(());
+        }
+        for arg in args {
+            self.walk(arg)?;
+        }
+        self.emit(Operation::Call(id.clone(), args.len()));
+        Ok(())
+    }
+
+    fn walk_literal(&mut self, lit: &Literal) -> CompileResult<()> {
+        match lit {
+            Literal::Null => self.emit(Operation::Lit(Value::Null)),
+            Literal::I64(v) => self.emit(Operation::Lit(Value::I64(*v))),
+            Literal::F64(v) => self.emit(Operation::Lit(Value::F64(*v))),
+            Literal::Bool(v) => self.emit(Operation::Lit(Value::Bool(*v))),
+            Literal::String(v) => self.emit(Operation::Lit(Value::String(v.clone()))),
+            Literal::Bytes(v) => self.emit(Operation::Lit(Value::Bytes(v.clone()))),
+            Literal::List(vs) => {
+                for v in vs {
+                    self.walk(v)?;
+                }
+                self.emit(Operation::MakeList(vs.len()));
+            }
+            Literal::Map(vs) => {
+                for (k, v) in vs {
+                    self.walk(k)?;
+                    self.walk(v)?;
+                }
+                self.emit(Operation::MakeMap(vs.len()));
+            }
+            Literal::Message(name, fields) => {
+                for (_, v) in fields {
+                    self.walk(v)?;
+                }
+                let ids = fields.iter().map(|(id, _)| id.clone()).collect();
+                self.emit(Operation::MakeMessage(name.clone(), ids));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The operands of `e`, an operator.
+fn operands(e: &Expression) -> (&Expression, Option<&Expression>) {
+    match e {
+        Expression::Eq(a, b)
+        | Expression::Neq(a, b)
+        | Expression::Lt(a, b)
+        | Expression::Lte(a, b)
+        | Expression::Gte(a, b)
+        | Expression::Gt(a, b)
+        | Expression::Add(a, b)
+        | Expression::Sub(a, b)
+        | Expression::Mul(a, b)
+        | Expression::Div(a, b)
+        | Expression::Mod(a, b)
+        | Expression::OptionalIndex(a, b) => (a, Some(b)),
+        Expression::Neg(a)
+        | Expression::Not(a)
+        | Expression::Member(a, _)
+        | Expression::OptionalMember(a, _)
+        | Expression::Has(a, _) => (a, None),
+        _ => unreachable!("not an operator"),
+    }
+}
+
+/// Recognizes `matches` calls whose pattern (the last of `arity` arguments) is a
+/// string literal, so the regex can be compiled ahead of time.
+fn constant_pattern(
+    id: &Identifier,
+    args: &[Expression],
+    arity: usize,
+) -> Result<Option<Pattern>, String> {
+    if id.0 != "matches" || args.len() != arity {
+        return Ok(None);
+    }
+    match args.last() {
+        Some(Expression::Lit(Literal::String(p))) => Ok(Some(Pattern::new(p)?)),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parser::parse;
+
+    use super::*;
+
+    #[test]
+    fn linearize_add() {
+        let expr = parse(r#" 1 + 2 "#).unwrap();
+        assert_eq!(
+            linearize(expr).unwrap(),
+            vec![
+                Operation::Lit(Value::I64(1)),
+                Operation::Lit(Value::I64(2)),
+                Operation::Add,
+            ]
+        );
+    }
+
+    #[test]
+    fn linearize_sub() {
+        let expr = parse(r#" 1 - 2 "#).unwrap();
+        assert_eq!(
+            linearize(expr).unwrap(),
+            vec![
+                Operation::Lit(Value::I64(1)),
+                Operation::Lit(Value::I64(2)),
+                Operation::Sub,
+            ]
+        );
+    }
+
+    #[test]
+    fn linearize_list() {
+        let expr = parse(r#" [1, 2 + 3] "#).unwrap();
+        assert_eq!(
+            linearize(expr).unwrap(),
+            vec![
+                Operation::Lit(Value::I64(1)),
+                Operation::Lit(Value::I64(2)),
+                Operation::Lit(Value::I64(3)),
+                Operation::Add,
+                Operation::MakeList(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn linearize_list_empty() {
+        let expr = parse(r#" [] "#).unwrap();
+        assert_eq!(linearize(expr).unwrap(), vec![Operation::MakeList(0),]);
+    }
+
+    #[test]
+    fn linearize_or_simple() {
+        let expr = parse(r#" true || false "#).unwrap();
+        assert_eq!(
+            linearize(expr).unwrap(),
+            vec![
+                Operation::Lit(Value::Bool(true)),
+                Operation::JumpIf(2),
+                Operation::Lit(Value::Bool(false)),
+                Operation::Or,
+            ]
+        );
+    }
+
+    #[test]
+    fn linearize_or_many() {
+        let expr = parse(r#" 0 || 1 || 2 || 3 "#).unwrap();
+        assert_eq!(
+            linearize(expr).unwrap(),
+            vec![
+                Operation::Lit(Value::I64(0)),
+                Operation::JumpIf(2),
+                Operation::Lit(Value::I64(1)),
+                Operation::Or,
+                Operation::JumpIf(2),
+                Operation::Lit(Value::I64(2)),
+                Operation::Or,
+                Operation::JumpIf(2),
+                Operation::Lit(Value::I64(3)),
+                Operation::Or,
+            ]
+        );
+    }
+
+    #[test]
+    fn linearize_ternary() {
+        let expr = parse(r#" true ? 1 : 2 "#).unwrap();
+        assert_eq!(
+            linearize(expr).unwrap(),
+            vec![
+                Operation::Lit(Value::Bool(true)),
+                Operation::JumpIf(3),
+                Operation::JumpError(4),
+                Operation::Lit(Value::I64(2)),
+                Operation::Jump(1),
+                Operation::Lit(Value::I64(1)),
+                Operation::Ternary,
+            ]
+        );
+    }
+
+    #[test]
+    fn linearize_let() {
+        let expr = parse(r#" let x = 1; let y = x + 2; [x, y] "#).unwrap();
+        assert_eq!(
+            linearize(expr).unwrap(),
+            vec![
+                Operation::Lit(Value::I64(1)),
+                Operation::Local(0),
+                Operation::Lit(Value::I64(2)),
+                Operation::Add,
+                Operation::Local(0),
+                Operation::Local(1),
+                Operation::MakeList(2),
+                Operation::EndLet,
+                Operation::EndLet,
+            ]
+        );
+    }
+
+    #[test]
+    fn origins_follow_preorder() {
+        // Nodes: 0 `+`, 1 `*`, 2 `a`, 3 `2`, 4 `b.size()`, 5 `b`.
+        let expr = parse(r#" a * 2 + b.size() "#).unwrap();
+        assert_eq!(expr.node_count(), 6);
+        let (operations, origins) = linearize_with_origins(expr).unwrap();
+        assert_eq!(operations.len(), origins.len());
+        assert_eq!(origins, vec![2, 3, 1, 5, 4, 0]);
+    }
+
+    #[test]
+    fn origins_of_branches() {
+        // Nodes: 0 `?:`, 1 `c`, 2 `1`, 3 `x || y`, 4 `x`, 5 `y`.
+        let expr = parse(r#" c ? 1 : x || y "#).unwrap();
+        let (operations, origins) = linearize_with_origins(expr).unwrap();
+        assert_eq!(operations.len(), origins.len());
+        assert_eq!(origins, vec![1, 0, 0, 4, 3, 5, 3, 0, 2, 0]);
+    }
+
+    #[test]
+    fn origins_skip_precompiled_patterns() {
+        // Nodes: 0 `[...]`, 1 `matches`, 2 `s`, 3 `"a+"`, 4 `1`.
+        let expr = parse(r#" [matches(s, "a+"), 1] "#).unwrap();
+        let (_, origins) = linearize_with_origins(expr).unwrap();
+        assert_eq!(origins, vec![2, 1, 4, 0]);
+    }
+}
@@ -0,0 +1,1072 @@
+//! Compiles a `stack::Program` into a standalone WebAssembly module, so that
+//! an expression can run in any wasm host, e.g. the metered ones in `10-gas`
+//! and `11-authz`.
+//!
+//! Only scalar programs are supported for now: `null`, `bool`, `int`, `uint`
+//! and `double` values, arithmetic, comparisons, the logical operators,
+//! ternaries and variable lookups. Anything else is rejected at compile time.
+//!
+//! # ABI
+//!
+//! The module exports a memory named `memory` (one page to start with) and a
+//! function `evaluate(input: i32) -> i32`. Values cross the boundary as
+//! 16-byte little-endian records:
+//!
+//! | offset | size | contents                                  |
+//! |--------|------|-------------------------------------------|
+//! | 0      | 4    | tag, one of the `TAG_*` constants         |
+//! | 4      | 4    | reserved, zero                            |
+//! | 8      | 8    | payload: `0`/`1`, an `i64`, a `u64`, an `f64`, or an error |
+//!
+//! Before calling `evaluate`, the host writes one record per variable, in the
+//! order of `Module::variables`, starting at `input` (see `Module::encode_input`).
+//! The function writes the result record immediately after the variables and
+//! returns its address (see `Module::decode_result`).
+//!
+//! Programs that use `%` import `cel.fmod: (f64, f64) -> f64`, which must
+//! compute the remainder with the sign of the dividend, like Rust's `%`.
+
+use std::convert::TryInto;
+use std::fmt;
+
+use wasm_encoder::{
+    BlockType, CodeSection, EntityType, ExportKind, ExportSection, Function, FunctionSection,
+    ImportSection, Instruction, MemArg, MemorySection, MemoryType, TypeSection, ValType,
+};
+
+use crate::model::{Activation, Error, EvalResult, Identifier, Kind, Op, Value};
+use crate::stack::{self, Operation, Program, VerifyError};
+
+pub const RECORD_SIZE: usize = 16;
+
+pub const TAG_NULL: i32 = 0;
+pub const TAG_BOOL: i32 = 1;
+pub const TAG_INT: i32 = 2;
+pub const TAG_UINT: i32 = 3;
+pub const TAG_DOUBLE: i32 = 4;
+/// The payload of an error packs a code (the low byte), up to two operand
+/// kinds (given by their tags) and an operator; see `decode_error`.
+pub const TAG_ERROR: i32 = 15;
+
+const INVALID_TYPE: i64 = 1;
+const INVALID_TYPES: i64 = 2;
+const DIVISION_BY_ZERO: i64 = 3;
+const INTEGER_OVERFLOW: i64 = 4;
+const NO_SUCH_BINDING: i64 = 5;
+const ABORTED: i64 = 6;
+
+/// The operators that can appear in an error, by their code.
+const OPS: [Op; 24] = [
+    Op::Not,
+    Op::Neg,
+    Op::Plus,
+    Op::Minus,
+    Op::Times,
+    Op::Div,
+    Op::Mod,
+    Op::Or,
+    Op::And,
+    Op::Eq,
+    Op::Neq,
+    Op::Lte,
+    Op::Lt,
+    Op::Gt,
+    Op::Gte,
+    Op::Lit,
+    Op::Lookup,
+    Op::Member,
+    Op::OptionalMember,
+    Op::OptionalIndex,
+    Op::Has,
+    Op::LetBinding,
+    Op::Ternary,
+    Op::Jump,
+];
+
+#[derive(Debug, PartialEq)]
+pub enum WasmError {
+    /// The program uses an operation that has no wasm lowering yet.
+    UnsupportedOperation(String),
+    /// A literal or variable whose kind can't be passed as a record.
+    UnsupportedValue(Kind),
+    /// The program's stack doesn't have a fixed height at every operation.
+    MalformedProgram(VerifyError),
+    /// A result record that `evaluate` can't have produced.
+    MalformedResult(String),
+}
+
+impl fmt::Display for WasmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WasmError::UnsupportedOperation(op) => write!(f, "unsupported operation {}", op),
+            WasmError::UnsupportedValue(kind) => {
+                write!(f, "unsupported value of type {}", kind.type_name())
+            }
+            WasmError::MalformedProgram(msg) => write!(f, "malformed program: {}", msg),
+            WasmError::MalformedResult(msg) => write!(f, "malformed result: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for WasmError {}
+
+/// A compiled module and the variables its input consists of.
+#[derive(Debug)]
+pub struct Module {
+    pub bytes: Vec<u8>,
+    pub variables: Vec<Identifier>,
+}
+
+impl Module {
+    /// The input records for `activation`. Variables it doesn't bind are
+    /// passed as `NoSuchBinding` errors, as the interpreter would produce.
+    pub fn encode_input(&self, activation: &Activation) -> Result<Vec<u8>, WasmError> {
+        let mut input = Vec::with_capacity(RECORD_SIZE * self.variables.len());
+        for (i, id) in self.variables.iter().enumerate() {
+            let (tag, payload) = match activation.get(&id.0) {
+                Some(v) => encode_value(v)?,
+                None => (TAG_ERROR, NO_SUCH_BINDING | (i as i64) << 32),
+            };
+            input.extend_from_slice(&record(tag, payload));
+        }
+        Ok(input)
+    }
+
+    /// Reads the record that `evaluate` returned the address of.
+    pub fn decode_result(&self, record: &[u8]) -> Result<EvalResult, WasmError> {
+        if record.len() != RECORD_SIZE {
+            return Err(WasmError::MalformedResult(format!(
+                "expected {} bytes, got {}",
+                RECORD_SIZE,
+                record.len()
+            )));
+        }
+        let tag = i32::from_le_bytes(record[0..4].try_into().unwrap());
+        let payload = i64::from_le_bytes(record[8..16].try_into().unwrap());
+        match tag {
+            TAG_ERROR => self.decode_error(payload).map(Err),
+            _ => decode_value(tag, payload).map(Ok),
+        }
+    }
+
+    fn decode_error(&self, payload: i64) -> Result<Error, WasmError> {
+        let byte = |i: u32| (payload >> (8 * i)) & 0xff;
+        let malformed = || WasmError::MalformedResult(format!("error payload {:#x}", payload));
+        let operand = |i| kind(byte(i) as i32).ok_or_else(malformed);
+        let op = || OPS.get(byte(3) as usize).cloned().ok_or_else(malformed);
+        match byte(0) {
+            INVALID_TYPE => Ok(Error::InvalidTypeForOperator(operand(1)?, op()?)),
+            INVALID_TYPES => Ok(Error::InvalidTypesForOperator(
+                operand(1)?,
+                operand(2)?,
+                op()?,
+            )),
+            DIVISION_BY_ZERO => Ok(Error::DivisionByZero),
+            INTEGER_OVERFLOW => Ok(Error::IntegerOverflow(op()?)),
+            NO_SUCH_BINDING => self
+                .variables
+                .get((payload >> 32) as usize)
+                .map(|id| Error::NoSuchBinding(id.clone()))
+                .ok_or_else(malformed),
+            ABORTED => Ok(Error::Aborted),
+            _ => Err(malformed()),
+        }
+    }
+}
+
+fn record(tag: i32, payload: i64) -> [u8; RECORD_SIZE] {
+    let mut record = [0; RECORD_SIZE];
+    record[0..4].copy_from_slice(&tag.to_le_bytes());
+    record[8..16].copy_from_slice(&payload.to_le_bytes());
+    record
+}
+
+fn encode_value(v: &Value) -> Result<(i32, i64), WasmError> {
+    match *v {
+        Value::Null => Ok((TAG_NULL, 0)),
+        Value::Bool(b) => Ok((TAG_BOOL, i64::from(b))),
+        Value::I64(n) => Ok((TAG_INT, n)),
+        Value::U64(n) => Ok((TAG_UINT, n as i64)),
+        Value::F64(x) => Ok((TAG_DOUBLE, x.to_bits() as i64)),
+        ref other => Err(WasmError::UnsupportedValue(other.kind())),
+    }
+}
+
+fn decode_value(tag: i32, payload: i64) -> Result<Value, WasmError> {
+    match tag {
+        TAG_NULL => Ok(Value::Null),
+        TAG_BOOL => Ok(Value::Bool(payload != 0)),
+        TAG_INT => Ok(Value::I64(payload)),
+        TAG_UINT => Ok(Value::U64(payload as u64)),
+        TAG_DOUBLE => Ok(Value::F64(f64::from_bits(payload as u64))),
+        _ => Err(WasmError::MalformedResult(format!("tag {}", tag))),
+    }
+}
+
+fn kind(tag: i32) -> Option<Kind> {
+    match tag {
+        TAG_NULL => Some(Kind::Null),
+        TAG_BOOL => Some(Kind::Bool),
+        TAG_INT => Some(Kind::I64),
+        TAG_UINT => Some(Kind::U64),
+        TAG_DOUBLE => Some(Kind::F64),
+        _ => None,
+    }
+}
+
+fn op_code(op: &Op) -> Result<i64, WasmError> {
+    OPS.iter()
+        .position(|o| o == op)
+        .map(|i| i as i64)
+        .ok_or_else(|| WasmError::UnsupportedOperation(format!("{:?}", op)))
+}
+
+/// Compiles `program` into a module with the ABI described above.
+pub fn compile(program: &Program) -> Result<Module, WasmError> {
+    let operations = &program.operations;
+    let heights = stack::stack_heights(operations).map_err(WasmError::MalformedProgram)?;
+    let max_height = heights.iter().flatten().max().cloned().unwrap_or(0) + 1;
+    let uses_fmod = operations.contains(&Operation::Mod);
+
+    let mut codegen = Codegen::new(operations, max_height);
+    for (pc, op) in operations.iter().enumerate() {
+        codegen.close_labels(pc);
+        if let Some(height) = heights[pc] {
+            codegen.operation(pc, op, height)?;
+        }
+    }
+    codegen.close_labels(operations.len());
+    let (function, variables) = codegen.finish();
+
+    let mut types = TypeSection::new();
+    types.ty().function([ValType::I32], [ValType::I32]);
+    let mut imports = ImportSection::new();
+    if uses_fmod {
+        types
+            .ty()
+            .function([ValType::F64, ValType::F64], [ValType::F64]);
+        imports.import("cel", "fmod", EntityType::Function(1));
+    }
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: 1,
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    });
+    let mut exports = ExportSection::new();
+    exports.export("evaluate", ExportKind::Func, imports.len());
+    exports.export("memory", ExportKind::Memory, 0);
+    let mut code = CodeSection::new();
+    code.function(&function);
+
+    let mut module = wasm_encoder::Module::new();
+    module.section(&types);
+    if uses_fmod {
+        module.section(&imports);
+    }
+    module
+        .section(&functions)
+        .section(&memories)
+        .section(&exports)
+        .section(&code);
+    Ok(Module {
+        bytes: module.finish(),
+        variables,
+    })
+}
+
+const INPUT: u32 = 0;
+const OUTPUT: u32 = 1;
+const FMOD: u32 = 0;
+
+fn tag(slot: usize) -> u32 {
+    2 + 2 * slot as u32
+}
+
+fn bits(slot: usize) -> u32 {
+    3 + 2 * slot as u32
+}
+
+fn mem(offset: u64, align: u32) -> MemArg {
+    MemArg {
+        offset,
+        align,
+        memory_index: 0,
+    }
+}
+
+/// Emits the body of `evaluate`. Each stack slot lives in a pair of locals, a
+/// tag and a payload, and forward jumps become `br`s out of nested blocks that
+/// each end at one jump target.
+struct Codegen {
+    instructions: Vec<Instruction<'static>>,
+    /// Jump targets whose blocks are still open, innermost (i.e. smallest) last.
+    labels: Vec<usize>,
+    /// Blocks opened within the current operation.
+    nesting: u32,
+    variables: Vec<Identifier>,
+    slots: usize,
+    scratch: u32,
+}
+
+impl Codegen {
+    fn new(operations: &[Operation], slots: usize) -> Codegen {
+        let mut labels: Vec<usize> = operations
+            .iter()
+            .enumerate()
+            .filter_map(|(pc, op)| op.jump_offset().map(|n| pc + 1 + n))
+            .collect();
+        labels.sort_unstable_by(|a, b| b.cmp(a));
+        labels.dedup();
+        let instructions = labels
+            .iter()
+            .map(|_| Instruction::Block(BlockType::Empty))
+            .collect();
+        Codegen {
+            instructions,
+            labels,
+            nesting: 0,
+            variables: Vec::new(),
+            slots,
+            scratch: tag(slots),
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction<'static>) {
+        self.instructions.push(instruction);
+    }
+
+    fn open(&mut self, instruction: Instruction<'static>) {
+        self.nesting += 1;
+        self.emit(instruction);
+    }
+
+    fn close(&mut self) {
+        self.nesting -= 1;
+        self.emit(Instruction::End);
+    }
+
+    /// Ends the blocks of the jumps that land on `pc`.
+    fn close_labels(&mut self, pc: usize) {
+        while self.labels.last() == Some(&pc) {
+            self.labels.pop();
+            self.emit(Instruction::End);
+        }
+    }
+
+    /// The `br` depth that continues at operation `target`.
+    fn depth(&self, target: usize) -> u32 {
+        self.nesting + self.labels.iter().filter(|&&l| l < target).count() as u32
+    }
+
+    fn finish(mut self) -> (Function, Vec<Identifier>) {
+        self.store_result(0);
+        self.emit(Instruction::LocalGet(OUTPUT));
+        self.emit(Instruction::End);
+
+        let mut locals = vec![(1, ValType::I32)];
+        for _ in 0..self.slots {
+            locals.push((1, ValType::I32));
+            locals.push((1, ValType::I64));
+        }
+        locals.push((1, ValType::I64));
+        let mut function = Function::new(locals);
+        // The result goes right after the variables, which are only all known now.
+        function
+            .instruction(&Instruction::LocalGet(INPUT))
+            .instruction(&Instruction::I32Const(
+                (RECORD_SIZE * self.variables.len()) as i32,
+            ))
+            .instruction(&Instruction::I32Add)
+            .instruction(&Instruction::LocalSet(OUTPUT));
+        for instruction in &self.instructions {
+            function.instruction(instruction);
+        }
+        (function, self.variables)
+    }
+
+    fn operation(&mut self, pc: usize, op: &Operation, h: usize) -> Result<(), WasmError> {
+        match *op {
+            Operation::Lit(ref v) => {
+                let (t, payload) = encode_value(v)?;
+                self.set(h, t, payload);
+            }
+            Operation::Lookup(ref id) => {
+                let i = match self.variables.iter().position(|v| v == id) {
+                    Some(i) => i,
+                    None => {
+                        self.variables.push(id.clone());
+                        self.variables.len() - 1
+                    }
+                };
+                let offset = (RECORD_SIZE * i) as u64;
+                self.emit(Instruction::LocalGet(INPUT));
+                self.emit(Instruction::I32Load(mem(offset, 2)));
+                self.emit(Instruction::LocalSet(tag(h)));
+                self.emit(Instruction::LocalGet(INPUT));
+                self.emit(Instruction::I64Load(mem(offset + 8, 3)));
+                self.emit(Instruction::LocalSet(bits(h)));
+            }
+            Operation::Clone => self.copy(h - 1, h),
+            Operation::Pop => {}
+            Operation::Ternary | Operation::EndLet => self.copy(h - 1, h - 2),
+            Operation::Local(slot) => self.copy(slot, h),
+            Operation::TypeError(ref op) => {
+                let op = op_code(op)?;
+                self.is_not(h - 1, TAG_ERROR);
+                self.open(Instruction::If(BlockType::Empty));
+                self.error_kinds(h - 1, INVALID_TYPE, h - 1, None, op);
+                self.close();
+            }
+            Operation::Neg => {
+                let neg = op_code(&Op::Neg)?;
+                self.unary(h - 1, Op::Neg, |cg, k| {
+                    cg.case(&[k], TAG_INT, |cg| {
+                        cg.emit(Instruction::LocalGet(bits(k)));
+                        cg.emit(Instruction::I64Const(i64::MIN));
+                        cg.emit(Instruction::I64Eq);
+                        cg.open(Instruction::If(BlockType::Empty));
+                        cg.set(k, TAG_ERROR, INTEGER_OVERFLOW | neg << 24);
+                        cg.emit(Instruction::Else);
+                        cg.emit(Instruction::I64Const(0));
+                        cg.emit(Instruction::LocalGet(bits(k)));
+                        cg.emit(Instruction::I64Sub);
+                        cg.emit(Instruction::LocalSet(bits(k)));
+                        cg.close();
+                    });
+                    cg.case(&[k], TAG_DOUBLE, |cg| {
+                        cg.double(k);
+                        cg.emit(Instruction::F64Neg);
+                        cg.emit(Instruction::I64ReinterpretF64);
+                        cg.emit(Instruction::LocalSet(bits(k)));
+                    });
+                })?
+            }
+            Operation::Not => self.unary(h - 1, Op::Not, |cg, k| {
+                cg.case(&[k], TAG_BOOL, |cg| {
+                    cg.emit(Instruction::LocalGet(bits(k)));
+                    cg.emit(Instruction::I64Const(1));
+                    cg.emit(Instruction::I64Xor);
+                    cg.emit(Instruction::LocalSet(bits(k)));
+                });
+            })?,
+            Operation::Add => self.arithmetic(h, Op::Plus)?,
+            Operation::Sub => self.arithmetic(h, Op::Minus)?,
+            Operation::Mul => self.arithmetic(h, Op::Times)?,
+            Operation::Div => self.division(h, Op::Div)?,
+            Operation::Mod => self.division(h, Op::Mod)?,
+            Operation::Or => self.logical(h, Op::Or)?,
+            Operation::And => self.logical(h, Op::And)?,
+            Operation::Eq => self.equality(h),
+            Operation::Lt => self.comparison(h, Op::Lt)?,
+            Operation::Lte => self.comparison(h, Op::Lte)?,
+            Operation::Gte => self.comparison(h, Op::Gte)?,
+            Operation::Gt => self.comparison(h, Op::Gt)?,
+            Operation::JumpIf(n) | Operation::JumpIfNot(n) => {
+                let k = h - 1;
+                let jump = op_code(&Op::Jump)?;
+                self.is(k, TAG_BOOL);
+                self.open(Instruction::If(BlockType::Empty));
+                self.emit(Instruction::LocalGet(bits(k)));
+                if let Operation::JumpIfNot(_) = *op {
+                    self.emit(Instruction::I64Eqz);
+                } else {
+                    self.emit(Instruction::I64Const(0));
+                    self.emit(Instruction::I64Ne);
+                }
+                self.emit(Instruction::BrIf(self.depth(pc + 1 + n)));
+                self.emit(Instruction::Else);
+                self.is_not(k, TAG_ERROR);
+                self.open(Instruction::If(BlockType::Empty));
+                self.error_kinds(k, INVALID_TYPE, k, None, jump);
+                self.close();
+                self.close();
+            }
+            Operation::JumpError(n) => {
+                self.is(h - 1, TAG_ERROR);
+                self.emit(Instruction::BrIf(self.depth(pc + 1 + n)));
+            }
+            Operation::Jump(n) => {
+                self.emit(Instruction::Br(self.depth(pc + 1 + n)));
+            }
+            Operation::Abort => {
+                self.set(0, TAG_ERROR, ABORTED);
+                self.store_result(0);
+                self.emit(Instruction::LocalGet(OUTPUT));
+                self.emit(Instruction::Return);
+            }
+            ref other => return Err(WasmError::UnsupportedOperation(other.short())),
+        }
+        Ok(())
+    }
+
+    /// Pushes whether `slot` holds a value with tag `t`.
+    fn is(&mut self, slot: usize, t: i32) {
+        self.emit(Instruction::LocalGet(tag(slot)));
+        self.emit(Instruction::I32Const(t));
+        self.emit(Instruction::I32Eq);
+    }
+
+    fn is_not(&mut self, slot: usize, t: i32) {
+        self.emit(Instruction::LocalGet(tag(slot)));
+        self.emit(Instruction::I32Const(t));
+        self.emit(Instruction::I32Ne);
+    }
+
+    fn set(&mut self, slot: usize, t: i32, payload: i64) {
+        self.emit(Instruction::I32Const(t));
+        self.emit(Instruction::LocalSet(tag(slot)));
+        self.emit(Instruction::I64Const(payload));
+        self.emit(Instruction::LocalSet(bits(slot)));
+    }
+
+    fn copy(&mut self, from: usize, to: usize) {
+        self.emit(Instruction::LocalGet(tag(from)));
+        self.emit(Instruction::LocalSet(tag(to)));
+        self.emit(Instruction::LocalGet(bits(from)));
+        self.emit(Instruction::LocalSet(bits(to)));
+    }
+
+    fn double(&mut self, slot: usize) {
+        self.emit(Instruction::LocalGet(bits(slot)));
+        self.emit(Instruction::F64ReinterpretI64);
+    }
+
+    fn store_result(&mut self, slot: usize) {
+        self.emit(Instruction::LocalGet(OUTPUT));
+        self.emit(Instruction::LocalGet(tag(slot)));
+        self.emit(Instruction::I32Store(mem(0, 2)));
+        self.emit(Instruction::LocalGet(OUTPUT));
+        self.emit(Instruction::I32Const(0));
+        self.emit(Instruction::I32Store(mem(4, 2)));
+        self.emit(Instruction::LocalGet(OUTPUT));
+        self.emit(Instruction::LocalGet(bits(slot)));
+        self.emit(Instruction::I64Store(mem(8, 3)));
+    }
+
+    /// Replaces `dst` with an error whose operand kinds are the tags of `a` and `b`.
+    fn error_kinds(&mut self, dst: usize, code: i64, a: usize, b: Option<usize>, op: i64) {
+        self.emit(Instruction::LocalGet(tag(a)));
+        self.emit(Instruction::I64ExtendI32U);
+        self.emit(Instruction::I64Const(8));
+        self.emit(Instruction::I64Shl);
+        if let Some(b) = b {
+            self.emit(Instruction::LocalGet(tag(b)));
+            self.emit(Instruction::I64ExtendI32U);
+            self.emit(Instruction::I64Const(16));
+            self.emit(Instruction::I64Shl);
+            self.emit(Instruction::I64Or);
+        }
+        self.emit(Instruction::I64Const(code | op << 24));
+        self.emit(Instruction::I64Or);
+        self.emit(Instruction::LocalSet(bits(dst)));
+        self.emit(Instruction::I32Const(TAG_ERROR));
+        self.emit(Instruction::LocalSet(tag(dst)));
+    }
+
+    /// Runs `body` if all of `slots` have tag `t`, then skips to the end of the
+    /// enclosing operation.
+    fn case<F: FnOnce(&mut Self)>(&mut self, slots: &[usize], t: i32, body: F) {
+        for (i, &slot) in slots.iter().enumerate() {
+            self.is(slot, t);
+            if i > 0 {
+                self.emit(Instruction::I32And);
+            }
+        }
+        self.open(Instruction::If(BlockType::Empty));
+        body(self);
+        self.emit(Instruction::Br(1));
+        self.close();
+    }
+
+    /// Propagates the first error among `operands` into `dst`, and otherwise
+    /// runs `body`, all in a block that `case` can skip to the end of.
+    fn guarded<F: FnOnce(&mut Self)>(&mut self, dst: usize, operands: &[usize], body: F) {
+        self.open(Instruction::Block(BlockType::Empty));
+        for &slot in operands {
+            self.is(slot, TAG_ERROR);
+            if slot == dst {
+                self.emit(Instruction::BrIf(0));
+            } else {
+                self.open(Instruction::If(BlockType::Empty));
+                self.copy(slot, dst);
+                self.emit(Instruction::Br(1));
+                self.close();
+            }
+        }
+        body(self);
+        self.close();
+    }
+
+    fn unary<F: FnOnce(&mut Self, usize)>(
+        &mut self,
+        k: usize,
+        op: Op,
+        cases: F,
+    ) -> Result<(), WasmError> {
+        let op = op_code(&op)?;
+        self.guarded(k, &[k], |cg| {
+            cases(cg, k);
+            cg.error_kinds(k, INVALID_TYPE, k, None, op);
+        });
+        Ok(())
+    }
+
+    fn arithmetic(&mut self, h: usize, op: Op) -> Result<(), WasmError> {
+        let (a, b, scratch) = (h - 2, h - 1, self.scratch);
+        let (int, double) = match op {
+            Op::Plus => (Instruction::I64Add, Instruction::F64Add),
+            Op::Minus => (Instruction::I64Sub, Instruction::F64Sub),
+            _ => (Instruction::I64Mul, Instruction::F64Mul),
+        };
+        let code = op_code(&op)?;
+        self.guarded(a, &[a, b], |cg| {
+            for t in [TAG_INT, TAG_UINT] {
+                cg.case(&[a, b], t, |cg| {
+                    cg.emit(Instruction::LocalGet(bits(a)));
+                    cg.emit(Instruction::LocalGet(bits(b)));
+                    cg.emit(int.clone());
+                    cg.emit(Instruction::LocalSet(scratch));
+                    cg.overflowed(&op, t == TAG_INT, a, b);
+                    cg.open(Instruction::If(BlockType::Empty));
+                    cg.set(a, TAG_ERROR, INTEGER_OVERFLOW | code << 24);
+                    cg.emit(Instruction::Else);
+                    cg.emit(Instruction::LocalGet(scratch));
+                    cg.emit(Instruction::LocalSet(bits(a)));
+                    cg.close();
+                });
+            }
+            cg.case(&[a, b], TAG_DOUBLE, |cg| {
+                cg.double(a);
+                cg.double(b);
+                cg.emit(double);
+                cg.emit(Instruction::I64ReinterpretF64);
+                cg.emit(Instruction::LocalSet(bits(a)));
+            });
+            cg.error_kinds(a, INVALID_TYPES, a, Some(b), code);
+        });
+        Ok(())
+    }
+
+    /// Pushes whether `a op b`, whose wrapped result is in the scratch local,
+    /// overflowed.
+    fn overflowed(&mut self, op: &Op, signed: bool, a: usize, b: usize) {
+        let (a, b, r) = (bits(a), bits(b), self.scratch);
+        let xor_sign = |cg: &mut Self, (x, y): (u32, u32), (z, w): (u32, u32)| {
+            // Whether (x ^ y) & (z ^ w) is negative.
+            for &(p, q) in &[(x, y), (z, w)] {
+                cg.emit(Instruction::LocalGet(p));
+                cg.emit(Instruction::LocalGet(q));
+                cg.emit(Instruction::I64Xor);
+            }
+            cg.emit(Instruction::I64And);
+            cg.emit(Instruction::I64Const(0));
+            cg.emit(Instruction::I64LtS);
+        };
+        match (op, signed) {
+            // The result has a different sign from both operands.
+            (Op::Plus, true) => xor_sign(self, (a, r), (b, r)),
+            // The operands' signs differ, and the result's differs from `a`'s.
+            (Op::Minus, true) => xor_sign(self, (a, b), (a, r)),
+            (Op::Plus, false) => {
+                self.emit(Instruction::LocalGet(r));
+                self.emit(Instruction::LocalGet(a));
+                self.emit(Instruction::I64LtU);
+            }
+            (Op::Minus, false) => {
+                self.emit(Instruction::LocalGet(a));
+                self.emit(Instruction::LocalGet(b));
+                self.emit(Instruction::I64LtU);
+            }
+            _ => {
+                // r / a != b, taking care that neither a = 0 nor (for signed
+                // values) r / -1 can trap.
+                if signed {
+                    self.emit(Instruction::LocalGet(a));
+                    self.emit(Instruction::I64Const(-1));
+                    self.emit(Instruction::I64Eq);
+                    self.open(Instruction::If(BlockType::Result(ValType::I32)));
+                    self.emit(Instruction::LocalGet(b));
+                    self.emit(Instruction::I64Const(i64::MIN));
+                    self.emit(Instruction::I64Eq);
+                    self.emit(Instruction::Else);
+                }
+                self.emit(Instruction::LocalGet(a));
+                self.emit(Instruction::I64Eqz);
+                self.open(Instruction::If(BlockType::Result(ValType::I32)));
+                self.emit(Instruction::I32Const(0));
+                self.emit(Instruction::Else);
+                self.emit(Instruction::LocalGet(r));
+                self.emit(Instruction::LocalGet(a));
+                self.emit(if signed {
+                    Instruction::I64DivS
+                } else {
+                    Instruction::I64DivU
+                });
+                self.emit(Instruction::LocalGet(b));
+                self.emit(Instruction::I64Ne);
+                self.close();
+                if signed {
+                    self.close();
+                }
+            }
+        }
+    }
+
+    fn division(&mut self, h: usize, op: Op) -> Result<(), WasmError> {
+        let (a, b) = (h - 2, h - 1);
+        let (signed, unsigned, double) = match op {
+            Op::Div => (
+                Instruction::I64DivS,
+                Instruction::I64DivU,
+                Instruction::F64Div,
+            ),
+            _ => (
+                Instruction::I64RemS,
+                Instruction::I64RemU,
+                Instruction::Call(FMOD),
+            ),
+        };
+        let code = op_code(&op)?;
+        self.guarded(a, &[a, b], |cg| {
+            for (t, instruction) in [(TAG_INT, signed), (TAG_UINT, unsigned)] {
+                cg.case(&[a, b], t, |cg| {
+                    cg.emit(Instruction::LocalGet(bits(b)));
+                    cg.emit(Instruction::I64Eqz);
+                    cg.open(Instruction::If(BlockType::Empty));
+                    cg.set(a, TAG_ERROR, DIVISION_BY_ZERO);
+                    cg.emit(Instruction::Else);
+                    if t == TAG_INT {
+                        // The one quotient that doesn't fit, which Rust also
+                        // reports for the remainder.
+                        cg.emit(Instruction::LocalGet(bits(a)));
+                        cg.emit(Instruction::I64Const(i64::MIN));
+                        cg.emit(Instruction::I64Eq);
+                        cg.emit(Instruction::LocalGet(bits(b)));
+                        cg.emit(Instruction::I64Const(-1));
+                        cg.emit(Instruction::I64Eq);
+                        cg.emit(Instruction::I32And);
+                        cg.open(Instruction::If(BlockType::Empty));
+                        cg.set(a, TAG_ERROR, INTEGER_OVERFLOW | code << 24);
+                        cg.emit(Instruction::Br(3));
+                        cg.close();
+                    }
+                    cg.emit(Instruction::LocalGet(bits(a)));
+                    cg.emit(Instruction::LocalGet(bits(b)));
+                    cg.emit(instruction);
+                    cg.emit(Instruction::LocalSet(bits(a)));
+                    cg.close();
+                });
+            }
+            cg.case(&[a, b], TAG_DOUBLE, |cg| {
+                cg.double(b);
+                cg.emit(Instruction::F64Const(0.0));
+                cg.emit(Instruction::F64Eq);
+                cg.open(Instruction::If(BlockType::Empty));
+                cg.set(a, TAG_ERROR, DIVISION_BY_ZERO);
+                cg.emit(Instruction::Else);
+                cg.double(a);
+                cg.double(b);
+                cg.emit(double);
+                cg.emit(Instruction::I64ReinterpretF64);
+                cg.emit(Instruction::LocalSet(bits(a)));
+                cg.close();
+            });
+            cg.error_kinds(a, INVALID_TYPES, a, Some(b), code);
+        });
+        Ok(())
+    }
+
+    /// `||` and `&&`, where either operand being the absorbing value (`true`
+    /// and `false` respectively) wins even over an error in the other.
+    fn logical(&mut self, h: usize, op: Op) -> Result<(), WasmError> {
+        let (a, b) = (h - 2, h - 1);
+        let absorbing = if op == Op::Or { 1 } else { 0 };
+        let code = op_code(&op)?;
+        self.open(Instruction::Block(BlockType::Empty));
+        for &slot in &[a, b] {
+            self.is(slot, TAG_BOOL);
+            self.emit(Instruction::LocalGet(bits(slot)));
+            self.emit(Instruction::I64Const(absorbing));
+            self.emit(Instruction::I64Eq);
+            self.emit(Instruction::I32And);
+        }
+        self.emit(Instruction::I32Or);
+        self.open(Instruction::If(BlockType::Empty));
+        self.set(a, TAG_BOOL, absorbing);
+        self.emit(Instruction::Br(1));
+        self.close();
+        self.case(&[a, b], TAG_BOOL, |cg| {
+            cg.set(a, TAG_BOOL, 1 - absorbing);
+        });
+        self.guarded(a, &[a, b], |cg| {
+            cg.error_kinds(a, INVALID_TYPES, a, Some(b), code);
+        });
+        self.close();
+        Ok(())
+    }
+
+    fn equality(&mut self, h: usize) {
+        let (a, b) = (h - 2, h - 1);
+        self.guarded(a, &[a, b], |cg| {
+            // Doubles compare as floats, so that e.g. `-0.0 == 0.0`.
+            cg.is(a, TAG_DOUBLE);
+            cg.open(Instruction::If(BlockType::Result(ValType::I32)));
+            cg.double(a);
+            cg.double(b);
+            cg.emit(Instruction::F64Eq);
+            cg.emit(Instruction::Else);
+            cg.emit(Instruction::LocalGet(bits(a)));
+            cg.emit(Instruction::LocalGet(bits(b)));
+            cg.emit(Instruction::I64Eq);
+            cg.close();
+            cg.emit(Instruction::LocalGet(tag(a)));
+            cg.emit(Instruction::LocalGet(tag(b)));
+            cg.emit(Instruction::I32Eq);
+            cg.emit(Instruction::I32And);
+            cg.emit(Instruction::I64ExtendI32U);
+            cg.emit(Instruction::LocalSet(bits(a)));
+            cg.emit(Instruction::I32Const(TAG_BOOL));
+            cg.emit(Instruction::LocalSet(tag(a)));
+        });
+    }
+
+    /// Compares the left operand, the lower of the two, against the right one.
+    fn comparison(&mut self, h: usize, op: Op) -> Result<(), WasmError> {
+        let (a, b) = (h - 2, h - 1);
+        let (signed, unsigned) = match op {
+            Op::Lt => (Instruction::I64LtS, Instruction::I64LtU),
+            Op::Lte => (Instruction::I64LeS, Instruction::I64LeU),
+            Op::Gte => (Instruction::I64GeS, Instruction::I64GeU),
+            _ => (Instruction::I64GtS, Instruction::I64GtU),
+        };
+        let code = op_code(&op)?;
+        self.guarded(a, &[a, b], |cg| {
+            for (t, instruction) in [(TAG_INT, signed), (TAG_UINT, unsigned)] {
+                cg.case(&[a, b], t, |cg| {
+                    cg.emit(Instruction::LocalGet(bits(a)));
+                    cg.emit(Instruction::LocalGet(bits(b)));
+                    cg.emit(instruction);
+                    cg.emit(Instruction::I64ExtendI32U);
+                    cg.emit(Instruction::LocalSet(bits(a)));
+                    cg.emit(Instruction::I32Const(TAG_BOOL));
+                    cg.emit(Instruction::LocalSet(tag(a)));
+                });
+            }
+            cg.error_kinds(a, INVALID_TYPES, a, Some(b), code);
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Where the tests put the input records.
+    const INPUT_ADDRESS: usize = 64;
+
+    fn run(module: &Module, activation: &Activation) -> EvalResult {
+        let engine = wasmi::Engine::default();
+        let wasm = wasmi::Module::new(&engine, &module.bytes).unwrap();
+        let mut store = wasmi::Store::new(&engine, ());
+        let mut linker = wasmi::Linker::new(&engine);
+        linker
+            .func_wrap("cel", "fmod", |a: f64, b: f64| a % b)
+            .unwrap();
+        let instance = linker
+            .instantiate(&mut store, &wasm)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+        let memory = instance.get_memory(&store, "memory").unwrap();
+        let input = module.encode_input(activation).unwrap();
+        memory.write(&mut store, INPUT_ADDRESS, &input).unwrap();
+        let evaluate = instance
+            .get_typed_func::<i32, i32>(&store, "evaluate")
+            .unwrap();
+        let output = evaluate.call(&mut store, INPUT_ADDRESS as i32).unwrap();
+        let mut record = [0; RECORD_SIZE];
+        memory.read(&store, output as usize, &mut record).unwrap();
+        module.decode_result(&record).unwrap()
+    }
+
+    fn activation() -> Activation {
+        let mut activation = Activation::new();
+        activation.insert("x".to_owned(), Value::I64(3));
+        activation.insert("y".to_owned(), Value::I64(5));
+        activation.insert("imax".to_owned(), Value::I64(i64::MAX));
+        activation.insert("imin".to_owned(), Value::I64(i64::MIN));
+        activation.insert("u".to_owned(), Value::U64(7));
+        activation.insert("zero".to_owned(), Value::U64(0));
+        activation.insert("big".to_owned(), Value::U64(1 << 32));
+        activation.insert("max".to_owned(), Value::U64(u64::MAX));
+        activation.insert("d".to_owned(), Value::F64(2.5));
+        activation.insert("t".to_owned(), Value::Bool(true));
+        activation.insert("f".to_owned(), Value::Bool(false));
+        activation.insert("n".to_owned(), Value::Null);
+        activation
+    }
+
+    fn assert_agrees(source: &str) {
+        let activation = activation();
+        let mut program = crate::compile(source, activation.clone()).expect(source);
+        let module = compile(&program).unwrap();
+        assert_eq!(run(&module, &activation), *program.run(), "{}", source);
+    }
+
+    /// Checks that both the module and the interpreter evaluate `source` to
+    /// `expected`, rather than just to the same thing.
+    fn assert_evaluates(source: &str, expected: EvalResult) {
+        let activation = activation();
+        let mut program = crate::compile(source, activation.clone()).expect(source);
+        let module = compile(&program).unwrap();
+        assert_eq!(run(&module, &activation), expected, "{}", source);
+        assert_eq!(*program.run(), expected, "{}", source);
+    }
+
+    #[test]
+    fn integer_overflow() {
+        for (source, op) in [
+            ("imax + 1", Op::Plus),
+            ("imin - 1", Op::Minus),
+            ("imax * 2", Op::Times),
+            ("imin * -1", Op::Times),
+            ("imin / -1", Op::Div),
+            ("imin % -1", Op::Mod),
+            ("-imin", Op::Neg),
+        ] {
+            assert_evaluates(source, Err(Error::IntegerOverflow(op)));
+        }
+        assert_evaluates("imax - 1 + 1", Ok(Value::I64(i64::MAX)));
+        assert_evaluates("imin / 1", Ok(Value::I64(i64::MIN)));
+    }
+
+    #[test]
+    fn arithmetic() {
+        for source in &[
+            "1 + 2 * 3 - 4",
+            "7 / 2 + 7 % 3 + -7 % 3",
+            "-x * y",
+            "1.5 * d - 0.5 / d",
+            "7.5 % 2.0 + -7.5 % 2.0",
+            "-d",
+            "u * u / (u - zero) % big + big",
+            "1 / 0",
+            "u % zero",
+            "1.0 / 0.0",
+            "zero - u",
+            "max + u",
+            "big * big",
+            "zero * max",
+            "1 + u",
+            "imax + 1",
+            "imin - 1",
+            "imax - -1",
+            "imax * 2",
+            "imin * -1",
+            "-1 * imin",
+            "imax * -1",
+            "imin / -1",
+            "imin % -1",
+            "-imin",
+            "-imax",
+            "-t",
+            "x + missing",
+            "missing + 1.0",
+        ] {
+            assert_agrees(source);
+        }
+    }
+
+    #[test]
+    fn logic_and_comparisons() {
+        for source in &[
+            "!t",
+            "!x",
+            "t || x / 0 == 1",
+            "x / 0 == 1 || t",
+            "f || x",
+            "f && missing",
+            "missing && t",
+            "x && t",
+            "x == 3",
+            "x == u",
+            "0.0 == -0.0",
+            "n == null",
+            "d == d",
+            "x < y",
+            "x >= y",
+            "u <= max",
+            "big > u",
+            "y > x",
+            "x < 1.0",
+            "missing < 1",
+            "1 < missing",
+        ] {
+            assert_agrees(source);
+        }
+    }
+
+    #[test]
+    fn comparison_results() {
+        for (source, expected) in [
+            ("x < y", true),
+            ("y < x", false),
+            ("x <= y", true),
+            ("y <= x", false),
+            ("x >= y", false),
+            ("y >= x", true),
+            ("x > y", false),
+            ("y > x", true),
+            ("x <= x && x >= x", true),
+            ("imin < imax", true),
+            ("u < max", true),
+            ("max > u", true),
+            ("max <= zero", false),
+        ] {
+            assert_evaluates(source, Ok(Value::Bool(expected)));
+        }
+    }
+
+    #[test]
+    fn control_flow() {
+        for source in &[
+            "x > y ? x : y",
+            "t ? (f ? 1 : 2) : 3",
+            "x ? 1 : 2",
+            "missing ? 1 : 2",
+            "x == 3 && (y > 4 || d < 1.0)",
+            "let z = 1; z",
+        ] {
+            assert_agrees(source);
+        }
+    }
+
+    #[test]
+    fn unsupported() {
+        let program = crate::compile(r#" "a" + "b" "#, Activation::new()).unwrap();
+        assert_eq!(
+            compile(&program).unwrap_err(),
+            WasmError::UnsupportedValue(Kind::String)
+        );
+        let program = crate::compile("[1, 2].size()", Activation::new()).unwrap();
+        assert!(matches!(
+            compile(&program),
+            Err(WasmError::UnsupportedOperation(_))
+        ));
+
+        let program = crate::compile("s", Activation::new()).unwrap();
+        let module = compile(&program).unwrap();
+        let mut activation = Activation::new();
+        activation.insert("s".to_owned(), Value::String("a".to_owned()));
+        assert_eq!(
+            module.encode_input(&activation).unwrap_err(),
+            WasmError::UnsupportedValue(Kind::String)
+        );
+    }
+}
@@ -0,0 +1,167 @@
+//! Syntax highlighting for the expression editor. The text is drawn a second
+//! time behind the (transparent) textarea, split into segments that each get
+//! CSS classes for their token kind, for the bracket matching the caret's and
+//! for the parse error, if any.
+
+use cel_engine::parser::tokens::{match_brackets, tokens, Token, TokenKind};
+use cel_engine::parser::{self, Span};
+
+/// A run of text that's drawn with the same classes.
+#[derive(Debug, PartialEq)]
+pub struct Segment {
+    pub text: String,
+    pub classes: Vec<String>,
+}
+
+/// Splits `text` into segments to draw, with `caret` as a byte offset.
+pub fn highlight(text: &str, caret: Option<usize>) -> Vec<Segment> {
+    let tokens: Vec<Token> = tokens(text).collect();
+    let partners = match_brackets(&tokens, text);
+    let squiggle = error_span(text);
+
+    // The bracket the caret is on, or else the one it's just after.
+    let bracket = |at: fn(&Token) -> usize| {
+        let caret = caret?;
+        tokens
+            .iter()
+            .position(|t| t.kind == TokenKind::Bracket && at(t) == caret)
+    };
+    let mut matched = Vec::new();
+    if let Some(i) = bracket(|t| t.span.start).or_else(|| bracket(|t| t.span.end)) {
+        matched.push(i);
+        matched.extend(partners[i]);
+    }
+
+    let mut cuts = vec![0, text.len()];
+    for token in &tokens {
+        cuts.push(token.span.start);
+        cuts.push(token.span.end);
+    }
+    if let Some(span) = squiggle {
+        cuts.push(span.start.min(text.len()));
+        cuts.push(span.end.min(text.len()));
+    }
+    cuts.sort_unstable();
+    cuts.dedup();
+
+    let mut segments = Vec::new();
+    let mut next_token = 0;
+    for window in cuts.windows(2) {
+        let (start, end) = (window[0], window[1]);
+        while next_token < tokens.len() && tokens[next_token].span.end <= start {
+            next_token += 1;
+        }
+        let mut classes = Vec::new();
+        if let Some(token) = tokens.get(next_token).filter(|t| t.span.start <= start) {
+            classes.push(format!("tok-{}", token.kind.name()));
+            if token.kind == TokenKind::Bracket {
+                if matched.contains(&next_token) {
+                    classes.push("bracket-match".to_owned());
+                } else if partners[next_token].is_none() {
+                    classes.push("bracket-unmatched".to_owned());
+                }
+            }
+        }
+        if squiggle.is_some_and(|span| span.start <= start && end <= span.end) {
+            classes.push("squiggle".to_owned());
+        }
+        segments.push(Segment {
+            text: text[start..end].to_owned(),
+            classes,
+        });
+    }
+    // An error at the very end, e.g. an unfinished expression, still needs
+    // something to underline.
+    if squiggle.is_some_and(|span| span.start >= text.len()) {
+        segments.push(Segment {
+            text: " ".to_owned(),
+            classes: vec!["squiggle".to_owned()],
+        });
+    }
+    segments
+}
+
+/// Where `text` stops parsing, widened to at least one character. Empty text
+/// has nothing to point at.
+fn error_span(text: &str) -> Option<Span> {
+    if text.trim().is_empty() {
+        return None;
+    }
+    let span = parser::parse(text).err()?.span(text)?;
+    let width = text[span.start..].chars().next().map_or(1, char::len_utf8);
+    Some(Span {
+        start: span.start,
+        end: span.end.max(span.start + width),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn segments(text: &str, caret: Option<usize>) -> Vec<(String, String)> {
+        highlight(text, caret)
+            .into_iter()
+            .map(|s| (s.text, s.classes.join(" ")))
+            .collect()
+    }
+
+    fn expected(segments: &[(&str, &str)]) -> Vec<(String, String)> {
+        segments
+            .iter()
+            .map(|(text, classes)| (text.to_string(), classes.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn tokens_and_brackets() {
+        assert_eq!(
+            segments("f(x) + [1]", Some(4)),
+            expected(&[
+                ("f", "tok-identifier"),
+                ("(", "tok-bracket bracket-match"),
+                ("x", "tok-identifier"),
+                (")", "tok-bracket bracket-match"),
+                (" ", ""),
+                ("+", "tok-operator"),
+                (" ", ""),
+                ("[", "tok-bracket"),
+                ("1", "tok-int"),
+                ("]", "tok-bracket"),
+            ])
+        );
+        assert_eq!(
+            segments("[1] ", Some(0)),
+            expected(&[
+                ("[", "tok-bracket bracket-match"),
+                ("1", "tok-int"),
+                ("]", "tok-bracket bracket-match"),
+                (" ", ""),
+            ])
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            segments("(1 + )", None),
+            expected(&[
+                ("(", "tok-bracket"),
+                ("1", "tok-int"),
+                (" ", ""),
+                ("+", "tok-operator"),
+                (" ", ""),
+                (")", "tok-bracket squiggle"),
+            ])
+        );
+        assert_eq!(
+            segments("(1", None),
+            expected(&[
+                ("(", "tok-bracket bracket-unmatched"),
+                ("1", "tok-int"),
+                (" ", "squiggle"),
+            ])
+        );
+        assert_eq!(segments("", Some(0)), vec![]);
+    }
+}
@@ -1,50 +1,130 @@
-#![recursion_limit = "128"]
+#![recursion_limit = "256"]
 #![allow(dead_code)]
 
-use yew::services::ConsoleService;
+#[macro_use]
+extern crate stdweb;
+
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use stdweb::unstable::TryInto;
+use stdweb::web::event::IEvent;
+use stdweb::web::{Element, IElement};
 use yew::{html, Component, ComponentLink, Html, ShouldRender};
 
-use crate::model::EvalResult;
+use cel_engine::model::{Activation, EvalResult, Expression, Literal, Value};
+use cel_engine::parser::{self, ParseResult, Span};
+use cel_engine::stack::profile::Profile;
+use cel_engine::stack::{self, Operation, Program};
 use location::UrlLocation;
-use model::Expression;
-use parser::ParseResult;
-use stack::{Operation, Program};
+use state::State;
 
+mod editor;
 mod location;
-mod model;
-mod parser;
-mod stack;
+mod state;
+mod vars;
 
 pub struct Model {
-    console: ConsoleService,
     text: String,
+    /// The text that `ast`, `spans` and `program` were built from.
+    source: String,
+    /// The source text of the input variables, kept in the URL alongside the expression.
+    vars: String,
+    /// `vars`, parsed as the user types.
+    activation: Result<Activation, String>,
     ast: ParseResult<Expression>,
+    spans: Vec<Span>,
     location: UrlLocation,
-    program: Program,
+    /// `None` if the source didn't parse or compile; `diagnostic` says why.
+    program: Option<Program>,
+    /// `program`'s control-flow graph, which doesn't change as it runs.
+    graph: Option<Graph>,
+    diagnostic: Option<Diagnostic>,
+    hover: Option<Hover>,
+    /// How the program ran when last profiled, until it's recompiled.
+    profile: Option<Profile>,
+    /// Where the caret is in `text`, as a byte offset.
+    caret: Option<usize>,
+    /// How far the input is scrolled, so that its highlighting can follow.
+    scroll: f64,
+}
+
+/// A program's control-flow graph in Graphviz DOT, and a data URL to download
+/// it from.
+pub struct Graph {
+    dot: String,
+    download: String,
+}
+
+impl Graph {
+    fn of(program: &Program) -> Graph {
+        let dot = program.to_dot();
+        let download = format!(
+            "data:text/vnd.graphviz,{}",
+            utf8_percent_encode(&dot, NON_ALPHANUMERIC)
+        );
+        Graph { dot, download }
+    }
+}
+
+/// An error to show under the input, pointing at the part of the source it's about.
+pub struct Diagnostic {
+    message: String,
+    span: Option<Span>,
+}
+
+/// What the mouse is over, linking AST nodes to the operations compiled from them.
+#[derive(Clone, Copy)]
+pub enum Hover {
+    /// An AST node, given by the range of node indices its subtree covers.
+    Node(usize, usize),
+    Operation(usize),
 }
 
 pub enum Msg {
     TextChanged(String),
+    VarsChanged(String),
     Compile,
     Step,
+    Back,
+    Run,
+    /// Runs the program from the start, counting what every operation does.
+    Profile,
+    Reset,
+    RunTo(usize),
+    Seek(usize),
+    Hover(Option<Hover>),
+    /// The caret moved in the input, to an offset in UTF-16 code units.
+    Caret(Option<usize>),
+    Scrolled(f64),
+    /// The user went back or forward to another URL.
+    PopState,
 }
 
 impl Component for Model {
     type Message = Msg;
     type Properties = ();
 
-    fn create(_: Self::Properties, _: ComponentLink<Self>) -> Self {
+    fn create(_: Self::Properties, mut link: ComponentLink<Self>) -> Self {
         let location = UrlLocation::new();
-        let text = location.query_param.clone();
-        let ast = parser::parse(&text);
-        let program = stack::compile(ast.as_ref().unwrap().clone());
-        Model {
-            console: ConsoleService::new(),
-            text,
-            ast,
-            program,
+        location.on_pop(link.send_back(|_| Msg::PopState));
+        let state = location.state();
+        let mut model = Model {
+            text: String::new(),
+            source: String::new(),
+            vars: String::new(),
+            activation: Ok(Activation::new()),
+            ast: parser::parse(""),
+            spans: Vec::new(),
             location,
-        }
+            program: None,
+            graph: None,
+            diagnostic: None,
+            hover: None,
+            profile: None,
+            caret: None,
+            scroll: 0.0,
+        };
+        model.load(state);
+        model
     }
 
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
@@ -52,20 +132,50 @@ impl Component for Model {
             Msg::TextChanged(text) => {
                 self.text = text;
             }
+            Msg::VarsChanged(vars) => {
+                // The activation is bound at compile time, so the program
+                // has to be compiled again to see the new variables.
+                self.activation = vars::parse(&vars);
+                self.vars = vars;
+                self.compile(self.source.clone());
+                self.location.replace(&self.state());
+            }
             Msg::Compile => {
-                self.ast = parser::parse(&self.text);
-                self.location.update_route(self.text.clone());
-                match self.ast.as_ref() {
-                    Ok(expr) => {
-                        self.program = stack::compile(expr.clone());
+                self.compile(self.text.clone());
+                self.location.push(&self.state());
+            }
+            Msg::PopState => {
+                let state = self.location.state();
+                self.load(state);
+            }
+            Msg::Hover(hover) => self.hover = hover,
+            Msg::Caret(caret) => {
+                self.caret = caret.map(|utf16| parser::byte_offset(&self.text, utf16));
+            }
+            Msg::Scrolled(scroll) => self.scroll = scroll,
+            msg => {
+                let program = match self.program.as_mut() {
+                    Some(program) => program,
+                    None => return false,
+                };
+                match msg {
+                    Msg::Step => {
+                        program.step();
+                    }
+                    Msg::Back => {
+                        program.step_back();
                     }
-                    Err(e) => {
-                        self.console.error(&format!("{:?}", e));
+                    Msg::Run => program.run_to_end(),
+                    Msg::Profile => self.profile = Some(program.run_profiled()),
+                    Msg::Reset => program.reset(),
+                    Msg::RunTo(idx) => {
+                        program.run_to(idx);
                     }
+                    Msg::Seek(position) => program.seek(position),
+                    _ => unreachable!(),
                 }
-            }
-            Msg::Step => {
-                self.program.step();
+                self.diagnostic = self.runtime_diagnostic();
+                self.location.replace(&self.state());
             }
         }
         true
@@ -74,30 +184,350 @@ impl Component for Model {
     fn view(&self) -> Html<Self> {
         html! {
             <div id="main">
+                <div id="editors">
+                    {self.view_input()}
+                    {view_vars(&self.vars, &self.activation)}
+                </div>
+
+                <nav id="menu">
+                  <button onclick=|_| Msg::Compile>{ "Compile" }</button>
+                  <button onclick=|_| Msg::Reset>{ "Reset" }</button>
+                  <button onclick=|_| Msg::Back>{ "Back" }</button>
+                  <button onclick=|_| Msg::Step>{ "Step" }</button>
+                  <button onclick=|_| Msg::Run>{ "Run" }</button>
+                  <button onclick=|_| Msg::Profile>{ "Profile" }</button>
+                </nav>
+
+                {view_diagnostic(&self.source, self.diagnostic.as_ref())}
+
+                {self.view_workspace()}
+            </div>
+        }
+    }
+}
+
+impl Model {
+    /// The expression editor: a textarea with transparent text, over the same
+    /// text highlighted.
+    fn view_input(&self) -> Html<Model> {
+        html! {
+            <div id="input-editor">
+                <pre class="highlight"
+                     aria-hidden="true"
+                     style=format!("transform: translateY({}px)", -self.scroll)>
+                    { for editor::highlight(&self.text, self.caret).into_iter().map(|segment| html! {
+                        <span class=segment.classes.join(" ")>{ segment.text }</span>
+                    }) }
+                    // A trailing newline in the textarea starts a line, which
+                    // it doesn't in a `pre` without something after it.
+                    { "\n" }
+                </pre>
                 <textarea id="input"
                           rows=5
+                          spellcheck="false"
                           value=&self.text
                           oninput=|e| Msg::TextChanged(e.value)
+                          onkeyup=|e| Msg::Caret(selection_start(&e))
+                          onclick=|e| Msg::Caret(selection_start(&e))
+                          onscroll=|e| Msg::Scrolled(scroll_top(&e))
                           placeholder="1 + 1">
                 </textarea>
+            </div>
+        }
+    }
 
-                <nav id="menu">
-                  <button onclick=|_| Msg::Compile>{ "Compile" }</button>
-                  <button onclick=|_| Msg::Step>{ "Step" }</button>
-                </nav>
+    fn view_workspace(&self) -> Html<Model> {
+        let program = match &self.program {
+            Some(program) => program,
+            None => return html! {},
+        };
+        html! {
+            <>
+                {view_timeline(program)}
+                <div id="workspace">
+                    {view_ast(&self.ast, program, self.hover)}
+                    {view_program(program, self.hover, self.profile.as_ref())}
+                </div>
+                {view_profile(self.profile.as_ref())}
+                {view_graph(self.graph.as_ref())}
+            </>
+        }
+    }
 
-                {view_program(&self.program)}
-            </div>
+    /// Shows `state`, e.g. from the URL: compiles its expression and runs up
+    /// to its step.
+    fn load(&mut self, state: State) {
+        self.text = state.expr.clone();
+        self.activation = vars::parse(&state.vars);
+        self.vars = state.vars;
+        self.compile(state.expr);
+        if let (Some(program), Some(step)) = (self.program.as_mut(), state.step) {
+            program.seek(step);
+            self.diagnostic = self.runtime_diagnostic();
+        }
+    }
+
+    /// The state to put in the URL.
+    fn state(&self) -> State {
+        State {
+            expr: self.source.clone(),
+            vars: self.vars.clone(),
+            step: self
+                .program
+                .as_ref()
+                .map(Program::position)
+                .filter(|&step| step > 0),
+        }
+    }
+
+    /// Parses and compiles `source`, replacing the current program. Failures
+    /// leave no program and a diagnostic in its place.
+    fn compile(&mut self, source: String) {
+        self.hover = None;
+        self.profile = None;
+        self.program = None;
+        self.graph = None;
+        self.diagnostic = None;
+        self.spans = Vec::new();
+        match parser::parse_with_spans(&source) {
+            Ok((expr, spans)) => {
+                self.diagnostic = match &self.activation {
+                    Ok(activation) => match stack::compile_with(expr.clone(), activation.clone()) {
+                        Ok(program) => {
+                            self.graph = Some(Graph::of(&program));
+                            self.program = Some(program.recording());
+                            None
+                        }
+                        Err(e) => Some(Diagnostic {
+                            message: e.to_string(),
+                            span: spans.get(e.node()).copied(),
+                        }),
+                    },
+                    Err(e) => Some(Diagnostic {
+                        message: format!("invalid variables: {}", e),
+                        span: None,
+                    }),
+                };
+                self.ast = Ok(expr);
+                self.spans = spans;
+            }
+            Err(e) => {
+                self.diagnostic = Some(Diagnostic {
+                    message: e.to_string(),
+                    span: e.span(&source),
+                });
+                self.ast = Err(e);
+            }
         }
+        self.source = source;
     }
+
+    /// Once the program has finished with an error, points at the expression
+    /// the error came from.
+    fn runtime_diagnostic(&self) -> Option<Diagnostic> {
+        let program = self.program.as_ref()?;
+        if program.pointer < program.operations.len() {
+            return None;
+        }
+        let e = match program.stack.last() {
+            Some(Err(e)) => e,
+            _ => return None,
+        };
+        let span = program
+            .error_origin()
+            .and_then(|op| program.origins.get(op))
+            .and_then(|&node| self.spans.get(node))
+            .copied();
+        Some(Diagnostic {
+            message: e.to_string(),
+            span,
+        })
+    }
+}
+
+/// Where the caret is in the textarea that `event` happened in, in UTF-16
+/// code units.
+fn selection_start(event: &impl IEvent) -> Option<usize> {
+    let target = event.target()?;
+    let start: u32 = js!(return @{target}.selectionStart;).try_into().ok()?;
+    Some(start as usize)
+}
+
+fn scroll_top(event: &impl IEvent) -> f64 {
+    let element: Option<Element> = event.target().and_then(|t| t.try_into().ok());
+    element.map_or(0.0, |e| e.scroll_top())
 }
 
-fn view_program(program: &Program) -> Html<Model> {
+/// The variables editor, with the names it binds (or why it doesn't parse) underneath.
+fn view_vars(vars: &str, activation: &Result<Activation, String>) -> Html<Model> {
+    let summary = match activation {
+        Ok(activation) => html! {
+            <ul class="bindings">
+                { for vars::describe(activation).into_iter().map(|(name, kind)| html! {
+                    <li>{ format!("{}: {}", name, kind) }</li>
+                }) }
+            </ul>
+        },
+        Err(e) => html! { <div class="bindings-error">{ e }</div> },
+    };
+    html! {
+        <div id="vars">
+            <textarea rows=5
+                      value=vars
+                      oninput=|e| Msg::VarsChanged(e.value)
+                      placeholder=r#"{"name": "value"}"#>
+            </textarea>
+            { summary }
+        </div>
+    }
+}
+
+/// Shows the message and the source with the offending span marked.
+fn view_diagnostic(source: &str, diagnostic: Option<&Diagnostic>) -> Html<Model> {
+    let diagnostic = match diagnostic {
+        Some(d) => d,
+        None => return html! {},
+    };
+    let excerpt = match diagnostic.span {
+        Some(span) => {
+            let before = source.get(..span.start).unwrap_or(source);
+            let marked = source.get(span.start..span.end).unwrap_or("");
+            let after = source.get(span.end..).unwrap_or("");
+            // An empty span (e.g. unexpected end of input) still gets a visible marker.
+            let marked = if marked.is_empty() { " " } else { marked };
+            html! {
+                <pre>{ before }<mark>{ marked }</mark>{ after }</pre>
+            }
+        }
+        None => html! {},
+    };
+    html! {
+        <div id="diagnostic">
+            <div class="diagnostic-message">{ &diagnostic.message }</div>
+            { excerpt }
+        </div>
+    }
+}
+
+fn view_ast(ast: &ParseResult<Expression>, program: &Program, hover: Option<Hover>) -> Html<Model> {
+    match ast {
+        Ok(expr) => html! {
+            <div id="ast">
+                {view_ast_node(expr, 0, &program.origins, hover)}
+            </div>
+        },
+        Err(_) => html! { <div id="ast"></div> },
+    }
+}
+
+/// Renders `expr`, whose pre-order index is `id`, and its descendants as a
+/// collapsible tree.
+fn view_ast_node(
+    expr: &Expression,
+    id: usize,
+    origins: &[usize],
+    hover: Option<Hover>,
+) -> Html<Model> {
+    let end = id + expr.node_count();
+    let highlighted = match hover {
+        Some(Hover::Node(start, _)) => start == id,
+        Some(Hover::Operation(op)) => origins.get(op) == Some(&id),
+        None => false,
+    };
+    let class = if highlighted {
+        "ast-node highlighted"
+    } else {
+        "ast-node"
+    };
+    let children = expr.children();
+    if children.is_empty() {
+        return html! {
+            <div class=class
+                 onmouseover=|_| Msg::Hover(Some(Hover::Node(id, end)))
+                 onmouseout=|_| Msg::Hover(None)>
+                { node_label(expr) }
+            </div>
+        };
+    }
+    let mut child_ids = Vec::with_capacity(children.len());
+    let mut next = id + 1;
+    for child in &children {
+        child_ids.push(next);
+        next += child.node_count();
+    }
+    html! {
+        <details open=true>
+            <summary class=class
+                     onmouseover=|_| Msg::Hover(Some(Hover::Node(id, end)))
+                     onmouseout=|_| Msg::Hover(None)>
+                { node_label(expr) }
+            </summary>
+            { for children.iter().zip(child_ids).map(|(child, child_id)| view_ast_node(child, child_id, origins, hover)) }
+        </details>
+    }
+}
+
+fn node_label(expr: &Expression) -> String {
+    match expr {
+        Expression::LetBinding { id, .. } => format!("let {}", id.0),
+        Expression::Ternary { .. } => "?:".to_owned(),
+        Expression::Or(_) => "||".to_owned(),
+        Expression::And(_) => "&&".to_owned(),
+        Expression::Eq(_, _) => "==".to_owned(),
+        Expression::Neq(_, _) => "!=".to_owned(),
+        Expression::Lt(_, _) => "<".to_owned(),
+        Expression::Lte(_, _) => "<=".to_owned(),
+        Expression::Gte(_, _) => ">=".to_owned(),
+        Expression::Gt(_, _) => ">".to_owned(),
+        Expression::Add(_, _) => "+".to_owned(),
+        Expression::Sub(_, _) | Expression::Neg(_) => "-".to_owned(),
+        Expression::Mul(_, _) => "*".to_owned(),
+        Expression::Div(_, _) => "/".to_owned(),
+        Expression::Mod(_, _) => "%".to_owned(),
+        Expression::Not(_) => "!".to_owned(),
+        Expression::Member(_, id) => format!(".{}", id.0),
+        Expression::OptionalMember(_, id) => format!(".?{}", id.0),
+        Expression::OptionalIndex(_, _) => "[?]".to_owned(),
+        Expression::Has(_, id) => format!("has(.{})", id.0),
+        Expression::Method(_, id, _) => format!(".{}()", id.0),
+        Expression::FunctionCall(id, _) => format!("{}()", id.0),
+        Expression::Binding(id) => id.0.clone(),
+        Expression::Lit(lit) => match lit {
+            Literal::I64(n) => Value::I64(*n).to_string(),
+            Literal::F64(x) => Value::F64(*x).to_string(),
+            Literal::Bool(b) => Value::Bool(*b).to_string(),
+            Literal::String(s) => Value::String(s.clone()).to_string(),
+            Literal::Bytes(bs) => Value::Bytes(bs.clone()).to_string(),
+            Literal::Null => Value::Null.to_string(),
+            Literal::List(_) => "[...]".to_owned(),
+            Literal::Map(_) => "{...}".to_owned(),
+            Literal::Message(name, _) => format!("{}{{...}}", name),
+        },
+    }
+}
+
+fn view_program(program: &Program, hover: Option<Hover>, profile: Option<&Profile>) -> Html<Model> {
+    // Operations are shaded by how often they ran, relative to the busiest one.
+    let busiest = profile
+        .and_then(|p| p.operations.iter().map(|c| c.executions).max())
+        .unwrap_or(0)
+        .max(1);
     html! {
       <div id="program">
         <div id="operations">
             <div id="pointer"></div>
-            { for program.operations.iter().enumerate().map(|(idx, op)| view_op(op, idx as i32 - program.pointer as i32)) }
+            { for program.operations.iter().enumerate().map(|(idx, op)| {
+                let highlighted = match hover {
+                    Some(Hover::Node(start, end)) => (start..end).contains(&program.origins[idx]),
+                    Some(Hover::Operation(other)) => other == idx,
+                    None => false,
+                };
+                let heat = profile.map(|p| {
+                    let counts = p.operations[idx];
+                    (counts.executions as f64 / busiest as f64, counts.executions, counts.traffic)
+                });
+                view_op(op, idx, program.pointer, highlighted, heat)
+            }) }
         </div>
         <ol id="stack">
             { for program.stack.iter().rev().map(view_stack_value) }
@@ -106,13 +536,100 @@ fn view_program(program: &Program) -> Html<Model> {
     }
 }
 
-fn view_op(op: &Operation, idx: i32) -> Html<Model> {
+/// Totals by kind of operation, busiest first, and the whole profile as JSON
+/// to download.
+fn view_profile(profile: Option<&Profile>) -> Html<Model> {
+    let profile = match profile {
+        Some(profile) => profile,
+        None => return html! {},
+    };
+    let mut kinds: Vec<_> = profile
+        .kinds
+        .iter()
+        .filter(|(_, c)| c.executions > 0)
+        .collect();
+    kinds.sort_by_key(|(_, c)| std::cmp::Reverse(c.executions));
+    let download = format!(
+        "data:application/json,{}",
+        utf8_percent_encode(&profile.to_json(), NON_ALPHANUMERIC)
+    );
+    html! {
+        <div id="profile">
+            <table>
+                <tr><th>{ "operation" }</th><th>{ "runs" }</th><th>{ "bytes moved" }</th></tr>
+                { for kinds.into_iter().map(|(kind, counts)| html! {
+                    <tr><td>{ kind }</td><td>{ counts.executions }</td><td>{ counts.traffic }</td></tr>
+                }) }
+            </table>
+            <a href=download download="profile.json">{ "Download as JSON" }</a>
+        </div>
+    }
+}
+
+/// The program's control-flow graph as Graphviz DOT, with a link to download it.
+fn view_graph(graph: Option<&Graph>) -> Html<Model> {
+    let graph = match graph {
+        Some(graph) => graph,
+        None => return html! {},
+    };
+    html! {
+        <details id="graph">
+            <summary>{ "Control flow" }</summary>
+            <a href=graph.download.as_str() download="program.dot">{ "Download as DOT" }</a>
+            <pre>{ &graph.dot }</pre>
+        </details>
+    }
+}
+
+fn view_timeline(program: &Program) -> Html<Model> {
+    let last = program.history().len() - 1;
+    html! {
+        <div id="timeline">
+            <input type="range"
+                   min=0
+                   max=last
+                   value=program.position()
+                   oninput=|e| Msg::Seek(e.value.parse().unwrap_or(0))>
+            </input>
+            <span>{ format!("step {} of {}", program.position(), last) }</span>
+        </div>
+    }
+}
+
+/// Clicking an operation runs (or rewinds) the program up to it. With a
+/// profile, `heat` is how busy it was relative to the busiest operation, how
+/// often it ran and how much data it moved.
+fn view_op(
+    op: &Operation,
+    idx: usize,
+    pointer: usize,
+    highlighted: bool,
+    heat: Option<(f64, u64, u64)>,
+) -> Html<Model> {
+    let offset = idx as i32 - pointer as i32;
+    let mut style = format!("transform: translate({}px)", 64 * offset);
+    let mut tooltip = op.tooltip();
+    if let Some((heat, executions, traffic)) = heat {
+        if !highlighted {
+            style.push_str(&format!(
+                "; background-color: rgba(239, 68, 68, {:.2})",
+                heat
+            ));
+        }
+        tooltip.push_str(&format!(
+            " (ran {} times, moved {} bytes)",
+            executions, traffic
+        ));
+    }
     html! {
-        <div class=("stack-operation", "tooltip")
-             style=format!("transform: translate({}px)", 64*idx)>
+        <div class=("stack-operation", "tooltip", if highlighted { "highlighted" } else { "" })
+             style=style
+             onclick=|_| Msg::RunTo(idx)
+             onmouseover=|_| Msg::Hover(Some(Hover::Operation(idx)))
+             onmouseout=|_| Msg::Hover(None)>
            <div class="tooltip">
             { op.short() }
-            <div class="tooltiptext">{ op.tooltip() }</div>
+            <div class="tooltiptext">{ tooltip }</div>
            </div>
         </div>
     }
@@ -124,7 +641,7 @@ fn view_stack_value(v: &EvalResult) -> Html<Model> {
                    if v.is_ok() { "stack-value-ok" } else { "stack-value-err"})>
            { match v {
                Ok(v) => format!("{}", v),
-               Err(e) => format!("{:?}", e),
+               Err(e) => e.to_string(),
            } }
         </li>
     }
@@ -1,35 +1,49 @@
-use stdweb::js;
-use stdweb::web::window;
-use stdweb::web::History;
-use stdweb::web::Location;
+use stdweb::web::event::PopStateEvent;
+use stdweb::web::{window, History, IEventTarget};
+use yew::Callback;
 
+use crate::state::State;
+
+const DEFAULT_EXPR: &str = "1 + 1";
+
+/// Keeps the playground's `State` in the browser's URL and history.
 pub struct UrlLocation {
-    pub query_param: String,
-    location: Location,
     history: History,
 }
 
 impl UrlLocation {
     pub fn new() -> UrlLocation {
-        let location = window().location().expect("How is this possible?");
-        let queries = location.search().unwrap();
-        let query_param = queries.split("%22").nth(1).unwrap_or("1 + 1");
-        let decoded = js! {
-            return decodeURIComponent(@{query_param});
-        }
-        .into_string()
-        .expect("yo man");
-
         UrlLocation {
-            location,
-            query_param: decoded,
             history: window().history(),
         }
     }
 
-    pub fn update_route(&mut self, query: String) {
-        let full_query = format!("?expr=\"{}\"", query);
-        self.query_param = query.clone();
-        self.history.push_state("", "", Some(&full_query));
+    /// The state in the current URL, or a default one if it's missing or malformed.
+    pub fn state(&self) -> State {
+        window()
+            .location()
+            .and_then(|location| location.search().ok())
+            .and_then(|query| State::from_query(&query))
+            .unwrap_or_else(|| State {
+                expr: DEFAULT_EXPR.to_owned(),
+                ..State::default()
+            })
+    }
+
+    /// Adds a history entry for `state`.
+    pub fn push(&mut self, state: &State) {
+        self.history.push_state("", "", Some(&state.to_query()));
+    }
+
+    /// Updates the current history entry to `state`, e.g. as the user steps
+    /// through a program.
+    pub fn replace(&mut self, state: &State) {
+        // This only fails for cross-origin URLs, which a query string can't be.
+        let _ = self.history.replace_state("", "", Some(&state.to_query()));
+    }
+
+    /// Calls `callback` whenever the user navigates back or forward.
+    pub fn on_pop(&self, callback: Callback<()>) {
+        window().add_event_listener(move |_: PopStateEvent| callback.emit(()));
     }
 }
@@ -0,0 +1,188 @@
+//! The playground state that's kept in the URL, so that a link reproduces what
+//! its author was looking at.
+//!
+//! The query string is an ordinary form-encoded `?expr=...&vars=...&step=...`.
+//! Once that gets long it's deflated and base64-encoded into a single `?z=...`
+//! parameter instead.
+//!
+//! Older links wrapped the expression in quotes, as in
+//! `?expr=%221%20%2B%201%22`, and those still open as they did.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// Plain queries longer than this are compressed.
+const COMPRESS_OVER: usize = 256;
+/// Refuse to inflate anything bigger than this, in case of a hostile link.
+const INFLATE_LIMIT: usize = 1 << 20;
+const COMPRESSION_LEVEL: u8 = 9;
+
+// The characters that `encodeURIComponent` leaves alone, minus the ones that are
+// awkward to copy out of a URL.
+const COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct State {
+    pub expr: String,
+    /// The source text of the input variables.
+    pub vars: String,
+    /// How many steps into the program's history to show.
+    pub step: Option<usize>,
+}
+
+impl State {
+    /// Encodes the state as a query string, including the leading `?`.
+    pub fn to_query(&self) -> String {
+        let mut params = vec![format!("expr={}", encode(&self.expr))];
+        if !self.vars.is_empty() {
+            params.push(format!("vars={}", encode(&self.vars)));
+        }
+        if let Some(step) = self.step {
+            params.push(format!("step={}", step));
+        }
+        let plain = params.join("&");
+        if plain.len() <= COMPRESS_OVER && !is_legacy(&self.expr) {
+            return format!("?{}", plain);
+        }
+        let compressed = compress_to_vec(plain.as_bytes(), COMPRESSION_LEVEL);
+        format!("?z={}", URL_SAFE_NO_PAD.encode(compressed))
+    }
+
+    /// The inverse of `to_query`. Returns `None` if there's no expression or
+    /// anything is malformed.
+    pub fn from_query(query: &str) -> Option<State> {
+        let query = query.trim_start_matches('?');
+        match param(query, "z") {
+            Some(z) => {
+                let compressed = URL_SAFE_NO_PAD.decode(z).ok()?;
+                let plain = decompress_to_vec_with_limit(&compressed, INFLATE_LIMIT).ok()?;
+                State::from_plain(std::str::from_utf8(&plain).ok()?, false)
+            }
+            None => State::from_plain(query, true),
+        }
+    }
+
+    /// Parses `query`, or `legacy` if it's a plain one that might be from a
+    /// link of the old format.
+    fn from_plain(query: &str, legacy: bool) -> Option<State> {
+        let mut expr = decode(param(query, "expr")?)?;
+        if legacy && is_legacy(&expr) {
+            expr = expr[1..expr.len() - 1].to_owned();
+        }
+        let vars = match param(query, "vars") {
+            Some(vars) => decode(vars)?,
+            None => String::new(),
+        };
+        let step = match param(query, "step") {
+            Some(step) => Some(step.parse().ok()?),
+            None => None,
+        };
+        Some(State { expr, vars, step })
+    }
+}
+
+/// Whether a plain query's `expr` would be taken for an old, quoted one. Such
+/// expressions are always compressed so that they come back as they were.
+fn is_legacy(expr: &str) -> bool {
+    expr.len() >= 2 && expr.starts_with('"') && expr.ends_with('"')
+}
+
+fn param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|kv| {
+        let mut parts = kv.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(k), Some(v)) if k == name => Some(v),
+            _ => None,
+        }
+    })
+}
+
+fn encode(s: &str) -> String {
+    utf8_percent_encode(s, COMPONENT).to_string()
+}
+
+/// Form decoding, so `+` is a space as well as `%20`.
+fn decode(s: &str) -> Option<String> {
+    let s = s.replace('+', " ");
+    percent_decode_str(&s)
+        .decode_utf8()
+        .ok()
+        .map(|s| s.into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state(expr: &str, vars: &str, step: Option<usize>) -> State {
+        State {
+            expr: expr.to_owned(),
+            vars: vars.to_owned(),
+            step,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for s in [
+            state("1 + 1", "", None),
+            state(r#""say \"hi\"" + '%22' & x"#, r#"{"x": "é"}"#, Some(3)),
+            state("a == b ? c : d", "", Some(0)),
+            state(r#""quoted""#, "", None),
+            state(r#""a" + "b""#, "", None),
+        ] {
+            assert_eq!(State::from_query(&s.to_query()), Some(s));
+        }
+    }
+
+    #[test]
+    fn plain_queries_are_readable() {
+        assert_eq!(
+            state("x + 1", "{}", Some(2)).to_query(),
+            "?expr=x%20%2B%201&vars=%7B%7D&step=2"
+        );
+        assert_eq!(
+            State::from_query("?step=1&expr=1+%2B+2"),
+            Some(state("1 + 2", "", Some(1)))
+        );
+    }
+
+    #[test]
+    fn long_queries_are_compressed() {
+        let s = state(&"[1, 2, 3] + ".repeat(100), r#"{"a": 1}"#, Some(12));
+        let query = s.to_query();
+        assert!(query.starts_with("?z="));
+        assert!(query.len() < 200, "{} is too long", query.len());
+        assert_eq!(State::from_query(&query), Some(s));
+    }
+
+    #[test]
+    fn legacy_queries() {
+        assert_eq!(
+            State::from_query("?expr=%221%20%2B%201%22"),
+            Some(state("1 + 1", "", None))
+        );
+        assert_eq!(
+            State::from_query(r#"?expr="[1,%202].size()""#),
+            Some(state("[1, 2].size()", "", None))
+        );
+        assert_eq!(State::from_query("?expr=%22%22"), Some(state("", "", None)));
+        assert!(state(r#""hi""#, "", None).to_query().starts_with("?z="));
+    }
+
+    #[test]
+    fn malformed_queries() {
+        assert_eq!(State::from_query(""), None);
+        assert_eq!(State::from_query("?vars=%7B%7D"), None);
+        assert_eq!(State::from_query("?expr=%FF"), None);
+        assert_eq!(State::from_query("?expr=1&step=x"), None);
+        assert_eq!(State::from_query("?z=not-deflate"), None);
+    }
+}
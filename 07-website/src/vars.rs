@@ -0,0 +1,71 @@
+//! The playground's input variables: the activation that identifiers in the
+//! expression are looked up in.
+
+use cel_engine::model::{Activation, Value};
+use cel_engine::{parser, stack};
+
+/// Parses the variables, written either as a JSON object or as a CEL
+/// expression that evaluates to a map, e.g. `{"ttl": 60 * 60}`. Empty
+/// text binds nothing.
+pub fn parse(text: &str) -> Result<Activation, String> {
+    if text.trim().is_empty() {
+        return Ok(Activation::new());
+    }
+    let value = match serde_json::from_str::<Value>(text) {
+        Ok(value) => value,
+        Err(_) => eval(text)?,
+    };
+    match value {
+        Value::Map(kvs) => Ok(kvs),
+        other => Err(format!(
+            "variables must be a map, not {}",
+            other.kind().type_name()
+        )),
+    }
+}
+
+fn eval(text: &str) -> Result<Value, String> {
+    let expr = parser::parse(text).map_err(|e| e.to_string())?;
+    let mut program = stack::compile(expr).map_err(|e| e.to_string())?;
+    program.run().clone().map_err(|e| e.to_string())
+}
+
+/// The bound names and the type of each, sorted by name.
+pub fn describe(activation: &Activation) -> Vec<(&str, &'static str)> {
+    let mut bindings: Vec<(&str, &'static str)> = activation
+        .iter()
+        .map(|(name, v)| (name.as_str(), v.kind().type_name()))
+        .collect();
+    bindings.sort();
+    bindings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn json_and_cel() {
+        let json = parse(r#"{"user": {"name": "alice"}, "n": 1}"#).unwrap();
+        assert_eq!(describe(&json), vec![("n", "int"), ("user", "map")]);
+        let cel = parse(r#"{"n": 1, "xs": [1, 2, 3 * 4], "s": b"abc".size() > 2}"#).unwrap();
+        assert_eq!(
+            describe(&cel),
+            vec![("n", "int"), ("s", "bool"), ("xs", "list")]
+        );
+        assert_eq!(parse("  ").unwrap(), Activation::new());
+    }
+
+    #[test]
+    fn invalid_variables() {
+        assert_eq!(
+            parse("[1, 2]"),
+            Err("variables must be a map, not list".to_owned())
+        );
+        assert_eq!(parse("{"), Err("parse error @ L1:2".to_owned()));
+        assert_eq!(
+            parse("{\"a\": nope}"),
+            Err("no such variable nope".to_owned())
+        );
+    }
+}